@@ -0,0 +1,320 @@
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, ConfigError, ConfigSection};
+use crate::input_mapping::{ButtonCombo, Profile};
+use crate::mapping_layers::{Layer, LayerMode};
+use crate::processing::ProcessingStage;
+use crate::universal_gamepad::{Button, UniversalGamepad};
+
+/// The functions of the right half, reached with the shift and the left half
+const ONE_HANDED_LEFT: &str = "
+[profile one_handed_left]
+sticks.right.x = sticks.left.x
+sticks.right.y = sticks.left.y
+sticks.right.pressed = sticks.left.pressed
+main.upper = dpad.up
+main.lower = dpad.down
+main.left = dpad.left
+main.right = dpad.right
+bumpers.right = bumpers.left
+triggers.right = triggers.left
+specials.right = specials.left
+disable = sticks.left.x, sticks.left.y, sticks.left.pressed, dpad.up, dpad.down, dpad.left, dpad.right, bumpers.left, triggers.left, specials.left
+";
+
+/// The functions of the left half, reached with the shift and the right half
+const ONE_HANDED_RIGHT: &str = "
+[profile one_handed_right]
+sticks.left.x = sticks.right.x
+sticks.left.y = sticks.right.y
+sticks.left.pressed = sticks.right.pressed
+dpad.up = main.upper
+dpad.down = main.lower
+dpad.left = main.left
+dpad.right = main.right
+bumpers.left = bumpers.right
+triggers.left = triggers.right
+specials.left = specials.right
+disable = sticks.right.x, sticks.right.y, sticks.right.pressed, main.upper, main.lower, main.left, main.right, bumpers.right, triggers.right, specials.right
+";
+
+/// Sticky modifier: a tap latches the button until the next press of another button is over
+struct StickyButton {
+    button: Button,
+    was_pressed: bool,
+
+    /// another button was pressed while this one was held, so it is a normal chord
+    used_in_chord: bool,
+
+    /// this press releases the latch instead of setting it
+    cancelling: bool,
+    latched: bool,
+
+    /// another button was pressed while latched, the latch ends when it is released
+    chord_pending: bool,
+}
+
+impl StickyButton {
+    fn new(button: Button) -> Self {
+        Self {
+            button,
+            was_pressed: false,
+            used_in_chord: false,
+            cancelling: false,
+            latched: false,
+            chord_pending: false,
+        }
+    }
+
+    fn update(&mut self, is_pressed: bool, others_pressed: bool) -> bool {
+        if is_pressed && self.was_pressed == false {
+            self.used_in_chord = false;
+            self.cancelling = self.latched;
+            self.latched = false;
+            self.chord_pending = false;
+        }
+        if is_pressed && others_pressed {
+            self.used_in_chord = true;
+        }
+        if is_pressed == false && self.was_pressed && self.used_in_chord == false && self.cancelling == false {
+            self.latched = true;
+        }
+        self.was_pressed = is_pressed;
+
+        if self.latched {
+            if others_pressed {
+                self.chord_pending = true;
+            } else if self.chord_pending {
+                self.latched = false;
+                self.chord_pending = false;
+            }
+        }
+
+        return is_pressed || self.latched;
+    }
+}
+
+/// Hold-to-toggle: a long press latches the button until it is pressed again, short presses stay normal
+struct HoldToggleButton {
+    button: Button,
+    pressed_since: Option<Instant>,
+    latched: bool,
+}
+
+impl HoldToggleButton {
+    fn update(&mut self, is_pressed: bool, hold_time: Duration, now: Instant) -> bool {
+        match (is_pressed, self.pressed_since) {
+            (true, None) => {
+                self.pressed_since = Some(now);
+                self.latched = false;
+            }
+            (true, Some(since)) if now - since >= hold_time => self.latched = true,
+            (false, Some(_)) => self.pressed_since = None,
+            _ => {}
+        }
+
+        return is_pressed || self.latched;
+    }
+}
+
+/// Accessibility modes for players that can't hold several inputs at once, configured in the selected `[profile <name>]`
+///
+/// Works on the physical buttons, so the shift of a one-handed layout can be sticky as well
+pub struct Accessibility {
+    /// presses of the same button within this time after it was released are ignored
+    repeat_suppression: Option<Duration>,
+
+    /// for every button in the order of `Button::ALL`: last physical release and if the current press is ignored
+    last_release: Vec<Option<Instant>>,
+    suppressed: Vec<bool>,
+    was_pressed: Vec<bool>,
+
+    hold_toggle_time: Duration,
+    hold_toggle: Vec<HoldToggleButton>,
+    sticky: Vec<StickyButton>,
+}
+
+impl Accessibility {
+    /// Reads the accessibility keys of a profile section, returns `Ok(None)` if none is set:
+    /// - `repeat_suppression = <ms>`
+    /// - `hold_to_toggle = <button>, ...` with `hold_to_toggle_time = <ms>` (default 500)
+    /// - `sticky = <button>, ...`
+    pub fn from_section(section: &ConfigSection) -> Result<Option<Self>, ConfigError> {
+        let repeat_suppression: Option<Duration> = section.get_parsed::<u64>("repeat_suppression")?.map(Duration::from_millis);
+        let hold_toggle_time: Duration = Duration::from_millis(section.get_parsed::<u64>("hold_to_toggle_time")?.unwrap_or(500));
+
+        let hold_toggle: Vec<HoldToggleButton> = _buttons_from_section(section, "hold_to_toggle")?
+            .into_iter()
+            .map(|button| HoldToggleButton {
+                button,
+                pressed_since: None,
+                latched: false,
+            })
+            .collect();
+        let sticky: Vec<StickyButton> = _buttons_from_section(section, "sticky")?.into_iter().map(StickyButton::new).collect();
+
+        if repeat_suppression.is_none() && hold_toggle.is_empty() && sticky.is_empty() {
+            return Ok(None);
+        }
+
+        return Ok(Some(Self {
+            repeat_suppression,
+            last_release: vec![None; Button::ALL.len()],
+            suppressed: vec![false; Button::ALL.len()],
+            was_pressed: vec![false; Button::ALL.len()],
+            hold_toggle_time,
+            hold_toggle,
+            sticky,
+        }));
+    }
+
+    /// Finds the section of the selected profile
+    pub fn from_config(config: &Config, profile: &Profile) -> Result<Option<Self>, ConfigError> {
+        match config.section("profile", &profile.name) {
+            Some(section) => return Self::from_section(section),
+            None => return Ok(None),
+        }
+    }
+
+    /// Ignores presses that follow the last release of the same button too closely
+    fn _suppress_repeats(&mut self, gamepad: &mut UniversalGamepad, window: Duration, now: Instant) {
+        for (index, button) in Button::ALL.into_iter().enumerate() {
+            let is_pressed: bool = gamepad.button(button);
+
+            if is_pressed && self.was_pressed[index] == false {
+                self.suppressed[index] = self.last_release[index].is_some_and(|released| now - released < window);
+            }
+            if is_pressed == false && self.was_pressed[index] {
+                self.last_release[index] = Some(now);
+                self.suppressed[index] = false;
+            }
+            self.was_pressed[index] = is_pressed;
+
+            if self.suppressed[index] {
+                gamepad.set_button(button, false);
+            }
+        }
+    }
+}
+
+/// Buttons listed with `key = <button>, <button>, ...`
+fn _buttons_from_section(section: &ConfigSection, key: &str) -> Result<Vec<Button>, ConfigError> {
+    match section.get(key) {
+        Some(text) => match ButtonCombo::parse(text) {
+            Some(combo) => return Ok(combo.buttons),
+            None => return Err(section.error(format!("invalid buttons for {key}: {text}"))),
+        },
+        None => return Ok(Vec::new()),
+    }
+}
+
+impl ProcessingStage for Accessibility {
+    fn display_name(&self) -> &'static str {
+        return "Accessibility";
+    }
+
+    fn process(&mut self, gamepad: &mut UniversalGamepad, now: Instant) {
+        if let Some(window) = self.repeat_suppression {
+            self._suppress_repeats(gamepad, window, now);
+        }
+
+        for hold_toggle in self.hold_toggle.iter_mut() {
+            let output: bool = hold_toggle.update(gamepad.button(hold_toggle.button), self.hold_toggle_time, now);
+            gamepad.set_button(hold_toggle.button, output);
+        }
+
+        // sticky buttons are combined with each other, so only the other buttons end a latch
+        let sticky_buttons: Vec<Button> = self.sticky.iter().map(|sticky| sticky.button).collect();
+        let others_pressed: bool = Button::ALL
+            .into_iter()
+            .any(|button| sticky_buttons.contains(&button) == false && gamepad.button(button));
+        for sticky in self.sticky.iter_mut() {
+            let output: bool = sticky.update(gamepad.button(sticky.button), others_pressed);
+            gamepad.set_button(sticky.button, output);
+        }
+    }
+}
+
+/// The layer of a one-handed layout, read from the profile section of `base`:
+/// - `one_handed = left` or `one_handed = right`: the hand that plays
+/// - `one_handed_shift = <button>, ...` (default `touchpad.pressed`): while held, the playing half takes over the functions of the other half
+///
+/// Returns `Ok(None)` if `one_handed` is not set
+pub fn one_handed_layer(config: &Config, base: &Profile) -> Result<Option<Layer>, ConfigError> {
+    let section: &ConfigSection = match config.section("profile", &base.name) {
+        Some(section) => section,
+        None => return Ok(None),
+    };
+
+    let preset: &str = match section.get("one_handed") {
+        Some("left") => ONE_HANDED_LEFT,
+        Some("right") => ONE_HANDED_RIGHT,
+        Some(other) => return Err(section.error(format!("one_handed has to be left or right, not {other}"))),
+        None => return Ok(None),
+    };
+    let shift: ButtonCombo = ButtonCombo::from_section(section, "one_handed_shift")?.unwrap_or(ButtonCombo {
+        buttons: vec![Button::TouchpadPressed],
+    });
+
+    let preset_config: Config = Config::parse(preset)?;
+    let preset_profile: Profile = Profile::from_section(&preset_config.sections[0], &[])?;
+
+    return Ok(Some(Layer::new(base.overlaid(&preset_profile), shift, LayerMode::Hold)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sticky_button_latches_until_the_next_press_is_over() {
+        let mut sticky = StickyButton::new(Button::BumperLeft);
+
+        // tapped alone: latched
+        assert!(sticky.update(true, false));
+        assert!(sticky.update(false, false));
+
+        // the next press of another button is combined with it, its release ends the latch
+        assert!(sticky.update(false, true));
+        assert!(sticky.update(false, false) == false);
+
+        // held together with another button like a normal chord: not latched
+        assert!(sticky.update(true, true));
+        assert!(sticky.update(false, false) == false);
+
+        // tapped twice: the second tap cancels the latch
+        sticky.update(true, false);
+        sticky.update(false, false);
+        assert!(sticky.update(true, false));
+        assert!(sticky.update(false, false) == false);
+    }
+
+    #[test]
+    fn repeats_are_suppressed_and_long_presses_toggle() {
+        let config = Config::parse("[profile test]\nrepeat_suppression = 100\nhold_to_toggle = main.lower\nhold_to_toggle_time = 300\n").unwrap();
+        let mut accessibility = Accessibility::from_config(&config, &Profile::identity("test")).unwrap().unwrap();
+        let start = Instant::now();
+        let mut frame = |main_right: bool, main_lower: bool, ms: u64| -> (bool, bool) {
+            let mut gamepad = UniversalGamepad::nothing_pressed();
+            gamepad.buttons.main.right = main_right;
+            gamepad.buttons.main.lower = main_lower;
+            accessibility.process(&mut gamepad, start + Duration::from_millis(ms));
+            return (gamepad.buttons.main.right, gamepad.buttons.main.lower);
+        };
+
+        // a tremor press right after the release is ignored, a later one is not
+        assert_eq!(frame(true, false, 0), (true, false));
+        assert_eq!(frame(false, false, 50), (false, false));
+        assert_eq!(frame(true, false, 80), (false, false));
+        assert_eq!(frame(false, false, 100), (false, false));
+        assert_eq!(frame(true, false, 300), (true, false));
+        assert_eq!(frame(false, false, 320), (false, false));
+
+        // held long enough: stays pressed after the release until the next press ends
+        assert_eq!(frame(false, true, 1000), (false, true));
+        assert_eq!(frame(false, true, 1400), (false, true));
+        assert_eq!(frame(false, false, 1500), (false, true));
+        assert_eq!(frame(false, true, 2000), (false, true));
+        assert_eq!(frame(false, false, 2100), (false, false));
+    }
+}
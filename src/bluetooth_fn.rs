@@ -5,14 +5,17 @@ use std::{
     thread,
 };
 
+use crate::bluez::{BtDevice, BtEvent};
+
 // --------- bluetooth handling ---------
 
 // basic procedure:
 //     1. turn on bluetooth
-//     2. turn on scanning and read until e.g. "gamepad" is in name
+//     2. turn on scanning and read until a device with the class of device of a gamepad shows up
 //     3. stop scanning and connect via mac address
 
-// this is a bit unconventional but easier to implement, the alternative would be to talk to linux' bluez directly on dbus
+// BlueZ is normally asked directly over D-Bus, see bluez.rs
+// The bluetoothctl scraping in this file is only the fallback if the system bus is not reachable
 
 // bluetoothctl steps (commands)
 //     power on
@@ -69,92 +72,288 @@ pub fn bt_scan_on_threaded() -> (Arc<Mutex<Vec<String>>>, thread::JoinHandle<()>
     return (scan_output, handle);
 }
 
-/// Search the given output of `bluetoothctl scan on` for yet unknown gamepads. <br>
+/// Search the given output of `bluetoothctl scan on` for yet unknown gamepads and connect the first one. <br>
 /// Clears the output before returning.
 ///
 /// 1. Argument: `shared_mem_scan_output` = the output of the bluetooth scan, produced by `bt_scan_on_threaded()`
 pub fn handle_bt_scan_output(shared_mem_scan_output: &Arc<Mutex<Vec<String>>>) {
     let output_copy: Vec<String> = _move_from_shared_mem(shared_mem_scan_output);
 
-    // check if anything new was added and do something with it
     for line in output_copy {
-        let line_str: &str = line.as_str();
-
-        if line.contains("Discovery started") {
-            // First line of this command can be ignored
-            continue;
-        }
+        // the scan output does not contain the class of device, `bluetoothctl info` does
+        let address: String = match parse_bluetoothctl_line(&line) {
+            Some(BtEvent::NewDevice(device)) => device.address,
+            Some(BtEvent::Changed { address, class: Some(_), .. }) => address,
+            _ => continue,
+        };
 
-        let first_asci_upper: usize = match line.find(|c: char| c.is_ascii_uppercase()) {
+        let device: BtDevice = match _bluetoothctl_output(&["info", &address]).and_then(|info| parse_bluetoothctl_info(&info)) {
+            Some(device) => device,
             None => continue,
-            Some(usize) => usize,
         };
-        let log_type = &line_str[first_asci_upper..first_asci_upper + 3];
-        println!("Type: {:?}", log_type);
-
-        match log_type {
-            "NEW" => {
-                if _is_device_controller(line_str) == true {
-                    // TODO Connect to controller
-                    return;
+        if device.is_gamepad() && device.connected == false {
+            println!("Gamepad found: {} ({})", device.name.as_deref().unwrap_or("no name"), device.address);
+            bt_connect_gamepad(&device.address);
+            return;
+        }
+    }
+}
+
+/// Pairs, trusts and connects the gamepad with this mac address, see `pairing.rs` for the D-Bus version
+///
+/// Returns `false` if one of the steps failed
+pub fn bt_connect_gamepad(mac_address: &str) -> bool {
+    let mut success: bool = true;
+
+    // scanning slows down pairing
+    _bluetoothctl(&["scan", "off"]);
+    _bluetoothctl(&["pairable", "on"]);
+    for step in ["pair", "trust", "connect"] {
+        if _bluetoothctl(&[step, mac_address]) == false {
+            println!("bluetoothctl {step} {mac_address} failed");
+            success = false;
+            break;
+        }
+    }
+    _bluetoothctl(&["pairable", "off"]);
+
+    return success;
+}
+
+/// Runs one bluetoothctl command, returns if it was successful
+fn _bluetoothctl(args: &[&str]) -> bool {
+    return _bluetoothctl_output(args).is_some();
+}
+
+/// Runs one bluetoothctl command, returns its output if it was successful
+fn _bluetoothctl_output(args: &[&str]) -> Option<String> {
+    match Command::new("bluetoothctl").args(args).output() {
+        Ok(output) if output.status.success() => return Some(String::from_utf8_lossy(&output.stdout).to_string()),
+        Ok(_) => return None,
+        Err(err) => {
+            println!("bluetoothctl {:?} could not be run: {err}", args);
+            return None;
+        }
+    }
+}
+
+// --------- bluetoothctl output parsing ---------
+
+// Possible lines of `bluetoothctl scan on` (the log type is colored and the prompt may be in front of it):
+//     [NEW] Device 54:C2:8B:53:A4:3C 54-C2-8B-53-A4-3C         --> device without a name
+//     [NEW] Device A0:AB:51:12:34:56 DualSense Wireless Controller
+//     [CHG] Controller 14:F6:D8:7D:51:94 Discovering: yes
+//     [CHG] Device 6E:FF:68:D4:4D:CC RSSI: -92                 --> newer versions: RSSI: 0xffffffa4 (-92)
+//     [CHG] Device 6E:FF:68:D4:4D:CC ManufacturerData Key: 0x0075
+//     [DEL] Device 6E:FF:68:D4:4D:CC 6E-FF-68-D4-4D-CC
+
+/// One `key: value` property of a device or controller
+enum Property {
+    Name(String),
+    Rssi(i16),
+    Class(u32),
+    Appearance(u16),
+    Connected(bool),
+    Paired(bool),
+    Trusted(bool),
+    Powered(bool),
+    Discovering(bool),
+}
+
+/// Removes the color escape sequences (`ESC [ ... m`) and the readline markers `\x01` and `\x02` around them
+pub fn strip_ansi(line: &str) -> String {
+    let mut stripped: String = String::with_capacity(line.len());
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => {
+                // control sequence: parameters and intermediates, ended by a final byte between @ and ~
+                if chars.next() == Some('[') {
+                    for c in chars.by_ref() {
+                        if ('@'..='~').contains(&c) {
+                            break;
+                        }
+                    }
                 }
             }
-            "CHG" => (),
-            "DEL" => (),
-            _ => (),
+            '\x01' | '\x02' | '\r' => {}
+            c => stripped.push(c),
         }
     }
+
+    return stripped;
 }
 
-/// Check if a given output line represents a gamepad / game controller
+/// Parses one line of `bluetoothctl scan on`, returns `None` for every line that is no event this program cares about
 ///
-/// Arguments:
-/// - `output_line: &str` = One output line of the command `bluetoothctl scan on`
-fn _is_device_controller(output_line: &str) -> bool {
-    // Possible outputs
-    // [NEW] Device 54:C2:8B:53:A4:3C 54-C2-8B-53-A4-3C         --> irrelevant
-    // [NEW] Device 54:C2:8B:53:A4:3C Name of Device            --> THIS is interesting
-    // [CHG] Controller 14:F6:D8:7D:51:94 Discovering: yes      --> everything that starts with Controller can be discharged
-    // [CHG] Device 6E:FF:68:D4:4D:CC RSSI: -92                 --> irrelevant
-    // [CHG] Device 6E:FF:68:D4:4D:CC TxPower: 17               --> irrelevant
-    // [DEL]
-
-    // the contents of [] are a bit messed up because of the colors:
-    // [\u{1}\u{1b}[0;93m\u{2}CHG\u{1}\u{1b}[0m\u{2}]
-    // [\u{1}\u{1b}[0;92m\u{2}NEW\u{1}\u{1b}[0m\u{2}]
-
-    // Cut off the log type
-    let index_next_whitespace: usize = match output_line.find(|c: char| c.is_whitespace()) {
-        None => return false,
-        Some(usize) => usize,
+/// `NewDevice` only knows address and name, the class of device has to be asked for with `bluetoothctl info`
+pub fn parse_bluetoothctl_line(line: &str) -> Option<BtEvent> {
+    let line: String = strip_ansi(line);
+
+    // the prompt (e.g. `[bluetooth]# `) might be printed in front of the log type
+    let (log_type, rest) = ["[NEW] ", "[CHG] ", "[DEL] "]
+        .into_iter()
+        .find_map(|log_type| line.find(log_type).map(|index| (log_type, &line[index + log_type.len()..])))?;
+
+    let (object, rest) = rest.split_once(' ')?;
+    let (address, rest) = match rest.split_once(' ') {
+        Some((address, rest)) => (address, rest.trim()),
+        None => (rest.trim(), ""),
     };
-    let line_str = &output_line[index_next_whitespace + 1..];
+    if _is_mac_address(address) == false {
+        return None;
+    }
 
-    // get the descriptor and cut it off
-    let (descriptor, line_str) = match line_str.split_once(char::is_whitespace) {
-        Some((extracted, remainder)) => (extracted, remainder),
-        None => return false,
-    };
-    println!("descriptor: {:?}", &descriptor);
-    if descriptor != "Device" {
-        println!("");
-        return false;
+    match (log_type, object) {
+        ("[NEW] ", "Device") => {
+            // devices without a name are shown with their address
+            let name: Option<String> = match rest.is_empty() || rest == address.replace(':', "-") {
+                true => None,
+                false => Some(rest.to_string()),
+            };
+            return Some(BtEvent::NewDevice(BtDevice {
+                address: address.to_string(),
+                name,
+                class: None,
+                appearance: None,
+                rssi: None,
+                paired: false,
+                trusted: false,
+                connected: false,
+            }));
+        }
+        ("[DEL] ", "Device") => return Some(BtEvent::Deleted { address: address.to_string() }),
+        ("[CHG] ", "Device") => {
+            // one property per line, all others stay unchanged
+            let (mut rssi, mut name, mut class, mut appearance) = (None, None, None, None);
+            let (mut connected, mut paired, mut trusted) = (None, None, None);
+            match _parse_property(rest)? {
+                Property::Rssi(value) => rssi = Some(value),
+                Property::Name(value) => name = Some(value),
+                Property::Class(value) => class = Some(value),
+                Property::Appearance(value) => appearance = Some(value),
+                Property::Connected(value) => connected = Some(value),
+                Property::Paired(value) => paired = Some(value),
+                Property::Trusted(value) => trusted = Some(value),
+                Property::Powered(_) | Property::Discovering(_) => return None,
+            }
+            return Some(BtEvent::Changed {
+                address: address.to_string(),
+                rssi,
+                name,
+                class,
+                appearance,
+                connected,
+                paired,
+                trusted,
+            });
+        }
+        ("[CHG] ", "Controller") => match _parse_property(rest)? {
+            Property::Powered(powered) => {
+                return Some(BtEvent::AdapterChanged {
+                    powered: Some(powered),
+                    discovering: None,
+                })
+            }
+            Property::Discovering(discovering) => {
+                return Some(BtEvent::AdapterChanged {
+                    powered: None,
+                    discovering: Some(discovering),
+                })
+            }
+            _ => return None,
+        },
+        _ => return None,
+    }
+}
+
+/// Parses the output of `bluetoothctl info <address>`:
+///
+/// ```text
+/// Device A0:AB:51:12:34:56 (public)
+///     Name: DualSense Wireless Controller
+///     Class: 0x00002508
+///     Paired: no
+///     ...
+/// ```
+pub fn parse_bluetoothctl_info(output: &str) -> Option<BtDevice> {
+    let output: String = strip_ansi(output);
+    let mut lines = output.lines().map(|line| line.trim()).filter(|line| line.is_empty() == false);
+
+    let address: &str = lines.next()?.strip_prefix("Device ")?.split(' ').next()?;
+    if _is_mac_address(address) == false {
+        return None;
     }
 
-    // get mac address and device name
-    let (mac_address, device_name) = match line_str.split_once(char::is_whitespace) {
-        Some((extracted, remainder)) => (extracted, remainder),
-        None => return false,
+    let mut device = BtDevice {
+        address: address.to_string(),
+        name: None,
+        class: None,
+        appearance: None,
+        rssi: None,
+        paired: false,
+        trusted: false,
+        connected: false,
     };
-    println!("mac: {:?}", &mac_address);
-    println!("device_name: {:?}", &device_name);
-    println!("");
+    for line in lines {
+        match _parse_property(line) {
+            Some(Property::Name(name)) => device.name = Some(name),
+            Some(Property::Rssi(rssi)) => device.rssi = Some(rssi),
+            Some(Property::Class(class)) => device.class = Some(class),
+            Some(Property::Appearance(appearance)) => device.appearance = Some(appearance),
+            Some(Property::Connected(connected)) => device.connected = connected,
+            Some(Property::Paired(paired)) => device.paired = paired,
+            Some(Property::Trusted(trusted)) => device.trusted = trusted,
+            _ => {}
+        }
+    }
 
-    if device_name.contains(" controller") || device_name.contains(" Controller") {
-        return true;
+    return Some(device);
+}
+
+/// `RSSI: -60`, `Class: 0x00002508`, `Connected: yes`, ... Unknown keys and invalid values are `None`
+fn _parse_property(text: &str) -> Option<Property> {
+    let (key, value) = text.split_once(':')?;
+    let value: &str = value.trim();
+
+    match key.trim() {
+        "Name" if value.is_empty() == false => return Some(Property::Name(value.to_string())),
+        "RSSI" => return i16::try_from(_parse_number(value)?).ok().map(Property::Rssi),
+        "Class" => return u32::try_from(_parse_number(value)?).ok().map(Property::Class),
+        "Appearance" => return u16::try_from(_parse_number(value)?).ok().map(Property::Appearance),
+        "Connected" => return _parse_yes_no(value).map(Property::Connected),
+        "Paired" => return _parse_yes_no(value).map(Property::Paired),
+        "Trusted" => return _parse_yes_no(value).map(Property::Trusted),
+        "Powered" => return _parse_yes_no(value).map(Property::Powered),
+        "Discovering" => return _parse_yes_no(value).map(Property::Discovering),
+        _ => return None,
     }
+}
 
-    return false;
+/// `-60`, `0x00002508`, or `0xffffffc4 (-60)` where the decimal value in brackets is the signed one
+fn _parse_number(value: &str) -> Option<i64> {
+    if let Some((_, bracketed)) = value.split_once('(') {
+        return bracketed.strip_suffix(')')?.trim().parse::<i64>().ok();
+    }
+    match value.strip_prefix("0x") {
+        Some(hex) => return i64::from_str_radix(hex, 16).ok(),
+        None => return value.parse::<i64>().ok(),
+    }
+}
+
+fn _parse_yes_no(value: &str) -> Option<bool> {
+    match value {
+        "yes" => return Some(true),
+        "no" => return Some(false),
+        _ => return None,
+    }
+}
+
+/// `A0:AB:51:12:34:56`
+fn _is_mac_address(text: &str) -> bool {
+    let parts: Vec<&str> = text.split(':').collect();
+    return parts.len() == 6 && parts.iter().all(|part| part.len() == 2 && part.chars().all(|c| c.is_ascii_hexdigit()));
 }
 
 /// turn bluetoothctl scanning on and write output without buffering into file with param `output_file_name` <br>
@@ -225,3 +424,106 @@ fn _move_from_shared_mem(shared_memory: &Arc<Mutex<Vec<String>>>) -> Vec<String>
 
     // locks are released after a block goes out of sope
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Captured from `stdbuf -o0 bluetoothctl scan on` (BlueZ 5.66 and 5.72), including the colors
+    const SCAN_OUTPUT: [&str; 9] = [
+        "Discovery started",
+        "[\u{1}\u{1b}[0;93m\u{2}CHG\u{1}\u{1b}[0m\u{2}] Controller 14:F6:D8:7D:51:94 Discovering: yes",
+        "[\u{1}\u{1b}[0;92m\u{2}NEW\u{1}\u{1b}[0m\u{2}] Device 54:C2:8B:53:A4:3C 54-C2-8B-53-A4-3C",
+        "[\u{1}\u{1b}[0;92m\u{2}NEW\u{1}\u{1b}[0m\u{2}] Device A0:AB:51:12:34:56 DualSense Wireless Controller",
+        "[\u{1}\u{1b}[0;93m\u{2}CHG\u{1}\u{1b}[0m\u{2}] Device 6E:FF:68:D4:4D:CC RSSI: -92",
+        "\r\u{1b}[K[bluetooth]# [\u{1}\u{1b}[0;93m\u{2}CHG\u{1}\u{1b}[0m\u{2}] Device 6E:FF:68:D4:4D:CC RSSI: 0xffffffc4 (-60)",
+        "[\u{1}\u{1b}[0;93m\u{2}CHG\u{1}\u{1b}[0m\u{2}] Device 6E:FF:68:D4:4D:CC ManufacturerData Key: 0x0075",
+        "[\u{1}\u{1b}[0;93m\u{2}CHG\u{1}\u{1b}[0m\u{2}] Device A0:AB:51:12:34:56 Connected: yes",
+        "[\u{1}\u{1b}[0;91m\u{2}DEL\u{1}\u{1b}[0m\u{2}] Device 6E:FF:68:D4:4D:CC 6E-FF-68-D4-4D-CC",
+    ];
+
+    const INFO_OUTPUT: &str = "Device A0:AB:51:12:34:56 (public)
+\tName: DualSense Wireless Controller
+\tAlias: DualSense Wireless Controller
+\tClass: 0x00002508
+\tIcon: input-gaming
+\tPaired: no
+\tTrusted: no
+\tBlocked: no
+\tConnected: no
+\tLegacyPairing: no
+\tUUID: Human Interface Device... (00001124-0000-1000-8000-00805f9b34fb)
+\tRSSI: -45
+";
+
+    #[test]
+    fn scan_output_becomes_typed_events() {
+        let events: Vec<Option<BtEvent>> = SCAN_OUTPUT.iter().map(|line| parse_bluetoothctl_line(line)).collect();
+
+        assert_eq!(events[0], None);
+        assert_eq!(
+            events[1],
+            Some(BtEvent::AdapterChanged {
+                powered: None,
+                discovering: Some(true)
+            })
+        );
+        match &events[2] {
+            Some(BtEvent::NewDevice(device)) => assert_eq!((device.address.as_str(), &device.name), ("54:C2:8B:53:A4:3C", &None)),
+            other => panic!("expected a new device without a name, got {:?}", other),
+        }
+        match &events[3] {
+            Some(BtEvent::NewDevice(device)) => {
+                assert_eq!(device.name.as_deref(), Some("DualSense Wireless Controller"));
+                assert!(device.is_gamepad() == false, "the name alone does not make a gamepad");
+            }
+            other => panic!("expected a new device, got {:?}", other),
+        }
+
+        assert!(matches!(&events[4], Some(BtEvent::Changed { address, rssi: Some(-92), connected: None, .. }) if address == "6E:FF:68:D4:4D:CC"));
+        assert!(
+            matches!(&events[5], Some(BtEvent::Changed { rssi: Some(-60), .. })),
+            "prompt in front and hex value with the signed one in brackets"
+        );
+        assert_eq!(events[6], None);
+        assert!(matches!(
+            &events[7],
+            Some(BtEvent::Changed {
+                connected: Some(true),
+                rssi: None,
+                ..
+            })
+        ));
+        assert_eq!(
+            events[8],
+            Some(BtEvent::Deleted {
+                address: "6E:FF:68:D4:4D:CC".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn broken_lines_are_ignored_and_info_recognizes_gamepads() {
+        for line in [
+            "",
+            "[",
+            "[NEW]",
+            "[NEW] Device",
+            "[NEW] Device A0:AB",
+            "[CHG] Device A0:AB:51:12:34:56 RSSI: loud",
+            "[DEL] Device ü",
+        ] {
+            assert_eq!(parse_bluetoothctl_line(line), None, "{line:?}");
+        }
+
+        let device = parse_bluetoothctl_info(INFO_OUTPUT).unwrap();
+        assert_eq!(device.address, "A0:AB:51:12:34:56");
+        assert_eq!(device.class, Some(0x2508));
+        assert_eq!(device.rssi, Some(-45));
+        assert!(device.is_gamepad());
+
+        let keyboard = parse_bluetoothctl_info("Device 11:22:33:44:55:66 (public)\n\tName: Gamepad Controller Keyboard\n\tClass: 0x00002540\n").unwrap();
+        assert!(keyboard.is_gamepad() == false);
+        assert_eq!(parse_bluetoothctl_info("Device not-an-address\n"), None);
+    }
+}
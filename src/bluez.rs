@@ -0,0 +1,503 @@
+use std::time::{Duration, Instant};
+
+use crate::dbus::{DbusConnection, DbusError, DbusMessage, DbusValue};
+
+// BlueZ on the system bus, see https://git.kernel.org/pub/scm/bluetooth/bluez.git/tree/doc
+//  - every adapter is an object /org/bluez/hciX with the interface org.bluez.Adapter1
+//  - every known device is an object /org/bluez/hciX/dev_AA_BB_CC_DD_EE_FF with the interface org.bluez.Device1
+//  - the root object / has the interface org.freedesktop.DBus.ObjectManager, which announces added and removed objects
+
+pub const BLUEZ_SERVICE: &str = "org.bluez";
+const ADAPTER_INTERFACE: &str = "org.bluez.Adapter1";
+const DEVICE_INTERFACE: &str = "org.bluez.Device1";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+const OBJECT_MANAGER_INTERFACE: &str = "org.freedesktop.DBus.ObjectManager";
+
+/// Major device class "Peripheral" in bits 8 - 12 of the class of device
+const CLASS_MAJOR_PERIPHERAL: u32 = 0x05;
+
+/// Minor device classes of peripherals in bits 2 - 5: joystick and gamepad
+const CLASS_MINOR_JOYSTICK: u32 = 0x01;
+const CLASS_MINOR_GAMEPAD: u32 = 0x02;
+
+/// Bluetooth LE appearance values of the HID category
+const APPEARANCE_JOYSTICK: u16 = 0x03C3;
+const APPEARANCE_GAMEPAD: u16 = 0x03C4;
+
+/// `true` for the class of device of gamepads and joysticks, e.g. `0x002508` of a DualSense
+pub fn is_gamepad_class(class: u32) -> bool {
+    let major: u32 = (class >> 8) & 0x1F;
+    let minor: u32 = (class >> 2) & 0x0F;
+    return major == CLASS_MAJOR_PERIPHERAL && (minor == CLASS_MINOR_JOYSTICK || minor == CLASS_MINOR_GAMEPAD);
+}
+
+pub fn is_gamepad_appearance(appearance: u16) -> bool {
+    return appearance == APPEARANCE_GAMEPAD || appearance == APPEARANCE_JOYSTICK;
+}
+
+/// A bluetooth device BlueZ knows about
+#[derive(Clone, PartialEq, Debug)]
+pub struct BtDevice {
+    /// MAC address like `A0:AB:51:12:34:56`, also the serial number hidapi reports
+    pub address: String,
+    pub name: Option<String>,
+
+    /// Class of device of classic bluetooth devices, see `is_gamepad_class()`
+    pub class: Option<u32>,
+
+    /// Appearance of bluetooth LE devices, see `is_gamepad_appearance()`
+    pub appearance: Option<u16>,
+
+    /// Signal strength in dBm, only known while discovering
+    pub rssi: Option<i16>,
+    pub paired: bool,
+    pub trusted: bool,
+    pub connected: bool,
+}
+
+impl BtDevice {
+    /// Gamepads are recognized by what they report about themselves, not by their name
+    pub fn is_gamepad(&self) -> bool {
+        return self.class.is_some_and(is_gamepad_class) || self.appearance.is_some_and(is_gamepad_appearance);
+    }
+
+    /// Reads the `a{sv}` properties of `org.bluez.Device1`, returns `None` without an address
+    fn from_properties(properties: &DbusValue) -> Option<Self> {
+        return Some(Self {
+            address: properties.get("Address")?.as_str()?.to_string(),
+            name: properties.get("Name").and_then(|name| name.as_str()).map(|name| name.to_string()),
+            class: properties.get("Class").and_then(|class| class.as_i64()).map(|class| class as u32),
+            appearance: properties.get("Appearance").and_then(|appearance| appearance.as_i64()).map(|appearance| appearance as u16),
+            rssi: properties.get("RSSI").and_then(|rssi| rssi.as_i64()).map(|rssi| rssi as i16),
+            paired: properties.get("Paired").and_then(|paired| paired.as_bool()).unwrap_or(false),
+            trusted: properties.get("Trusted").and_then(|trusted| trusted.as_bool()).unwrap_or(false),
+            connected: properties.get("Connected").and_then(|connected| connected.as_bool()).unwrap_or(false),
+        });
+    }
+}
+
+/// Something changed on the bluetooth side
+#[derive(Clone, PartialEq, Debug)]
+pub enum BtEvent {
+    /// A device was discovered or added (e.g. by pairing)
+    NewDevice(BtDevice),
+
+    /// Properties of a known device changed, unchanged ones are `None`
+    Changed {
+        address: String,
+        rssi: Option<i16>,
+        name: Option<String>,
+        class: Option<u32>,
+        appearance: Option<u16>,
+        connected: Option<bool>,
+        paired: Option<bool>,
+        trusted: Option<bool>,
+    },
+
+    /// BlueZ forgot the device, e.g. because it was not seen for a while during discovery
+    Deleted { address: String },
+
+    /// The adapter was switched on or off or started or stopped discovering
+    AdapterChanged { powered: Option<bool>, discovering: Option<bool> },
+}
+
+/// `/org/bluez/hci0/dev_A0_AB_51_12_34_56` -> `A0:AB:51:12:34:56`
+fn _address_from_path(path: &str) -> Option<String> {
+    let (_, device) = path.rsplit_once("/dev_")?;
+    if device.len() != 17 {
+        return None;
+    }
+    return Some(device.replace('_', ":"));
+}
+
+/// Talks to BlueZ on the D-Bus system bus, always uses the first adapter
+pub struct BluezClient {
+    connection: DbusConnection,
+
+    /// e.g. `/org/bluez/hci0`
+    pub adapter_path: String,
+}
+
+impl BluezClient {
+    pub fn system() -> Result<Self, DbusError> {
+        return Self::new(DbusConnection::system_bus()?);
+    }
+
+    /// Finds the adapter and subscribes to all signals of BlueZ
+    pub fn new(mut connection: DbusConnection) -> Result<Self, DbusError> {
+        let objects: DbusValue = _managed_objects(&mut connection)?;
+
+        let adapter_path: String = match objects
+            .as_array()
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|entry| match entry {
+                DbusValue::DictEntry(path, interfaces) if interfaces.get(ADAPTER_INTERFACE).is_some() => path.as_str(),
+                _ => None,
+            })
+            .min()
+        {
+            Some(path) => path.to_string(),
+            None => return Err(DbusError::Protocol("BlueZ has no bluetooth adapter".to_string())),
+        };
+
+        for member in ["InterfacesAdded", "InterfacesRemoved"] {
+            connection.add_match(&format!("type='signal',sender='{BLUEZ_SERVICE}',interface='{OBJECT_MANAGER_INTERFACE}',member='{member}'"))?;
+        }
+        connection.add_match(&format!(
+            "type='signal',sender='{BLUEZ_SERVICE}',interface='{PROPERTIES_INTERFACE}',member='PropertiesChanged'"
+        ))?;
+
+        return Ok(Self { connection, adapter_path });
+    }
+
+    /// All devices BlueZ knows about: paired ones and those seen while discovering
+    pub fn devices(&mut self) -> Result<Vec<BtDevice>, DbusError> {
+        let objects: DbusValue = _managed_objects(&mut self.connection)?;
+
+        let mut devices: Vec<BtDevice> = Vec::new();
+        for entry in objects.as_array().unwrap_or_default() {
+            if let DbusValue::DictEntry(path, interfaces) = entry {
+                let is_on_adapter: bool = path.as_str().is_some_and(|path| path.starts_with(&self.adapter_path));
+                if let Some(device) = interfaces.get(DEVICE_INTERFACE).and_then(BtDevice::from_properties) {
+                    if is_on_adapter {
+                        devices.push(device);
+                    }
+                }
+            }
+        }
+        return Ok(devices);
+    }
+
+    pub fn power_on(&mut self) -> Result<(), DbusError> {
+        let adapter_path: String = self.adapter_path.clone();
+        return self._set_property(&adapter_path, ADAPTER_INTERFACE, "Powered", DbusValue::Bool(true));
+    }
+
+    /// Other devices can only pair while the adapter is pairable
+    pub fn set_pairable(&mut self, pairable: bool) -> Result<(), DbusError> {
+        let adapter_path: String = self.adapter_path.clone();
+        return self._set_property(&adapter_path, ADAPTER_INTERFACE, "Pairable", DbusValue::Bool(pairable));
+    }
+
+    pub fn start_discovery(&mut self) -> Result<(), DbusError> {
+        let adapter_path: String = self.adapter_path.clone();
+        return self._call(&adapter_path, ADAPTER_INTERFACE, "StartDiscovery", Vec::new());
+    }
+
+    pub fn stop_discovery(&mut self) -> Result<(), DbusError> {
+        let adapter_path: String = self.adapter_path.clone();
+        return self._call(&adapter_path, ADAPTER_INTERFACE, "StopDiscovery", Vec::new());
+    }
+
+    /// Blocks until pairing finished, which takes a few seconds
+    pub fn pair(&mut self, address: &str) -> Result<(), DbusError> {
+        return self._call(&self.device_path(address), DEVICE_INTERFACE, "Pair", Vec::new());
+    }
+
+    /// Trusted devices may connect on their own, e.g. when the PS button is pressed
+    pub fn trust(&mut self, address: &str) -> Result<(), DbusError> {
+        return self._set_property(&self.device_path(address), DEVICE_INTERFACE, "Trusted", DbusValue::Bool(true));
+    }
+
+    pub fn connect(&mut self, address: &str) -> Result<(), DbusError> {
+        return self._call(&self.device_path(address), DEVICE_INTERFACE, "Connect", Vec::new());
+    }
+
+    /// Gamepads like the DualSense turn themselves off when they are disconnected
+    pub fn disconnect(&mut self, address: &str) -> Result<(), DbusError> {
+        return self._call(&self.device_path(address), DEVICE_INTERFACE, "Disconnect", Vec::new());
+    }
+
+    /// Forgets the device including its pairing
+    pub fn remove(&mut self, address: &str) -> Result<(), DbusError> {
+        let adapter_path: String = self.adapter_path.clone();
+        return self._call(&adapter_path, ADAPTER_INTERFACE, "RemoveDevice", vec![DbusValue::ObjectPath(self.device_path(address))]);
+    }
+
+    pub fn device_path(&self, address: &str) -> String {
+        return format!("{}/dev_{}", self.adapter_path, address.to_ascii_uppercase().replace(':', "_"));
+    }
+
+    /// Waits up to `timeout` for the next event, returns `Ok(None)` if nothing happened
+    pub fn next_event(&mut self, timeout: Duration) -> Result<Option<BtEvent>, DbusError> {
+        let deadline: Instant = Instant::now() + timeout;
+
+        loop {
+            let remaining: Duration = deadline.saturating_duration_since(Instant::now());
+            let signal: DbusMessage = match self.connection.next_signal(remaining)? {
+                Some(signal) => signal,
+                None => return Ok(None),
+            };
+            if let Some(event) = self._event_from_signal(&signal) {
+                return Ok(Some(event));
+            }
+        }
+    }
+
+    fn _event_from_signal(&self, signal: &DbusMessage) -> Option<BtEvent> {
+        if signal.is_signal(OBJECT_MANAGER_INTERFACE, "InterfacesAdded") {
+            let properties: &DbusValue = signal.body.get(1)?.get(DEVICE_INTERFACE)?;
+            return Some(BtEvent::NewDevice(BtDevice::from_properties(properties)?));
+        }
+
+        if signal.is_signal(OBJECT_MANAGER_INTERFACE, "InterfacesRemoved") {
+            let interfaces: &[DbusValue] = signal.body.get(1)?.as_array()?;
+            if interfaces.iter().any(|interface| interface.as_str() == Some(DEVICE_INTERFACE)) == false {
+                return None;
+            }
+            return Some(BtEvent::Deleted {
+                address: _address_from_path(signal.body.first()?.as_str()?)?,
+            });
+        }
+
+        if signal.is_signal(PROPERTIES_INTERFACE, "PropertiesChanged") {
+            let interface: &str = signal.body.first()?.as_str()?;
+            let changed: &DbusValue = signal.body.get(1)?;
+
+            if interface == ADAPTER_INTERFACE && signal.path.as_deref() == Some(self.adapter_path.as_str()) {
+                return Some(BtEvent::AdapterChanged {
+                    powered: changed.get("Powered").and_then(|powered| powered.as_bool()),
+                    discovering: changed.get("Discovering").and_then(|discovering| discovering.as_bool()),
+                });
+            }
+            if interface == DEVICE_INTERFACE {
+                return Some(BtEvent::Changed {
+                    address: _address_from_path(signal.path.as_deref()?)?,
+                    rssi: changed.get("RSSI").and_then(|rssi| rssi.as_i64()).map(|rssi| rssi as i16),
+                    name: changed.get("Name").and_then(|name| name.as_str()).map(|name| name.to_string()),
+                    class: changed.get("Class").and_then(|class| class.as_i64()).map(|class| class as u32),
+                    appearance: changed.get("Appearance").and_then(|appearance| appearance.as_i64()).map(|appearance| appearance as u16),
+                    connected: changed.get("Connected").and_then(|connected| connected.as_bool()),
+                    paired: changed.get("Paired").and_then(|paired| paired.as_bool()),
+                    trusted: changed.get("Trusted").and_then(|trusted| trusted.as_bool()),
+                });
+            }
+        }
+
+        return None;
+    }
+
+    fn _call(&mut self, path: &str, interface: &str, member: &str, body: Vec<DbusValue>) -> Result<(), DbusError> {
+        self.connection.call(DbusMessage::method_call(BLUEZ_SERVICE, path, interface, member, body))?;
+        return Ok(());
+    }
+
+    fn _set_property(&mut self, path: &str, interface: &str, name: &str, value: DbusValue) -> Result<(), DbusError> {
+        let body: Vec<DbusValue> = vec![
+            DbusValue::String(interface.to_string()),
+            DbusValue::String(name.to_string()),
+            DbusValue::Variant(Box::new(value)),
+        ];
+        return self._call(path, PROPERTIES_INTERFACE, "Set", body);
+    }
+}
+
+/// `a{oa{sa{sv}}}`: every object of BlueZ with its interfaces and their properties
+fn _managed_objects(connection: &mut DbusConnection) -> Result<DbusValue, DbusError> {
+    let call = DbusMessage::method_call(BLUEZ_SERVICE, "/", OBJECT_MANAGER_INTERFACE, "GetManagedObjects", Vec::new());
+    match connection.call(call)?.into_iter().next() {
+        Some(objects) => return Ok(objects),
+        None => return Err(DbusError::Protocol("GetManagedObjects returned nothing".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dbus::MessageType;
+    use std::io::{Read, Write};
+    use std::net::Shutdown;
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixStream};
+    use std::thread;
+
+    const ADAPTER: &str = "/org/bluez/hci0";
+    const DUALSENSE: &str = "/org/bluez/hci0/dev_A0_AB_51_12_34_56";
+
+    fn _object(path: &str, interface: &str, properties: DbusValue) -> DbusValue {
+        let interfaces = DbusValue::Array(
+            "{sa{sv}}".to_string(),
+            vec![DbusValue::DictEntry(Box::new(DbusValue::String(interface.to_string())), Box::new(properties))],
+        );
+        return DbusValue::DictEntry(Box::new(DbusValue::ObjectPath(path.to_string())), Box::new(interfaces));
+    }
+
+    fn _to_bytes(mut message: DbusMessage, serial: u32) -> Vec<u8> {
+        message.serial = serial;
+        message.sender = Some(BLUEZ_SERVICE.to_string());
+        return message.to_bytes();
+    }
+
+    /// Plays the bus and BlueZ with one adapter and one paired DualSense, another gamepad appears when discovery starts
+    fn _mock_bluez(mut stream: UnixStream) -> Vec<String> {
+        let mut line: Vec<u8> = Vec::new();
+        let mut byte: [u8; 1] = [0];
+        while line.ends_with(b"\r\n") == false {
+            stream.read_exact(&mut byte).unwrap();
+            line.push(byte[0]);
+        }
+        assert!(line.starts_with(b"\0AUTH EXTERNAL "));
+        stream.write_all(b"OK 0123456789abcdef0123456789abcdef\r\n").unwrap();
+        let mut begin: [u8; 7] = [0; 7];
+        stream.read_exact(&mut begin).unwrap();
+        assert_eq!(&begin, b"BEGIN\r\n");
+
+        return _serve_bluez(stream);
+    }
+
+    /// Answers method calls until the stream is closed, returns `<path> <member>` of every call
+    fn _serve_bluez(mut stream: UnixStream) -> Vec<String> {
+        let mut calls: Vec<String> = Vec::new();
+        let mut serial: u32 = 100;
+        while let Ok(call) = DbusMessage::read_from(&mut stream) {
+            // a real bus sends signals as well, e.g. NameAcquired
+            if call.message_type != MessageType::MethodCall {
+                continue;
+            }
+            let member: String = call.member.clone().unwrap_or_default();
+            calls.push(format!("{} {}", call.path.as_deref().unwrap_or_default(), member));
+            serial += 1;
+
+            let reply: Vec<DbusValue> = match member.as_str() {
+                "Hello" => vec![DbusValue::String(":1.7".to_string())],
+                "GetManagedObjects" => vec![DbusValue::Array(
+                    "{oa{sa{sv}}}".to_string(),
+                    vec![
+                        _object(ADAPTER, ADAPTER_INTERFACE, DbusValue::properties(vec![("Powered", DbusValue::Bool(false))])),
+                        _object(
+                            DUALSENSE,
+                            DEVICE_INTERFACE,
+                            DbusValue::properties(vec![
+                                ("Address", DbusValue::String("A0:AB:51:12:34:56".to_string())),
+                                ("Name", DbusValue::String("Wireless Controller".to_string())),
+                                ("Class", DbusValue::Uint32(0x002508)),
+                                ("Paired", DbusValue::Bool(true)),
+                            ]),
+                        ),
+                    ],
+                )],
+                _ => Vec::new(),
+            };
+            // the reply and its signals are written at once, the client may hang up as soon as it has the reply
+            let mut answer: Vec<u8> = _to_bytes(DbusMessage::method_return(&call, reply), serial);
+
+            if member == "Set" {
+                serial += 1;
+                let changed = DbusValue::properties(vec![(call.body[1].as_str().unwrap(), call.body[2].clone())]);
+                let signal = DbusMessage::signal(
+                    ADAPTER,
+                    PROPERTIES_INTERFACE,
+                    "PropertiesChanged",
+                    vec![call.body[0].clone(), changed, DbusValue::Array("s".to_string(), Vec::new())],
+                );
+                answer.extend(_to_bytes(signal, serial));
+            }
+            if member == "StartDiscovery" {
+                serial += 1;
+                let added = _object(
+                    "/org/bluez/hci0/dev_00_11_22_33_44_55",
+                    DEVICE_INTERFACE,
+                    DbusValue::properties(vec![
+                        ("Address", DbusValue::String("00:11:22:33:44:55".to_string())),
+                        ("Appearance", DbusValue::Uint16(APPEARANCE_GAMEPAD)),
+                        ("RSSI", DbusValue::Int16(-70)),
+                    ]),
+                );
+                let (path, interfaces) = match added {
+                    DbusValue::DictEntry(path, interfaces) => (*path, *interfaces),
+                    _ => unreachable!(),
+                };
+                answer.extend(_to_bytes(
+                    DbusMessage::signal("/", OBJECT_MANAGER_INTERFACE, "InterfacesAdded", vec![path, interfaces]),
+                    serial,
+                ));
+            }
+            stream.write_all(&answer).unwrap();
+        }
+
+        return calls;
+    }
+
+    /// Everything the client does with the mock, ends with `trust()`
+    fn _use_client(mut client: BluezClient) {
+        assert_eq!(client.adapter_path, ADAPTER);
+
+        let devices = client.devices().unwrap();
+        assert_eq!(devices.len(), 1);
+        assert!(devices[0].is_gamepad(), "class 0x002508 is a gamepad");
+        assert!(devices[0].paired);
+
+        client.power_on().unwrap();
+        assert_eq!(
+            client.next_event(Duration::from_secs(1)).unwrap(),
+            Some(BtEvent::AdapterChanged {
+                powered: Some(true),
+                discovering: None
+            })
+        );
+
+        client.start_discovery().unwrap();
+        match client.next_event(Duration::from_secs(1)).unwrap() {
+            Some(BtEvent::NewDevice(device)) => {
+                assert_eq!(device.address, "00:11:22:33:44:55");
+                assert_eq!(device.rssi, Some(-70));
+                assert!(device.is_gamepad(), "found by its appearance");
+            }
+            other => panic!("expected a new device, got {other:?}"),
+        }
+        assert_eq!(client.next_event(Duration::from_millis(10)).unwrap(), None);
+
+        client.trust("a0:ab:51:12:34:56").unwrap();
+    }
+
+    #[test]
+    fn client_talks_to_a_mock_bluez() {
+        let (client_side, bluez_side) = UnixStream::pair().unwrap();
+        let mock = thread::spawn(move || _mock_bluez(bluez_side));
+
+        _use_client(BluezClient::new(DbusConnection::from_stream(client_side).unwrap()).unwrap());
+
+        let calls = mock.join().unwrap();
+        assert_eq!(calls.last().map(|call| call.as_str()), Some(format!("{DUALSENSE} Set").as_str()));
+    }
+
+    /// Same as `client_talks_to_a_mock_bluez()`, but through a dbus-daemon, e.g. with `dbus-run-session -- cargo test -- --ignored`
+    ///
+    /// The mock owns the name `org.bluez` on the session bus
+    #[test]
+    #[ignore = "needs a session bus in DBUS_SESSION_BUS_ADDRESS"]
+    fn client_talks_to_a_mock_bluez_on_a_session_bus() {
+        let address: String = std::env::var("DBUS_SESSION_BUS_ADDRESS").expect("DBUS_SESSION_BUS_ADDRESS is set");
+        let socket: &str = address.split(';').next().unwrap().trim_start_matches("unix:").split(',').next().unwrap();
+        let bluez_side: UnixStream = match socket.split_once('=') {
+            Some(("path", path)) => UnixStream::connect(path).unwrap(),
+            Some(("abstract", name)) => UnixStream::connect_addr(&SocketAddr::from_abstract_name(name.as_bytes()).unwrap()).unwrap(),
+            _ => panic!("unsupported address {address}"),
+        };
+
+        // the connection authenticates and takes the name, the mock answers the calls on the same socket
+        let mut bluez_connection = DbusConnection::from_stream(bluez_side.try_clone().unwrap()).unwrap();
+        let request_name = DbusMessage::method_call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "RequestName",
+            // 4: do not queue if the name is taken
+            vec![DbusValue::String(BLUEZ_SERVICE.to_string()), DbusValue::Uint32(4)],
+        );
+        assert_eq!(
+            bluez_connection.call(request_name).unwrap(),
+            vec![DbusValue::Uint32(1)],
+            "primary owner of the name"
+        );
+        let closer: UnixStream = bluez_side.try_clone().unwrap();
+        let mock = thread::spawn(move || _serve_bluez(bluez_side));
+
+        _use_client(BluezClient::new(DbusConnection::session_bus().unwrap()).unwrap());
+
+        closer.shutdown(Shutdown::Both).unwrap();
+        let calls = mock.join().unwrap();
+        assert_eq!(calls.last().map(|call| call.as_str()), Some(format!("{DUALSENSE} Set").as_str()));
+    }
+}
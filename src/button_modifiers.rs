@@ -0,0 +1,206 @@
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, ConfigError, ConfigSection};
+use crate::processing::ProcessingStage;
+use crate::universal_gamepad::{Button, UniversalGamepad};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ButtonMode {
+    /// Presses and releases the button repeatedly while it is held
+    ///
+    /// The half period is the time the button is pressed and the time it is released in one repetition
+    Turbo { half_period: Duration },
+
+    /// One press latches the button, the next one releases it
+    Toggle,
+
+    /// Every press lasts at least `min_duration`, even if the button is released earlier
+    HoldExtend { min_duration: Duration },
+}
+
+/// The mode of one button and everything it has to remember between frames
+struct ButtonModifier {
+    button: Button,
+    mode: ButtonMode,
+
+    /// physical state in the previous frame, to detect new presses
+    was_pressed: bool,
+
+    /// when the current (or last) physical press started
+    pressed_since: Option<Instant>,
+
+    /// only used by `ButtonMode::Toggle`
+    latched: bool,
+}
+
+impl ButtonModifier {
+    fn new(button: Button, mode: ButtonMode) -> Self {
+        Self {
+            button,
+            mode,
+            was_pressed: false,
+            pressed_since: None,
+            latched: false,
+        }
+    }
+
+    /// Reads a `[button <name>]` section
+    ///
+    /// - `mode = turbo` with `rate = <presses per second>` (default 10)
+    /// - `mode = toggle`
+    /// - `mode = hold` with `min_duration = <ms>` (default 200)
+    fn from_section(section: &ConfigSection) -> Result<Self, ConfigError> {
+        let button: Button = match Button::from_name(&section.name) {
+            Some(button) => button,
+            None => return Err(section.error(format!("unknown button: {}", section.name))),
+        };
+
+        let mode: ButtonMode = match section.get("mode") {
+            Some("turbo") => {
+                let rate: f32 = section.get_parsed::<f32>("rate")?.unwrap_or(10.0);
+                if rate.is_finite() == false || rate <= 0.0 {
+                    return Err(section.error(format!("rate has to be above 0, not {rate}")));
+                }
+                match Duration::try_from_secs_f32(0.5 / rate) {
+                    Ok(half_period) => ButtonMode::Turbo { half_period },
+                    Err(_) => return Err(section.error(format!("rate is too low: {rate}"))),
+                }
+            }
+            Some("toggle") => ButtonMode::Toggle,
+            Some("hold") => ButtonMode::HoldExtend {
+                min_duration: Duration::from_millis(section.get_parsed::<u64>("min_duration")?.unwrap_or(200)),
+            },
+            Some(other) => return Err(section.error(format!("unknown mode: {other}"))),
+            None => return Err(section.error("mode is missing".to_string())),
+        };
+
+        return Ok(Self::new(button, mode));
+    }
+
+    /// Returns the state the output button should have in this frame
+    fn update(&mut self, is_pressed: bool, now: Instant) -> bool {
+        let new_press: bool = is_pressed && self.was_pressed == false;
+        self.was_pressed = is_pressed;
+
+        if new_press {
+            self.pressed_since = Some(now);
+            self.latched = !self.latched;
+        }
+
+        match self.mode {
+            ButtonMode::Turbo { half_period } => {
+                if is_pressed == false {
+                    return false;
+                }
+                let held_for: Duration = now - self.pressed_since.unwrap_or(now);
+                let half_periods: u128 = held_for.as_nanos() / half_period.as_nanos().max(1);
+
+                // starts pressed, so a short tap is never lost
+                return half_periods.is_multiple_of(2);
+            }
+            ButtonMode::Toggle => return self.latched,
+            ButtonMode::HoldExtend { min_duration } => match self.pressed_since {
+                Some(since) => return is_pressed || now - since < min_duration,
+                None => return is_pressed,
+            },
+        }
+    }
+}
+
+/// Turbo, toggle and hold-extend for any button, configured with `[button <name>]` sections
+///
+/// The button names are those the host sees, so this runs after remapping
+pub struct ButtonModifiers {
+    modifiers: Vec<ButtonModifier>,
+}
+
+impl ButtonModifiers {
+    /// Returns `Ok(None)` if no button is configured
+    pub fn from_config(config: &Config) -> Result<Option<Self>, ConfigError> {
+        let mut modifiers: Vec<ButtonModifier> = Vec::new();
+
+        for section in config.sections("button") {
+            modifiers.push(ButtonModifier::from_section(section)?);
+        }
+
+        if modifiers.is_empty() {
+            return Ok(None);
+        }
+
+        return Ok(Some(Self { modifiers }));
+    }
+}
+
+impl ProcessingStage for ButtonModifiers {
+    fn display_name(&self) -> &'static str {
+        return "Turbo, toggle and hold buttons";
+    }
+
+    fn process(&mut self, gamepad: &mut UniversalGamepad, now: Instant) {
+        for modifier in self.modifiers.iter_mut() {
+            let is_pressed: bool = gamepad.button(modifier.button);
+            let output: bool = modifier.update(is_pressed, now);
+            gamepad.set_button(modifier.button, output);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn turbo_repeats_while_held() {
+        let mut modifier = ButtonModifier::new(
+            Button::MainLower,
+            ButtonMode::Turbo {
+                half_period: Duration::from_millis(50),
+            },
+        );
+        let start = Instant::now();
+
+        assert!(modifier.update(true, start));
+        assert!(modifier.update(true, start + Duration::from_millis(40)));
+        assert!(modifier.update(true, start + Duration::from_millis(60)) == false);
+        assert!(modifier.update(true, start + Duration::from_millis(110)));
+        assert!(modifier.update(false, start + Duration::from_millis(120)) == false);
+    }
+
+    #[test]
+    fn toggle_and_hold_extend() {
+        let start = Instant::now();
+
+        let mut toggle = ButtonModifier::new(Button::BumperLeft, ButtonMode::Toggle);
+        assert!(toggle.update(true, start));
+        assert!(toggle.update(false, start));
+        assert!(toggle.update(true, start) == false);
+        assert!(toggle.update(false, start) == false);
+
+        let mut hold = ButtonModifier::new(
+            Button::MainRight,
+            ButtonMode::HoldExtend {
+                min_duration: Duration::from_millis(100),
+            },
+        );
+        assert!(hold.update(true, start));
+        assert!(hold.update(false, start + Duration::from_millis(10)));
+        assert!(hold.update(false, start + Duration::from_millis(100)) == false);
+    }
+
+    #[test]
+    fn turbo_rate_has_to_be_a_positive_number() {
+        for rate in ["0", "-5", "nan", "inf", "1e-40"] {
+            let config = Config::parse(&format!("[button main.lower]\nmode = turbo\nrate = {rate}\n")).unwrap();
+            assert!(ButtonModifiers::from_config(&config).is_err(), "rate = {rate}");
+        }
+
+        let config = Config::parse("[button main.lower]\nmode = turbo\nrate = 2\n").unwrap();
+        let modifiers = ButtonModifiers::from_config(&config).unwrap().unwrap();
+        assert_eq!(
+            modifiers.modifiers[0].mode,
+            ButtonMode::Turbo {
+                half_period: Duration::from_millis(250)
+            }
+        );
+    }
+}
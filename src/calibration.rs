@@ -0,0 +1,518 @@
+use std::f32::consts::PI;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, ConfigError, ConfigSection};
+use crate::processing::ProcessingStage;
+use crate::universal_gamepad::{Stick, UniversalGamepad};
+
+/// Used if `[calibration]` has no `directory = <path>`
+pub const DEFAULT_CALIBRATION_DIRECTORY: &str = "/var/lib/gamepad-bridge/calibration";
+
+/// The full circle is split into this many directions, each gets its own outer radius
+pub const STICK_SECTORS: usize = 16;
+
+/// How long each step of the wizard samples at least
+const WIZARD_MIN_STEP_DURATION: Duration = Duration::from_secs(2);
+
+/// Measured range of one axis, for triggers `center` is the same as `min`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AxisCalibration {
+    pub min: u8,
+    pub center: u8,
+    pub max: u8,
+}
+
+impl AxisCalibration {
+    pub fn uncalibrated_stick() -> Self {
+        Self { min: 0, center: 128, max: 255 }
+    }
+
+    pub fn uncalibrated_trigger() -> Self {
+        Self { min: 0, center: 0, max: 255 }
+    }
+
+    /// `<min> <center> <max>`
+    fn parse(text: &str) -> Option<AxisCalibration> {
+        let values: Vec<u8> = text.split_whitespace().map(|word| word.parse::<u8>().ok()).collect::<Option<Vec<u8>>>()?;
+        if values.len() != 3 || values[0] > values[1] || values[1] > values[2] || values[0] == values[2] {
+            return None;
+        }
+        return Some(AxisCalibration {
+            min: values[0],
+            center: values[1],
+            max: values[2],
+        });
+    }
+
+    fn to_text(self) -> String {
+        return format!("{} {} {}", self.min, self.center, self.max);
+    }
+
+    /// -1 - 1 for sticks, 0 - 1 for triggers
+    fn normalize(&self, value: u8) -> f32 {
+        if value < self.center {
+            return -((self.center - value) as f32 / (self.center - self.min).max(1) as f32).min(1.0);
+        }
+        return ((value - self.center) as f32 / (self.max - self.center).max(1) as f32).min(1.0);
+    }
+}
+
+/// Calibration of one stick
+#[derive(Clone, PartialEq, Debug)]
+pub struct StickCalibration {
+    pub x: AxisCalibration,
+    pub y: AxisCalibration,
+
+    /// Furthest deflection (after the axis ranges are applied) in each direction, starting at the right, counter clockwise
+    pub outer_radius: [f32; STICK_SECTORS],
+}
+
+impl StickCalibration {
+    pub fn uncalibrated() -> Self {
+        Self {
+            x: AxisCalibration::uncalibrated_stick(),
+            y: AxisCalibration::uncalibrated_stick(),
+            outer_radius: [1.0; STICK_SECTORS],
+        }
+    }
+
+    /// Smallest outer radius divided by the largest, 1 is a perfect circle
+    pub fn circularity(&self) -> f32 {
+        let smallest: f32 = self.outer_radius.iter().copied().fold(f32::MAX, f32::min);
+        let largest: f32 = self.outer_radius.iter().copied().fold(0.0, f32::max);
+        return smallest / largest.max(f32::EPSILON);
+    }
+
+    /// Centers the stick, stretches both axes to the full range and every direction to the same radius
+    pub fn apply(&self, x: u8, y: u8) -> (u8, u8) {
+        let mut x: f32 = self.x.normalize(x);
+        let mut y: f32 = self.y.normalize(y);
+
+        let magnitude: f32 = (x * x + y * y).sqrt();
+        if magnitude > f32::EPSILON {
+            let radius: f32 = _radius_in_direction(&self.outer_radius, x, y);
+            x /= radius;
+            y /= radius;
+        }
+
+        return (_to_u8(x), _to_u8(y));
+    }
+}
+
+/// Outer radius for the direction of (x, y), interpolated between the two nearest sectors
+fn _radius_in_direction(outer_radius: &[f32; STICK_SECTORS], x: f32, y: f32) -> f32 {
+    // y grows downwards
+    let angle: f32 = (-y).atan2(x).rem_euclid(2.0 * PI);
+    let position: f32 = angle / (2.0 * PI) * STICK_SECTORS as f32;
+
+    let lower: usize = position.floor() as usize % STICK_SECTORS;
+    let upper: usize = (lower + 1) % STICK_SECTORS;
+    let progress: f32 = position - position.floor();
+
+    let radius: f32 = outer_radius[lower] * (1.0 - progress) + outer_radius[upper] * progress;
+    return radius.max(0.1);
+}
+
+fn _sector(x: f32, y: f32) -> usize {
+    let angle: f32 = (-y).atan2(x).rem_euclid(2.0 * PI);
+    return (angle / (2.0 * PI) * STICK_SECTORS as f32).round() as usize % STICK_SECTORS;
+}
+
+/// -1 is 0, 1 is 255
+fn _to_u8(value: f32) -> u8 {
+    return (127.5 + value * 127.5).round().clamp(0.0, 255.0) as u8;
+}
+
+/// Calibration of all sticks and triggers of one controller, stored in `<directory>/<serial>.calibration`
+#[derive(Clone, PartialEq, Debug)]
+pub struct Calibration {
+    pub left_stick: StickCalibration,
+    pub right_stick: StickCalibration,
+    pub left_trigger: AxisCalibration,
+    pub right_trigger: AxisCalibration,
+}
+
+impl Calibration {
+    pub fn uncalibrated() -> Self {
+        Self {
+            left_stick: StickCalibration::uncalibrated(),
+            right_stick: StickCalibration::uncalibrated(),
+            left_trigger: AxisCalibration::uncalibrated_trigger(),
+            right_trigger: AxisCalibration::uncalibrated_trigger(),
+        }
+    }
+
+    /// MAC addresses contain `:`, which is replaced to get a file name that works everywhere
+    pub fn file_path(directory: &Path, serial: &str) -> PathBuf {
+        return directory.join(format!("{}.calibration", serial.to_lowercase().replace(':', "-")));
+    }
+
+    /// Same format as the config file:
+    ///
+    /// ```text
+    /// [stick left]
+    /// x = <min> <center> <max>
+    /// y = <min> <center> <max>
+    /// outer_radius = <radius> <radius> ...
+    ///
+    /// [trigger left]
+    /// range = <min> <min> <max>
+    /// ```
+    pub fn to_text(&self) -> String {
+        let mut text: String = String::from("# gamepad-bridge calibration, created by `gamepad-bridge calibrate`\n");
+
+        for (name, stick) in [("left", &self.left_stick), ("right", &self.right_stick)] {
+            let radius: Vec<String> = stick.outer_radius.iter().map(|radius| format!("{radius:.3}")).collect();
+            text += &format!("\n[stick {name}]\nx = {}\ny = {}\n", stick.x.to_text(), stick.y.to_text());
+            text += &format!("outer_radius = {}\n", radius.join(" "));
+        }
+        for (name, trigger) in [("left", &self.left_trigger), ("right", &self.right_trigger)] {
+            text += &format!("\n[trigger {name}]\nrange = {}\n", trigger.to_text());
+        }
+
+        return text;
+    }
+
+    pub fn from_text(text: &str) -> Result<Self, ConfigError> {
+        let config: Config = Config::parse(text)?;
+        let mut calibration = Self::uncalibrated();
+
+        for (name, stick) in [("left", &mut calibration.left_stick), ("right", &mut calibration.right_stick)] {
+            let section: &ConfigSection = match config.section("stick", name) {
+                Some(section) => section,
+                None => continue,
+            };
+            stick.x = _axis_from_section(section, "x")?.unwrap_or(stick.x);
+            stick.y = _axis_from_section(section, "y")?.unwrap_or(stick.y);
+
+            if let Some(text) = section.get("outer_radius") {
+                let radius: Vec<f32> = match text.split_whitespace().map(|word| word.parse::<f32>().ok()).collect::<Option<Vec<f32>>>() {
+                    Some(radius) if radius.len() == STICK_SECTORS && radius.iter().all(|radius| *radius > 0.0) => radius,
+                    _ => return Err(section.error(format!("outer_radius needs {STICK_SECTORS} values above 0"))),
+                };
+                stick.outer_radius.copy_from_slice(&radius);
+            }
+        }
+        for (name, trigger) in [("left", &mut calibration.left_trigger), ("right", &mut calibration.right_trigger)] {
+            if let Some(section) = config.section("trigger", name) {
+                *trigger = _axis_from_section(section, "range")?.unwrap_or(*trigger);
+            }
+        }
+
+        return Ok(calibration);
+    }
+
+    /// Returns `Ok(None)` if this controller has not been calibrated yet
+    pub fn load(directory: &Path, serial: &str) -> Result<Option<Self>, ConfigError> {
+        return Self::load_file(&Self::file_path(directory, serial));
+    }
+
+    /// A missing file is not an error, the controller just was not calibrated
+    pub fn load_file(path: &Path) -> Result<Option<Self>, ConfigError> {
+        if path.exists() == false {
+            return Ok(None);
+        }
+
+        match fs::read_to_string(path) {
+            Ok(text) => return Self::from_text(&text).map(Some),
+            Err(err) => return Err(ConfigError::Io(err)),
+        }
+    }
+
+    pub fn save(&self, directory: &Path, serial: &str) -> std::io::Result<()> {
+        fs::create_dir_all(directory)?;
+        return fs::write(Self::file_path(directory, serial), self.to_text());
+    }
+
+    /// `directory = <path>` of the `[calibration]` section
+    pub fn directory_from_config(config: &Config) -> PathBuf {
+        match config.section("calibration", "").and_then(|section| section.get("directory")) {
+            Some(directory) => return PathBuf::from(directory),
+            None => return PathBuf::from(DEFAULT_CALIBRATION_DIRECTORY),
+        }
+    }
+}
+
+fn _axis_from_section(section: &ConfigSection, key: &str) -> Result<Option<AxisCalibration>, ConfigError> {
+    match section.get(key) {
+        Some(text) => match AxisCalibration::parse(text) {
+            Some(axis) => return Ok(Some(axis)),
+            None => return Err(section.error(format!("invalid {key}, expected `<min> <center> <max>`: {text}"))),
+        },
+        None => return Ok(None),
+    }
+}
+
+/// Applies the stored calibration of the connected controller, runs before every other stage
+pub struct CalibrationStage {
+    pub calibration: Calibration,
+}
+
+impl CalibrationStage {
+    /// Uses the calibration file remembered for the controller, or `<directory>/<serial>.calibration` if there is none
+    ///
+    /// Returns `Ok(None)` if the controller has no serial number or was not calibrated yet
+    pub fn from_config(config: &Config, controller_serial: Option<&str>, known_calibration: Option<&Path>) -> Result<Option<Self>, ConfigError> {
+        let serial: &str = match controller_serial {
+            Some(serial) => serial,
+            None => return Ok(None),
+        };
+        let path: PathBuf = match known_calibration {
+            Some(path) => path.to_path_buf(),
+            None => Calibration::file_path(&Calibration::directory_from_config(config), serial),
+        };
+
+        match Calibration::load_file(&path)? {
+            Some(calibration) => {
+                println!("Using calibration of {serial}");
+                return Ok(Some(Self { calibration }));
+            }
+            None => return Ok(None),
+        }
+    }
+}
+
+impl ProcessingStage for CalibrationStage {
+    fn display_name(&self) -> &'static str {
+        return "Calibration";
+    }
+
+    fn process(&mut self, gamepad: &mut UniversalGamepad, _now: Instant) {
+        let sticks: [(&mut Stick, &StickCalibration); 2] = [
+            (&mut gamepad.sticks.left, &self.calibration.left_stick),
+            (&mut gamepad.sticks.right, &self.calibration.right_stick),
+        ];
+        for (stick, calibration) in sticks {
+            (stick.x, stick.y) = calibration.apply(stick.x, stick.y);
+        }
+
+        let triggers: [(&mut u8, &AxisCalibration); 2] = [
+            (&mut gamepad.triggers.left, &self.calibration.left_trigger),
+            (&mut gamepad.triggers.right, &self.calibration.right_trigger),
+        ];
+        for (trigger, calibration) in triggers {
+            *trigger = (calibration.normalize(*trigger).max(0.0) * 255.0).round() as u8;
+        }
+    }
+}
+
+// ----- Wizard
+
+/// Minimum, sum and maximum of one axis while sampling
+struct AxisSamples {
+    min: u8,
+    max: u8,
+    sum: u64,
+    count: u64,
+}
+
+impl AxisSamples {
+    fn new() -> Self {
+        Self {
+            min: 255,
+            max: 0,
+            sum: 0,
+            count: 0,
+        }
+    }
+
+    fn add(&mut self, value: u8) {
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.sum += value as u64;
+        self.count += 1;
+    }
+
+    fn average(&self) -> u8 {
+        return (self.sum / self.count.max(1)) as u8;
+    }
+}
+
+/// Reads gamepads with `read` until `main.lower` (✕) is pressed and released, but at least `WIZARD_MIN_STEP_DURATION`
+///
+/// `read` returns `None` if no report arrived in time
+fn _sample_until_confirmed(read: &mut dyn FnMut() -> Option<UniversalGamepad>, mut on_sample: impl FnMut(&UniversalGamepad)) {
+    let start: Instant = Instant::now();
+    let mut confirm_pressed: bool = false;
+
+    loop {
+        let gamepad: UniversalGamepad = match read() {
+            Some(gamepad) => gamepad,
+            None => continue,
+        };
+
+        if gamepad.buttons.main.lower {
+            confirm_pressed = true;
+            continue;
+        }
+        if confirm_pressed && start.elapsed() >= WIZARD_MIN_STEP_DURATION {
+            return;
+        }
+        confirm_pressed = false;
+
+        on_sample(&gamepad);
+    }
+}
+
+/// Walks the user through all steps and returns the measured calibration
+///
+/// `read` should return the next report of the controller, or `None` after a short timeout
+pub fn run_wizard(read: &mut dyn FnMut() -> Option<UniversalGamepad>) -> Calibration {
+    let mut calibration = Calibration::uncalibrated();
+
+    // ----- neutral
+    println!("Step 1/3: Let go of both sticks and triggers, then press and release ✕");
+    let mut neutral: [AxisSamples; 6] = [
+        AxisSamples::new(),
+        AxisSamples::new(),
+        AxisSamples::new(),
+        AxisSamples::new(),
+        AxisSamples::new(),
+        AxisSamples::new(),
+    ];
+    _sample_until_confirmed(read, |gamepad| {
+        neutral[0].add(gamepad.sticks.left.x);
+        neutral[1].add(gamepad.sticks.left.y);
+        neutral[2].add(gamepad.sticks.right.x);
+        neutral[3].add(gamepad.sticks.right.y);
+        neutral[4].add(gamepad.triggers.left);
+        neutral[5].add(gamepad.triggers.right);
+    });
+
+    calibration.left_stick.x.center = neutral[0].average();
+    calibration.left_stick.y.center = neutral[1].average();
+    calibration.right_stick.x.center = neutral[2].average();
+    calibration.right_stick.y.center = neutral[3].average();
+
+    // a trigger that does not rest at 0 would otherwise always be slightly pressed
+    for (trigger, samples) in [(&mut calibration.left_trigger, &neutral[4]), (&mut calibration.right_trigger, &neutral[5])] {
+        trigger.min = samples.max.min(254);
+        trigger.center = trigger.min;
+    }
+
+    // ----- full circle
+    println!("Step 2/3: Rotate both sticks slowly along their edge a few times, then press and release ✕");
+    let mut positions: Vec<[u8; 4]> = Vec::new();
+    let mut ranges: [AxisSamples; 4] = [AxisSamples::new(), AxisSamples::new(), AxisSamples::new(), AxisSamples::new()];
+    _sample_until_confirmed(read, |gamepad| {
+        let position: [u8; 4] = [gamepad.sticks.left.x, gamepad.sticks.left.y, gamepad.sticks.right.x, gamepad.sticks.right.y];
+        for (samples, value) in ranges.iter_mut().zip(position) {
+            samples.add(value);
+        }
+        positions.push(position);
+    });
+
+    let sticks = [(&mut calibration.left_stick, 0), (&mut calibration.right_stick, 2)];
+    for (stick, offset) in sticks {
+        for (axis, samples) in [(&mut stick.x, &ranges[offset]), (&mut stick.y, &ranges[offset + 1])] {
+            axis.min = samples.min.min(axis.center.saturating_sub(1));
+            axis.max = samples.max.max(axis.center.saturating_add(1));
+        }
+
+        let mut outer_radius: [f32; STICK_SECTORS] = [0.0; STICK_SECTORS];
+        for position in &positions {
+            let x: f32 = stick.x.normalize(position[offset]);
+            let y: f32 = stick.y.normalize(position[offset + 1]);
+            let sector: usize = _sector(x, y);
+            outer_radius[sector] = outer_radius[sector].max((x * x + y * y).sqrt());
+        }
+
+        // directions that were never reached keep the full radius
+        let unreached: usize = outer_radius.iter().filter(|radius| **radius < 0.5).count();
+        if unreached > 0 {
+            println!("{unreached} of {STICK_SECTORS} directions were not reached, they stay uncalibrated");
+        }
+        for radius in outer_radius.iter_mut() {
+            if *radius < 0.5 {
+                *radius = 1.0;
+            }
+        }
+        stick.outer_radius = outer_radius;
+    }
+
+    // ----- triggers
+    println!("Step 3/3: Pull both triggers fully a few times, then press and release ✕");
+    let mut triggers: [AxisSamples; 2] = [AxisSamples::new(), AxisSamples::new()];
+    _sample_until_confirmed(read, |gamepad| {
+        triggers[0].add(gamepad.triggers.left);
+        triggers[1].add(gamepad.triggers.right);
+    });
+    for (trigger, samples) in [(&mut calibration.left_trigger, &triggers[0]), (&mut calibration.right_trigger, &triggers[1])] {
+        trigger.max = samples.max.max(trigger.min + 1);
+    }
+
+    // ----- summary
+    for (name, stick) in [("Left", &calibration.left_stick), ("Right", &calibration.right_stick)] {
+        println!(
+            "{name} stick: center offset ({:+}, {:+}), range x {} - {}, y {} - {}, circularity {:.0}%",
+            stick.x.center as i16 - 128,
+            stick.y.center as i16 - 128,
+            stick.x.min,
+            stick.x.max,
+            stick.y.min,
+            stick.y.max,
+            stick.circularity() * 100.0
+        );
+    }
+    for (name, trigger) in [("Left", &calibration.left_trigger), ("Right", &calibration.right_trigger)] {
+        println!("{name} trigger: range {} - {}", trigger.min, trigger.max);
+    }
+
+    return calibration;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calibration_survives_the_file_format() {
+        let mut calibration = Calibration::uncalibrated();
+        calibration.left_stick.x = AxisCalibration {
+            min: 10,
+            center: 131,
+            max: 250,
+        };
+        calibration.left_stick.outer_radius[3] = 0.875;
+        calibration.right_trigger = AxisCalibration { min: 5, center: 5, max: 240 };
+
+        let parsed = Calibration::from_text(&calibration.to_text()).expect("valid calibration");
+
+        assert_eq!(parsed, calibration);
+    }
+
+    #[test]
+    fn offset_center_and_short_range_are_corrected() {
+        let mut stick = StickCalibration::uncalibrated();
+        stick.x = AxisCalibration {
+            min: 20,
+            center: 135,
+            max: 240,
+        };
+
+        assert_eq!(stick.apply(135, 128).0, 128);
+        assert_eq!(stick.apply(240, 128).0, 255);
+        assert_eq!(stick.apply(20, 128).0, 0);
+
+        let trigger = AxisCalibration { min: 8, center: 8, max: 230 };
+        assert_eq!(trigger.normalize(8), 0.0);
+        assert_eq!(trigger.normalize(230), 1.0);
+    }
+
+    #[test]
+    fn the_remembered_calibration_file_is_used() {
+        let directory: PathBuf = std::env::temp_dir().join(format!("gamepad-bridge-calibration-{}", std::process::id()));
+        let mut calibration = Calibration::uncalibrated();
+        calibration.right_trigger = AxisCalibration { min: 5, center: 5, max: 240 };
+        calibration.save(&directory, "moved").unwrap();
+        let known_calibration: PathBuf = Calibration::file_path(&directory, "moved");
+
+        // not in the calibration directory of the config, and named after another serial
+        let stage = CalibrationStage::from_config(&Config::empty(), Some("a0:ab:51:12:34:56"), Some(&known_calibration)).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(stage.map(|stage| stage.calibration), Some(calibration));
+    }
+}
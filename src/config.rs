@@ -0,0 +1,176 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// Used if no `--config <path>` is given on the command line
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/gamepad-bridge.conf";
+
+/// The config file is made of sections, each starting with a header `[kind name]`, followed by `key = value` lines.
+/// Everything after a `#` is a comment.
+///
+/// ```text
+/// [profile nintendo]
+/// main.lower = main.right
+/// main.right = main.lower
+///
+/// [persona ps5]
+/// profile = nintendo
+/// ```
+///
+/// See `doc/Configuration.md` for all sections that are understood
+pub struct Config {
+    pub sections: Vec<ConfigSection>,
+}
+
+pub struct ConfigSection {
+    /// first word of the header
+    pub kind: String,
+
+    /// everything in the header after the first word, can be empty
+    pub name: String,
+
+    /// all `key = value` lines in the order of the file, keys can appear more than once
+    pub entries: Vec<(String, String)>,
+
+    /// line number of the header, used for error messages
+    pub line: usize,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+
+    /// line number and description of the problem
+    Syntax(usize, String),
+}
+
+impl Config {
+    /// A config without any sections, every setting has its default value
+    pub fn empty() -> Self {
+        Self { sections: Vec::new() }
+    }
+
+    /// Loads the file given with `--config <path>`, or `DEFAULT_CONFIG_PATH` if none was given
+    ///
+    /// A missing default config file is not an error, but a missing file that was given explicitly is
+    pub fn from_cmdline_args() -> Result<Self, ConfigError> {
+        let args: Vec<String> = env::args().collect();
+
+        match args.iter().position(|arg| arg == "--config") {
+            Some(index) => match args.get(index + 1) {
+                Some(path) => return Self::load(Path::new(path)),
+                None => return Err(ConfigError::Syntax(0, "--config needs a path as the next argument".to_string())),
+            },
+            None => {
+                if Path::new(DEFAULT_CONFIG_PATH).exists() == false {
+                    return Ok(Self::empty());
+                }
+                return Self::load(Path::new(DEFAULT_CONFIG_PATH));
+            }
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let content: String = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) => return Err(ConfigError::Io(err)),
+        };
+
+        return Self::parse(&content);
+    }
+
+    pub fn parse(content: &str) -> Result<Self, ConfigError> {
+        let mut sections: Vec<ConfigSection> = Vec::new();
+
+        for (index, raw_line) in content.lines().enumerate() {
+            let line_number: usize = index + 1;
+
+            let line: &str = match raw_line.split_once('#') {
+                Some((before_comment, _)) => before_comment.trim(),
+                None => raw_line.trim(),
+            };
+
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                let header: &str = match line.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+                    Some(header) => header.trim(),
+                    None => return Err(ConfigError::Syntax(line_number, format!("section header is not closed: {line}"))),
+                };
+
+                let (kind, name) = match header.split_once(char::is_whitespace) {
+                    Some((kind, name)) => (kind, name.trim()),
+                    None => (header, ""),
+                };
+
+                if kind.is_empty() {
+                    return Err(ConfigError::Syntax(line_number, "section header is empty".to_string()));
+                }
+
+                sections.push(ConfigSection {
+                    kind: kind.to_string(),
+                    name: name.to_string(),
+                    entries: Vec::new(),
+                    line: line_number,
+                });
+                continue;
+            }
+
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => return Err(ConfigError::Syntax(line_number, format!("expected `key = value`: {line}"))),
+            };
+
+            match sections.last_mut() {
+                Some(section) => section.entries.push((key.to_string(), value.to_string())),
+                None => return Err(ConfigError::Syntax(line_number, "`key = value` before the first section header".to_string())),
+            }
+        }
+
+        return Ok(Self { sections });
+    }
+
+    /// All sections with the header `[kind ...]`
+    pub fn sections<'a>(&'a self, kind: &'a str) -> impl Iterator<Item = &'a ConfigSection> + 'a {
+        return self.sections.iter().filter(move |section| section.kind == kind);
+    }
+
+    /// The first section with the header `[kind name]`
+    pub fn section(&self, kind: &str, name: &str) -> Option<&ConfigSection> {
+        return self.sections.iter().find(|section| section.kind == kind && section.name == name);
+    }
+}
+
+impl ConfigSection {
+    /// The value of the last line with this key
+    pub fn get(&self, key: &str) -> Option<&str> {
+        return self
+            .entries
+            .iter()
+            .rev()
+            .find(|(entry_key, _)| entry_key == key)
+            .map(|(_, value)| value.as_str());
+    }
+
+    /// Parses the value of `key` with `str::parse()`
+    ///
+    /// Returns `Ok(None)` if the key is not set
+    pub fn get_parsed<T: std::str::FromStr>(&self, key: &str) -> Result<Option<T>, ConfigError> {
+        let value: &str = match self.get(key) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+
+        match value.parse::<T>() {
+            Ok(parsed) => return Ok(Some(parsed)),
+            Err(_) => return Err(self.error(format!("invalid value for {key}: {value}"))),
+        }
+    }
+
+    /// Creates an error pointing to the header of this section
+    pub fn error(&self, message: String) -> ConfigError {
+        return ConfigError::Syntax(self.line, format!("[{} {}] {}", self.kind, self.name, message));
+    }
+}
@@ -0,0 +1,70 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the command file, it is placed next to the status file
+pub const CONTROL_FILE_NAME: &str = "control";
+
+/// The command file in the directory of the status file, e.g. `/run/gamepad-bridge/control`
+pub fn path_from_status_path(status_path: &Path) -> PathBuf {
+    return status_path.with_file_name(CONTROL_FILE_NAME);
+}
+
+/// Takes every command that was written to the command file since the last call, one per line
+///
+/// The file is moved away before it is read, so lines that are appended meanwhile are taken with the next call.
+/// Empty lines and lines starting with `#` are skipped
+pub fn take_commands(path: &Path) -> Vec<String> {
+    let taken_path: PathBuf = path.with_extension("taken");
+    // most of the time nobody has written a command
+    if fs::rename(path, &taken_path).is_err() {
+        return Vec::new();
+    }
+
+    let text: String = match fs::read_to_string(&taken_path) {
+        Ok(text) => text,
+        Err(err) => {
+            println!("Could not read control file {:?}: {:?}", path, err);
+            String::new()
+        }
+    };
+    let _ = fs::remove_file(&taken_path);
+
+    return text
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| line.is_empty() == false && line.starts_with('#') == false)
+        .map(|line| line.to_string())
+        .collect();
+}
+
+/// Removes commands that were not taken anymore, so they are not run by the next start
+pub fn clean_up(path: &Path) {
+    let _ = fs::remove_file(path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_are_taken_once() {
+        let directory: PathBuf = std::env::temp_dir().join(format!("gamepad-bridge-control-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path: PathBuf = path_from_status_path(&directory.join("status"));
+
+        assert_eq!(take_commands(&path), Vec::<String>::new());
+
+        fs::write(
+            &path,
+            "controllers forget a0:ab:51:12:34:56\n\n# comment\n  controllers set a0:ab:51:12:34:56 name Couch pad \n",
+        )
+        .unwrap();
+        assert_eq!(
+            take_commands(&path),
+            vec!["controllers forget a0:ab:51:12:34:56", "controllers set a0:ab:51:12:34:56 name Couch pad"]
+        );
+        assert_eq!(take_commands(&path), Vec::<String>::new());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
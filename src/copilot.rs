@@ -0,0 +1,268 @@
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::calibration::CalibrationStage;
+use crate::config::{Config, ConfigError, ConfigSection};
+use crate::input_mapping::Input;
+use crate::processing::ProcessingStage;
+use crate::universal_gamepad::{Axis, Button, UniversalGamepad};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AxisMerge {
+    /// The controller with the bigger deflection wins
+    Larger,
+
+    /// Deflections of all controllers are added up
+    Sum,
+}
+
+/// How many controllers are merged and how, read from the `[copilot]` section
+#[derive(Clone, PartialEq, Debug)]
+pub struct CoPilotSettings {
+    pub controllers: usize,
+    pub axes: AxisMerge,
+}
+
+impl CoPilotSettings {
+    /// Returns `Ok(None)` if there is no `[copilot]` section
+    ///
+    /// - `controllers = <count>` (default 2)
+    /// - `axes = larger` (default) or `axes = sum`
+    pub fn from_config(config: &Config) -> Result<Option<Self>, ConfigError> {
+        let section: &ConfigSection = match config.section("copilot", "") {
+            Some(section) => section,
+            None => return Ok(None),
+        };
+
+        let controllers: usize = section.get_parsed::<usize>("controllers")?.unwrap_or(2);
+        if controllers < 2 {
+            return Err(section.error(format!("controllers has to be at least 2, not {controllers}")));
+        }
+        let axes: AxisMerge = match section.get("axes") {
+            None | Some("larger") => AxisMerge::Larger,
+            Some("sum") => AxisMerge::Sum,
+            Some(other) => return Err(section.error(format!("unknown axes: {other}"))),
+        };
+
+        return Ok(Some(Self { controllers, axes }));
+    }
+}
+
+/// One of the merged controllers
+pub struct CoPilotController {
+    /// Inputs of this controller that are ignored
+    pub masked: Vec<Input>,
+
+    /// Calibration belongs to the physical controller, so it is applied before merging
+    pub calibration: Option<CalibrationStage>,
+
+    /// `None` until the first report arrived
+    latest: Option<UniversalGamepad>,
+    latest_at: Option<Instant>,
+}
+
+impl CoPilotController {
+    /// Reads the mask from `[controller <serial>]`:
+    /// - `copilot_inputs = <input>, ...`: only these inputs are used
+    /// - `copilot_ignore = <input>, ...`: these inputs are not used
+    ///
+    /// `known_calibration` is the calibration file remembered for this controller
+    pub fn from_config(config: &Config, serial: Option<&str>, known_calibration: Option<&Path>) -> Result<Self, ConfigError> {
+        let mut controller = Self {
+            masked: Vec::new(),
+            calibration: CalibrationStage::from_config(config, serial, known_calibration)?,
+            latest: None,
+            latest_at: None,
+        };
+
+        let section: &ConfigSection = match serial.and_then(|serial| config.sections("controller").find(|section| section.name.eq_ignore_ascii_case(serial))) {
+            Some(section) => section,
+            None => return Ok(controller),
+        };
+
+        if let Some(names) = section.get("copilot_inputs") {
+            let allowed: Vec<Input> = _inputs_from_names(section, names)?;
+            controller
+                .masked
+                .extend(Input::all().into_iter().filter(|input| allowed.contains(input) == false));
+        }
+        if let Some(names) = section.get("copilot_ignore") {
+            controller.masked.extend(_inputs_from_names(section, names)?);
+        }
+
+        return Ok(controller);
+    }
+}
+
+fn _inputs_from_names(section: &ConfigSection, names: &str) -> Result<Vec<Input>, ConfigError> {
+    let mut inputs: Vec<Input> = Vec::new();
+
+    for name in names.split(',').map(|name| name.trim()).filter(|name| name.is_empty() == false) {
+        match Input::from_name(name) {
+            Some(input) => inputs.push(input),
+            None => return Err(section.error(format!("unknown input: {name}"))),
+        }
+    }
+
+    return Ok(inputs);
+}
+
+/// Merges several physical controllers into one gamepad
+///
+/// Shared by all input threads, each one updates its own controller and sends the merged result
+pub struct CoPilot {
+    pub axes: AxisMerge,
+
+    /// Controllers without a report for this long are left out, so a lost controller does not keep its buttons pressed
+    pub stale_after: Option<Duration>,
+    controllers: Mutex<Vec<CoPilotController>>,
+}
+
+impl CoPilot {
+    pub fn new(axes: AxisMerge, controllers: Vec<CoPilotController>) -> Self {
+        Self {
+            axes,
+            stale_after: None,
+            controllers: Mutex::new(controllers),
+        }
+    }
+
+    /// Replaces controller `index`, or adds it if `index` is the next unused one
+    pub fn set_controller(&self, index: usize, controller: CoPilotController) {
+        let mut controllers = match self.controllers.lock() {
+            Ok(controllers) => controllers,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        match index < controllers.len() {
+            true => controllers[index] = controller,
+            false => controllers.push(controller),
+        }
+    }
+
+    /// Stores the newest report of controller `index` and returns all controllers merged
+    ///
+    /// Touchpad, motion sensors and battery are taken from the first controller that sent a report
+    pub fn update(&self, index: usize, mut gamepad: UniversalGamepad) -> UniversalGamepad {
+        let mut controllers = match self.controllers.lock() {
+            Ok(controllers) => controllers,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        let controller: &mut CoPilotController = &mut controllers[index];
+        if let Some(calibration) = &mut controller.calibration {
+            calibration.process(&mut gamepad, Instant::now());
+        }
+        for input in &controller.masked {
+            input.release(&mut gamepad);
+        }
+        let now: Instant = Instant::now();
+        controller.latest = Some(gamepad);
+        controller.latest_at = Some(now);
+
+        let is_stale = |controller: &CoPilotController| match (self.stale_after, controller.latest_at) {
+            (Some(stale_after), Some(latest_at)) => now - latest_at > stale_after,
+            _ => false,
+        };
+        let mut latest = controllers
+            .iter()
+            .filter(|controller| is_stale(controller) == false)
+            .filter_map(|controller| controller.latest.as_ref());
+        let mut merged: UniversalGamepad = match latest.next() {
+            Some(first) => first.clone(),
+            None => return UniversalGamepad::nothing_pressed(),
+        };
+        for other in latest {
+            match self.axes {
+                AxisMerge::Larger => merged.merge(other),
+                AxisMerge::Sum => _merge_summed(&mut merged, other),
+            }
+        }
+
+        return merged;
+    }
+}
+
+/// Like `UniversalGamepad::merge()`, but the deflections of the axes are added up
+fn _merge_summed(gamepad: &mut UniversalGamepad, other: &UniversalGamepad) {
+    for button in Button::ALL {
+        if other.button(button) {
+            gamepad.set_button(button, true);
+        }
+    }
+    for axis in Axis::ALL {
+        let rest: i16 = axis.rest_value() as i16;
+        let sum: i16 = gamepad.axis(axis) as i16 + other.axis(axis) as i16 - rest;
+        gamepad.set_axis(axis, sum.clamp(0, 255) as u8);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn _controller(masked: Vec<Input>) -> CoPilotController {
+        return CoPilotController {
+            masked,
+            calibration: None,
+            latest: None,
+            latest_at: None,
+        };
+    }
+
+    #[test]
+    fn buttons_are_combined_and_masks_apply() {
+        let copilot = CoPilot::new(
+            AxisMerge::Larger,
+            vec![_controller(Vec::new()), _controller(vec![Input::Button(Button::MainLower)])],
+        );
+
+        let mut first = UniversalGamepad::nothing_pressed();
+        first.buttons.dpad.up = true;
+        copilot.update(0, first);
+
+        let mut second = UniversalGamepad::nothing_pressed();
+        second.buttons.main.lower = true;
+        second.buttons.main.right = true;
+        let merged = copilot.update(1, second);
+
+        assert!(merged.buttons.dpad.up);
+        assert!(merged.buttons.main.right);
+        assert!(merged.buttons.main.lower == false, "masked for the second controller");
+    }
+
+    #[test]
+    fn axes_follow_the_larger_deflection_or_the_sum() {
+        for (mode, expected) in [(AxisMerge::Larger, 178), (AxisMerge::Sum, 208)] {
+            let copilot = CoPilot::new(mode, vec![_controller(Vec::new()), _controller(Vec::new())]);
+
+            let mut first = UniversalGamepad::nothing_pressed();
+            first.sticks.left.x = 158;
+            first.triggers.left = 30;
+            copilot.update(0, first);
+
+            let mut second = UniversalGamepad::nothing_pressed();
+            second.sticks.left.x = 178;
+            second.triggers.left = 0;
+            let merged = copilot.update(1, second);
+
+            assert_eq!(merged.sticks.left.x, expected, "{mode:?}");
+            assert_eq!(merged.triggers.left, 30, "{mode:?}");
+        }
+    }
+
+    #[test]
+    fn a_lost_controller_is_left_out() {
+        let mut copilot = CoPilot::new(AxisMerge::Larger, vec![_controller(Vec::new()), _controller(Vec::new())]);
+        copilot.stale_after = Some(Duration::from_millis(10));
+
+        let mut lost = UniversalGamepad::nothing_pressed();
+        lost.triggers.right = 255;
+        copilot.update(0, lost);
+        std::thread::sleep(Duration::from_millis(20));
+
+        let merged = copilot.update(1, UniversalGamepad::nothing_pressed());
+        assert_eq!(merged, UniversalGamepad::nothing_pressed());
+    }
+}
@@ -0,0 +1,889 @@
+/* Important Notes
+ *
+ * https://dbus.freedesktop.org/doc/dbus-specification.html
+ *
+ * Only what is needed to talk to BlueZ:
+ *  - unix sockets and the EXTERNAL authentication
+ *  - method calls, their returns and errors, signals
+ *  - no unix fd passing
+ *
+ * Every value is aligned to its own size relative to the start of the message,
+ * structs and dict entries to 8 bytes, the body starts on an 8 byte boundary.
+*/
+
+use std::collections::VecDeque;
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::{SocketAddr, UnixStream};
+use std::time::{Duration, Instant};
+
+/// Used if `DBUS_SYSTEM_BUS_ADDRESS` is not set
+pub const SYSTEM_BUS_SOCKET: &str = "/run/dbus/system_bus_socket";
+
+/// Same as the default of libdbus
+const METHOD_CALL_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// The specification allows 128 MiB, anything bigger is a broken stream
+const MAX_MESSAGE_SIZE: usize = 128 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum DbusError {
+    Io(io::Error),
+
+    /// The bus did not accept the authentication
+    Auth(String),
+
+    /// Malformed message or an answer that does not fit the call
+    Protocol(String),
+
+    /// Error reply of the called object: error name and message
+    Remote(String, String),
+
+    /// No reply within `METHOD_CALL_TIMEOUT`
+    Timeout,
+}
+
+/// A value of the D-Bus type system
+#[derive(Clone, PartialEq, Debug)]
+pub enum DbusValue {
+    Byte(u8),
+    Bool(bool),
+    Int16(i16),
+    Uint16(u16),
+    Int32(i32),
+    Uint32(u32),
+    Int64(i64),
+    Uint64(u64),
+    Double(f64),
+    String(String),
+    ObjectPath(String),
+    Signature(String),
+    UnixFd(u32),
+
+    /// Signature of one element and all elements, dictionaries are arrays of `DictEntry`
+    Array(String, Vec<DbusValue>),
+    Struct(Vec<DbusValue>),
+    DictEntry(Box<DbusValue>, Box<DbusValue>),
+    Variant(Box<DbusValue>),
+}
+
+impl DbusValue {
+    pub fn signature(&self) -> String {
+        match self {
+            DbusValue::Byte(_) => return "y".to_string(),
+            DbusValue::Bool(_) => return "b".to_string(),
+            DbusValue::Int16(_) => return "n".to_string(),
+            DbusValue::Uint16(_) => return "q".to_string(),
+            DbusValue::Int32(_) => return "i".to_string(),
+            DbusValue::Uint32(_) => return "u".to_string(),
+            DbusValue::Int64(_) => return "x".to_string(),
+            DbusValue::Uint64(_) => return "t".to_string(),
+            DbusValue::Double(_) => return "d".to_string(),
+            DbusValue::String(_) => return "s".to_string(),
+            DbusValue::ObjectPath(_) => return "o".to_string(),
+            DbusValue::Signature(_) => return "g".to_string(),
+            DbusValue::UnixFd(_) => return "h".to_string(),
+            DbusValue::Array(element, _) => return format!("a{element}"),
+            DbusValue::Struct(fields) => return format!("({})", fields.iter().map(|field| field.signature()).collect::<String>()),
+            DbusValue::DictEntry(key, value) => return format!("{{{}{}}}", key.signature(), value.signature()),
+            DbusValue::Variant(_) => return "v".to_string(),
+        }
+    }
+
+    /// An `a{sv}` dictionary, as used for properties
+    pub fn properties(entries: Vec<(&str, DbusValue)>) -> DbusValue {
+        let entries = entries
+            .into_iter()
+            .map(|(key, value)| DbusValue::DictEntry(Box::new(DbusValue::String(key.to_string())), Box::new(DbusValue::Variant(Box::new(value)))));
+        return DbusValue::Array("{sv}".to_string(), entries.collect());
+    }
+
+    /// Variants are looked through, so properties can be read without unpacking them
+    fn _unwrapped(&self) -> &DbusValue {
+        match self {
+            DbusValue::Variant(inner) => return inner._unwrapped(),
+            other => return other,
+        }
+    }
+
+    /// Strings, object paths and signatures
+    pub fn as_str(&self) -> Option<&str> {
+        match self._unwrapped() {
+            DbusValue::String(text) | DbusValue::ObjectPath(text) | DbusValue::Signature(text) => return Some(text),
+            _ => return None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self._unwrapped() {
+            DbusValue::Bool(value) => return Some(*value),
+            _ => return None,
+        }
+    }
+
+    /// Every integer type
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self._unwrapped() {
+            DbusValue::Byte(value) => return Some(value as i64),
+            DbusValue::Int16(value) => return Some(value as i64),
+            DbusValue::Uint16(value) => return Some(value as i64),
+            DbusValue::Int32(value) => return Some(value as i64),
+            DbusValue::Uint32(value) => return Some(value as i64),
+            DbusValue::Int64(value) => return Some(value),
+            DbusValue::Uint64(value) => return Some(value as i64),
+            _ => return None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[DbusValue]> {
+        match self._unwrapped() {
+            DbusValue::Array(_, items) => return Some(items),
+            _ => return None,
+        }
+    }
+
+    /// The value of `key` in a dictionary with string or object path keys
+    pub fn get(&self, key: &str) -> Option<&DbusValue> {
+        for entry in self.as_array()? {
+            if let DbusValue::DictEntry(entry_key, value) = entry {
+                if entry_key.as_str() == Some(key) {
+                    return Some(value._unwrapped());
+                }
+            }
+        }
+        return None;
+    }
+}
+
+/// Alignment of the first complete type in `signature`
+fn _alignment(signature: &str) -> usize {
+    match signature.as_bytes().first() {
+        Some(b'n' | b'q') => return 2,
+        Some(b'b' | b'i' | b'u' | b's' | b'o' | b'a' | b'h') => return 4,
+        Some(b'x' | b't' | b'd' | b'(' | b'{') => return 8,
+        _ => return 1,
+    }
+}
+
+/// Splits the first complete type off `signature`, e.g. `a{sv}s` -> (`a{sv}`, `s`)
+fn _split_first_type(signature: &str) -> Result<(&str, &str), DbusError> {
+    match signature.as_bytes().first() {
+        None => return Err(DbusError::Protocol("signature ended early".to_string())),
+        Some(b'a') => {
+            let (element, _) = _split_first_type(&signature[1..])?;
+            return Ok(signature.split_at(1 + element.len()));
+        }
+        Some(open @ (b'(' | b'{')) => {
+            let close: u8 = if *open == b'(' { b')' } else { b'}' };
+            let mut depth: usize = 0;
+            for (index, character) in signature.bytes().enumerate() {
+                if character == *open {
+                    depth += 1;
+                } else if character == close {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(signature.split_at(index + 1));
+                    }
+                }
+            }
+            return Err(DbusError::Protocol(format!("unclosed container in signature {signature}")));
+        }
+        Some(b'y' | b'b' | b'n' | b'q' | b'i' | b'u' | b'x' | b't' | b'd' | b's' | b'o' | b'g' | b'h' | b'v') => return Ok(signature.split_at(1)),
+        Some(other) => return Err(DbusError::Protocol(format!("unknown type in signature: {}", *other as char))),
+    }
+}
+
+/// All complete types of `signature`
+fn _split_types(mut signature: &str) -> Result<Vec<&str>, DbusError> {
+    let mut types: Vec<&str> = Vec::new();
+    while signature.is_empty() == false {
+        let (first, rest) = _split_first_type(signature)?;
+        types.push(first);
+        signature = rest;
+    }
+    return Ok(types);
+}
+
+/// Marshals little endian values, positions are relative to the start of the message
+struct Writer {
+    buffer: Vec<u8>,
+}
+
+impl Writer {
+    fn _pad(&mut self, alignment: usize) {
+        while self.buffer.len().is_multiple_of(alignment) == false {
+            self.buffer.push(0);
+        }
+    }
+
+    fn write(&mut self, value: &DbusValue) {
+        self._pad(_alignment(&value.signature()));
+
+        match value {
+            DbusValue::Byte(value) => self.buffer.push(*value),
+            DbusValue::Bool(value) => self.buffer.extend((*value as u32).to_le_bytes()),
+            DbusValue::Int16(value) => self.buffer.extend(value.to_le_bytes()),
+            DbusValue::Uint16(value) => self.buffer.extend(value.to_le_bytes()),
+            DbusValue::Int32(value) => self.buffer.extend(value.to_le_bytes()),
+            DbusValue::Uint32(value) | DbusValue::UnixFd(value) => self.buffer.extend(value.to_le_bytes()),
+            DbusValue::Int64(value) => self.buffer.extend(value.to_le_bytes()),
+            DbusValue::Uint64(value) => self.buffer.extend(value.to_le_bytes()),
+            DbusValue::Double(value) => self.buffer.extend(value.to_le_bytes()),
+            DbusValue::String(text) | DbusValue::ObjectPath(text) => {
+                self.buffer.extend((text.len() as u32).to_le_bytes());
+                self.buffer.extend(text.as_bytes());
+                self.buffer.push(0);
+            }
+            DbusValue::Signature(text) => {
+                self.buffer.push(text.len() as u8);
+                self.buffer.extend(text.as_bytes());
+                self.buffer.push(0);
+            }
+            DbusValue::Array(element, items) => {
+                // the length does not include the padding before the first element
+                let length_position: usize = self.buffer.len();
+                self.buffer.extend([0; 4]);
+                self._pad(_alignment(element));
+
+                let start: usize = self.buffer.len();
+                for item in items {
+                    self.write(item);
+                }
+                let length: u32 = (self.buffer.len() - start) as u32;
+                self.buffer[length_position..length_position + 4].copy_from_slice(&length.to_le_bytes());
+            }
+            DbusValue::Struct(fields) => {
+                for field in fields {
+                    self.write(field);
+                }
+            }
+            DbusValue::DictEntry(key, value) => {
+                self.write(key);
+                self.write(value);
+            }
+            DbusValue::Variant(inner) => {
+                self.write(&DbusValue::Signature(inner.signature()));
+                self.write(inner);
+            }
+        }
+    }
+}
+
+/// Unmarshals values of either endianness, positions are relative to the start of the message
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+    is_big_endian: bool,
+}
+
+impl Reader<'_> {
+    fn _align(&mut self, alignment: usize) {
+        self.position = self.position.div_ceil(alignment) * alignment;
+    }
+
+    fn _take<const N: usize>(&mut self) -> Result<[u8; N], DbusError> {
+        let end: usize = self.position + N;
+        let bytes: [u8; N] = match self.data.get(self.position..end) {
+            Some(bytes) => bytes.try_into().expect("slice has N bytes"),
+            None => return Err(DbusError::Protocol("message ended early".to_string())),
+        };
+        self.position = end;
+
+        match self.is_big_endian {
+            true => return Ok(_reversed(bytes)),
+            false => return Ok(bytes),
+        }
+    }
+
+    fn _u32(&mut self) -> Result<u32, DbusError> {
+        self._align(4);
+        return Ok(u32::from_le_bytes(self._take::<4>()?));
+    }
+
+    /// `length` bytes followed by a 0 byte
+    fn _text(&mut self, length: usize) -> Result<String, DbusError> {
+        let end: usize = self.position + length;
+        let text: String = match self.data.get(self.position..end) {
+            Some(bytes) if self.data.get(end) == Some(&0) => match String::from_utf8(bytes.to_vec()) {
+                Ok(text) => text,
+                Err(_) => return Err(DbusError::Protocol("string is not utf-8".to_string())),
+            },
+            _ => return Err(DbusError::Protocol("string ended early".to_string())),
+        };
+        self.position = end + 1;
+        return Ok(text);
+    }
+
+    /// Reads one complete type
+    fn read(&mut self, signature: &str) -> Result<DbusValue, DbusError> {
+        self._align(_alignment(signature));
+
+        match signature.as_bytes()[0] {
+            b'y' => return Ok(DbusValue::Byte(self._take::<1>()?[0])),
+            b'b' => return Ok(DbusValue::Bool(u32::from_le_bytes(self._take::<4>()?) != 0)),
+            b'n' => return Ok(DbusValue::Int16(i16::from_le_bytes(self._take::<2>()?))),
+            b'q' => return Ok(DbusValue::Uint16(u16::from_le_bytes(self._take::<2>()?))),
+            b'i' => return Ok(DbusValue::Int32(i32::from_le_bytes(self._take::<4>()?))),
+            b'u' => return Ok(DbusValue::Uint32(u32::from_le_bytes(self._take::<4>()?))),
+            b'h' => return Ok(DbusValue::UnixFd(u32::from_le_bytes(self._take::<4>()?))),
+            b'x' => return Ok(DbusValue::Int64(i64::from_le_bytes(self._take::<8>()?))),
+            b't' => return Ok(DbusValue::Uint64(u64::from_le_bytes(self._take::<8>()?))),
+            b'd' => return Ok(DbusValue::Double(f64::from_le_bytes(self._take::<8>()?))),
+            b's' | b'o' => {
+                let length: usize = self._u32()? as usize;
+                let text: String = self._text(length)?;
+                match signature.as_bytes()[0] {
+                    b's' => return Ok(DbusValue::String(text)),
+                    _ => return Ok(DbusValue::ObjectPath(text)),
+                }
+            }
+            b'g' => {
+                let length: usize = self._take::<1>()?[0] as usize;
+                return Ok(DbusValue::Signature(self._text(length)?));
+            }
+            b'a' => {
+                let element: &str = &signature[1..];
+                let length: usize = self._u32()? as usize;
+                self._align(_alignment(element));
+
+                let end: usize = self.position + length;
+                if end > self.data.len() {
+                    return Err(DbusError::Protocol("array is longer than the message".to_string()));
+                }
+                let mut items: Vec<DbusValue> = Vec::new();
+                while self.position < end {
+                    items.push(self.read(element)?);
+                }
+                return Ok(DbusValue::Array(element.to_string(), items));
+            }
+            b'(' => {
+                let mut fields: Vec<DbusValue> = Vec::new();
+                for field in _split_types(&signature[1..signature.len() - 1])? {
+                    fields.push(self.read(field)?);
+                }
+                return Ok(DbusValue::Struct(fields));
+            }
+            b'{' => {
+                let types: Vec<&str> = _split_types(&signature[1..signature.len() - 1])?;
+                if types.len() != 2 {
+                    return Err(DbusError::Protocol(format!("dict entry needs a key and a value: {signature}")));
+                }
+                let key: DbusValue = self.read(types[0])?;
+                let value: DbusValue = self.read(types[1])?;
+                return Ok(DbusValue::DictEntry(Box::new(key), Box::new(value)));
+            }
+            b'v' => {
+                let inner_signature: String = match self.read("g")? {
+                    DbusValue::Signature(inner_signature) => inner_signature,
+                    _ => return Err(DbusError::Protocol("variant without signature".to_string())),
+                };
+                match _split_first_type(&inner_signature)? {
+                    (single, "") => return Ok(DbusValue::Variant(Box::new(self.read(single)?))),
+                    _ => return Err(DbusError::Protocol(format!("variant has more than one type: {inner_signature}"))),
+                }
+            }
+            other => return Err(DbusError::Protocol(format!("unknown type in signature: {}", other as char))),
+        }
+    }
+}
+
+/// Byte order of the values is reversed, so they can always be read with `from_le_bytes()`
+fn _reversed<const N: usize>(mut bytes: [u8; N]) -> [u8; N] {
+    bytes.reverse();
+    return bytes;
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum MessageType {
+    MethodCall = 1,
+    MethodReturn = 2,
+    Error = 3,
+    Signal = 4,
+}
+
+/// Codes of the header fields
+const FIELD_PATH: u8 = 1;
+const FIELD_INTERFACE: u8 = 2;
+const FIELD_MEMBER: u8 = 3;
+const FIELD_ERROR_NAME: u8 = 4;
+const FIELD_REPLY_SERIAL: u8 = 5;
+const FIELD_DESTINATION: u8 = 6;
+const FIELD_SENDER: u8 = 7;
+const FIELD_SIGNATURE: u8 = 8;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct DbusMessage {
+    pub message_type: MessageType,
+    pub flags: u8,
+
+    /// set by `DbusConnection::send()`
+    pub serial: u32,
+
+    pub path: Option<String>,
+    pub interface: Option<String>,
+    pub member: Option<String>,
+    pub error_name: Option<String>,
+    pub reply_serial: Option<u32>,
+    pub destination: Option<String>,
+    pub sender: Option<String>,
+    pub body: Vec<DbusValue>,
+}
+
+impl DbusMessage {
+    fn _new(message_type: MessageType) -> Self {
+        Self {
+            message_type,
+            flags: 0,
+            serial: 0,
+            path: None,
+            interface: None,
+            member: None,
+            error_name: None,
+            reply_serial: None,
+            destination: None,
+            sender: None,
+            body: Vec::new(),
+        }
+    }
+
+    pub fn method_call(destination: &str, path: &str, interface: &str, member: &str, body: Vec<DbusValue>) -> Self {
+        let mut message = Self::_new(MessageType::MethodCall);
+        message.destination = Some(destination.to_string());
+        message.path = Some(path.to_string());
+        message.interface = Some(interface.to_string());
+        message.member = Some(member.to_string());
+        message.body = body;
+        return message;
+    }
+
+    pub fn signal(path: &str, interface: &str, member: &str, body: Vec<DbusValue>) -> Self {
+        let mut message = Self::_new(MessageType::Signal);
+        message.path = Some(path.to_string());
+        message.interface = Some(interface.to_string());
+        message.member = Some(member.to_string());
+        message.body = body;
+        return message;
+    }
+
+    pub fn method_return(call: &DbusMessage, body: Vec<DbusValue>) -> Self {
+        let mut message = Self::_new(MessageType::MethodReturn);
+        message.reply_serial = Some(call.serial);
+        message.destination = call.sender.clone();
+        message.body = body;
+        return message;
+    }
+
+    pub fn error(call: &DbusMessage, error_name: &str, text: &str) -> Self {
+        let mut message = Self::_new(MessageType::Error);
+        message.reply_serial = Some(call.serial);
+        message.destination = call.sender.clone();
+        message.error_name = Some(error_name.to_string());
+        message.body = vec![DbusValue::String(text.to_string())];
+        return message;
+    }
+
+    /// `true` for a signal with this interface and member
+    pub fn is_signal(&self, interface: &str, member: &str) -> bool {
+        return self.message_type == MessageType::Signal && self.interface.as_deref() == Some(interface) && self.member.as_deref() == Some(member);
+    }
+
+    /// Little endian wire format
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut body = Writer { buffer: Vec::new() };
+        for value in &self.body {
+            body.write(value);
+        }
+        let body_signature: String = self.body.iter().map(|value| value.signature()).collect();
+
+        let mut fields: Vec<DbusValue> = Vec::new();
+        let mut add_field = |code: u8, value: DbusValue| fields.push(DbusValue::Struct(vec![DbusValue::Byte(code), DbusValue::Variant(Box::new(value))]));
+        if let Some(path) = &self.path {
+            add_field(FIELD_PATH, DbusValue::ObjectPath(path.clone()));
+        }
+        if let Some(interface) = &self.interface {
+            add_field(FIELD_INTERFACE, DbusValue::String(interface.clone()));
+        }
+        if let Some(member) = &self.member {
+            add_field(FIELD_MEMBER, DbusValue::String(member.clone()));
+        }
+        if let Some(error_name) = &self.error_name {
+            add_field(FIELD_ERROR_NAME, DbusValue::String(error_name.clone()));
+        }
+        if let Some(reply_serial) = self.reply_serial {
+            add_field(FIELD_REPLY_SERIAL, DbusValue::Uint32(reply_serial));
+        }
+        if let Some(destination) = &self.destination {
+            add_field(FIELD_DESTINATION, DbusValue::String(destination.clone()));
+        }
+        if let Some(sender) = &self.sender {
+            add_field(FIELD_SENDER, DbusValue::String(sender.clone()));
+        }
+        if body_signature.is_empty() == false {
+            add_field(FIELD_SIGNATURE, DbusValue::Signature(body_signature));
+        }
+
+        let mut message = Writer {
+            buffer: vec![b'l', self.message_type as u8, self.flags, 1],
+        };
+        message.write(&DbusValue::Uint32(body.buffer.len() as u32));
+        message.write(&DbusValue::Uint32(self.serial));
+        message.write(&DbusValue::Array("(yv)".to_string(), fields));
+        message._pad(8);
+        message.buffer.extend(body.buffer);
+
+        return message.buffer;
+    }
+
+    /// Size of the whole message, read from its first 16 bytes
+    fn _size(start: &[u8; 16]) -> Result<usize, DbusError> {
+        let read_u32 = |bytes: &[u8]| -> usize {
+            let bytes: [u8; 4] = bytes.try_into().expect("4 bytes");
+            match start[0] {
+                b'B' => return u32::from_be_bytes(bytes) as usize,
+                _ => return u32::from_le_bytes(bytes) as usize,
+            }
+        };
+        if start[0] != b'l' && start[0] != b'B' {
+            return Err(DbusError::Protocol(format!("unknown endianness: {}", start[0])));
+        }
+
+        let body_length: usize = read_u32(&start[4..8]);
+        let fields_length: usize = read_u32(&start[12..16]);
+        let size: usize = (16 + fields_length).div_ceil(8) * 8 + body_length;
+        if size > MAX_MESSAGE_SIZE {
+            return Err(DbusError::Protocol(format!("message is too big: {size} bytes")));
+        }
+        return Ok(size);
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Self, DbusError> {
+        let mut reader = Reader {
+            data,
+            position: 0,
+            is_big_endian: data.first() == Some(&b'B'),
+        };
+
+        let (message_type, flags, version) = match data.get(..4) {
+            Some(start) => (start[1], start[2], start[3]),
+            None => return Err(DbusError::Protocol("message ended early".to_string())),
+        };
+        reader.position = 4;
+        let message_type: MessageType = match message_type {
+            1 => MessageType::MethodCall,
+            2 => MessageType::MethodReturn,
+            3 => MessageType::Error,
+            4 => MessageType::Signal,
+            other => return Err(DbusError::Protocol(format!("unknown message type: {other}"))),
+        };
+        if version != 1 {
+            return Err(DbusError::Protocol(format!("unknown protocol version: {version}")));
+        }
+
+        let mut message = Self::_new(message_type);
+        message.flags = flags;
+        let body_length: usize = reader._u32()? as usize;
+        message.serial = reader._u32()?;
+
+        let mut body_signature: String = String::new();
+        for field in reader.read("a(yv)")?.as_array().unwrap_or_default() {
+            let (code, value) = match field {
+                DbusValue::Struct(code_and_value) => match code_and_value.as_slice() {
+                    [DbusValue::Byte(code), value] => (*code, value),
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            let text: Option<String> = value.as_str().map(|text| text.to_string());
+            match code {
+                FIELD_PATH => message.path = text,
+                FIELD_INTERFACE => message.interface = text,
+                FIELD_MEMBER => message.member = text,
+                FIELD_ERROR_NAME => message.error_name = text,
+                FIELD_REPLY_SERIAL => message.reply_serial = value.as_i64().map(|serial| serial as u32),
+                FIELD_DESTINATION => message.destination = text,
+                FIELD_SENDER => message.sender = text,
+                FIELD_SIGNATURE => body_signature = text.unwrap_or_default(),
+                // unknown fields have to be ignored
+                _ => {}
+            }
+        }
+
+        reader._align(8);
+        if reader.position + body_length != data.len() {
+            return Err(DbusError::Protocol("body length does not match the message".to_string()));
+        }
+        for value_signature in _split_types(&body_signature)? {
+            message.body.push(reader.read(value_signature)?);
+        }
+
+        return Ok(message);
+    }
+
+    /// Blocks until a whole message was read
+    pub fn read_from(stream: &mut impl Read) -> Result<Self, DbusError> {
+        let mut start: [u8; 16] = [0; 16];
+        stream.read_exact(&mut start).map_err(DbusError::Io)?;
+
+        let mut data: Vec<u8> = vec![0; Self::_size(&start)?];
+        data[..16].copy_from_slice(&start);
+        stream.read_exact(&mut data[16..]).map_err(DbusError::Io)?;
+
+        return Self::from_bytes(&data);
+    }
+}
+
+/// A connection to a message bus (or directly to a peer, which is how the tests use it)
+pub struct DbusConnection {
+    stream: UnixStream,
+    next_serial: u32,
+
+    /// Name the bus gave this connection, e.g. `:1.42`
+    pub unique_name: String,
+
+    /// Signals that arrived while waiting for a method return
+    pending_signals: VecDeque<DbusMessage>,
+}
+
+impl DbusConnection {
+    /// `DBUS_SYSTEM_BUS_ADDRESS` or `SYSTEM_BUS_SOCKET`
+    pub fn system_bus() -> Result<Self, DbusError> {
+        match env::var("DBUS_SYSTEM_BUS_ADDRESS") {
+            Ok(address) => return Self::from_address(&address),
+            Err(_) => return Self::from_address(&format!("unix:path={SYSTEM_BUS_SOCKET}")),
+        }
+    }
+
+    /// `DBUS_SESSION_BUS_ADDRESS`
+    pub fn session_bus() -> Result<Self, DbusError> {
+        match env::var("DBUS_SESSION_BUS_ADDRESS") {
+            Ok(address) => return Self::from_address(&address),
+            Err(_) => return Err(DbusError::Protocol("DBUS_SESSION_BUS_ADDRESS is not set".to_string())),
+        }
+    }
+
+    /// Connects to the first usable address of a `;` separated list, only `unix:path=` and `unix:abstract=` are supported
+    pub fn from_address(addresses: &str) -> Result<Self, DbusError> {
+        let mut last_error = DbusError::Protocol(format!("no supported address in {addresses}"));
+
+        for address in addresses.split(';') {
+            let parameters: &str = match address.strip_prefix("unix:") {
+                Some(parameters) => parameters,
+                None => continue,
+            };
+
+            for parameter in parameters.split(',') {
+                let connected = match parameter.split_once('=') {
+                    Some(("path", path)) => UnixStream::connect(path),
+                    Some(("abstract", name)) => SocketAddr::from_abstract_name(name.as_bytes()).and_then(|socket| UnixStream::connect_addr(&socket)),
+                    _ => continue,
+                };
+                match connected {
+                    Ok(stream) => return Self::from_stream(stream),
+                    Err(err) => last_error = DbusError::Io(err),
+                }
+            }
+        }
+
+        return Err(last_error);
+    }
+
+    /// Authenticates and registers on the bus with `Hello`
+    pub fn from_stream(mut stream: UnixStream) -> Result<Self, DbusError> {
+        _authenticate(&mut stream)?;
+
+        let mut connection = Self {
+            stream,
+            next_serial: 1,
+            unique_name: String::new(),
+            pending_signals: VecDeque::new(),
+        };
+        let hello = DbusMessage::method_call("org.freedesktop.DBus", "/org/freedesktop/DBus", "org.freedesktop.DBus", "Hello", Vec::new());
+        connection.unique_name = match connection.call(hello)?.first().and_then(|name| name.as_str()) {
+            Some(name) => name.to_string(),
+            None => return Err(DbusError::Protocol("Hello returned no name".to_string())),
+        };
+
+        return Ok(connection);
+    }
+
+    /// Returns the serial the message was sent with
+    pub fn send(&mut self, mut message: DbusMessage) -> Result<u32, DbusError> {
+        message.serial = self.next_serial;
+        self.next_serial = self.next_serial.wrapping_add(1).max(1);
+
+        self.stream.write_all(&message.to_bytes()).map_err(DbusError::Io)?;
+        return Ok(message.serial);
+    }
+
+    /// Sends a method call and waits for its return, signals that arrive in the meantime are kept for `next_signal()`
+    pub fn call(&mut self, message: DbusMessage) -> Result<Vec<DbusValue>, DbusError> {
+        let serial: u32 = self.send(message)?;
+        let deadline: Instant = Instant::now() + METHOD_CALL_TIMEOUT;
+
+        loop {
+            let received: DbusMessage = match self._receive(deadline)? {
+                Some(received) => received,
+                None => return Err(DbusError::Timeout),
+            };
+
+            match received.message_type {
+                MessageType::Signal => self.pending_signals.push_back(received),
+                MessageType::MethodReturn if received.reply_serial == Some(serial) => return Ok(received.body),
+                MessageType::Error if received.reply_serial == Some(serial) => {
+                    let text: String = received.body.first().and_then(|text| text.as_str()).unwrap_or_default().to_string();
+                    return Err(DbusError::Remote(received.error_name.unwrap_or_default(), text));
+                }
+                // calls to this connection and late returns of other calls are not expected
+                _ => {}
+            }
+        }
+    }
+
+    /// Subscribes to signals, e.g. `type='signal',sender='org.bluez'`
+    pub fn add_match(&mut self, rule: &str) -> Result<(), DbusError> {
+        let add_match = DbusMessage::method_call(
+            "org.freedesktop.DBus",
+            "/org/freedesktop/DBus",
+            "org.freedesktop.DBus",
+            "AddMatch",
+            vec![DbusValue::String(rule.to_string())],
+        );
+        self.call(add_match)?;
+        return Ok(());
+    }
+
+    /// Waits up to `timeout` for the next signal
+    pub fn next_signal(&mut self, timeout: Duration) -> Result<Option<DbusMessage>, DbusError> {
+        let deadline: Instant = Instant::now() + timeout;
+
+        loop {
+            if let Some(signal) = self.pending_signals.pop_front() {
+                return Ok(Some(signal));
+            }
+            match self._receive(deadline)? {
+                Some(received) if received.message_type == MessageType::Signal => return Ok(Some(received)),
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Returns `Ok(None)` if nothing arrived before `deadline`
+    ///
+    /// Only the wait for the first byte has a timeout, so a message is never read halfway
+    fn _receive(&mut self, deadline: Instant) -> Result<Option<DbusMessage>, DbusError> {
+        let remaining: Duration = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(None);
+        }
+
+        let mut first: [u8; 1] = [0];
+        self.stream.set_read_timeout(Some(remaining)).map_err(DbusError::Io)?;
+        let first_read = self.stream.read(&mut first);
+        self.stream.set_read_timeout(None).map_err(DbusError::Io)?;
+
+        match first_read {
+            Ok(0) => return Err(DbusError::Io(io::Error::from(io::ErrorKind::UnexpectedEof))),
+            Ok(_) => {}
+            Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => return Ok(None),
+            Err(err) => return Err(DbusError::Io(err)),
+        }
+
+        let message: DbusMessage = DbusMessage::read_from(&mut (&first[..]).chain(&mut self.stream))?;
+        return Ok(Some(message));
+    }
+}
+
+/// `AUTH EXTERNAL` with the user id of this process, the bus checks it against the socket credentials
+fn _authenticate(stream: &mut UnixStream) -> Result<(), DbusError> {
+    let uid: u32 = fs::metadata("/proc/self").map_err(DbusError::Io)?.uid();
+    let hex_uid: String = uid.to_string().bytes().map(|byte| format!("{byte:02x}")).collect();
+
+    stream.write_all(format!("\0AUTH EXTERNAL {hex_uid}\r\n").as_bytes()).map_err(DbusError::Io)?;
+    let answer: String = _read_line(stream)?;
+    if answer.starts_with("OK ") == false {
+        return Err(DbusError::Auth(answer));
+    }
+    stream.write_all(b"BEGIN\r\n").map_err(DbusError::Io)?;
+
+    return Ok(());
+}
+
+/// One line of the authentication protocol, without `\r\n`
+fn _read_line(stream: &mut UnixStream) -> Result<String, DbusError> {
+    let mut line: Vec<u8> = Vec::new();
+    let mut byte: [u8; 1] = [0];
+
+    while line.ends_with(b"\r\n") == false {
+        if stream.read(&mut byte).map_err(DbusError::Io)? == 0 {
+            return Err(DbusError::Auth("connection closed during authentication".to_string()));
+        }
+        line.push(byte[0]);
+    }
+    line.truncate(line.len() - 2);
+
+    return Ok(String::from_utf8_lossy(&line).to_string());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_survive_the_wire_format() {
+        let mut signal = DbusMessage::signal(
+            "/org/bluez/hci0",
+            "org.freedesktop.DBus.ObjectManager",
+            "InterfacesAdded",
+            vec![
+                DbusValue::ObjectPath("/org/bluez/hci0/dev_A0_AB_51_12_34_56".to_string()),
+                DbusValue::Array(
+                    "{sa{sv}}".to_string(),
+                    vec![DbusValue::DictEntry(
+                        Box::new(DbusValue::String("org.bluez.Device1".to_string())),
+                        Box::new(DbusValue::properties(vec![
+                            ("Address", DbusValue::String("A0:AB:51:12:34:56".to_string())),
+                            ("RSSI", DbusValue::Int16(-60)),
+                            ("Class", DbusValue::Uint32(0x2508)),
+                            ("Paired", DbusValue::Bool(false)),
+                            ("UUIDs", DbusValue::Array("s".to_string(), Vec::new())),
+                        ])),
+                    )],
+                ),
+                DbusValue::Struct(vec![DbusValue::Byte(1), DbusValue::Uint64(u64::MAX), DbusValue::Double(0.5)]),
+            ],
+        );
+        signal.serial = 7;
+        signal.sender = Some(":1.3".to_string());
+
+        let bytes: Vec<u8> = signal.to_bytes();
+        assert_eq!(bytes.len() % 8, 0, "the body of this message ends on a struct field of 8 bytes");
+        let decoded = DbusMessage::from_bytes(&bytes).unwrap();
+
+        assert_eq!(decoded, signal);
+        let device = decoded.body[1].get("org.bluez.Device1").unwrap();
+        assert_eq!(device.get("RSSI").and_then(|rssi| rssi.as_i64()), Some(-60));
+        assert_eq!(device.get("Address").and_then(|address| address.as_str()), Some("A0:AB:51:12:34:56"));
+    }
+
+    #[test]
+    fn big_endian_messages_are_read() {
+        // method return with reply serial 1 and the string "hi", as sent by a big endian peer
+        let bytes: Vec<u8> = [
+            &[b'B', 2, 0, 1, 0, 0, 0, 7, 0, 0, 0, 9, 0, 0, 0, 15][..],
+            &[5, 1, b'u', 0, 0, 0, 0, 1, 8, 1, b'g', 0, 1, b's', 0, 0],
+            &[0, 0, 0, 2, b'h', b'i', 0],
+        ]
+        .concat();
+
+        let message = DbusMessage::from_bytes(&bytes).unwrap();
+
+        assert_eq!(message.message_type, MessageType::MethodReturn);
+        assert_eq!(message.serial, 9);
+        assert_eq!(message.reply_serial, Some(1));
+        assert_eq!(message.body, vec![DbusValue::String("hi".to_string())]);
+    }
+}
@@ -0,0 +1,307 @@
+use std::time::{Duration, Instant};
+
+use crate::calibration::{AxisCalibration, StickCalibration};
+use crate::config::{Config, ConfigError, ConfigSection};
+use crate::input_mapping::Input;
+use crate::processing::ProcessingStage;
+use crate::status;
+use crate::universal_gamepad::{Axis, Stick, UniversalGamepad};
+
+/// A stick counts as untouched while it moves less than this (max - min of one axis)
+const IDLE_MOVEMENT: u8 = 4;
+
+/// Weight of a new idle measurement in the drift estimation
+const DRIFT_SMOOTHING: f32 = 0.5;
+
+/// Resting position of one stick, measured while nobody touches the gamepad
+struct StickDrift {
+    name: &'static str,
+
+    /// current idle period: start, (min x, max x, min y, max y) and the sums for the average
+    idle_since: Option<Instant>,
+    idle_range: (u8, u8, u8, u8),
+    idle_sum: (u64, u64, u64),
+
+    /// estimated resting position minus the center, `None` until the first idle period ended
+    offset: Option<(f32, f32)>,
+    was_warned: bool,
+}
+
+impl StickDrift {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            idle_since: None,
+            idle_range: (255, 0, 255, 0),
+            idle_sum: (0, 0, 0),
+            offset: None,
+            was_warned: false,
+        }
+    }
+
+    fn _reset_idle(&mut self) {
+        self.idle_since = None;
+        self.idle_range = (255, 0, 255, 0);
+        self.idle_sum = (0, 0, 0);
+    }
+
+    /// Returns `true` if an idle period ended and the offset was updated
+    fn update(&mut self, x: u8, y: u8, others_are_idle: bool, settings: &DriftSettings, now: Instant) -> bool {
+        let max_deviation: u8 = (settings.max_offset * 128.0) as u8;
+        let is_near_center: bool = x.abs_diff(128) <= max_deviation && y.abs_diff(128) <= max_deviation;
+        if others_are_idle == false || is_near_center == false {
+            self._reset_idle();
+            return false;
+        }
+
+        let range = &mut self.idle_range;
+        *range = (range.0.min(x), range.1.max(x), range.2.min(y), range.3.max(y));
+        if range.1 - range.0 > IDLE_MOVEMENT || range.3 - range.2 > IDLE_MOVEMENT {
+            self._reset_idle();
+            return false;
+        }
+
+        let since: Instant = *self.idle_since.get_or_insert(now);
+        self.idle_sum = (self.idle_sum.0 + x as u64, self.idle_sum.1 + y as u64, self.idle_sum.2 + 1);
+        if now - since < settings.idle_time {
+            return false;
+        }
+
+        let count: f32 = self.idle_sum.2 as f32;
+        let measured: (f32, f32) = (self.idle_sum.0 as f32 / count - 128.0, self.idle_sum.1 as f32 / count - 128.0);
+        self.offset = match self.offset {
+            Some(offset) => Some((
+                offset.0 + (measured.0 - offset.0) * DRIFT_SMOOTHING,
+                offset.1 + (measured.1 - offset.1) * DRIFT_SMOOTHING,
+            )),
+            None => Some(measured),
+        };
+        self._reset_idle();
+
+        return true;
+    }
+
+    /// The offset if it is big enough to matter
+    fn drift(&self, settings: &DriftSettings) -> Option<(f32, f32)> {
+        let offset: (f32, f32) = self.offset?;
+        let threshold: f32 = settings.warn_threshold * 128.0;
+        if offset.0.abs() < threshold && offset.1.abs() < threshold {
+            return None;
+        }
+        return Some(offset);
+    }
+
+    fn _report(&mut self, settings: &DriftSettings) {
+        let key: String = format!("drift.{}", self.name);
+
+        match self.drift(settings) {
+            Some(offset) => {
+                let action: &str = if settings.compensate { "compensated" } else { "not compensated" };
+                status::set(&key, format!("x {:+.1}, y {:+.1} ({action})", offset.0, offset.1));
+                if self.was_warned == false {
+                    println!(
+                        "Warning: the {} stick drifts, it rests at x {:+.1}, y {:+.1} ({action})",
+                        self.name, offset.0, offset.1
+                    );
+                    self.was_warned = true;
+                }
+            }
+            None => {
+                status::set(&key, "none".to_string());
+                self.was_warned = false;
+            }
+        }
+    }
+
+    /// Moves the measured resting position back to the center, the edges stay where they are
+    fn compensate(&self, stick: &mut Stick, settings: &DriftSettings) {
+        let offset: (f32, f32) = match self.drift(settings) {
+            Some(offset) => offset,
+            None => return,
+        };
+
+        let mut calibration = StickCalibration::uncalibrated();
+        calibration.x = AxisCalibration {
+            min: 0,
+            center: (128.0 + offset.0).round() as u8,
+            max: 255,
+        };
+        calibration.y = AxisCalibration {
+            min: 0,
+            center: (128.0 + offset.1).round() as u8,
+            max: 255,
+        };
+        (stick.x, stick.y) = calibration.apply(stick.x, stick.y);
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct DriftSettings {
+    /// Only warn (and show on the status interface) if `false`
+    pub compensate: bool,
+
+    /// Fraction of the full deflection, resting positions further off are not measured (and not compensated)
+    pub max_offset: f32,
+
+    /// Fraction of the full deflection, smaller offsets are no drift
+    pub warn_threshold: f32,
+
+    /// How long the gamepad has to be untouched for one measurement
+    pub idle_time: Duration,
+}
+
+impl DriftSettings {
+    pub fn default() -> Self {
+        Self {
+            compensate: false,
+            max_offset: 0.1,
+            warn_threshold: 0.03,
+            idle_time: Duration::from_secs(10),
+        }
+    }
+
+    /// - `enabled = false` turns drift detection off
+    /// - `compensate = true` shifts the center of the stick to the measured resting position
+    /// - `max_offset`, `warn_threshold` as fractions of the full deflection
+    /// - `idle_time = <seconds>`
+    fn from_section(section: &ConfigSection) -> Result<Self, ConfigError> {
+        let mut settings = Self::default();
+
+        if let Some(value) = section.get_parsed::<bool>("compensate")? {
+            settings.compensate = value;
+        }
+        if let Some(value) = section.get_parsed::<f32>("max_offset")? {
+            settings.max_offset = value;
+        }
+        if let Some(value) = section.get_parsed::<f32>("warn_threshold")? {
+            settings.warn_threshold = value;
+        }
+        if let Some(value) = section.get_parsed::<f32>("idle_time")? {
+            if value.is_finite() == false || value <= 0.0 {
+                return Err(section.error(format!("idle_time has to be above 0, not {value}")));
+            }
+            settings.idle_time = match Duration::try_from_secs_f32(value) {
+                Ok(idle_time) => idle_time,
+                Err(_) => return Err(section.error(format!("idle_time is too long: {value}"))),
+            };
+        }
+
+        for value in [settings.max_offset, settings.warn_threshold] {
+            if (0.0..=0.5).contains(&value) == false {
+                return Err(section.error(format!("max_offset and warn_threshold have to be between 0 and 0.5, not {value}")));
+            }
+        }
+
+        return Ok(settings);
+    }
+}
+
+/// Notices sticks that rest off-center while the gamepad is not used, configured with a `[drift]` section
+///
+/// Detection is on without a config, compensation has to be enabled
+pub struct DriftCompensation {
+    pub settings: DriftSettings,
+    left: StickDrift,
+    right: StickDrift,
+}
+
+impl DriftCompensation {
+    pub fn new(settings: DriftSettings) -> Self {
+        status::set("drift.left", "not measured yet".to_string());
+        status::set("drift.right", "not measured yet".to_string());
+
+        Self {
+            settings,
+            left: StickDrift::new("left"),
+            right: StickDrift::new("right"),
+        }
+    }
+
+    /// Returns `Ok(None)` if `[drift]` has `enabled = false`
+    pub fn from_config(config: &Config) -> Result<Option<Self>, ConfigError> {
+        let section: &ConfigSection = match config.section("drift", "") {
+            Some(section) => section,
+            None => return Ok(Some(Self::new(DriftSettings::default()))),
+        };
+
+        if section.get_parsed::<bool>("enabled")? == Some(false) {
+            return Ok(None);
+        }
+        return Ok(Some(Self::new(DriftSettings::from_section(section)?)));
+    }
+}
+
+impl ProcessingStage for DriftCompensation {
+    fn display_name(&self) -> &'static str {
+        return "Stick drift detection";
+    }
+
+    fn process(&mut self, gamepad: &mut UniversalGamepad, now: Instant) {
+        // the other stick may be touched while one rests, but any other input means the gamepad is in use
+        let buttons_are_idle: bool = Input::all()
+            .iter()
+            .filter(|input| matches!(input, Input::Axis(Axis::StickLeftX | Axis::StickLeftY | Axis::StickRightX | Axis::StickRightY)) == false)
+            .all(|input| input.is_active(gamepad, 0) == false);
+
+        let sticks = [(&mut self.left, &mut gamepad.sticks.left), (&mut self.right, &mut gamepad.sticks.right)];
+        for (drift, stick) in sticks {
+            if drift.update(stick.x, stick.y, buttons_are_idle, &self.settings, now) {
+                drift._report(&self.settings);
+            }
+            if self.settings.compensate {
+                drift.compensate(stick, &self.settings);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resting_offset_is_detected_and_compensated() {
+        let mut settings = DriftSettings::default();
+        settings.compensate = true;
+        settings.idle_time = Duration::from_secs(1);
+        let mut stage = DriftCompensation::new(settings);
+        let start = Instant::now();
+
+        let mut resting = UniversalGamepad::nothing_pressed();
+        resting.sticks.left = Stick {
+            x: 138,
+            y: 127,
+            pressed: false,
+        };
+        resting.sticks.right = Stick {
+            x: 128,
+            y: 128,
+            pressed: false,
+        };
+
+        for step in 0..=100 {
+            let mut gamepad = resting.clone();
+            stage.process(&mut gamepad, start + Duration::from_millis(step * 10));
+        }
+
+        let mut gamepad = resting.clone();
+        stage.process(&mut gamepad, start + Duration::from_millis(1010));
+        assert_eq!((gamepad.sticks.left.x, gamepad.sticks.left.y), (128, 128));
+        assert_eq!((gamepad.sticks.right.x, gamepad.sticks.right.y), (128, 128));
+
+        // the edges are not shifted
+        let mut gamepad = resting.clone();
+        gamepad.sticks.left.x = 255;
+        stage.process(&mut gamepad, start + Duration::from_millis(1020));
+        assert_eq!(gamepad.sticks.left.x, 255);
+    }
+
+    #[test]
+    fn idle_time_has_to_be_a_positive_number() {
+        for idle_time in ["0", "-1", "nan", "inf", "1e30"] {
+            let config = Config::parse(&format!("[drift]\nidle_time = {idle_time}\n")).unwrap();
+            assert!(DriftCompensation::from_config(&config).is_err(), "idle_time = {idle_time}");
+        }
+        assert!(DriftCompensation::from_config(&Config::parse("[drift]\nidle_time = 2.5\n").unwrap()).is_ok());
+    }
+}
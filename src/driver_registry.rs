@@ -0,0 +1,137 @@
+use std::env;
+use std::process::exit;
+
+use crate::usb_gamepad::{InputDriver, OutputPersona};
+use crate::usb_gamepad_ps4::{DUALSHOCK_INPUT, DUALSHOCK_OUTPUT};
+use crate::usb_gamepad_ps5::{DUALSENSE_INPUT, DUALSENSE_OUTPUT};
+
+/// Describes a gamepad model that can be read from over bluetooth
+pub struct InputDriverEntry {
+    /// Used for verbose output
+    pub display_name: &'static str,
+
+    /// The hid device with this vendor and product id will be read with this driver
+    pub vendor_id: u16,
+    pub product_id: u16,
+
+    /// Is this gamepad fully usable as an input gamepad
+    pub is_supported: bool,
+
+    /// Creates a new driver instance for one connected gamepad
+    pub create: fn() -> Box<dyn InputDriver>,
+}
+
+/// Describes a gamepad model that can be presented to the host via the usb gadget
+pub struct OutputPersonaEntry {
+    /// Used for verbose output
+    pub display_name: &'static str,
+
+    /// what strings can a user input as the second commandline argument to select this gamepad for use as the output gamepad
+    pub associated_args: [&'static str; 2],
+
+    /// Is this gamepad fully usable as an output gamepad
+    pub is_supported: bool,
+
+    /// Creates a new persona instance for one gadget
+    pub create: fn() -> Box<dyn OutputPersona>,
+}
+
+/// All input drivers and output personas this program knows about
+pub struct DriverRegistry {
+    input_drivers: Vec<InputDriverEntry>,
+    output_personas: Vec<OutputPersonaEntry>,
+}
+
+impl DriverRegistry {
+    /// A registry without any drivers, see `with_builtin_drivers()`
+    pub fn new() -> Self {
+        Self {
+            input_drivers: Vec::new(),
+            output_personas: Vec::new(),
+        }
+    }
+
+    /// A registry containing every gamepad model implemented in this program
+    pub fn with_builtin_drivers() -> Self {
+        let mut registry = Self::new();
+
+        registry.register_input_driver(DUALSENSE_INPUT);
+        registry.register_input_driver(DUALSHOCK_INPUT);
+
+        registry.register_output_persona(DUALSENSE_OUTPUT);
+        registry.register_output_persona(DUALSHOCK_OUTPUT);
+
+        return registry;
+    }
+
+    pub fn register_input_driver(&mut self, entry: InputDriverEntry) {
+        self.input_drivers.push(entry);
+    }
+
+    pub fn register_output_persona(&mut self, entry: OutputPersonaEntry) {
+        self.output_personas.push(entry);
+    }
+
+    pub fn input_drivers(&self) -> &[InputDriverEntry] {
+        return &self.input_drivers;
+    }
+
+    pub fn output_personas(&self) -> &[OutputPersonaEntry] {
+        return &self.output_personas;
+    }
+
+    /// Returns the supported input driver for the hid device with the given vendor and product id
+    pub fn input_driver_for(&self, vendor_id: u16, product_id: u16) -> Option<&InputDriverEntry> {
+        return self
+            .input_drivers
+            .iter()
+            .find(|entry| entry.is_supported && entry.vendor_id == vendor_id && entry.product_id == product_id);
+    }
+
+    /// Checks if the first command line argument was given, exits with descriptive error if not
+    ///
+    /// If argument was given, checks if it contains a string describing any supported output persona
+    pub fn output_persona_from_cmdline_args(&self) -> &OutputPersonaEntry {
+        let args: Vec<String> = env::args().collect();
+
+        if args.len() < 2 {
+            println!("One command line argument was expected to describe the desired output gamepad");
+            println!("If run with cargo, use: cargo run -- <argument> [--config <path>]");
+            self._display_supported_output_personas();
+        }
+
+        let given_arg: &String = &args[1];
+
+        for entry in &self.output_personas {
+            for associated_arg in entry.associated_args {
+                if given_arg.contains(associated_arg) {
+                    if entry.is_supported {
+                        println!("Output gamepad is {}", entry.display_name);
+                        return entry;
+                    } else {
+                        println!("The gamepad {} is not yet supported", entry.display_name);
+                        break;
+                    }
+                }
+            }
+        }
+
+        println!("No supported gamepad is associated with the input '{}'", given_arg);
+        self._display_supported_output_personas();
+    }
+
+    fn _display_supported_output_personas(&self) -> ! {
+        println!();
+        println!("Supported gamepads are:");
+        for entry in &self.output_personas {
+            if entry.is_supported {
+                println!("{}: with any of {:?} as the argument", entry.display_name, entry.associated_args);
+            }
+        }
+        println!();
+        println!("Other commands are:");
+        println!("calibrate: measures the sticks and triggers of the connected gamepad");
+        println!("controllers: lists the known controllers, or changes them with set <serial> <key> <value> and forget <serial>");
+        exit(1);
+    }
+}
@@ -0,0 +1,406 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, ConfigError, ConfigSection};
+use crate::input_mapping::ButtonCombo;
+use crate::processing::ProcessingStage;
+use crate::universal_gamepad::{Accelerometer, Gyroscope, MouseMovement, UniversalGamepad};
+
+/// Raw gyroscope value of a DualSense for a rotation of 1°/s
+pub const GYRO_COUNTS_PER_DEGREE_PER_SECOND: f32 = 16.0;
+
+/// How much a turn in player space may exceed the yaw around gravity, see `_turn_rate()`
+const PLAYER_SPACE_RELAX_FACTOR: f32 = 1.41;
+
+/// Weight of a new accelerometer value in the gravity estimation, small values ignore short shakes
+const GRAVITY_SMOOTHING: f32 = 0.05;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GyroOutput {
+    LeftStick,
+    RightStick,
+
+    /// Relative mouse movement, sent by a mouse function on the gadget
+    Mouse,
+}
+
+/// Which rotation of the gamepad turns the camera left and right
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum GyroSpace {
+    /// Rotation around the axis pointing out of the gamepad's top, depends on how the gamepad is held
+    Local,
+
+    /// Rotation around gravity, works the same no matter how the gamepad is tilted
+    World,
+
+    /// Like `World`, but also accepts rolling the gamepad (turning it like a steering wheel)
+    Player,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RatchetMode {
+    /// Gyro aiming is paused while the ratchet is held, to re-center the gamepad
+    HoldToDisable,
+
+    /// Gyro aiming only works while the ratchet is held
+    HoldToEnable,
+
+    /// Each press switches gyro aiming on or off
+    Toggle,
+}
+
+/// Turns the rotation of the gamepad into stick deflection or mouse movement, configured with a `[gyro]` section
+///
+/// Runs on the physical inputs, so a ratchet button can be disabled by a profile without losing its function here
+pub struct GyroAiming {
+    pub output: GyroOutput,
+    pub space: GyroSpace,
+
+    /// Multiplier for the turn rate
+    pub sensitivity: f32,
+
+    /// Additional sensitivity for every 100°/s the gamepad is turned, fast flicks reach further than slow aiming
+    pub acceleration: f32,
+
+    /// Turn rates are averaged over this time, reduces the shaking of the hands
+    pub smoothing: Duration,
+
+    /// Turn rate in °/s (after sensitivity) that fully deflects the stick
+    pub full_deflection_speed: f32,
+
+    /// The smallest stick deflection that is sent, to cancel out the deadzone of the game
+    pub anti_deadzone: f32,
+
+    /// Mouse counts per degree of rotation (after sensitivity)
+    pub counts_per_degree: f32,
+
+    pub ratchet: Option<(ButtonCombo, RatchetMode)>,
+
+    /// (time, yaw, pitch) of the recent reports, used for smoothing
+    samples: VecDeque<(Instant, f32, f32)>,
+
+    /// Normalized direction the accelerometer measures when the gamepad rests, opposite of gravity
+    up: (f32, f32, f32),
+
+    last_update: Option<Instant>,
+    ratchet_was_pressed: bool,
+    toggled_on: bool,
+
+    /// Fractions of mouse counts that were not sent yet
+    mouse_remainder: (f32, f32),
+}
+
+impl GyroAiming {
+    fn new(output: GyroOutput, space: GyroSpace) -> Self {
+        Self {
+            output,
+            space,
+            sensitivity: 1.0,
+            acceleration: 0.0,
+            smoothing: Duration::ZERO,
+            full_deflection_speed: 180.0,
+            anti_deadzone: 0.0,
+            counts_per_degree: 10.0,
+            ratchet: None,
+            samples: VecDeque::new(),
+            up: (0.0, 1.0, 0.0),
+            last_update: None,
+            ratchet_was_pressed: false,
+            toggled_on: true,
+            mouse_remainder: (0.0, 0.0),
+        }
+    }
+
+    /// Returns `Ok(None)` if there is no `[gyro]` section
+    pub fn from_config(config: &Config) -> Result<Option<Self>, ConfigError> {
+        match config.section("gyro", "") {
+            Some(section) => return Ok(Some(Self::from_section(section)?)),
+            None => return Ok(None),
+        }
+    }
+
+    /// - `output = right_stick` (default), `left_stick` or `mouse`
+    /// - `space = player` (default), `world` or `local`
+    /// - `sensitivity`, `acceleration`, `smoothing = <ms>`
+    /// - `full_deflection_speed = <°/s>` and `anti_deadzone` for stick output
+    /// - `counts_per_degree` for mouse output
+    /// - `ratchet = <button>, ...` with `ratchet_mode = hold_to_disable` (default), `hold_to_enable` or `toggle`
+    fn from_section(section: &ConfigSection) -> Result<Self, ConfigError> {
+        let output: GyroOutput = match section.get("output") {
+            None | Some("right_stick") => GyroOutput::RightStick,
+            Some("left_stick") => GyroOutput::LeftStick,
+            Some("mouse") => GyroOutput::Mouse,
+            Some(other) => return Err(section.error(format!("unknown output: {other}"))),
+        };
+        let space: GyroSpace = match section.get("space") {
+            None | Some("player") => GyroSpace::Player,
+            Some("world") => GyroSpace::World,
+            Some("local") => GyroSpace::Local,
+            Some(other) => return Err(section.error(format!("unknown space: {other}"))),
+        };
+
+        let mut gyro = Self::new(output, space);
+
+        if let Some(value) = section.get_parsed::<f32>("sensitivity")? {
+            gyro.sensitivity = value;
+        }
+        if let Some(value) = section.get_parsed::<f32>("acceleration")? {
+            gyro.acceleration = value;
+        }
+        if let Some(value) = section.get_parsed::<u64>("smoothing")? {
+            gyro.smoothing = Duration::from_millis(value);
+        }
+        if let Some(value) = section.get_parsed::<f32>("full_deflection_speed")? {
+            if value <= 0.0 {
+                return Err(section.error(format!("full_deflection_speed has to be above 0, not {value}")));
+            }
+            gyro.full_deflection_speed = value;
+        }
+        if let Some(value) = section.get_parsed::<f32>("anti_deadzone")? {
+            if (0.0..1.0).contains(&value) == false {
+                return Err(section.error(format!("anti_deadzone has to be between 0 and 1, not {value}")));
+            }
+            gyro.anti_deadzone = value;
+        }
+        if let Some(value) = section.get_parsed::<f32>("counts_per_degree")? {
+            if value.is_finite() == false || value <= 0.0 {
+                return Err(section.error(format!("counts_per_degree has to be above 0, not {value}")));
+            }
+            gyro.counts_per_degree = value;
+        }
+
+        if let Some(combo) = ButtonCombo::from_section(section, "ratchet")? {
+            let mode: RatchetMode = match section.get("ratchet_mode") {
+                None | Some("hold_to_disable") => RatchetMode::HoldToDisable,
+                Some("hold_to_enable") => RatchetMode::HoldToEnable,
+                Some("toggle") => RatchetMode::Toggle,
+                Some(other) => return Err(section.error(format!("unknown ratchet_mode: {other}"))),
+            };
+            gyro.ratchet = Some((combo, mode));
+        }
+
+        return Ok(gyro);
+    }
+
+    fn _is_enabled(&mut self, gamepad: &UniversalGamepad) -> bool {
+        let (combo, mode) = match &self.ratchet {
+            Some((combo, mode)) => (combo, *mode),
+            None => return true,
+        };
+
+        let is_pressed: bool = combo.is_pressed(gamepad);
+        let new_press: bool = is_pressed && self.ratchet_was_pressed == false;
+        self.ratchet_was_pressed = is_pressed;
+
+        match mode {
+            RatchetMode::HoldToDisable => return is_pressed == false,
+            RatchetMode::HoldToEnable => return is_pressed,
+            RatchetMode::Toggle => {
+                if new_press {
+                    self.toggled_on = !self.toggled_on;
+                }
+                return self.toggled_on;
+            }
+        }
+    }
+
+    fn _update_gravity(&mut self, accelerometer: &Accelerometer) {
+        let measured: (f32, f32, f32) = _normalize((accelerometer.x as f32, accelerometer.y as f32, accelerometer.z as f32));
+        if measured == (0.0, 0.0, 0.0) {
+            return;
+        }
+
+        self.up = _normalize((
+            self.up.0 + (measured.0 - self.up.0) * GRAVITY_SMOOTHING,
+            self.up.1 + (measured.1 - self.up.1) * GRAVITY_SMOOTHING,
+            self.up.2 + (measured.2 - self.up.2) * GRAVITY_SMOOTHING,
+        ));
+    }
+
+    /// Average (yaw, pitch) over the smoothing window in °/s
+    fn _smoothed(&mut self, yaw: f32, pitch: f32, now: Instant) -> (f32, f32) {
+        self.samples.push_back((now, yaw, pitch));
+        while self.samples.len() > 1 && now - self.samples[0].0 > self.smoothing {
+            self.samples.pop_front();
+        }
+
+        let count: f32 = self.samples.len() as f32;
+        let yaw_sum: f32 = self.samples.iter().map(|sample| sample.1).sum();
+        let pitch_sum: f32 = self.samples.iter().map(|sample| sample.2).sum();
+
+        return (yaw_sum / count, pitch_sum / count);
+    }
+
+    fn _to_stick(&self, yaw: f32, pitch: f32, gamepad: &mut UniversalGamepad) {
+        // turning right and tilting up move the stick right and up (towards 0)
+        let mut x: f32 = -yaw / self.full_deflection_speed;
+        let mut y: f32 = -pitch / self.full_deflection_speed;
+
+        let magnitude: f32 = (x * x + y * y).sqrt();
+        if magnitude <= f32::EPSILON {
+            return;
+        }
+        let deflection: f32 = self.anti_deadzone + (1.0 - self.anti_deadzone) * magnitude.min(1.0);
+        x *= deflection / magnitude;
+        y *= deflection / magnitude;
+
+        let stick = match self.output {
+            GyroOutput::LeftStick => &mut gamepad.sticks.left,
+            _ => &mut gamepad.sticks.right,
+        };
+
+        // added to the physical stick, so both can be used at once
+        stick.x = _add_deflection(stick.x, x);
+        stick.y = _add_deflection(stick.y, y);
+    }
+
+    fn _to_mouse(&mut self, yaw: f32, pitch: f32, elapsed: Duration, gamepad: &mut UniversalGamepad) {
+        let degrees_to_counts: f32 = elapsed.as_secs_f32() * self.counts_per_degree;
+        let x: f32 = -yaw * degrees_to_counts + self.mouse_remainder.0;
+        let y: f32 = -pitch * degrees_to_counts + self.mouse_remainder.1;
+
+        let whole: (f32, f32) = (x.trunc(), y.trunc());
+        self.mouse_remainder = (x - whole.0, y - whole.1);
+
+        let movement: &mut MouseMovement = gamepad.other.mouse.get_or_insert(MouseMovement { x: 0, y: 0 });
+        movement.x = movement.x.saturating_add(whole.0.clamp(i16::MIN as f32, i16::MAX as f32) as i16);
+        movement.y = movement.y.saturating_add(whole.1.clamp(i16::MIN as f32, i16::MAX as f32) as i16);
+    }
+}
+
+impl ProcessingStage for GyroAiming {
+    fn display_name(&self) -> &'static str {
+        return "Gyro aiming";
+    }
+
+    fn process(&mut self, gamepad: &mut UniversalGamepad, now: Instant) {
+        // the first report after a pause must not turn the whole pause into mouse movement
+        let elapsed: Duration = match self.last_update {
+            Some(last) => (now - last).min(Duration::from_millis(100)),
+            None => Duration::ZERO,
+        };
+        self.last_update = Some(now);
+
+        if let Some(accelerometer) = &gamepad.other.accelerometer {
+            let accelerometer: Accelerometer = accelerometer.clone();
+            self._update_gravity(&accelerometer);
+        }
+
+        let enabled: bool = self._is_enabled(gamepad);
+
+        let gyroscope: &Gyroscope = match &gamepad.other.gyroscope {
+            Some(gyroscope) => gyroscope,
+            None => return,
+        };
+        if enabled == false {
+            self.samples.clear();
+            self.mouse_remainder = (0.0, 0.0);
+            return;
+        }
+
+        let (yaw, pitch) = _turn_rate(self.space, gyroscope, self.up);
+        let (yaw, pitch) = self._smoothed(yaw, pitch, now);
+
+        let speed: f32 = (yaw * yaw + pitch * pitch).sqrt();
+        let factor: f32 = self.sensitivity * (1.0 + self.acceleration * speed / 100.0);
+
+        match self.output {
+            GyroOutput::Mouse => self._to_mouse(yaw * factor, pitch * factor, elapsed, gamepad),
+            _ => self._to_stick(yaw * factor, pitch * factor, gamepad),
+        }
+    }
+}
+
+/// Returns (yaw, pitch) in °/s, positive yaw turns left and positive pitch tilts the top of the gamepad up
+///
+/// `up` is the normalized direction opposite of gravity, in the coordinates of the gamepad
+fn _turn_rate(space: GyroSpace, gyroscope: &Gyroscope, up: (f32, f32, f32)) -> (f32, f32) {
+    let x: f32 = gyroscope.x as f32 / GYRO_COUNTS_PER_DEGREE_PER_SECOND;
+    let y: f32 = gyroscope.y as f32 / GYRO_COUNTS_PER_DEGREE_PER_SECOND;
+    let z: f32 = gyroscope.z as f32 / GYRO_COUNTS_PER_DEGREE_PER_SECOND;
+
+    match space {
+        GyroSpace::Local => return (y, x),
+        GyroSpace::World => return (x * up.0 + y * up.1 + z * up.2, x),
+        GyroSpace::Player => {
+            // yaw around gravity, but rolling the gamepad counts as well, up to the size of the local rotation
+            let world_yaw: f32 = y * up.1 + z * up.2;
+            let local_yaw: f32 = (y * y + z * z).sqrt();
+            return (world_yaw.signum() * (world_yaw.abs() * PLAYER_SPACE_RELAX_FACTOR).min(local_yaw), x);
+        }
+    }
+}
+
+fn _normalize(vector: (f32, f32, f32)) -> (f32, f32, f32) {
+    let length: f32 = (vector.0 * vector.0 + vector.1 * vector.1 + vector.2 * vector.2).sqrt();
+    if length <= f32::EPSILON {
+        return (0.0, 0.0, 0.0);
+    }
+    return (vector.0 / length, vector.1 / length, vector.2 / length);
+}
+
+/// `value` is 0 - 255 with 128 as center, `deflection` is -1 - 1
+fn _add_deflection(value: u8, deflection: f32) -> u8 {
+    let current: f32 = (value as f32 - 127.5) / 127.5;
+    return (127.5 + (current + deflection).clamp(-1.0, 1.0) * 127.5).round() as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn _gamepad_turning(yaw_degrees_per_second: f32, pressed_ratchet: bool) -> UniversalGamepad {
+        let mut gamepad = UniversalGamepad::nothing_pressed();
+        gamepad.sticks.right = crate::universal_gamepad::Stick {
+            x: 128,
+            y: 128,
+            pressed: false,
+        };
+        gamepad.buttons.bumpers.left = pressed_ratchet;
+        gamepad.other.gyroscope = Some(Gyroscope {
+            x: 0,
+            y: (yaw_degrees_per_second * GYRO_COUNTS_PER_DEGREE_PER_SECOND) as i16,
+            z: 0,
+        });
+        return gamepad;
+    }
+
+    #[test]
+    fn turning_right_deflects_the_stick_right() {
+        let mut gyro = GyroAiming::new(GyroOutput::RightStick, GyroSpace::Local);
+        let now = Instant::now();
+
+        let mut gamepad = _gamepad_turning(-90.0, false);
+        gyro.process(&mut gamepad, now);
+        assert!(gamepad.sticks.right.x > 180, "x = {}", gamepad.sticks.right.x);
+        assert_eq!(gamepad.sticks.right.y, 128);
+
+        let mut gamepad = _gamepad_turning(-1000.0, false);
+        gyro.process(&mut gamepad, now);
+        assert_eq!(gamepad.sticks.right.x, 255);
+    }
+
+    #[test]
+    fn ratchet_pauses_mouse_movement() {
+        let mut gyro = GyroAiming::new(GyroOutput::Mouse, GyroSpace::Player);
+        gyro.ratchet = Some((ButtonCombo::parse("bumpers.left").expect("valid combo"), RatchetMode::HoldToDisable));
+        let start = Instant::now();
+
+        let mut gamepad = _gamepad_turning(90.0, false);
+        gyro.process(&mut gamepad, start);
+        gyro.process(&mut gamepad, start + Duration::from_millis(10));
+        let movement = gamepad.other.mouse.clone().expect("mouse movement");
+        assert!(movement.x < 0, "x = {}", movement.x);
+
+        let mut gamepad = _gamepad_turning(90.0, true);
+        gyro.process(&mut gamepad, start + Duration::from_millis(20));
+        assert_eq!(gamepad.other.mouse, None);
+    }
+
+    #[test]
+    fn mouse_output_is_read() {
+        let config = Config::parse("[gyro]\noutput = mouse\n").unwrap();
+        let gyro = GyroAiming::from_config(&config).unwrap().expect("gyro section");
+        assert_eq!(gyro.output, GyroOutput::Mouse);
+    }
+}
@@ -74,7 +74,7 @@ pub fn run_cmd(current_dir: &str, cmd: &str) -> Result<(), ()> {
 /// - Interval: 1000 µs
 /// - Rounds: 10 000x
 /// - Code max "runtime": 500 µs = interval / 2   <br>
-/// (its not actually running, just sleeping)
+///   (its not actually running, just sleeping)
 ///
 /// Results:
 /// - Code ran 9 996x out of 10 000x
@@ -145,7 +145,7 @@ fn _single_thread_interval_benchmarked(interval: Duration) {
         avg_perc += error_percent;
     }
 
-    println!("");
+    println!();
     println!("Code ran {}x, target was {}x", code_counter, ROUNDS);
     println!("Avg ABS  {} ns", avg_ns / ROUNDS);
     println!("Avg PERC {:2.3?} %", avg_perc / ROUNDS as f64);
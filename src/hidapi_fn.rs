@@ -1,12 +1,24 @@
 use ::hidapi::BusType;
 use ::hidapi::HidApi;
 use flume::Receiver;
-use flume::Sender;
 use flume::TryRecvError;
 use hidapi::DeviceInfo;
 use hidapi::HidDevice;
+use hidapi::HidResult;
 
-use crate::{universal_gamepad::UniversalGamepad, usb_gamepad::Gamepad};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::copilot::CoPilot;
+use crate::driver_registry::DriverRegistry;
+use crate::latest_state::Publisher;
+use crate::link_quality::LinkMonitor;
+use crate::pairing::PairingHotkey;
+use crate::power::{self, PowerMonitor};
+use crate::{
+    universal_gamepad::UniversalGamepad,
+    usb_gamepad::{Feedback, HostFeedback, InputDriver},
+};
 
 #[derive(Debug)]
 pub enum HidApiGamepadError {
@@ -15,11 +27,6 @@ pub enum HidApiGamepadError {
     OpenFailed,
 }
 
-pub enum SupportedInputGamepads {
-    Ps5DualSense,
-    PS4DualShock,
-}
-
 // TODO Use for first manual debugging / interpreting of new gamepads
 pub fn read_unknown_usb_input() {
     // _process_input_unknown()
@@ -29,52 +36,66 @@ fn _process_input_unknown(input: Vec<u8>) {
     print!("{}", termion::cursor::Goto(1, 1));
 
     // adjust which bytes should be visible. For PS Gamepads the first two bytes are just counters
-    let mut i: usize = 0;
+    let first: usize = 0;
 
-    for byte in input[i..].iter() {
+    for (i, byte) in input.iter().enumerate().skip(first) {
         print!("{}|{:03}\t", i, byte);
-        i += 1;
     }
 }
 
+/// An opened gamepad and the driver for its input reports
+pub type HidGamepad = (HidDevice, Box<dyn InputDriver>);
+
 /// Checks for connected HID Devices, tries to find a supported one
 ///
 /// Returns in `HidApiGamepadError` if:
 /// - No bluetooth hid device is connected
-/// - None of the connected devices are from a supported vendor
-/// - None of known vendor devices are supported products
+/// - None of the connected devices has a supported input driver in `registry`
 /// - Opening a device failed
-pub fn get_hid_gamepad(api: &HidApi) -> Result<(HidDevice, SupportedInputGamepads), HidApiGamepadError> {
+pub fn get_hid_gamepad(api: &HidApi, registry: &DriverRegistry) -> Result<HidGamepad, HidApiGamepadError> {
+    let mut gamepads = get_hid_gamepads(api, registry)?;
+    return Ok(gamepads.remove(0));
+}
+
+/// Like `get_hid_gamepad()`, but returns every supported gamepad, in the order hidapi lists them
+///
+/// The returned vec is never empty
+pub fn get_hid_gamepads(api: &HidApi, registry: &DriverRegistry) -> Result<Vec<HidGamepad>, HidApiGamepadError> {
     let bluetooth_devices: Vec<&DeviceInfo> = match _get_bluetooth_hid_devices(api) {
         Ok(vec) => vec,
         Err(_) => return Err(HidApiGamepadError::NoBTDevice),
     };
 
     // most likely only one gamepad will be connected at one time, so its fastest to assume an vec size of 1
+    let mut gamepads: Vec<HidGamepad> = Vec::with_capacity(1);
     let mut error_info: Vec<(u16, u16, Option<&str>)> = Vec::with_capacity(1);
 
     for device_info in bluetooth_devices {
         let vid: u16 = device_info.vendor_id();
         let pid: u16 = device_info.product_id();
 
-        match (vid, pid) {
-            // PS5 Gamepad
-            (0x054c, 0x0ce6) => {
-                match api.open(vid, pid) {
-                    Ok(hid_device) => return Ok((hid_device, SupportedInputGamepads::Ps5DualSense)),
+        match registry.input_driver_for(vid, pid) {
+            Some(entry) => {
+                // opened by path, because two gamepads of the same model share vendor and product id
+                match api.open_path(device_info.path()) {
+                    Ok(hid_device) => gamepads.push((hid_device, (entry.create)())),
                     Err(err) => {
                         println!("OpenFailed: vendor {:?}, product {:?}, Error {:?}", vid, pid, err);
                         return Err(HidApiGamepadError::OpenFailed);
                     }
                 };
             }
-            _ => {
+            None => {
                 error_info.push((vid, pid, device_info.product_string()));
                 continue;
             }
         };
     }
 
+    if gamepads.is_empty() == false {
+        return Ok(gamepads);
+    }
+
     println!("All of these devices are connected but not supported:");
     for device in error_info {
         println!("vendor {:?}, product {:?} {:?}", device.0, device.1, device.2);
@@ -116,23 +137,94 @@ fn _get_bluetooth_hid_devices(api: &HidApi) -> Result<Vec<&DeviceInfo>, ()> {
     return Ok(bluetooth_devices);
 }
 
-pub fn read_bt_gamepad_input(device: HidDevice, input_gamepad: &Gamepad, sender: Sender<UniversalGamepad>, receiver_exit_request: Receiver<()>) {
+/// Reads one report, returns `None` if none arrived within `timeout_ms` or it was too short
+pub fn read_single_gamepad(device: &HidDevice, input_driver: &mut dyn InputDriver, timeout_ms: i32) -> Option<UniversalGamepad> {
+    let mut buf: [u8; 100] = [0_u8; 100];
+
+    match device.read_timeout(&mut buf[..], timeout_ms) {
+        Ok(value) if value > input_driver.min_bt_report_size() => return Some(input_driver.bt_input_to_universal_gamepad(&buf[..value])),
+        Ok(_) => return None,
+        Err(e) => {
+            println!("read_timeout error: {e}");
+            return None;
+        }
+    }
+}
+
+/// Optional watchers of one input thread, each one looks at every report of its gamepad
+pub struct InputMonitors {
+    /// Watches the timing and sequence of the reports and warns the player on the gamepad itself
+    pub link: Option<LinkMonitor>,
+
+    /// Turns the gamepad off after inactivity or by hotkey
+    pub power: Option<PowerMonitor>,
+
+    /// Asks the main thread to pair another gamepad
+    pub pairing: Option<PairingHotkey>,
+
+    /// Rumble and lightbar color the host sent to the output gamepad, passed on to this gamepad
+    pub host: Option<Arc<HostFeedback>>,
+}
+
+/// The part of a `HidDevice` an input thread uses, so the thread can be driven by something else in tests
+pub trait ReportDevice {
+    fn set_blocking_mode(&self, blocking: bool) -> HidResult<()>;
+    fn read_timeout(&self, buf: &mut [u8], timeout_ms: i32) -> HidResult<usize>;
+    fn write(&self, data: &[u8]) -> HidResult<usize>;
+}
+
+impl ReportDevice for HidDevice {
+    fn set_blocking_mode(&self, blocking: bool) -> HidResult<()> {
+        return HidDevice::set_blocking_mode(self, blocking);
+    }
+
+    fn read_timeout(&self, buf: &mut [u8], timeout_ms: i32) -> HidResult<usize> {
+        return HidDevice::read_timeout(self, buf, timeout_ms);
+    }
+
+    fn write(&self, data: &[u8]) -> HidResult<usize> {
+        return HidDevice::write(self, data);
+    }
+}
+
+/// Sends rumble (right, left) and lightbar color to the gamepad, if there is something to send and the gamepad supports it
+fn _write_feedback(device: &impl ReportDevice, input_driver: &mut dyn InputDriver, feedback: Option<Feedback>) {
+    let output_report = feedback.and_then(|(rumble, lightbar)| input_driver.bt_output_report(rumble, lightbar));
+    if let Some(output_report) = output_report {
+        if let Err(err) = device.write(&output_report) {
+            println!("Writing rumble and lightbar to the gamepad failed: {err}");
+        }
+    }
+}
+
+/// Reads reports until `receiver_exit_request` receives something or is disconnected
+///
+/// Every gamepad is published as the newest state for the output thread.
+/// In co-pilot mode, `copilot` is the shared merger and the index of this controller, the merged gamepad is published.
+/// If the power monitor decides to turn the gamepad off, a neutral state is published, the gamepad is disconnected and this returns.
+/// If reading fails, e.g. because the gamepad is gone, a neutral state is published and this returns
+pub fn read_bt_gamepad_input(
+    device: impl ReportDevice,
+    mut input_driver: Box<dyn InputDriver>,
+    mut publisher: Publisher<UniversalGamepad>,
+    receiver_exit_request: Receiver<()>,
+    copilot: Option<(Arc<CoPilot>, usize)>,
+    mut monitors: InputMonitors,
+) {
     // if set to false, calls to read may return nothing, but also dont block
     match device.set_blocking_mode(true) {
         Ok(_) => (),
         Err(err) => panic!("HidError: {:?}", err),
     };
 
-    let min_size: usize = input_gamepad.min_bt_report_size;
-    let mut buf: [u8; 100] = [0 as u8; 100];
+    let min_size: usize = input_driver.min_bt_report_size();
+    let mut buf: [u8; 100] = [0_u8; 100];
+    let mut host_generation: u32 = 0;
 
     loop {
         // did the main thread request that this thread stops?
         match receiver_exit_request.try_recv() {
-            Ok(_) | Err(TryRecvError::Disconnected) => {
-                sender.downgrade();
-                break;
-            }
+            Ok(_) | Err(TryRecvError::Disconnected) => break,
             Err(TryRecvError::Empty) => (),
         }
 
@@ -145,18 +237,128 @@ pub fn read_bt_gamepad_input(device: HidDevice, input_gamepad: &Gamepad, sender:
         match device.read_timeout(&mut buf[..], -1) {
             Ok(value) => match value.cmp(&min_size) {
                 std::cmp::Ordering::Greater => {
-                    let gamepad = input_gamepad.bt_input_to_universal_gamepad(&buf.to_vec());
-                    match sender.send(gamepad) {
-                        Ok(_) => {}
-                        Err(err) => println!("Error sending gamepad to output thread: {err}"),
-                    };
+                    if let Some(link_monitor) = &mut monitors.link {
+                        let feedback = link_monitor.report_received(Instant::now(), input_driver.report_sequence(&buf[..value]));
+                        _write_feedback(&device, input_driver.as_mut(), feedback);
+                    }
+
+                    if let Some(host_feedback) = &monitors.host {
+                        let feedback = host_feedback.changed_since(&mut host_generation);
+                        _write_feedback(&device, input_driver.as_mut(), feedback);
+                    }
+
+                    let mut gamepad = input_driver.bt_input_to_universal_gamepad(&buf[..value]);
+                    if let Some(pairing_hotkey) = &mut monitors.pairing {
+                        let feedback = pairing_hotkey.update(&gamepad, Instant::now());
+                        _write_feedback(&device, input_driver.as_mut(), feedback);
+                    }
+                    if let Some(power_monitor) = &mut monitors.power {
+                        if let Some(reason) = power_monitor.update(&gamepad, Instant::now()) {
+                            println!("Turning gamepad {} off: {:?}", power_monitor.address, reason);
+                            // the host keeps the last state, so nothing may stay pressed
+                            gamepad = UniversalGamepad::nothing_pressed();
+                            if let Some((copilot, index)) = &copilot {
+                                gamepad = copilot.update(*index, gamepad);
+                            }
+                            publisher.publish(gamepad);
+                            if power::power_off(&power_monitor.address) == false {
+                                println!("Turning gamepad {} off failed", power_monitor.address);
+                            }
+                            return;
+                        }
+                    }
+                    if let Some((copilot, index)) = &copilot {
+                        gamepad = copilot.update(*index, gamepad);
+                    }
+                    publisher.publish(gamepad);
                 }
                 _ => continue,
             },
             Err(e) => {
-                println!("read_timeout error: {e}");
-                continue;
+                println!("Reading from gamepad failed, stopping its input: {e}");
+                // the host keeps the last state, so nothing may stay pressed
+                let mut gamepad = UniversalGamepad::nothing_pressed();
+                if let Some((copilot, index)) = &copilot {
+                    gamepad = copilot.update(*index, gamepad);
+                }
+                publisher.publish(gamepad);
+                return;
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::latest_state::latest_state;
+    use crate::universal_gamepad::Button;
+    use hidapi::HidError;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// Sends one report, then fails like a gamepad that is gone
+    struct FailingDevice {
+        reads: Rc<Cell<usize>>,
+    }
+
+    impl ReportDevice for FailingDevice {
+        fn set_blocking_mode(&self, _blocking: bool) -> HidResult<()> {
+            return Ok(());
+        }
+
+        fn read_timeout(&self, _buf: &mut [u8], _timeout_ms: i32) -> HidResult<usize> {
+            self.reads.set(self.reads.get() + 1);
+            if self.reads.get() == 1 {
+                return Ok(10);
             }
+            return Err(HidError::HidApiError {
+                message: "device disconnected".to_string(),
+            });
+        }
+
+        fn write(&self, data: &[u8]) -> HidResult<usize> {
+            return Ok(data.len());
+        }
+    }
+
+    /// Every report presses the lower main button
+    struct PressingDriver;
+
+    impl InputDriver for PressingDriver {
+        fn display_name(&self) -> &'static str {
+            return "Test";
+        }
+
+        fn min_bt_report_size(&self) -> usize {
+            return 0;
+        }
+
+        fn bt_input_to_universal_gamepad(&mut self, _bt_input: &[u8]) -> UniversalGamepad {
+            let mut gamepad = UniversalGamepad::nothing_pressed();
+            gamepad.set_button(Button::MainLower, true);
+            return gamepad;
+        }
+    }
+
+    #[test]
+    fn read_error_publishes_nothing_pressed_and_stops() {
+        let (state, mut subscriber) = latest_state(UniversalGamepad::nothing_pressed(), 1);
+        let publisher = state.publisher().unwrap();
+        let (_sender_exit_request, receiver_exit_request) = flume::bounded(1);
+        let reads: Rc<Cell<usize>> = Rc::new(Cell::new(0));
+        let device = FailingDevice { reads: reads.clone() };
+        let monitors = InputMonitors {
+            link: None,
+            power: None,
+            pairing: None,
+            host: None,
         };
+
+        // returns instead of retrying the failing read forever
+        read_bt_gamepad_input(device, Box::new(PressingDriver), publisher, receiver_exit_request, None, monitors);
+
+        assert_eq!(reads.get(), 2);
+        assert_eq!(subscriber.try_recv(), Some(UniversalGamepad::nothing_pressed()));
     }
 }
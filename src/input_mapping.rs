@@ -0,0 +1,466 @@
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, ConfigError, ConfigSection};
+use crate::processing::ProcessingStage;
+use crate::universal_gamepad::{Axis, Button, UniversalGamepad};
+
+/// A button or axis of a `UniversalGamepad`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Input {
+    Button(Button),
+    Axis(Axis),
+}
+
+impl Input {
+    /// Every button followed by every axis
+    pub fn all() -> Vec<Input> {
+        let buttons = Button::ALL.into_iter().map(Input::Button);
+        let axes = Axis::ALL.into_iter().map(Input::Axis);
+        return buttons.chain(axes).collect();
+    }
+
+    /// `true` if the input is pressed or deflected more than `threshold` from its rest value
+    pub fn is_active(&self, gamepad: &UniversalGamepad, threshold: u8) -> bool {
+        match self {
+            Input::Button(button) => return gamepad.button(*button),
+            Input::Axis(axis) => return gamepad.axis(*axis).abs_diff(axis.rest_value()) > threshold,
+        }
+    }
+
+    /// Releases the button or moves the axis to its rest value
+    pub fn release(&self, gamepad: &mut UniversalGamepad) {
+        match self {
+            Input::Button(button) => gamepad.set_button(*button, false),
+            Input::Axis(axis) => gamepad.set_axis(*axis, axis.rest_value()),
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Input> {
+        if let Some(button) = Button::from_name(name) {
+            return Some(Input::Button(button));
+        }
+        if let Some(axis) = Axis::from_name(name) {
+            return Some(Input::Axis(axis));
+        }
+        return None;
+    }
+}
+
+/// Buttons that have to be held at the same time, written as `<button>, <button>, ...` in config files
+#[derive(Clone, PartialEq, Debug)]
+pub struct ButtonCombo {
+    pub buttons: Vec<Button>,
+}
+
+impl ButtonCombo {
+    pub fn parse(text: &str) -> Option<ButtonCombo> {
+        let mut buttons: Vec<Button> = Vec::new();
+
+        for name in text.split(',').map(|name| name.trim()) {
+            buttons.push(Button::from_name(name)?);
+        }
+
+        if buttons.is_empty() {
+            return None;
+        }
+        return Some(ButtonCombo { buttons });
+    }
+
+    /// Reads the combo from `key` in `section`, returns `Ok(None)` if the key is not set
+    pub fn from_section(section: &ConfigSection, key: &str) -> Result<Option<ButtonCombo>, ConfigError> {
+        match section.get(key) {
+            Some(text) => match ButtonCombo::parse(text) {
+                Some(combo) => return Ok(Some(combo)),
+                None => return Err(section.error(format!("invalid button combo for {key}: {text}"))),
+            },
+            None => return Ok(None),
+        }
+    }
+
+    pub fn is_pressed(&self, gamepad: &UniversalGamepad) -> bool {
+        return self.buttons.iter().all(|button| gamepad.button(*button));
+    }
+
+    /// Releases all buttons of this combo, so the host never sees them
+    pub fn release(&self, gamepad: &mut UniversalGamepad) {
+        for button in &self.buttons {
+            gamepad.set_button(*button, false);
+        }
+    }
+}
+
+/// A `ButtonCombo` that has to be held for a while, so hotkeys do not trigger by accident
+#[derive(Clone, PartialEq, Debug)]
+pub struct HeldCombo {
+    pub combo: ButtonCombo,
+    pub hold_time: Duration,
+    since: Option<Instant>,
+    fired: bool,
+}
+
+impl HeldCombo {
+    pub fn new(combo: ButtonCombo, hold_time: Duration) -> Self {
+        Self {
+            combo,
+            hold_time,
+            since: None,
+            fired: false,
+        }
+    }
+
+    /// Returns `true` once per hold, as soon as the combo was held for `hold_time`
+    pub fn update(&mut self, gamepad: &UniversalGamepad, now: Instant) -> bool {
+        if self.combo.is_pressed(gamepad) == false {
+            self.since = None;
+            self.fired = false;
+            return false;
+        }
+
+        let since: Instant = *self.since.get_or_insert(now);
+        if self.fired || now - since < self.hold_time {
+            return false;
+        }
+        self.fired = true;
+        return true;
+    }
+}
+
+/// Where the value of a mapped input comes from
+///
+/// A leading `-` in the config file sets `negative`:
+/// - for a source axis, only the deflection below the rest value is used
+/// - for a target axis, a pressed source button moves the axis to 0 instead of 255
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Source {
+    pub input: Input,
+    pub negative: bool,
+}
+
+impl Source {
+    fn parse(text: &str) -> Option<Source> {
+        let (negative, name) = match text.strip_prefix('-') {
+            Some(name) => (true, name.trim()),
+            None => (false, text),
+        };
+
+        return Input::from_name(name).map(|input| Source { input, negative });
+    }
+
+    /// How far this source is pressed, 0 - 255
+    fn deflection(&self, gamepad: &UniversalGamepad) -> u8 {
+        match self.input {
+            Input::Button(button) => match gamepad.button(button) {
+                true => 255,
+                false => 0,
+            },
+            Input::Axis(axis) => {
+                let value: u8 = gamepad.axis(axis);
+                let rest: u8 = axis.rest_value();
+
+                if rest == 0 {
+                    return value;
+                }
+
+                // sticks have half the range per direction, so the deflection is doubled
+                match self.negative {
+                    true => (rest.saturating_sub(value) as u16 * 2).min(255) as u8,
+                    false => (value.saturating_sub(rest) as u16 * 2).min(255) as u8,
+                }
+            }
+        }
+    }
+}
+
+/// Axes count as a pressed button if they are deflected more than this
+const AXIS_AS_BUTTON_THRESHOLD: u8 = 128;
+
+/// Keys of the accessibility modes, which are set in the `[profile <name>]` sections
+pub const ACCESSIBILITY_KEYS: [&str; 6] = [
+    "repeat_suppression",
+    "hold_to_toggle",
+    "hold_to_toggle_time",
+    "sticky",
+    "one_handed",
+    "one_handed_shift",
+];
+
+/// A named set of remapping rules, read from a `[profile <name>]` section
+///
+/// Inputs that are not mentioned stay mapped 1:1
+#[derive(Clone, PartialEq, Debug)]
+pub struct Profile {
+    pub name: String,
+
+    /// target input and the sources it is read from, if there are multiple sources the strongest wins
+    pub rules: Vec<(Input, Vec<Source>)>,
+
+    /// axes that are mirrored around their rest value after mapping
+    pub inverted: Vec<Axis>,
+
+    /// inputs that are always released / at rest after mapping
+    pub disabled: Vec<Input>,
+
+    /// names of the `[layer <name>]` sections that can be shifted to, see `mapping_layers`
+    pub layers: Vec<String>,
+}
+
+impl Profile {
+    /// A profile without rules, every input is mapped 1:1
+    pub fn identity(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            rules: Vec::new(),
+            inverted: Vec::new(),
+            disabled: Vec::new(),
+            layers: Vec::new(),
+        }
+    }
+
+    /// Reads all lines of a `[profile <name>]` section:
+    /// - `<target> = <source>, <source>, ...`
+    /// - `invert = <axis>, <axis>, ...`
+    /// - `disable = <input>, <input>, ...`
+    /// - `layers = <layer>, <layer>, ...`
+    ///
+    /// `extra_keys` belong to whatever else reads the same section, any other key without a `.` is an error
+    pub fn from_section(section: &ConfigSection, extra_keys: &[&str]) -> Result<Self, ConfigError> {
+        let mut profile = Self::identity(&section.name);
+
+        for (key, value) in &section.entries {
+            let names = value.split(',').map(|name| name.trim()).filter(|name| name.is_empty() == false);
+
+            match key.as_str() {
+                "invert" => {
+                    for name in names {
+                        match Axis::from_name(name) {
+                            Some(axis) => profile.inverted.push(axis),
+                            None => return Err(section.error(format!("only axes can be inverted: {name}"))),
+                        }
+                    }
+                }
+                "disable" => {
+                    for name in names {
+                        match Input::from_name(name) {
+                            Some(input) => profile.disabled.push(input),
+                            None => return Err(section.error(format!("unknown input: {name}"))),
+                        }
+                    }
+                }
+                "layers" => profile.layers.extend(names.map(|name| name.to_string())),
+                _ if extra_keys.contains(&key.as_str()) => continue,
+                _ if key.contains('.') == false => return Err(section.error(format!("unknown key {key}"))),
+                target_name => {
+                    let target: Input = match Input::from_name(target_name) {
+                        Some(target) => target,
+                        None => return Err(section.error(format!("unknown input: {target_name}"))),
+                    };
+
+                    let mut sources: Vec<Source> = Vec::new();
+                    for name in names {
+                        match Source::parse(name) {
+                            Some(source) => sources.push(source),
+                            None => return Err(section.error(format!("unknown input: {name}"))),
+                        }
+                    }
+
+                    profile.rules.push((target, sources));
+                }
+            }
+        }
+
+        return Ok(profile);
+    }
+
+    /// Finds the section `[profile <name>]` in `config`
+    pub fn from_config(config: &Config, name: &str) -> Result<Self, ConfigError> {
+        match config.section("profile", name) {
+            Some(section) => return Self::from_section(section, &ACCESSIBILITY_KEYS),
+            None => return Err(ConfigError::Syntax(0, format!("there is no section [profile {name}]"))),
+        }
+    }
+
+    /// This profile with the rules of `layer` on top, like a transparent keyboard layer
+    ///
+    /// Rules of `layer` replace the rules for the same target, inverted and disabled inputs of both are combined
+    pub fn overlaid(&self, layer: &Profile) -> Profile {
+        let mut combined: Profile = self.clone();
+        combined.name = layer.name.clone();
+        combined.layers = Vec::new();
+
+        combined
+            .rules
+            .retain(|(target, _)| layer.rules.iter().any(|(layer_target, _)| layer_target == target) == false);
+        combined.rules.extend(layer.rules.iter().cloned());
+        combined.inverted.extend(layer.inverted.iter().copied());
+        combined.disabled.extend(layer.disabled.iter().copied());
+
+        return combined;
+    }
+
+    /// The inputs that `target` is read from
+    pub fn sources_of(&self, target: Input) -> Vec<Input> {
+        if self.disabled.contains(&target) {
+            return Vec::new();
+        }
+
+        // the last rule for a target wins in `apply()`
+        match self.rules.iter().rev().find(|(rule_target, _)| *rule_target == target) {
+            Some((_, sources)) => return sources.iter().map(|source| source.input).collect(),
+            None => return vec![target],
+        }
+    }
+
+    /// All rules read from the unchanged `input`, so swapping two buttons needs no temporary value
+    pub fn apply(&self, input: &UniversalGamepad) -> UniversalGamepad {
+        let mut output: UniversalGamepad = input.clone();
+
+        for (target, sources) in &self.rules {
+            match target {
+                Input::Button(button) => {
+                    let pressed: bool = sources.iter().any(|source| source.deflection(input) > AXIS_AS_BUTTON_THRESHOLD);
+                    output.set_button(*button, pressed);
+                }
+                Input::Axis(axis) => {
+                    let rest: u8 = axis.rest_value();
+                    let mut value: u8 = rest;
+                    let mut strongest: u8 = 0;
+
+                    for source in sources {
+                        let (deflection, source_value) = match (source.input, source.negative) {
+                            // an axis mapped onto the same kind of axis keeps its value in both directions
+                            (Input::Axis(source_axis), false) if source_axis.rest_value() == rest => {
+                                let source_value: u8 = input.axis(source_axis);
+                                let deflection: u16 = source_value.abs_diff(rest) as u16 * if rest == 0 { 1 } else { 2 };
+                                (deflection.min(255) as u8, source_value)
+                            }
+                            (_, true) if rest != 0 => {
+                                let deflection: u8 = source.deflection(input);
+                                (deflection, rest - (deflection as u16 * rest as u16 / 255) as u8)
+                            }
+                            _ => {
+                                let deflection: u8 = source.deflection(input);
+                                (deflection, rest + (deflection as u16 * (255 - rest) as u16 / 255) as u8)
+                            }
+                        };
+
+                        if deflection > strongest {
+                            strongest = deflection;
+                            value = source_value;
+                        }
+                    }
+
+                    output.set_axis(*axis, value);
+                }
+            }
+        }
+
+        for axis in &self.inverted {
+            let rest: u8 = axis.rest_value();
+            let value: u8 = output.axis(*axis);
+
+            // triggers are mirrored between 0 and 255, sticks around the center
+            let inverted: u8 = match rest {
+                0 => 255 - value,
+                _ => (256 - value.max(1) as u16) as u8,
+            };
+            output.set_axis(*axis, inverted);
+        }
+
+        for input in &self.disabled {
+            match input {
+                Input::Button(button) => output.set_button(*button, false),
+                Input::Axis(axis) => output.set_axis(*axis, axis.rest_value()),
+            }
+        }
+
+        return output;
+    }
+}
+
+/// Selects the profile for one controller:
+/// 1. `[controller <serial>]` with the serial number (MAC address for bluetooth) of the input gamepad
+/// 2. `[persona <arg>]` with any of the command line arguments of the output gamepad
+/// 3. `known_profile`, the profile this controller used last time (see `known_controllers.rs`)
+///
+/// Both sections name the profile with `profile = <name>`. Returns `Ok(None)` if no profile is selected
+pub fn select_profile(
+    config: &Config,
+    controller_serial: Option<&str>,
+    persona_args: &[&str],
+    known_profile: Option<&str>,
+) -> Result<Option<Profile>, ConfigError> {
+    let by_controller = controller_serial.and_then(|serial| config.sections("controller").find(|section| section.name.eq_ignore_ascii_case(serial)));
+    let by_persona = config.sections("persona").find(|section| persona_args.contains(&section.name.as_str()));
+
+    for section in [by_controller, by_persona].into_iter().flatten() {
+        if let Some(profile_name) = section.get("profile") {
+            return Profile::from_config(config, profile_name).map(Some);
+        }
+    }
+
+    // the profile might have been removed from the config since
+    if let Some(profile_name) = known_profile {
+        if config.section("profile", profile_name).is_some() {
+            return Profile::from_config(config, profile_name).map(Some);
+        }
+    }
+
+    return Ok(None);
+}
+
+impl ProcessingStage for Profile {
+    fn display_name(&self) -> &'static str {
+        return "Remapping";
+    }
+
+    fn process(&mut self, gamepad: &mut UniversalGamepad, _now: Instant) {
+        *gamepad = self.apply(gamepad);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(content: &str) -> Profile {
+        let config = Config::parse(content).expect("test config is valid");
+        return Profile::from_config(&config, "test").expect("test profile is valid");
+    }
+
+    #[test]
+    fn swapped_buttons_read_the_original_state() {
+        let profile = profile("[profile test]\nmain.lower = main.right\nmain.right = main.lower\n");
+        let mut input = UniversalGamepad::nothing_pressed();
+        input.buttons.main.lower = true;
+
+        let output = profile.apply(&input);
+
+        assert!(output.buttons.main.right);
+        assert!(output.buttons.main.lower == false);
+    }
+
+    #[test]
+    fn buttons_and_axes_map_onto_each_other() {
+        let profile = profile("[profile test]\nsticks.left.x = -dpad.left, dpad.right\nmain.lower = triggers.right\ninvert = sticks.right.y\n");
+        let mut input = UniversalGamepad::nothing_pressed();
+        input.buttons.dpad.left = true;
+        input.triggers.right = 200;
+        input.sticks.right.y = 255;
+
+        let output = profile.apply(&input);
+
+        assert_eq!(output.sticks.left.x, 0);
+        assert!(output.buttons.main.lower);
+        assert_eq!(output.sticks.right.y, 1);
+    }
+
+    #[test]
+    fn unknown_keys_are_an_error() {
+        let config = Config::parse("[profile test]\nsticky = main.lower\ninvrt = sticks.right.y\n").unwrap();
+        assert!(Profile::from_config(&config, "test").is_err());
+
+        // layer keys only belong to layer sections
+        let config = Config::parse("[profile test]\nmode = toggle\n").unwrap();
+        assert!(Profile::from_config(&config, "test").is_err());
+    }
+}
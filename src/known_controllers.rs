@@ -0,0 +1,292 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{Config, ConfigError, ConfigSection};
+
+/// Used if `[known_controllers]` has no `path = <path>`
+pub const DEFAULT_KNOWN_CONTROLLERS_PATH: &str = "/var/lib/gamepad-bridge/controllers";
+
+/// Everything the bridge remembers about one controller
+#[derive(Clone, PartialEq, Debug)]
+pub struct KnownController {
+    /// serial number, the MAC address for bluetooth gamepads
+    pub serial: String,
+
+    /// display name of the input driver
+    pub model: Option<String>,
+
+    /// set by the user, shown instead of the serial number
+    pub name: Option<String>,
+
+    /// player slot, starting at 1. Controllers with a slot are used before the others, in the order of their slots
+    pub player: Option<usize>,
+
+    /// the profile that was used last time, selected again if the config selects none
+    pub profile: Option<String>,
+
+    /// calibration file created by `gamepad-bridge calibrate`
+    pub calibration: Option<PathBuf>,
+
+    /// seconds since the unix epoch
+    pub last_seen: Option<u64>,
+}
+
+impl KnownController {
+    pub fn new(serial: &str) -> Self {
+        Self {
+            serial: serial.to_lowercase(),
+            model: None,
+            name: None,
+            player: None,
+            profile: None,
+            calibration: None,
+            last_seen: None,
+        }
+    }
+
+    fn from_section(section: &ConfigSection) -> Result<Self, ConfigError> {
+        let mut controller = Self::new(&section.name);
+
+        for (key, value) in &section.entries {
+            if let Err(message) = controller.set(key, value) {
+                return Err(section.error(message));
+            }
+        }
+        if let Some(last_seen) = section.get_parsed::<u64>("last_seen")? {
+            controller.last_seen = Some(last_seen);
+        }
+
+        return Ok(controller);
+    }
+
+    fn to_text(&self) -> String {
+        let mut text: String = format!("[controller {}]\n", self.serial);
+
+        let values: [(&str, Option<String>); 6] = [
+            ("model", self.model.clone()),
+            ("name", self.name.clone()),
+            ("player", self.player.map(|player| player.to_string())),
+            ("profile", self.profile.clone()),
+            ("calibration", self.calibration.as_ref().map(|path| path.display().to_string())),
+            ("last_seen", self.last_seen.map(|last_seen| last_seen.to_string())),
+        ];
+        for (key, value) in values {
+            if let Some(value) = value {
+                text += &format!("{key} = {value}\n");
+            }
+        }
+
+        return text;
+    }
+
+    /// Sets one of the keys the user may edit: `name`, `player`, `profile`, `model` or `calibration`
+    ///
+    /// An empty value removes the key
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let value: &str = value.trim();
+        if value.contains('#') || value.contains('\n') {
+            return Err(format!("{key} can not contain # or line breaks"));
+        }
+        let text: Option<String> = match value.is_empty() {
+            true => None,
+            false => Some(value.to_string()),
+        };
+
+        match key {
+            "model" => self.model = text,
+            "name" => self.name = text,
+            "profile" => self.profile = text,
+            "calibration" => self.calibration = text.map(PathBuf::from),
+            "player" => {
+                self.player = match text {
+                    None => None,
+                    Some(text) => match text.parse::<usize>() {
+                        Ok(player) if player >= 1 => Some(player),
+                        _ => return Err(format!("player has to be a number starting at 1, not {text}")),
+                    },
+                }
+            }
+            // only written by the bridge itself, see `seen()`
+            "last_seen" => {}
+            _ => return Err(format!("unknown key {key}, expected name, player, profile, model or calibration")),
+        }
+
+        return Ok(());
+    }
+
+    /// `name (serial)`, or only the serial if the controller has no name
+    pub fn display_name(&self) -> String {
+        match &self.name {
+            Some(name) => return format!("{name} ({})", self.serial),
+            None => return self.serial.clone(),
+        }
+    }
+}
+
+/// On-disk database of every controller the bridge has seen, keyed by serial number
+///
+/// Same format as the config file, one `[controller <serial>]` section per controller
+pub struct KnownControllers {
+    pub path: PathBuf,
+    pub controllers: Vec<KnownController>,
+}
+
+impl KnownControllers {
+    /// A missing file is an empty registry
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let mut known_controllers = Self {
+            path: path.to_path_buf(),
+            controllers: Vec::new(),
+        };
+        if path.exists() == false {
+            return Ok(known_controllers);
+        }
+
+        let config: Config = Config::load(path)?;
+        for section in config.sections("controller") {
+            known_controllers.controllers.push(KnownController::from_section(section)?);
+        }
+
+        return Ok(known_controllers);
+    }
+
+    /// Loads the file named by `path = <path>` of the `[known_controllers]` section
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        match config.section("known_controllers", "").and_then(|section| section.get("path")) {
+            Some(path) => return Self::load(Path::new(path)),
+            None => return Self::load(Path::new(DEFAULT_KNOWN_CONTROLLERS_PATH)),
+        }
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        if let Some(directory) = self.path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        return fs::write(&self.path, self.to_text());
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text: String = String::from("# gamepad-bridge known controllers, edit with `gamepad-bridge controllers`\n");
+        for controller in &self.controllers {
+            text += "\n";
+            text += &controller.to_text();
+        }
+        return text;
+    }
+
+    pub fn get(&self, serial: &str) -> Option<&KnownController> {
+        return self.controllers.iter().find(|controller| controller.serial.eq_ignore_ascii_case(serial));
+    }
+
+    /// Adds the controller if it is not known yet
+    pub fn get_or_insert(&mut self, serial: &str) -> &mut KnownController {
+        let index: usize = match self.controllers.iter().position(|controller| controller.serial.eq_ignore_ascii_case(serial)) {
+            Some(index) => index,
+            None => {
+                self.controllers.push(KnownController::new(serial));
+                self.controllers.len() - 1
+            }
+        };
+        return &mut self.controllers[index];
+    }
+
+    /// Runs `set <serial> <key> <value>` or `forget <serial>`, from the command line or the control file
+    ///
+    /// The words after the key are the value, so names can contain spaces. Without a value the key is removed
+    pub fn run_command(&mut self, args: &[&str]) -> Result<(), String> {
+        match args {
+            ["set", serial, key, value @ ..] => {
+                // a controller is only added if the value is valid
+                let mut controller: KnownController = self.get(serial).cloned().unwrap_or_else(|| KnownController::new(serial));
+                controller.set(key, &value.join(" "))?;
+                *self.get_or_insert(serial) = controller;
+                return Ok(());
+            }
+            ["forget", serial] => match self.remove(serial) {
+                true => return Ok(()),
+                false => return Err(format!("{serial} is not a known controller")),
+            },
+            _ => return Err(format!("expected set <serial> <key> <value> or forget <serial>, not {}", args.join(" "))),
+        }
+    }
+
+    /// Returns `false` if the controller was not known
+    pub fn remove(&mut self, serial: &str) -> bool {
+        let count: usize = self.controllers.len();
+        self.controllers.retain(|controller| controller.serial.eq_ignore_ascii_case(serial) == false);
+        return self.controllers.len() != count;
+    }
+
+    /// Remembers that the controller is connected right now
+    pub fn seen(&mut self, serial: &str, model: &str) {
+        let now: u64 = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+        let controller: &mut KnownController = self.get_or_insert(serial);
+        controller.model = Some(model.to_string());
+        controller.last_seen = Some(now);
+    }
+
+    /// Sort key for the connected controllers: the ones with a player slot first, in the order of their slots
+    pub fn player_order(&self, serial: Option<&str>) -> usize {
+        return serial
+            .and_then(|serial| self.get(serial))
+            .and_then(|controller| controller.player)
+            .unwrap_or(usize::MAX);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn controllers_survive_the_file_format() {
+        let mut known_controllers = KnownControllers {
+            path: PathBuf::from("/nonexistent"),
+            controllers: Vec::new(),
+        };
+        known_controllers.seen("A0:AB:51:12:34:56", "DualSense");
+        known_controllers.get_or_insert("a0:ab:51:12:34:56").set("name", "Couch pad").unwrap();
+        known_controllers.get_or_insert("a0:ab:51:12:34:56").set("player", "2").unwrap();
+        known_controllers.get_or_insert("a0:ab:51:65:43:21").set("profile", "racing").unwrap();
+
+        let config = Config::parse(&known_controllers.to_text()).unwrap();
+        let loaded: Vec<KnownController> = config
+            .sections("controller")
+            .map(|section| KnownController::from_section(section).unwrap())
+            .collect();
+
+        assert_eq!(loaded, known_controllers.controllers);
+        assert_eq!(
+            loaded[0].serial, "a0:ab:51:12:34:56",
+            "one entry per controller, whatever the case of the serial"
+        );
+        assert_eq!(known_controllers.player_order(Some("a0:ab:51:12:34:56")), 2);
+        assert_eq!(known_controllers.player_order(Some("a0:ab:51:65:43:21")), usize::MAX);
+
+        assert!(known_controllers.get_or_insert("x").set("player", "0").is_err());
+        assert!(known_controllers.get_or_insert("x").set("name", "a # b").is_err());
+    }
+
+    #[test]
+    fn commands_edit_the_registry() {
+        let mut known_controllers = KnownControllers {
+            path: PathBuf::from("/nonexistent"),
+            controllers: Vec::new(),
+        };
+
+        known_controllers.run_command(&["set", "a0:ab:51:12:34:56", "name", "Couch", "pad"]).unwrap();
+        assert_eq!(known_controllers.get("a0:ab:51:12:34:56").unwrap().name.as_deref(), Some("Couch pad"));
+        known_controllers.run_command(&["set", "a0:ab:51:12:34:56", "name"]).unwrap();
+        assert_eq!(known_controllers.get("a0:ab:51:12:34:56").unwrap().name, None);
+
+        // an invalid value does not add the controller
+        assert!(known_controllers.run_command(&["set", "a0:ab:51:65:43:21", "player", "0"]).is_err());
+        assert!(known_controllers.get("a0:ab:51:65:43:21").is_none());
+
+        known_controllers.run_command(&["forget", "A0:AB:51:12:34:56"]).unwrap();
+        assert!(known_controllers.controllers.is_empty());
+        assert!(known_controllers.run_command(&["forget", "a0:ab:51:12:34:56"]).is_err());
+        assert!(known_controllers.run_command(&["rename"]).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{self, Thread};
+use std::time::Instant;
+
+/// Set in `LatestState::middle` while the middle buffer holds a value the subscriber has not taken yet
+const FRESH: usize = 1 << (usize::BITS - 1);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LatestStateError {
+    /// The deadline passed without a new value
+    Timeout,
+
+    /// `close()` was called and the newest value was taken already
+    Closed,
+}
+
+/// Hands the newest value from the input threads to the output thread, older values are simply overwritten
+///
+/// Works like a triple buffer with one buffer per publisher: every buffer is owned by exactly one publisher, the subscriber,
+/// the middle or the pool of unused buffers, and publishing or taking a value is a single atomic swap with the middle.
+/// Nothing is queued and nothing is allocated after `latest_state()`
+pub struct LatestState<T> {
+    buffers: Box<[UnsafeCell<T>]>,
+
+    /// index of the middle buffer, with `FRESH` if it holds an unread value
+    middle: AtomicUsize,
+
+    /// buffers of publishers that were dropped, only locked when publishers are created or dropped
+    unused: Mutex<Vec<usize>>,
+
+    is_closed: AtomicBool,
+
+    /// woken up by every publish
+    subscriber_thread: OnceLock<Thread>,
+}
+
+// A buffer is only accessed by its current owner, the swaps of `middle` hand buffers over between threads
+unsafe impl<T: Send> Sync for LatestState<T> {}
+
+/// Creates a slot for up to `max_publishers` publishers at the same time, and its only subscriber
+pub fn latest_state<T: Clone + Send>(initial: T, max_publishers: usize) -> (Arc<LatestState<T>>, Subscriber<T>) {
+    let buffers: Vec<UnsafeCell<T>> = (0..max_publishers + 2).map(|_| UnsafeCell::new(initial.clone())).collect();
+    let state = Arc::new(LatestState {
+        buffers: buffers.into_boxed_slice(),
+        middle: AtomicUsize::new(1),
+        // 0 belongs to the subscriber, 1 is the middle
+        unused: Mutex::new((2..max_publishers + 2).rev().collect()),
+        is_closed: AtomicBool::new(false),
+        subscriber_thread: OnceLock::new(),
+    });
+
+    let subscriber = Subscriber {
+        state: state.clone(),
+        index: 0,
+    };
+    return (state, subscriber);
+}
+
+impl<T> LatestState<T> {
+    /// Returns `None` if `max_publishers` publishers exist already
+    pub fn publisher(self: &Arc<Self>) -> Option<Publisher<T>> {
+        let index: usize = self.unused.lock().ok()?.pop()?;
+        return Some(Publisher { state: self.clone(), index });
+    }
+
+    /// The subscriber returns `LatestStateError::Closed` once it took the newest value
+    pub fn close(&self) {
+        self.is_closed.store(true, Ordering::SeqCst);
+        self._wake_subscriber();
+    }
+
+    fn _wake_subscriber(&self) {
+        if let Some(thread) = self.subscriber_thread.get() {
+            thread.unpark();
+        }
+    }
+}
+
+/// Writing end, owned by one input thread
+pub struct Publisher<T> {
+    state: Arc<LatestState<T>>,
+    index: usize,
+}
+
+impl<T> Publisher<T> {
+    /// Makes `value` the newest value, an unread older one is dropped
+    pub fn publish(&mut self, value: T) {
+        // SAFETY: nobody else accesses the buffer of this publisher
+        unsafe { *self.state.buffers[self.index].get() = value };
+        let previous: usize = self.state.middle.swap(self.index | FRESH, Ordering::AcqRel);
+        self.index = previous & !FRESH;
+        self.state._wake_subscriber();
+    }
+}
+
+impl<T> Drop for Publisher<T> {
+    fn drop(&mut self) {
+        if let Ok(mut unused) = self.state.unused.lock() {
+            unused.push(self.index);
+        }
+    }
+}
+
+/// Reading end, owned by the output thread
+pub struct Subscriber<T> {
+    state: Arc<LatestState<T>>,
+    index: usize,
+}
+
+impl<T: Clone> Subscriber<T> {
+    /// The newest value, if there is one that was not taken yet
+    pub fn try_recv(&mut self) -> Option<T> {
+        if self.state.middle.load(Ordering::Acquire) & FRESH == 0 {
+            return None;
+        }
+
+        let previous: usize = self.state.middle.swap(self.index, Ordering::AcqRel);
+        self.index = previous & !FRESH;
+        // SAFETY: the buffer belongs to the subscriber until the next swap
+        return Some(unsafe { (*self.state.buffers[self.index].get()).clone() });
+    }
+
+    /// Waits for a new value until the `deadline`, or forever without one
+    pub fn recv_deadline(&mut self, deadline: Option<Instant>) -> Result<T, LatestStateError> {
+        self.state.subscriber_thread.get_or_init(thread::current);
+
+        loop {
+            // read before taking the value, so the newest value is never lost to `close()`
+            let is_closed: bool = self.state.is_closed.load(Ordering::SeqCst);
+            if let Some(value) = self.try_recv() {
+                return Ok(value);
+            }
+            if is_closed {
+                return Err(LatestStateError::Closed);
+            }
+
+            // an unpark between the check above and park() makes park() return at once, so no publish is missed
+            match deadline {
+                Some(deadline) => {
+                    let now: Instant = Instant::now();
+                    if now >= deadline {
+                        return Err(LatestStateError::Timeout);
+                    }
+                    thread::park_timeout(deadline - now);
+                }
+                None => thread::park(),
+            }
+        }
+    }
+
+    pub fn recv(&mut self) -> Result<T, LatestStateError> {
+        return self.recv_deadline(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn only_the_newest_value_is_received() {
+        let (state, mut subscriber) = latest_state(0_u32, 2);
+        let mut first = state.publisher().unwrap();
+        let mut second = state.publisher().unwrap();
+        assert!(state.publisher().is_none(), "only 2 publishers");
+
+        assert_eq!(subscriber.try_recv(), None);
+        first.publish(1);
+        second.publish(2);
+        first.publish(3);
+        assert_eq!(subscriber.try_recv(), Some(3));
+        assert_eq!(subscriber.try_recv(), None);
+
+        // a dropped publisher makes room for a new one
+        drop(second);
+        let mut third = state.publisher().unwrap();
+
+        let waiting = thread::spawn(move || {
+            let received = subscriber.recv();
+            let timeout = subscriber.recv_deadline(Some(Instant::now() + Duration::from_millis(10)));
+            let closed = subscriber.recv();
+            (received, timeout, closed)
+        });
+        thread::sleep(Duration::from_millis(10));
+        third.publish(4);
+        thread::sleep(Duration::from_millis(30));
+        state.close();
+
+        let (received, timeout, closed) = waiting.join().unwrap();
+        assert_eq!(received, Ok(4));
+        assert_eq!(timeout, Err(LatestStateError::Timeout));
+        assert_eq!(closed, Err(LatestStateError::Closed));
+    }
+}
@@ -0,0 +1,406 @@
+use std::process::Command;
+use std::sync::atomic::{AtomicI16, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, ConfigError, ConfigSection};
+use crate::status;
+use crate::usb_gamepad::Feedback;
+
+/// Statistics are collected over this time, then evaluated and shown on the status interface
+pub const LINK_STATS_WINDOW: Duration = Duration::from_secs(1);
+
+/// How often the signal strength of every controller is asked for
+pub const RSSI_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Stored in the shared RSSI while it is not known
+pub const RSSI_UNKNOWN: i16 = i16::MIN;
+
+/// How long the rumble warning lasts
+const RUMBLE_WARNING_DURATION: Duration = Duration::from_millis(300);
+
+/// Lightbar color while the link is degraded, and the color it goes back to
+const LIGHTBAR_WARNING: (u8, u8, u8) = (255, 64, 0);
+const LIGHTBAR_DEFAULT: (u8, u8, u8) = (0, 0, 255);
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct LinkQualitySettings {
+    /// Longest time between two reports before the link counts as degraded
+    pub max_gap: Duration,
+
+    /// Standard deviation of the report interval
+    pub max_jitter: Duration,
+
+    /// Lost reports in percent of all reports
+    pub max_loss: f32,
+
+    /// Signal strength as reported by `hcitool rssi`, `None` if a weak signal alone is no degradation
+    pub min_rssi: Option<i16>,
+
+    /// How the player is warned when the link degrades
+    pub warn_rumble: bool,
+    pub warn_lightbar: bool,
+}
+
+impl LinkQualitySettings {
+    pub fn default() -> Self {
+        Self {
+            max_gap: Duration::from_millis(50),
+            max_jitter: Duration::from_millis(5),
+            max_loss: 5.0,
+            min_rssi: None,
+            warn_rumble: false,
+            warn_lightbar: false,
+        }
+    }
+
+    /// Reads the `[link_quality]` section, returns `Ok(None)` if `enabled = false`:
+    /// - `max_gap = <ms>`, `max_jitter = <ms>`, `max_loss = <percent>`, `min_rssi = <value>`
+    /// - `warn = rumble, lightbar`
+    pub fn from_config(config: &Config) -> Result<Option<Self>, ConfigError> {
+        let mut settings = Self::default();
+        let section: &ConfigSection = match config.section("link_quality", "") {
+            Some(section) => section,
+            None => return Ok(Some(settings)),
+        };
+
+        if section.get_parsed::<bool>("enabled")? == Some(false) {
+            return Ok(None);
+        }
+        if let Some(max_gap) = section.get_parsed::<u64>("max_gap")? {
+            settings.max_gap = Duration::from_millis(max_gap);
+        }
+        if let Some(max_jitter) = section.get_parsed::<f32>("max_jitter")? {
+            if max_jitter.is_finite() == false {
+                return Err(section.error(format!("max_jitter has to be a number of milliseconds, not {max_jitter}")));
+            }
+            settings.max_jitter = match Duration::try_from_secs_f32(max_jitter.max(0.0) / 1000.0) {
+                Ok(max_jitter) => max_jitter,
+                Err(_) => return Err(section.error(format!("max_jitter is too long: {max_jitter}"))),
+            };
+        }
+        if let Some(max_loss) = section.get_parsed::<f32>("max_loss")? {
+            if max_loss.is_finite() == false {
+                return Err(section.error(format!("max_loss has to be a percentage, not {max_loss}")));
+            }
+            settings.max_loss = max_loss;
+        }
+        settings.min_rssi = section.get_parsed::<i16>("min_rssi")?;
+
+        if let Some(warn) = section.get("warn") {
+            for way in warn.split(',').map(|way| way.trim()) {
+                match way {
+                    "rumble" => settings.warn_rumble = true,
+                    "lightbar" => settings.warn_lightbar = true,
+                    "none" => {}
+                    other => return Err(section.error(format!("warn has to be rumble, lightbar or none, not {other}"))),
+                }
+            }
+        }
+
+        return Ok(Some(settings));
+    }
+}
+
+/// What was measured in one `LINK_STATS_WINDOW`
+#[derive(Clone, PartialEq, Debug)]
+pub struct LinkStats {
+    pub reports: u32,
+
+    /// gaps in the sequence counter of the reports
+    pub lost: u32,
+    pub mean_interval: Duration,
+    pub jitter: Duration,
+    pub longest_gap: Duration,
+}
+
+impl LinkStats {
+    pub fn loss_percent(&self) -> f32 {
+        let sent: u32 = self.reports + self.lost;
+        if sent == 0 {
+            return 0.0;
+        }
+        return self.lost as f32 * 100.0 / sent as f32;
+    }
+
+    /// Why the link counts as degraded, `None` if it is fine
+    pub fn degradation(&self, settings: &LinkQualitySettings, rssi: Option<i16>) -> Option<String> {
+        if self.longest_gap > settings.max_gap {
+            return Some(format!("dropout of {:.0} ms", self.longest_gap.as_secs_f32() * 1000.0));
+        }
+        if self.loss_percent() > settings.max_loss {
+            return Some(format!("{:.1} % of the reports lost", self.loss_percent()));
+        }
+        if self.jitter > settings.max_jitter {
+            return Some(format!("jitter of {:.1} ms", self.jitter.as_secs_f32() * 1000.0));
+        }
+        if let (Some(rssi), Some(min_rssi)) = (rssi, settings.min_rssi) {
+            if rssi < min_rssi {
+                return Some(format!("weak signal (RSSI {rssi})"));
+            }
+        }
+        return None;
+    }
+}
+
+/// Watches the reports of one controller, owned by its input thread
+///
+/// Results are shown on the status interface as `link.<index>.*`
+pub struct LinkMonitor {
+    index: usize,
+    settings: LinkQualitySettings,
+
+    /// written by `poll_rssi_continously()`
+    rssi: Arc<AtomicI16>,
+
+    window_start: Option<Instant>,
+    last_report: Option<Instant>,
+    last_sequence: Option<u8>,
+
+    reports: u32,
+    lost: u32,
+
+    /// sum and sum of squares of the intervals in seconds, for the standard deviation
+    interval_sum: f64,
+    interval_square_sum: f64,
+    longest_gap: Duration,
+
+    degraded: bool,
+    rumble_until: Option<Instant>,
+}
+
+impl LinkMonitor {
+    pub fn new(index: usize, settings: LinkQualitySettings, rssi: Arc<AtomicI16>) -> Self {
+        status::set(&format!("link.{index}.quality"), "not measured yet".to_string());
+
+        Self {
+            index,
+            settings,
+            rssi,
+            window_start: None,
+            last_report: None,
+            last_sequence: None,
+            reports: 0,
+            lost: 0,
+            interval_sum: 0.0,
+            interval_square_sum: 0.0,
+            longest_gap: Duration::ZERO,
+            degraded: false,
+            rumble_until: None,
+        }
+    }
+
+    /// Call with every input report, `sequence` is its sequence counter if the gamepad model has one
+    ///
+    /// Returns the rumble (right, left) and lightbar color the gamepad should get, if they have to change
+    pub fn report_received(&mut self, now: Instant, sequence: Option<u8>) -> Option<Feedback> {
+        if let Some(last_report) = self.last_report {
+            let interval: Duration = now - last_report;
+            self.interval_sum += interval.as_secs_f64();
+            self.interval_square_sum += interval.as_secs_f64() * interval.as_secs_f64();
+            self.longest_gap = self.longest_gap.max(interval);
+        }
+        if let (Some(last_sequence), Some(sequence)) = (self.last_sequence, sequence) {
+            self.lost += sequence.wrapping_sub(last_sequence).wrapping_sub(1) as u32;
+        }
+        self.last_report = Some(now);
+        self.last_sequence = sequence;
+        self.reports += 1;
+
+        let window_start: Instant = *self.window_start.get_or_insert(now);
+        let mut feedback: Option<Feedback> = None;
+
+        if now - window_start >= LINK_STATS_WINDOW {
+            let stats: LinkStats = self._take_stats();
+            self.window_start = Some(now);
+
+            let rssi: Option<i16> = match self.rssi.load(Ordering::Relaxed) {
+                RSSI_UNKNOWN => None,
+                rssi => Some(rssi),
+            };
+            let degradation: Option<String> = stats.degradation(&self.settings, rssi);
+            self._show(&stats, degradation.as_deref());
+
+            if degradation.is_some() != self.degraded {
+                self.degraded = degradation.is_some();
+                feedback = self._warning(now);
+            }
+        }
+
+        if self.rumble_until.is_some_and(|until| now >= until) {
+            self.rumble_until = None;
+            feedback = Some(((0, 0), self._lightbar()));
+        }
+
+        return feedback;
+    }
+
+    fn _take_stats(&mut self) -> LinkStats {
+        // the first report of a window has no interval
+        let intervals: f64 = (self.reports.saturating_sub(1)).max(1) as f64;
+        let mean: f64 = self.interval_sum / intervals;
+        let variance: f64 = (self.interval_square_sum / intervals - mean * mean).max(0.0);
+
+        let stats = LinkStats {
+            reports: self.reports,
+            lost: self.lost,
+            mean_interval: Duration::from_secs_f64(mean),
+            jitter: Duration::from_secs_f64(variance.sqrt()),
+            longest_gap: self.longest_gap,
+        };
+
+        // the last report starts the next window
+        self.reports = 1;
+        self.lost = 0;
+        self.interval_sum = 0.0;
+        self.interval_square_sum = 0.0;
+        self.longest_gap = Duration::ZERO;
+
+        return stats;
+    }
+
+    fn _show(&self, stats: &LinkStats, degradation: Option<&str>) {
+        let index: usize = self.index;
+        status::set(
+            &format!("link.{index}.interval"),
+            format!("{:.1} ms", stats.mean_interval.as_secs_f32() * 1000.0),
+        );
+        status::set(&format!("link.{index}.jitter"), format!("{:.1} ms", stats.jitter.as_secs_f32() * 1000.0));
+        status::set(
+            &format!("link.{index}.longest_gap"),
+            format!("{:.1} ms", stats.longest_gap.as_secs_f32() * 1000.0),
+        );
+        status::set(&format!("link.{index}.lost"), format!("{} ({:.1} %)", stats.lost, stats.loss_percent()));
+
+        match degradation {
+            Some(reason) => {
+                if self.degraded == false {
+                    println!("Bluetooth link of gamepad {index} degraded: {reason}");
+                }
+                status::set(&format!("link.{index}.quality"), format!("degraded: {reason}"));
+            }
+            None => {
+                if self.degraded {
+                    println!("Bluetooth link of gamepad {index} recovered");
+                }
+                status::set(&format!("link.{index}.quality"), "good".to_string());
+            }
+        }
+    }
+
+    /// Rumble once when the link degrades, the lightbar shows the state as long as it lasts
+    fn _warning(&mut self, now: Instant) -> Option<Feedback> {
+        if self.settings.warn_rumble && self.degraded {
+            self.rumble_until = Some(now + RUMBLE_WARNING_DURATION);
+            return Some(((255, 255), self._lightbar()));
+        }
+        if self.settings.warn_lightbar {
+            return Some(((0, 0), self._lightbar()));
+        }
+        return None;
+    }
+
+    fn _lightbar(&self) -> (u8, u8, u8) {
+        if self.settings.warn_lightbar && self.degraded {
+            return LIGHTBAR_WARNING;
+        }
+        return LIGHTBAR_DEFAULT;
+    }
+}
+
+/// Asks for the signal strength of every connected controller every `RSSI_POLL_INTERVAL`, never returns
+///
+/// `controllers` are the index, bluetooth address and shared RSSI of each controller
+pub fn poll_rssi_continously(controllers: Vec<(usize, String, Arc<AtomicI16>)>) {
+    loop {
+        for (index, address, rssi) in &controllers {
+            match _read_rssi(address) {
+                Some(value) => {
+                    rssi.store(value, Ordering::Relaxed);
+                    status::set(&format!("link.{index}.rssi"), value.to_string());
+                }
+                None => {
+                    rssi.store(RSSI_UNKNOWN, Ordering::Relaxed);
+                    status::set(&format!("link.{index}.rssi"), "unknown".to_string());
+                }
+            }
+        }
+
+        thread::sleep(RSSI_POLL_INTERVAL);
+    }
+}
+
+/// BlueZ only knows the RSSI while discovering, for connected devices it has to be asked from the controller with `hcitool rssi <address>`
+///
+/// The value is relative to the golden receive power range: 0 is inside, negative values are below it
+fn _read_rssi(address: &str) -> Option<i16> {
+    let output = Command::new("hcitool").args(["rssi", address]).output().ok()?;
+    if output.status.success() == false {
+        return None;
+    }
+
+    // RSSI return value: -4
+    let text: String = String::from_utf8_lossy(&output.stdout).to_string();
+    let (_, value) = text.split_once("RSSI return value:")?;
+    return value.trim().parse::<i16>().ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gaps_in_reports_and_sequence_degrade_the_link() {
+        let settings = LinkQualitySettings {
+            warn_lightbar: true,
+            ..LinkQualitySettings::default()
+        };
+        let mut monitor = LinkMonitor::new(7, settings, Arc::new(AtomicI16::new(RSSI_UNKNOWN)));
+        let start = Instant::now();
+
+        // a steady report every 4 ms
+        let mut sequence: u8 = 250;
+        for report in 0..=250 {
+            let feedback = monitor.report_received(start + Duration::from_millis(report * 4), Some(sequence));
+            assert_eq!(feedback, None);
+            sequence = sequence.wrapping_add(1);
+        }
+        assert_eq!(status::get("link.7.quality").as_deref(), Some("good"));
+        assert_eq!(status::get("link.7.lost").as_deref(), Some("0 (0.0 %)"));
+
+        // a dropout of 80 ms, during which 19 reports got lost
+        let mut time: u64 = 1000 + 80;
+        sequence = sequence.wrapping_add(19);
+        let mut feedback = None;
+        for _ in 0..250 {
+            feedback = feedback.or(monitor.report_received(start + Duration::from_millis(time), Some(sequence)));
+            time += 4;
+            sequence = sequence.wrapping_add(1);
+        }
+        assert_eq!(feedback, Some(((0, 0), LIGHTBAR_WARNING)));
+        assert_eq!(status::get("link.7.quality").as_deref(), Some("degraded: dropout of 80 ms"));
+        assert!(status::get("link.7.lost").unwrap().starts_with("19 "));
+
+        // steady again: the lightbar goes back
+        let mut feedback = None;
+        for _ in 0..260 {
+            feedback = feedback.or(monitor.report_received(start + Duration::from_millis(time), Some(sequence)));
+            time += 4;
+            sequence = sequence.wrapping_add(1);
+        }
+        assert_eq!(feedback, Some(((0, 0), LIGHTBAR_DEFAULT)));
+        assert_eq!(status::get("link.7.quality").as_deref(), Some("good"));
+    }
+
+    #[test]
+    fn limits_have_to_be_finite() {
+        for line in ["max_jitter = inf", "max_jitter = nan", "max_jitter = 1e38", "max_loss = nan"] {
+            let config = Config::parse(&format!("[link_quality]\n{line}\n")).unwrap();
+            assert!(LinkQualitySettings::from_config(&config).is_err(), "{line}");
+        }
+
+        let config = Config::parse("[link_quality]\nmax_jitter = 8\n").unwrap();
+        assert_eq!(LinkQualitySettings::from_config(&config).unwrap().unwrap().max_jitter, Duration::from_millis(8));
+    }
+}
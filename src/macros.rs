@@ -0,0 +1,450 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, ConfigError, ConfigSection};
+use crate::input_mapping::ButtonCombo;
+use crate::processing::ProcessingStage;
+use crate::universal_gamepad::{Axis, Button, UniversalGamepad};
+
+/// Used if `[macros]` has no `directory = <path>`
+pub const DEFAULT_MACRO_DIRECTORY: &str = "/var/lib/gamepad-bridge/macros";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PlaybackMode {
+    /// The macro is combined with the live input like a second gamepad
+    Merge,
+
+    /// The live input is ignored while the macro plays
+    Override,
+}
+
+/// The state of all buttons and axes at `offset` after the recording started
+///
+/// Touchpad and motion sensors are not recorded
+#[derive(Clone, PartialEq, Debug)]
+pub struct MacroFrame {
+    pub offset: Duration,
+    pub gamepad: UniversalGamepad,
+}
+
+/// A recorded sequence of inputs, configured with a `[macro <name>]` section
+pub struct Macro {
+    pub name: String,
+    pub record_combo: ButtonCombo,
+    pub play_combo: ButtonCombo,
+    pub mode: PlaybackMode,
+
+    /// sorted by offset, a new frame is only stored when the input changed
+    pub frames: Vec<MacroFrame>,
+}
+
+impl Macro {
+    /// - `record = <button>, <button>, ...` starts and stops recording
+    /// - `play = <button>, <button>, ...` starts and stops playback
+    /// - `mode = merge` (default) or `mode = override`
+    ///
+    /// The name is used as file name, so it may not leave the macro directory
+    fn from_section(section: &ConfigSection) -> Result<Self, ConfigError> {
+        if section.name.is_empty() || section.name.contains('/') || section.name.contains("..") {
+            return Err(section.error(format!("invalid macro name {:?}, it can not be empty or contain / or ..", section.name)));
+        }
+        let record_combo: ButtonCombo = match ButtonCombo::from_section(section, "record")? {
+            Some(combo) => combo,
+            None => return Err(section.error("record is missing".to_string())),
+        };
+        let play_combo: ButtonCombo = match ButtonCombo::from_section(section, "play")? {
+            Some(combo) => combo,
+            None => return Err(section.error("play is missing".to_string())),
+        };
+        let mode: PlaybackMode = match section.get("mode") {
+            None | Some("merge") => PlaybackMode::Merge,
+            Some("override") => PlaybackMode::Override,
+            Some(other) => return Err(section.error(format!("unknown mode: {other}"))),
+        };
+
+        return Ok(Self {
+            name: section.name.clone(),
+            record_combo,
+            play_combo,
+            mode,
+            frames: Vec::new(),
+        });
+    }
+
+    fn file_path(&self, directory: &Path) -> PathBuf {
+        return directory.join(format!("{}.macro", self.name));
+    }
+
+    /// One line per frame: `<offset in µs> <buttons as hex bitmask> <all axes>`
+    ///
+    /// The bits of the bitmask are in the order of `Button::ALL`, the axes in the order of `Axis::ALL`
+    pub fn to_text(&self) -> String {
+        let mut text: String = String::from("# gamepad-bridge macro\n# offset_us buttons axes...\n");
+
+        for frame in &self.frames {
+            let mut buttons: u32 = 0;
+            for (bit, button) in Button::ALL.into_iter().enumerate() {
+                if frame.gamepad.button(button) {
+                    buttons |= 1 << bit;
+                }
+            }
+
+            text += &format!("{} {:04x}", frame.offset.as_micros(), buttons);
+            for axis in Axis::ALL {
+                text += &format!(" {}", frame.gamepad.axis(axis));
+            }
+            text += "\n";
+        }
+
+        return text;
+    }
+
+    /// Inverse of `to_text()`, returns `None` if any line is invalid
+    pub fn frames_from_text(text: &str) -> Option<Vec<MacroFrame>> {
+        let mut frames: Vec<MacroFrame> = Vec::new();
+
+        for line in text.lines().map(|line| line.trim()) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let offset = Duration::from_micros(words.next()?.parse().ok()?);
+            let buttons: u32 = u32::from_str_radix(words.next()?, 16).ok()?;
+
+            let mut gamepad = UniversalGamepad::nothing_pressed();
+            for (bit, button) in Button::ALL.into_iter().enumerate() {
+                gamepad.set_button(button, buttons & (1 << bit) != 0);
+            }
+            for axis in Axis::ALL {
+                gamepad.set_axis(axis, words.next()?.parse().ok()?);
+            }
+
+            frames.push(MacroFrame { offset, gamepad });
+        }
+
+        return Some(frames);
+    }
+
+    /// Writes the file in its own thread, the output thread must not wait for the disk
+    fn save(&self, directory: &Path) -> Option<JoinHandle<()>> {
+        let name: String = self.name.clone();
+        let frame_count: usize = self.frames.len();
+        let directory: PathBuf = directory.to_path_buf();
+        let path: PathBuf = self.file_path(&directory);
+        let text: String = self.to_text();
+
+        let saving = thread::Builder::new().name("macro".to_string()).spawn(move || {
+            if let Err(err) = fs::create_dir_all(&directory) {
+                println!("Could not create macro directory {:?}: {:?}", directory, err);
+                return;
+            }
+
+            match fs::write(path, text) {
+                Ok(_) => println!("Macro {} saved ({} frames)", name, frame_count),
+                Err(err) => println!("Could not save macro {}: {:?}", name, err),
+            }
+        });
+
+        match saving {
+            Ok(handle) => return Some(handle),
+            Err(err) => {
+                println!("Could not save macro {}: {:?}", self.name, err);
+                return None;
+            }
+        }
+    }
+
+    /// A missing file is not an error, the macro just has not been recorded yet
+    fn load(&mut self, directory: &Path) {
+        let text: String = match fs::read_to_string(self.file_path(directory)) {
+            Ok(text) => text,
+            Err(_) => return,
+        };
+
+        match Self::frames_from_text(&text) {
+            Some(frames) => self.frames = frames,
+            None => println!("Macro file of {} is invalid and was ignored", self.name),
+        }
+    }
+}
+
+/// Only the parts of `gamepad` that are recorded, the motion sensors would create a new frame every report
+fn _buttons_and_axes(gamepad: &UniversalGamepad) -> UniversalGamepad {
+    let mut recorded = UniversalGamepad::nothing_pressed();
+
+    for button in Button::ALL {
+        recorded.set_button(button, gamepad.button(button));
+    }
+    for axis in Axis::ALL {
+        recorded.set_axis(axis, gamepad.axis(axis));
+    }
+
+    return recorded;
+}
+
+enum MacroState {
+    Idle,
+    Recording { index: usize, start: Instant },
+    Playing { index: usize, start: Instant },
+}
+
+/// Records and plays macros, runs as the last stage so it sees exactly what the host sees
+pub struct MacroStage {
+    macros: Vec<Macro>,
+    directory: PathBuf,
+    state: MacroState,
+
+    /// writes the last recording, joined before the next save so two saves of the same macro can't overtake each other
+    saving: Option<JoinHandle<()>>,
+
+    /// (record combo, play combo) of every macro in the previous frame, to detect new presses
+    combos_were_pressed: Vec<(bool, bool)>,
+}
+
+impl MacroStage {
+    /// Returns `Ok(None)` if no macro is configured
+    pub fn from_config(config: &Config) -> Result<Option<Self>, ConfigError> {
+        let directory: PathBuf = match config.section("macros", "").and_then(|section| section.get("directory")) {
+            Some(directory) => PathBuf::from(directory),
+            None => PathBuf::from(DEFAULT_MACRO_DIRECTORY),
+        };
+
+        let mut macros: Vec<Macro> = Vec::new();
+        for section in config.sections("macro") {
+            let mut new_macro: Macro = Macro::from_section(section)?;
+            new_macro.load(&directory);
+            macros.push(new_macro);
+        }
+
+        if macros.is_empty() {
+            return Ok(None);
+        }
+
+        let combos_were_pressed = vec![(false, false); macros.len()];
+        return Ok(Some(Self {
+            macros,
+            directory,
+            state: MacroState::Idle,
+            saving: None,
+            combos_were_pressed,
+        }));
+    }
+
+    /// Returns the indices of all macros whose (record combo, play combo) was pressed in this frame
+    fn _new_combo_presses(&mut self, gamepad: &UniversalGamepad) -> (Option<usize>, Option<usize>) {
+        let mut record_pressed: Option<usize> = None;
+        let mut play_pressed: Option<usize> = None;
+
+        for (index, current_macro) in self.macros.iter().enumerate() {
+            let is_pressed: (bool, bool) = (current_macro.record_combo.is_pressed(gamepad), current_macro.play_combo.is_pressed(gamepad));
+            let was_pressed: (bool, bool) = self.combos_were_pressed[index];
+
+            if is_pressed.0 && was_pressed.0 == false {
+                record_pressed = Some(index);
+            }
+            if is_pressed.1 && was_pressed.1 == false {
+                play_pressed = Some(index);
+            }
+            self.combos_were_pressed[index] = is_pressed;
+        }
+
+        return (record_pressed, play_pressed);
+    }
+}
+
+impl ProcessingStage for MacroStage {
+    fn display_name(&self) -> &'static str {
+        return "Macros";
+    }
+
+    fn process(&mut self, gamepad: &mut UniversalGamepad, now: Instant) {
+        let (record_pressed, play_pressed) = self._new_combo_presses(gamepad);
+
+        // the host should never see the combos
+        for current_macro in &self.macros {
+            if current_macro.record_combo.is_pressed(gamepad) {
+                current_macro.record_combo.release(gamepad);
+            }
+            if current_macro.play_combo.is_pressed(gamepad) {
+                current_macro.play_combo.release(gamepad);
+            }
+        }
+
+        match self.state {
+            MacroState::Idle => {
+                if let Some(index) = record_pressed {
+                    println!("Recording macro {}", self.macros[index].name);
+                    self.macros[index].frames.clear();
+                    self.state = MacroState::Recording { index, start: now };
+                } else if let Some(index) = play_pressed {
+                    if self.macros[index].frames.is_empty() {
+                        println!("Macro {} has not been recorded yet", self.macros[index].name);
+                    } else {
+                        self.state = MacroState::Playing { index, start: now };
+                    }
+                }
+            }
+            MacroState::Recording { index, start } => {
+                let current_macro: &mut Macro = &mut self.macros[index];
+
+                if record_pressed == Some(index) {
+                    // end with everything released, so nothing stays pressed after playback
+                    let mut released: UniversalGamepad = _buttons_and_axes(gamepad);
+                    released.release_all();
+                    current_macro.frames.push(MacroFrame {
+                        offset: now - start,
+                        gamepad: released,
+                    });
+
+                    if let Some(saving) = self.saving.take() {
+                        let _ = saving.join();
+                    }
+                    self.saving = current_macro.save(&self.directory);
+                    self.state = MacroState::Idle;
+                    return;
+                }
+
+                let recorded: UniversalGamepad = _buttons_and_axes(gamepad);
+                let changed: bool = match current_macro.frames.last() {
+                    Some(last) => last.gamepad != recorded,
+                    None => true,
+                };
+                if changed {
+                    current_macro.frames.push(MacroFrame {
+                        offset: now - start,
+                        gamepad: recorded,
+                    });
+                }
+            }
+            MacroState::Playing { index, start } => {
+                let current_macro: &Macro = &self.macros[index];
+                let elapsed: Duration = now - start;
+
+                // pressing the play combo again stops the playback early
+                if play_pressed == Some(index) {
+                    self.state = MacroState::Idle;
+                    return;
+                }
+
+                let frame: &MacroFrame = match current_macro.frames.iter().rev().find(|frame| frame.offset <= elapsed) {
+                    Some(frame) => frame,
+                    None => &current_macro.frames[0],
+                };
+
+                match current_macro.mode {
+                    PlaybackMode::Merge => gamepad.merge(&frame.gamepad),
+                    PlaybackMode::Override => {
+                        gamepad.release_all();
+                        gamepad.merge(&frame.gamepad);
+                    }
+                }
+
+                let is_finished: bool = match current_macro.frames.last() {
+                    Some(last) => elapsed >= last.offset,
+                    None => true,
+                };
+                if is_finished {
+                    self.state = MacroState::Idle;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn _config(directory: &Path) -> Config {
+        let content: String = format!(
+            "[macros]\ndirectory = {}\n[macro combo1]\nrecord = specials.logo, dpad.left\nplay = specials.logo, dpad.right\n",
+            directory.display()
+        );
+        return Config::parse(&content).unwrap();
+    }
+
+    fn _gamepad(buttons: &[Button]) -> UniversalGamepad {
+        let mut gamepad = UniversalGamepad::nothing_pressed();
+        for button in buttons {
+            gamepad.set_button(*button, true);
+        }
+        return gamepad;
+    }
+
+    #[test]
+    fn a_saved_macro_is_loaded_again() {
+        let directory: PathBuf = std::env::temp_dir().join(format!("gamepad-bridge-macros-{}-file", std::process::id()));
+        let config = _config(&directory);
+        let mut saved = Macro::from_section(config.section("macro", "combo1").unwrap()).unwrap();
+        let mut pressed = _gamepad(&[Button::MainLower]);
+        pressed.set_axis(Axis::TriggerRight, 200);
+        saved.frames = vec![
+            MacroFrame {
+                offset: Duration::from_micros(1500),
+                gamepad: pressed,
+            },
+            MacroFrame {
+                offset: Duration::from_millis(40),
+                gamepad: UniversalGamepad::nothing_pressed(),
+            },
+        ];
+
+        saved.save(&directory).unwrap().join().unwrap();
+        let mut loaded = Macro::from_section(config.section("macro", "combo1").unwrap()).unwrap();
+        loaded.load(&directory);
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(loaded.frames, saved.frames);
+    }
+
+    #[test]
+    fn macro_names_stay_in_the_directory() {
+        for name in ["../combo1", "sub/combo1", ".."] {
+            let config = Config::parse(&format!("[macro {name}]\nrecord = specials.logo\nplay = specials.right\n")).unwrap();
+            assert!(Macro::from_section(&config.sections[0]).is_err(), "{name}");
+        }
+    }
+
+    #[test]
+    fn playback_keeps_the_recorded_timing() {
+        let directory: PathBuf = std::env::temp_dir().join(format!("gamepad-bridge-macros-{}-timing", std::process::id()));
+        let mut stage = MacroStage::from_config(&_config(&directory)).unwrap().unwrap();
+        let record = _gamepad(&[Button::SpecialLogo, Button::DPadLeft]);
+        let play = _gamepad(&[Button::SpecialLogo, Button::DPadRight]);
+        let pressed = _gamepad(&[Button::MainLower]);
+        let start = Instant::now();
+        let at = |millis: u64| start + Duration::from_millis(millis);
+
+        // recorded: pressed from 10 to 30 ms
+        let inputs = [
+            (0, &record),
+            (1, &UniversalGamepad::nothing_pressed()),
+            (10, &pressed),
+            (30, &UniversalGamepad::nothing_pressed()),
+            (50, &record),
+        ];
+        for (millis, input) in inputs {
+            let mut gamepad: UniversalGamepad = input.clone();
+            stage.process(&mut gamepad, at(millis));
+        }
+        stage.saving.take().unwrap().join().unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        // played back 1000 ms later, the combo itself is never passed on
+        let mut gamepad: UniversalGamepad = play.clone();
+        stage.process(&mut gamepad, at(1000));
+        assert_eq!(gamepad, UniversalGamepad::nothing_pressed());
+
+        let mut played: Vec<bool> = Vec::new();
+        for millis in [1005, 1015, 1029, 1035, 1060] {
+            let mut gamepad = UniversalGamepad::nothing_pressed();
+            stage.process(&mut gamepad, at(millis));
+            played.push(gamepad.buttons.main.lower);
+        }
+        assert_eq!(played, vec![false, true, true, false, false]);
+        assert!(matches!(stage.state, MacroState::Idle));
+    }
+}
@@ -8,36 +8,82 @@ extern crate termion;
 
 use ctrlc::set_handler;
 use flume::bounded;
-use flume::unbounded;
 use flume::Receiver;
 use flume::Sender;
 use hidapi::HidApi;
 use std::env;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::process::Command;
 use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicI16;
+use std::sync::atomic::AtomicU32;
 use std::sync::atomic::Ordering;
 use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
 use std::sync::Arc;
 use std::thread;
+use std::thread::JoinHandle;
 use std::time::Duration;
+use std::time::Instant;
 use usb_gadget::UsbGadgetDescriptor;
 
+mod accessibility;
 mod bluetooth_fn;
+mod bluez;
+mod button_modifiers;
+mod calibration;
+mod config;
+mod control;
+mod copilot;
+mod dbus;
+mod drift;
+mod driver_registry;
+mod gyro_aiming;
 mod helper_fn;
 mod hidapi_fn;
+mod input_mapping;
+mod known_controllers;
+mod latest_state;
+mod link_quality;
+mod macros;
+mod mapping_layers;
+mod pacing;
+mod pairing;
+mod power;
+mod processing;
+mod socd;
+mod split_players;
+mod status;
+mod stick_processing;
+mod touchpad_mapping;
 mod universal_gamepad;
 mod usb_gadget;
 mod usb_gamepad;
 mod usb_gamepad_keyboard;
 mod usb_gamepad_ps4;
 mod usb_gamepad_ps5;
+mod watchdog;
 
 use crate::bluetooth_fn::*;
+use crate::bluez::{BluezClient, BtEvent};
+use crate::calibration::Calibration;
+use crate::config::Config;
+use crate::copilot::{CoPilot, CoPilotController, CoPilotSettings};
+use crate::driver_registry::{DriverRegistry, OutputPersonaEntry};
+use crate::gyro_aiming::{GyroAiming, GyroOutput};
+use crate::hidapi_fn::{HidApiGamepadError, HidGamepad, InputMonitors};
+use crate::known_controllers::{KnownController, KnownControllers};
+use crate::latest_state::latest_state;
+use crate::link_quality::{LinkMonitor, LinkQualitySettings, RSSI_UNKNOWN};
+use crate::pacing::{Pacer, Pacing};
+use crate::pairing::{PairingError, PairingHotkey, PairingSettings};
+use crate::power::{PowerMonitor, PowerSettings};
+use crate::processing::Pipeline;
+use crate::split_players::SplitPlayers;
 use crate::universal_gamepad::UniversalGamepad;
-use crate::usb_gamepad::Gamepad;
-use crate::usb_gamepad_ps4::DUALSHOCK;
-use crate::usb_gamepad_ps5::DUALSENSE;
+use crate::usb_gamepad::{HidgFile, HostFeedback, InputDriver, OutputPersona};
+use crate::watchdog::{InputWatchdog, WatchdogSettings};
 
 //  if working inside a docker container: (started with the docker-compose from project root)
 //  - build and run (inside container)  `cargo run`
@@ -47,83 +93,567 @@ use crate::usb_gamepad_ps5::DUALSENSE;
 
 // for benchmarking in tests use: cargo test -- --show-output
 
+/// How often gamepads that were turned off are looked for, and a running pairing is checked
+const RECONNECT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 fn main() {
     println!("\nGamepad-Bridge started: v{:}", version!());
     println!("This program needs to be run as root user. Please set uuid accordingly.\n");
 
+    if env::args().nth(1).as_deref() == Some("calibrate") {
+        _calibration_program_flow();
+        return;
+    }
+    if env::args().nth(1).as_deref() == Some("controllers") {
+        _known_controllers_program_flow();
+        return;
+    }
+
+    let config: Config = match Config::from_cmdline_args() {
+        Ok(config) => config,
+        Err(err) => print_error_and_exit!("Error reading config file", err, 1),
+    };
+
+    // ----- Split mode: one gamepad is two players, the gadget needs one hid function per player
+    let split_players: Option<SplitPlayers> = match SplitPlayers::from_config(&config) {
+        Ok(split_players) => split_players,
+        Err(err) => print_error_and_exit!("Error in config file", err, 1),
+    };
+    let function_count: usize = match split_players {
+        Some(_) => SplitPlayers::PLAYERS,
+        None => 1,
+    };
+
+    let registry = DriverRegistry::with_builtin_drivers();
+    let persona_entry: &OutputPersonaEntry = registry.output_persona_from_cmdline_args();
+
+    // ----- Every section is read before the gadget is configured, so a config error never leaves a gadget behind
+    let copilot_settings: Option<CoPilotSettings> = match CoPilotSettings::from_config(&config) {
+        Ok(settings) => settings,
+        Err(err) => print_error_and_exit!("Error in config file", err, 1),
+    };
+    if copilot_settings.is_some() && split_players.is_some() {
+        print_and_exit!(
+            "Co-pilot mode and split mode can not be used at the same time, please remove [copilot] or [split] from the config file",
+            1
+        );
+    }
+    let pairing_settings: PairingSettings = match PairingSettings::from_config(&config) {
+        Ok(settings) => settings,
+        Err(err) => print_error_and_exit!("Error in config file", err, 1),
+    };
+    let watchdog_settings: WatchdogSettings = match WatchdogSettings::from_config(&config) {
+        Ok(settings) => settings,
+        Err(err) => print_error_and_exit!("Error in config file", err, 1),
+    };
+    let link_quality_settings: Option<LinkQualitySettings> = match LinkQualitySettings::from_config(&config) {
+        Ok(settings) => settings,
+        Err(err) => print_error_and_exit!("Error in config file", err, 1),
+    };
+    let power_settings: PowerSettings = match PowerSettings::from_config(&config) {
+        Ok(settings) => settings,
+        Err(err) => print_error_and_exit!("Error in config file", err, 1),
+    };
+    let pacing: Pacing = match Pacing::from_config(&config) {
+        Ok(pacing) => pacing,
+        Err(err) => print_error_and_exit!("Error in config file", err, 1),
+    };
+    if let Err(err) = Pipeline::check_config(&config, &persona_entry.associated_args) {
+        print_error_and_exit!("Error in config file", err, 1);
+    }
+    // gyro aiming onto the mouse gets a mouse function after the gamepad functions
+    let with_mouse: bool = matches!(GyroAiming::from_config(&config), Ok(Some(gyro)) if gyro.output == GyroOutput::Mouse);
+    let mut known_controllers: KnownControllers = match KnownControllers::from_config(&config) {
+        Ok(known_controllers) => known_controllers,
+        Err(err) => print_error_and_exit!("Error reading known controllers", err, 1),
+    };
+
     // ----- Enable Gadget
     // If this is done at a later point, the host might run into errors when trying to classify this device and turn it off
-    let output_gamepad: &Gamepad = Gamepad::from_cmdline_args();
-    output_gamepad.gadget.configure_device();
+    let mut output_persona: Box<dyn OutputPersona> = (persona_entry.create)();
+    let gadget: &UsbGadgetDescriptor = output_persona.gadget();
+    gadget.configure_composite_device(function_count, with_mouse);
     println!("Gadget enabled");
 
     // ----- Create all channels
     // These are used to tell the reading and writing threads to finish (they are normally infinite loops)
     let (sender_ctrlc, recv_ctrlc) = mpsc::channel();
     let (sender_exit_request, recv_exit_request): (Sender<()>, Receiver<()>) = bounded(1);
-    let (sender_gamepad, recv_gamepad): (Sender<UniversalGamepad>, Receiver<UniversalGamepad>) = unbounded();
 
     // ----- Setup CTRL+C handler
     ctrlc::set_handler(move || sender_ctrlc.send(()).expect("Could not send signal on channel.")).expect("Error setting Ctrl-C handler");
 
-    // ----- BT connection here
-    // TODO
-
     // ----- What gamepad is connected?
-    let api = match HidApi::new() {
+    let mut api = match HidApi::new() {
         Ok(api) => api,
         Err(err) => print_error_and_exit!("Error getting HidApi access", err, 2),
     };
 
-    let (device, input_gamepad): (hidapi::HidDevice, &Gamepad) = match hidapi_fn::get_hid_gamepad(&api) {
-        Ok((device, model)) => match model {
-            hidapi_fn::SupportedInputGamepads::Ps5DualSense => (device, &DUALSENSE),
-            hidapi_fn::SupportedInputGamepads::PS4DualShock => (device, &DUALSHOCK),
-        },
+    let mut gamepads: Vec<(hidapi::HidDevice, Box<dyn InputDriver>)> = match hidapi_fn::get_hid_gamepads(&api, &registry) {
+        Ok(gamepads) => gamepads,
+        // with pairing enabled, the gamepads can still be connected now
+        Err(HidApiGamepadError::NoBTDevice | HidApiGamepadError::NoSupportedDevice) if pairing_settings.enabled => Vec::new(),
         Err(err) => print_error_and_exit!("Error accessing connected hid gamepad", err, 1),
     };
 
-    println!("Gamepad connected");
+    // without co-pilot mode only the first gamepad is used
+    let wanted_gamepads: usize = copilot_settings.as_ref().map(|settings| settings.controllers).unwrap_or(1);
+
+    // ----- BT connection: missing gamepads are paired and connected
+    if gamepads.len() < wanted_gamepads && pairing_settings.enabled {
+        let in_use: Vec<String> = gamepads
+            .iter()
+            .filter_map(|(device, _)| device.get_serial_number_string().unwrap_or(None))
+            .collect();
+        let missing: usize = wanted_gamepads - gamepads.len();
+        gamepads.extend(pairing::connect_gamepads(&mut api, &registry, &pairing_settings, missing, &in_use));
+    }
+    if gamepads.is_empty() {
+        println!("No gamepad is connected");
+        gadget.clean_up_composite_device(function_count, with_mouse);
+        exit(1);
+    }
+    if gamepads.len() < wanted_gamepads {
+        println!("Co-pilot mode expects {} gamepads, but only {} are connected", wanted_gamepads, gamepads.len());
+    }
+
+    // ----- Known controllers: the ones with a player slot are used first, in the order of their slots
+    gamepads.sort_by_key(|(device, _)| known_controllers.player_order(device.get_serial_number_string().unwrap_or(None).as_deref()));
+    gamepads.truncate(wanted_gamepads);
+
+    let mut controller_serials: Vec<Option<String>> = gamepads.iter().map(|(device, _)| device.get_serial_number_string().unwrap_or(None)).collect();
+    for ((_, input_driver), serial) in gamepads.iter().zip(&controller_serials) {
+        match serial {
+            Some(serial) => {
+                known_controllers.seen(serial, input_driver.display_name());
+                let display_name: String = known_controllers.get_or_insert(serial).display_name();
+                println!("Gamepad connected: {} ({})", input_driver.display_name(), display_name);
+            }
+            None => println!("Gamepad connected: {} (no serial number)", input_driver.display_name()),
+        }
+    }
+
+    // ----- Co-pilot mode: all gamepads are merged before processing
+    let copilot: Option<Arc<CoPilot>> = match &copilot_settings {
+        Some(settings) => {
+            let mut controllers: Vec<CoPilotController> = Vec::new();
+            for serial in &controller_serials {
+                let known_calibration: Option<&Path> = serial
+                    .as_deref()
+                    .and_then(|serial| known_controllers.get(serial))
+                    .and_then(|controller| controller.calibration.as_deref());
+                match CoPilotController::from_config(&config, serial.as_deref(), known_calibration) {
+                    Ok(controller) => controllers.push(controller),
+                    Err(err) => {
+                        println!("Error in config file: {:?}", err);
+                        gadget.clean_up_composite_device(function_count, with_mouse);
+                        exit(1);
+                    }
+                }
+            }
+            let mut copilot: CoPilot = CoPilot::new(settings.axes, controllers);
+            copilot.stale_after = watchdog_settings.timeout;
+            Some(Arc::new(copilot))
+        }
+        None => None,
+    };
+
+    // ----- Which processing stages are configured for this gamepad?
+    // In co-pilot mode the calibration is applied per gamepad before merging, and only the persona selects the profile
+    let pipeline_serial: Option<&str> = match copilot {
+        Some(_) => None,
+        None => controller_serials[0].as_deref(),
+    };
+    let known_controller: Option<&KnownController> = pipeline_serial.and_then(|serial| known_controllers.get(serial));
+    let pipeline: Pipeline = match Pipeline::from_config(&config, pipeline_serial, &persona_entry.associated_args, known_controller) {
+        Ok(pipeline) => pipeline,
+        Err(err) => {
+            println!("Error in config file: {:?}", err);
+            gadget.clean_up_composite_device(function_count, with_mouse);
+            exit(1);
+        }
+    };
+    pipeline.print_stages();
+    if let (Some(serial), Some(profile_name)) = (pipeline_serial, &pipeline.profile_name) {
+        known_controllers.get_or_insert(serial).profile = Some(profile_name.clone());
+    }
+    if let Err(err) = known_controllers.save() {
+        println!("Saving known controllers to {:?} failed: {:?}", known_controllers.path, err);
+    }
+
+    // ----- Status interface
+    let status_path = status::path_from_config(&config);
+    for (index, ((_, input_driver), serial)) in gamepads.iter().zip(&controller_serials).enumerate() {
+        status::set(&format!("gamepad.input.{index}"), input_driver.display_name().to_string());
+        if let Some(serial) = serial {
+            status::set(&format!("gamepad.input.{index}.serial"), serial.clone());
+        }
+        _set_known_controller_status(index, serial.as_deref().and_then(|serial| known_controllers.get(serial)));
+    }
+    status::set("gamepad.output", output_persona.display_name().to_string());
+    if split_players.is_some() {
+        status::set("gamepad.output.players", SplitPlayers::PLAYERS.to_string());
+    }
+    let status_path_for_thread = status_path.clone();
+    thread::Builder::new()
+        .name("status".to_string())
+        .spawn(move || status::write_continously(status_path_for_thread))
+        .expect("creating status thread failed");
+
+    // ----- Link quality: report timing is watched by the input threads, the signal strength by its own thread
+    let mut rssi_values: Vec<Arc<AtomicI16>> = Vec::new();
+    let mut rssi_controllers: Vec<(usize, String, Arc<AtomicI16>)> = Vec::new();
+    for (index, serial) in controller_serials.iter().enumerate() {
+        let rssi: Arc<AtomicI16> = Arc::new(AtomicI16::new(RSSI_UNKNOWN));
+        if let (Some(_), Some(serial)) = (&link_quality_settings, serial) {
+            rssi_controllers.push((index, serial.clone(), rssi.clone()));
+        }
+        rssi_values.push(rssi);
+    }
+    if rssi_controllers.is_empty() == false {
+        thread::Builder::new()
+            .name("rssi".to_string())
+            .spawn(move || link_quality::poll_rssi_continously(rssi_controllers))
+            .expect("creating rssi thread failed");
+    }
+
+    // ----- Power: idle gamepads are turned off, the off combo turns off all of them
+    let hotkey_uses: Arc<AtomicU32> = Arc::new(AtomicU32::new(0));
+
+    // ----- Pairing combo: set by the input threads, the main thread pairs the gamepad
+    let pairing_requested: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+
+    // ----- Host feedback: the output thread reads rumble and lightbar from the host, the input threads send them to the gamepads
+    let host_feedback: Arc<HostFeedback> = Arc::new(HostFeedback::new());
+
+    // ----- Latest state: the input threads publish, the output thread always takes the newest gamepad
+    let (gamepad_state, gamepad_subscriber) = latest_state(UniversalGamepad::nothing_pressed(), wanted_gamepads);
+
+    // ----- Reading input of BT gamepads, one thread each
+    // also used again for gamepads that reconnect after they were turned off, or were paired with the pairing combo
+    let spawn_input_thread = |index: usize, device: hidapi::HidDevice, input_driver: Box<dyn InputDriver>, serial: Option<&str>| -> JoinHandle<()> {
+        // a thread that ended gave its publisher back
+        let gamepad_publisher = gamepad_state.publisher().expect("more input threads than gamepads");
+        let recv_exit_request = recv_exit_request.clone();
+        let copilot_slot = copilot.as_ref().map(|copilot| (copilot.clone(), index));
+        // gamepads paired later have no signal strength
+        let rssi: Arc<AtomicI16> = rssi_values.get(index).cloned().unwrap_or_else(|| Arc::new(AtomicI16::new(RSSI_UNKNOWN)));
+        let monitors = InputMonitors {
+            link: link_quality_settings.clone().map(|settings| LinkMonitor::new(index, settings, rssi)),
+            power: serial.map(|address| PowerMonitor::new(address, power_settings.clone(), hotkey_uses.clone(), Instant::now())),
+            pairing: pairing_settings
+                .combo
+                .clone()
+                .map(|combo| PairingHotkey::new(combo, pairing_settings.combo_time, pairing_requested.clone())),
+            host: Some(host_feedback.clone()),
+        };
 
-    // ----- Reading input of BT gamepad
-    let thread_handle_input = thread::Builder::new()
-        .name("input".to_string())
-        .spawn(move || hidapi_fn::read_bt_gamepad_input(device, input_gamepad, sender_gamepad, recv_exit_request))
-        .expect("creating input thread failed");
-    println!("Input thread running");
+        thread::Builder::new()
+            .name(format!("input {index}"))
+            .spawn(move || hidapi_fn::read_bt_gamepad_input(device, input_driver, gamepad_publisher, recv_exit_request, copilot_slot, monitors))
+            .expect("creating input thread failed")
+    };
+    let mut thread_handles_input: Vec<JoinHandle<()>> = Vec::new();
+    for (index, (device, input_driver)) in gamepads.into_iter().enumerate() {
+        thread_handles_input.push(spawn_input_thread(index, device, input_driver, controller_serials[index].as_deref()));
+    }
+    println!("Input threads running");
 
     // TODO Maybe remove this later, but currently the output-writing step is reached so fast that /dev/hidg0 is not yet ready.
     // This just prevents some of the "Cannot send after transport endpoint shutdown" errors because of this ^
     thread::sleep(Duration::from_secs(1));
 
     // ----- Write Output to gadget
+    let create_persona = persona_entry.create;
+    // the watchdog sends a neutral report instead of the last state if input stops arriving
+    let pacer: Pacer = Pacer::new(pacing, InputWatchdog::new(&watchdog_settings));
+    let output_host_feedback: Arc<HostFeedback> = host_feedback.clone();
+    let mouse: Option<HidgFile> = match with_mouse {
+        true => Some(HidgFile::open(&format!("/dev/hidg{function_count}"))),
+        false => None,
+    };
     let thread_handle_output = thread::Builder::new()
         .name("output".to_string())
-        .spawn(move || output_gamepad.write_to_gadget_continously(recv_gamepad))
+        .spawn(move || match split_players {
+            Some(split_players) => {
+                let mut personas: [Box<dyn OutputPersona>; 2] = [output_persona, create_persona()];
+                split_players.write_to_gadget_continously(&mut personas, gamepad_subscriber, pipeline, pacer, output_host_feedback, mouse);
+            }
+            None => output_persona.write_to_gadget_continously(gamepad_subscriber, pipeline, pacer, output_host_feedback, mouse),
+        })
         .expect("creating output thread failed");
     println!("Output thread running");
-    println!("");
+    println!();
+
+    // ----- Control interface: commands written to the control file edit the known controllers while the bridge runs
+    let control_path: PathBuf = control::path_from_status_path(&status_path);
+
+    // ----- Pairing: runs on its own thread, so Ctrl+C and reconnects are handled while it waits for a gamepad
+    // a pairing thread that still waits on Ctrl+C is not joined, it ends with the process
+    let mut pairing_thread: Option<JoinHandle<Result<String, PairingError>>> = None;
+    // address of a gamepad that was connected by the pairing thread, until its hidraw node shows up or the deadline passes
+    let mut pairing_hidraw: Option<(String, Instant)> = None;
+
+    // ----- Wait for Ctrl + C, gamepads that were turned off are read again once they reconnect
+    loop {
+        match recv_ctrlc.recv_timeout(RECONNECT_POLL_INTERVAL) {
+            Ok(_) => break,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(err) => print_error_and_exit!("Receiving from CTRL C channel failed:", err, 1),
+        }
 
-    // ----- Clean up (if Ctrl + C is pressed)
+        // the bridge saves its own copy of the known controllers, so they are edited here and not in the file
+        let commands: Vec<String> = control::take_commands(&control_path);
+        for command in &commands {
+            let args: Vec<&str> = command.split_whitespace().collect();
+            let result: Result<(), String> = match args.as_slice() {
+                ["controllers", args @ ..] => known_controllers.run_command(args),
+                _ => Err("unknown command, expected controllers set ... or controllers forget ...".to_string()),
+            };
+            match result {
+                Ok(_) => {
+                    println!("Control command: {command}");
+                    status::set("control.last", format!("ok: {command}"));
+                }
+                Err(message) => {
+                    println!("Control command {command} failed: {message}");
+                    status::set("control.last", format!("error: {command}: {message}"));
+                }
+            }
+        }
+        if commands.is_empty() == false {
+            if let Err(err) = known_controllers.save() {
+                println!("Saving known controllers to {:?} failed: {:?}", known_controllers.path, err);
+            }
+            for (index, serial) in controller_serials.iter().enumerate() {
+                _set_known_controller_status(index, serial.as_deref().and_then(|serial| known_controllers.get(serial)));
+            }
+        }
+
+        // input threads only end early if their gamepad was turned off
+        for (index, thread_handle_input) in thread_handles_input.iter_mut().enumerate() {
+            let address: &str = match &controller_serials[index] {
+                Some(address) if thread_handle_input.is_finished() => address,
+                _ => continue,
+            };
+            if let Ok((device, input_driver)) = pairing::wait_for_hid_gamepad(&mut api, &registry, address, Duration::ZERO) {
+                println!("Gamepad reconnected: {}", address);
+                *thread_handle_input = spawn_input_thread(index, device, input_driver, Some(address));
+            }
+        }
+
+        if pairing_requested.load(Ordering::SeqCst) && pairing_thread.is_none() && pairing_hidraw.is_none() {
+            let in_use: Vec<String> = controller_serials.iter().flatten().cloned().collect();
+            let thread_pairing_settings: PairingSettings = pairing_settings.clone();
+            let spawned = thread::Builder::new().name("pairing".to_string()).spawn(move || {
+                let mut bluez: BluezClient = BluezClient::system().map_err(PairingError::Bluetooth)?;
+                return pairing::connect_new_gamepad(&mut bluez, &thread_pairing_settings, &in_use);
+            });
+            pairing_thread = Some(spawned.expect("creating pairing thread failed"));
+        }
 
-    // This is blocking
-    match recv_ctrlc.recv() {
-        Ok(_) => println!(""),
-        Err(e) => print_error_and_exit!("Receiving from CTRL C channel failed:", e, 1),
+        let mut paired: Option<Result<(String, HidGamepad), PairingError>> = None;
+        if pairing_thread.as_ref().is_some_and(|thread_handle_pairing| thread_handle_pairing.is_finished()) {
+            let thread_handle_pairing: JoinHandle<Result<String, PairingError>> = pairing_thread.take().expect("pairing thread is running");
+            match thread_handle_pairing.join().expect("pairing thread panicked") {
+                Ok(address) => pairing_hidraw = Some((address, Instant::now() + pairing_settings.hidraw_timeout)),
+                Err(err) => paired = Some(Err(err)),
+            }
+        }
+        if let Some((address, deadline)) = &pairing_hidraw {
+            match pairing::wait_for_hid_gamepad(&mut api, &registry, address, Duration::ZERO) {
+                Ok(gamepad) => paired = Some(Ok((address.clone(), gamepad))),
+                // looked for again with the next poll
+                Err(PairingError::NoHidrawNode(_)) if Instant::now() < *deadline => {}
+                Err(err) => paired = Some(Err(err)),
+            }
+            if paired.is_some() {
+                pairing_hidraw = None;
+            }
+        }
+
+        // the new gamepad takes the slot of a gamepad that was turned off, or a slot that is still missing
+        if let Some(paired) = paired {
+            // ends the pairing color on the gamepad that asked
+            pairing_requested.store(false, Ordering::SeqCst);
+
+            let free_slot: Option<usize> = match thread_handles_input.iter().position(|thread_handle_input| thread_handle_input.is_finished()) {
+                Some(index) => Some(index),
+                None if thread_handles_input.len() < wanted_gamepads => Some(thread_handles_input.len()),
+                None => None,
+            };
+            match (paired, free_slot) {
+                (Ok((address, (device, input_driver))), Some(index)) => {
+                    println!("Gamepad connected: {} ({}) as gamepad {}", input_driver.display_name(), address, index);
+                    known_controllers.seen(&address, input_driver.display_name());
+                    if let Err(err) = known_controllers.save() {
+                        println!("Saving known controllers to {:?} failed: {:?}", known_controllers.path, err);
+                    }
+                    status::set(&format!("gamepad.input.{index}"), input_driver.display_name().to_string());
+                    status::set(&format!("gamepad.input.{index}.serial"), address.clone());
+                    if let Some(copilot) = &copilot {
+                        let known_calibration: Option<&Path> = known_controllers.get(&address).and_then(|controller| controller.calibration.as_deref());
+                        match CoPilotController::from_config(&config, Some(&address), known_calibration) {
+                            Ok(controller) => copilot.set_controller(index, controller),
+                            Err(err) => println!("Error in config file: {:?}", err),
+                        }
+                    }
+
+                    let thread_handle_input = spawn_input_thread(index, device, input_driver, Some(&address));
+                    if index < thread_handles_input.len() {
+                        thread_handles_input[index] = thread_handle_input;
+                        controller_serials[index] = Some(address);
+                    } else {
+                        thread_handles_input.push(thread_handle_input);
+                        controller_serials.push(Some(address));
+                    }
+                }
+                (Ok((address, _)), None) => {
+                    println!("Gamepad {} was paired, but all {} gamepads are in use", address, wanted_gamepads);
+                    power::power_off(&address);
+                }
+                (Err(err), _) => println!("Pairing a gamepad failed: {:?}", err),
+            }
+        }
     }
+    println!();
+
+    // ----- Clean up
+    let is_turned_off: Vec<bool> = thread_handles_input
+        .iter()
+        .map(|thread_handle_input| thread_handle_input.is_finished())
+        .collect();
 
     println!("Waiting for input and output threads to finish");
-    sender_exit_request.send(()).expect("sending to input thread failed");
-    thread_handle_input.join().unwrap();
+    // disconnecting the channel reaches every input thread
+    drop(sender_exit_request);
+    for thread_handle_input in thread_handles_input {
+        thread_handle_input.join().unwrap();
+    }
+    gamepad_state.close();
     thread_handle_output.join().unwrap();
 
+    if power_settings.off_on_exit {
+        for (serial, is_turned_off) in controller_serials.iter().zip(is_turned_off) {
+            if let (Some(address), false) = (serial, is_turned_off) {
+                println!("Turning gamepad {} off", address);
+                power::power_off(address);
+            }
+        }
+    }
+
     // clean_up_device() removes hidg0 file, so this has to run after write output thread is closed
     println!("Disabling gadget");
-    output_gamepad.gadget.clean_up_device();
+    gadget.clean_up_composite_device(function_count, with_mouse);
+    status::clean_up(&status_path);
+    control::clean_up(&control_path);
 
     println!("Everything is cleaned up :)");
 }
 
+/// Shows the name and player slot of the gamepad at `index`, or removes them if it has none
+fn _set_known_controller_status(index: usize, known_controller: Option<&KnownController>) {
+    match known_controller.and_then(|controller| controller.name.clone()) {
+        Some(name) => status::set(&format!("gamepad.input.{index}.name"), name),
+        None => status::remove(&format!("gamepad.input.{index}.name")),
+    }
+    match known_controller.and_then(|controller| controller.player) {
+        Some(player) => status::set(&format!("gamepad.input.{index}.player"), player.to_string()),
+        None => status::remove(&format!("gamepad.input.{index}.player")),
+    }
+}
+
+/// `gamepad-bridge calibrate [--config <path>]`
+///
+/// Runs the calibration wizard for the connected gamepad and stores the result for its serial number.
+/// No gadget is created, the wizard only reads input
+fn _calibration_program_flow() {
+    let config: Config = match Config::from_cmdline_args() {
+        Ok(config) => config,
+        Err(err) => print_error_and_exit!("Error reading config file", err, 1),
+    };
+
+    let registry = DriverRegistry::with_builtin_drivers();
+    let api = match HidApi::new() {
+        Ok(api) => api,
+        Err(err) => print_error_and_exit!("Error getting HidApi access", err, 2),
+    };
+    let (device, mut input_driver): (hidapi::HidDevice, Box<dyn InputDriver>) = match hidapi_fn::get_hid_gamepad(&api, &registry) {
+        Ok(device_and_driver) => device_and_driver,
+        Err(err) => print_error_and_exit!("Error accessing connected hid gamepad", err, 1),
+    };
+
+    let serial: String = match device.get_serial_number_string() {
+        Ok(Some(serial)) if serial.is_empty() == false => serial,
+        _ => print_and_exit!("The gamepad has no serial number, its calibration could not be found again", 1),
+    };
+    println!("Calibrating {} ({})", input_driver.display_name(), serial);
+    println!();
+
+    let calibration: Calibration = calibration::run_wizard(&mut || hidapi_fn::read_single_gamepad(&device, input_driver.as_mut(), 100));
+
+    let directory = Calibration::directory_from_config(&config);
+    match calibration.save(&directory, &serial) {
+        Ok(_) => println!("Calibration saved to {:?}", Calibration::file_path(&directory, &serial)),
+        Err(err) => print_error_and_exit!("Saving the calibration failed", err, 1),
+    }
+
+    let mut known_controllers: KnownControllers = match KnownControllers::from_config(&config) {
+        Ok(known_controllers) => known_controllers,
+        Err(err) => print_error_and_exit!("Error reading known controllers", err, 1),
+    };
+    known_controllers.seen(&serial, input_driver.display_name());
+    known_controllers.get_or_insert(&serial).calibration = Some(Calibration::file_path(&directory, &serial));
+    if let Err(err) = known_controllers.save() {
+        println!("Saving known controllers to {:?} failed: {:?}", known_controllers.path, err);
+    }
+}
+
+/// `gamepad-bridge controllers [--config <path>]` lists the known controllers
+///
+/// - `gamepad-bridge controllers set <serial> <key> <value>` sets `name`, `player`, `profile`, `model` or `calibration`, an empty value removes it
+/// - `gamepad-bridge controllers forget <serial>` removes the controller
+///
+/// While the bridge runs, the same commands are written to its control file instead (see `control.rs`)
+fn _known_controllers_program_flow() {
+    let config: Config = match Config::from_cmdline_args() {
+        Ok(config) => config,
+        Err(err) => print_error_and_exit!("Error reading config file", err, 1),
+    };
+    let mut known_controllers: KnownControllers = match KnownControllers::from_config(&config) {
+        Ok(known_controllers) => known_controllers,
+        Err(err) => print_error_and_exit!("Error reading known controllers", err, 1),
+    };
+
+    // --config <path> can be anywhere, so it is removed before the arguments are matched
+    let mut args: Vec<String> = env::args().skip(2).collect();
+    if let Some(index) = args.iter().position(|arg| arg == "--config") {
+        args.drain(index..(index + 2).min(args.len()));
+    }
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+
+    match args.as_slice() {
+        [] | ["list"] => {
+            if known_controllers.controllers.is_empty() {
+                println!("No controllers known yet");
+            }
+            print!("{}", known_controllers.to_text());
+            return;
+        }
+        ["set", ..] | ["forget", ..] => {
+            if let Err(message) = known_controllers.run_command(&args) {
+                print_and_exit!(message, 1);
+            }
+        }
+        _ => print_and_exit!("Usage: gamepad-bridge controllers [list | set <serial> <key> <value> | forget <serial>]", 1),
+    }
+
+    match known_controllers.save() {
+        Ok(_) => println!("Known controllers saved to {:?}", known_controllers.path),
+        Err(err) => print_error_and_exit!("Saving known controllers failed", err, 1),
+    }
+}
+
 fn _bt_program_flow() {
     // Create a shared boolean flag to indicate if Ctrl+C was pressed
     let ctrlc = Arc::new(AtomicBool::new(true));
@@ -138,6 +668,54 @@ fn _bt_program_flow() {
         }
     };
 
+    // BlueZ is asked directly over D-Bus, bluetoothctl is only used if the system bus is not reachable (e.g. inside a container)
+    let mut bluez: BluezClient = match BluezClient::system() {
+        Ok(bluez) => bluez,
+        Err(err) => {
+            println!("BlueZ is not reachable over D-Bus, using bluetoothctl instead: {:?}", err);
+            _bt_program_flow_bluetoothctl(ctrlc);
+            return;
+        }
+    };
+
+    if let Err(err) = bluez.power_on() {
+        print_error_and_exit!("Turning bluetooth on failed", err, 1);
+    }
+    if let Err(err) = bluez.start_discovery() {
+        print_error_and_exit!("Starting bluetooth discovery failed", err, 1);
+    }
+
+    // find new controllers
+    // loop while ctrlc has not been pressed (.load == true)
+    while ctrlc.load(Ordering::SeqCst) {
+        match bluez.next_event(Duration::from_millis(500)) {
+            Ok(Some(BtEvent::NewDevice(device))) if device.is_gamepad() => {
+                println!("Gamepad found: {} ({})", device.name.as_deref().unwrap_or("no name"), device.address);
+                if let Err(err) = bluez.stop_discovery() {
+                    println!("Stopping bluetooth discovery failed: {:?}", err);
+                }
+                if let Err(err) = pairing::pair_trust_connect(&mut bluez, &device, &PairingSettings::default()) {
+                    println!("Connecting the gamepad failed: {:?}", err);
+                }
+                if let Err(err) = bluez.start_discovery() {
+                    print_error_and_exit!("Starting bluetooth discovery failed", err, 1);
+                }
+            }
+            Ok(_) => (),
+            Err(err) => {
+                println!("Reading bluetooth events failed: {:?}", err);
+                break;
+            }
+        }
+    }
+
+    if let Err(err) = bluez.stop_discovery() {
+        println!("Stopping bluetooth discovery failed: {:?}", err);
+    }
+}
+
+/// The old way of finding gamepads, by reading the output of `bluetoothctl scan on`
+fn _bt_program_flow_bluetoothctl(ctrlc: Arc<AtomicBool>) {
     {
         let output_power_on = match Command::new("bluetoothctl").args(["power", "on"]).output() {
             Ok(out) => out,
@@ -177,7 +755,7 @@ fn _bt_program_flow() {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::usb_gamepad::OUTPUT_GAMEPADS;
+    use crate::latest_state::{LatestState, Subscriber};
     use flume::{unbounded, Sender};
     use std::time::Instant;
 
@@ -186,19 +764,23 @@ mod tests {
     /// on my machine this takes 1.5µs
     #[test]
     fn bench1_all_gamepads_bt_input_to_gamepad() {
-        println!("");
+        println!();
         println!("Benchmark BT input -> UniversalGamepad output");
         println!("{} runs per gamepad", RUNS);
 
-        for gamepad in OUTPUT_GAMEPADS {
+        let registry = DriverRegistry::with_builtin_drivers();
+
+        for entry in registry.input_drivers() {
             // Skip unfinished gamepads
-            if gamepad.is_supported == false {
-                println!("{} skipped, not supported", gamepad.display_name);
+            if entry.is_supported == false {
+                println!("{} skipped, not supported", entry.display_name);
                 continue;
             }
 
+            let mut input_driver: Box<dyn InputDriver> = (entry.create)();
+
             // prepare fake input
-            let bt_input: Vec<u8> = vec![0; gamepad.min_bt_report_size];
+            let bt_input: Vec<u8> = vec![0; input_driver.min_bt_report_size()];
 
             // prepare benchmark value
             let mut counter: u32 = 0;
@@ -209,31 +791,35 @@ mod tests {
 
                 // It might be better not to use "let _ =" because this never assignes the output
                 // and could result in faster but unrealistic runtime
-                let _universal_gamepad = gamepad.bt_input_to_universal_gamepad(&bt_input);
+                let _universal_gamepad = input_driver.bt_input_to_universal_gamepad(&bt_input);
 
                 let diff = Instant::now() - before;
                 times += diff;
                 counter += 1;
             }
             let avg = times / RUNS;
-            println!("{} took: {:4.2?}", gamepad.display_name, avg);
+            println!("{} took: {:4.2?}", entry.display_name, avg);
         }
     }
 
     /// on my machine this takes ~1.5µs
     #[test]
     fn bench2_all_gamepads_gamepad_to_usb() {
-        println!("");
+        println!();
         println!("Benchmark UniversalGamepad input -> Usb gadget output");
         println!("{} runs per gamepad", RUNS);
 
-        for gamepad in OUTPUT_GAMEPADS {
+        let registry = DriverRegistry::with_builtin_drivers();
+
+        for entry in registry.output_personas() {
             // Skip unfinished gamepads
-            if gamepad.is_supported == false {
-                println!("{} skipped, not supported", gamepad.display_name);
+            if entry.is_supported == false {
+                println!("{} skipped, not supported", entry.display_name);
                 continue;
             }
 
+            let mut output_persona: Box<dyn OutputPersona> = (entry.create)();
+
             // prepare fake input
             let universal_gamepad = UniversalGamepad::nothing_pressed();
 
@@ -246,61 +832,125 @@ mod tests {
 
                 // It might be better not to use "let _ =" because this never assignes the output
                 // and could result in faster but unrealistic runtime
-                let _usb_output = gamepad.universal_gamepad_to_usb_output(&universal_gamepad);
+                let _usb_output = output_persona.universal_gamepad_to_usb_output(&universal_gamepad);
 
                 let diff = Instant::now() - before;
                 times += diff;
                 counter += 1;
             }
             let avg = times / RUNS;
-            println!("{} took: {:4.2?}", gamepad.display_name, avg);
+            println!("{} took: {:4.2?}", entry.display_name, avg);
         }
     }
 
     /// on my machine this takes 10µs
     #[test]
     fn bench3_all_gamepads_with_channels() {
-        println!("");
+        println!();
         println!("Benchmark BT Input to UniversalGamepad - channel - to usb gadget output");
         println!("{} runs per gamepad", RUNS);
 
-        for gamepad in OUTPUT_GAMEPADS {
-            // Skip unfinished gamepads
-            if gamepad.is_supported == false {
-                println!("{} skipped, not supported", gamepad.display_name);
-                continue;
+        let registry = DriverRegistry::with_builtin_drivers();
+
+        for input_entry in registry.input_drivers() {
+            for output_entry in registry.output_personas() {
+                // Skip unfinished gamepads
+                if input_entry.is_supported == false || output_entry.is_supported == false {
+                    println!("{} -> {} skipped, not supported", input_entry.display_name, output_entry.display_name);
+                    continue;
+                }
+
+                let input_driver: Box<dyn InputDriver> = (input_entry.create)();
+                let output_persona: Box<dyn OutputPersona> = (output_entry.create)();
+
+                let (sender_gamepad, recv_gamepad) = unbounded::<(UniversalGamepad, Instant)>();
+
+                let thread_handle_input = thread::Builder::new()
+                    .name("input".to_string())
+                    .spawn(move || _bench3_input_thread(sender_gamepad, input_driver))
+                    .expect("creating input thread failed");
+
+                let thread_handle_output = thread::Builder::new()
+                    .name("output".to_string())
+                    .spawn(move || _bench3_output_thread(recv_gamepad, output_persona))
+                    .expect("creating input thread failed");
+
+                thread_handle_input.join().unwrap();
+                match thread_handle_output.join() {
+                    Ok(avg) => println!("{} -> {} took: {:4.2?}", input_entry.display_name, output_entry.display_name, avg),
+                    Err(_) => println!("error unwrapping output handle"),
+                }
+
+                // the same with the latest state the bridge uses, which never queues
+                let input_driver: Box<dyn InputDriver> = (input_entry.create)();
+                let output_persona: Box<dyn OutputPersona> = (output_entry.create)();
+                let (gamepad_state, gamepad_subscriber) = latest_state((UniversalGamepad::nothing_pressed(), Instant::now()), 1);
+
+                let thread_handle_input = thread::Builder::new()
+                    .name("input".to_string())
+                    .spawn(move || _bench3_latest_state_input_thread(gamepad_state, input_driver))
+                    .expect("creating input thread failed");
+
+                let thread_handle_output = thread::Builder::new()
+                    .name("output".to_string())
+                    .spawn(move || _bench3_latest_state_output_thread(gamepad_subscriber, output_persona))
+                    .expect("creating output thread failed");
+
+                thread_handle_input.join().unwrap();
+                match thread_handle_output.join() {
+                    Ok((avg, taken)) => println!(
+                        "{} -> {} with latest state took: {:4.2?} ({} of {} inputs taken)",
+                        input_entry.display_name, output_entry.display_name, avg, taken, RUNS
+                    ),
+                    Err(_) => println!("error unwrapping output handle"),
+                }
             }
+        }
+    }
 
-            let (sender_gamepad, recv_gamepad): (Sender<(UniversalGamepad, Instant)>, Receiver<(UniversalGamepad, Instant)>) = unbounded();
+    fn _bench3_latest_state_input_thread(gamepad_state: Arc<LatestState<(UniversalGamepad, Instant)>>, mut input_driver: Box<dyn InputDriver>) {
+        let bt_input: Vec<u8> = vec![0; input_driver.min_bt_report_size()];
+        let mut publisher = gamepad_state.publisher().expect("no publisher left");
 
-            let thread_handle_input = thread::Builder::new()
-                .name("input".to_string())
-                .spawn(move || _bench3_input_thread(sender_gamepad, &gamepad))
-                .expect("creating input thread failed");
+        for _ in 0..RUNS {
+            let start = Instant::now();
 
-            let thread_handle_output = thread::Builder::new()
-                .name("output".to_string())
-                .spawn(move || _bench3_output_thread(recv_gamepad, &gamepad))
-                .expect("creating input thread failed");
+            let universal_gamepad = input_driver.bt_input_to_universal_gamepad(&bt_input);
+            publisher.publish((universal_gamepad, start));
 
-            thread_handle_input.join().unwrap();
-            match thread_handle_output.join() {
-                Ok(avg) => println!("{} took: {:4.2?}", gamepad.display_name, avg),
-                Err(_) => println!("error unwrapping output handle"),
-            }
+            thread::sleep(Duration::from_micros(10));
         }
+        gamepad_state.close();
+    }
+
+    /// Returns the average latency and how many inputs were taken, the others were overwritten before
+    fn _bench3_latest_state_output_thread(
+        mut subscriber: Subscriber<(UniversalGamepad, Instant)>,
+        mut output_persona: Box<dyn OutputPersona>,
+    ) -> (Duration, u32) {
+        let mut duration_sum: Duration = Duration::from_secs(0);
+        let mut taken: u32 = 0;
+
+        while let Ok((universal_gamepad, start)) = subscriber.recv() {
+            let _usb_out = output_persona.universal_gamepad_to_usb_output(&universal_gamepad);
+
+            duration_sum += Instant::now() - start;
+            taken += 1;
+        }
+
+        return (duration_sum / taken.max(1), taken);
     }
 
-    fn _bench3_input_thread(sender: Sender<(UniversalGamepad, Instant)>, gamepad: &Gamepad) {
+    fn _bench3_input_thread(sender: Sender<(UniversalGamepad, Instant)>, mut input_driver: Box<dyn InputDriver>) {
         // prepare fake input
-        let bt_input: Vec<u8> = vec![0; gamepad.min_bt_report_size];
+        let bt_input: Vec<u8> = vec![0; input_driver.min_bt_report_size()];
 
         let mut counter: u32 = 0;
 
         while counter < RUNS {
             let start = Instant::now();
 
-            let universal_gamepad = gamepad.bt_input_to_universal_gamepad(&bt_input);
+            let universal_gamepad = input_driver.bt_input_to_universal_gamepad(&bt_input);
             match sender.send((universal_gamepad, start)) {
                 Ok(_) => {}
                 Err(err) => println!("Error sending gamepad to output thread: {err}"),
@@ -311,11 +961,11 @@ mod tests {
         }
     }
 
-    fn _bench3_output_thread(receiver: Receiver<(UniversalGamepad, Instant)>, gamepad: &Gamepad) -> Duration {
+    fn _bench3_output_thread(receiver: Receiver<(UniversalGamepad, Instant)>, mut output_persona: Box<dyn OutputPersona>) -> Duration {
         let mut duration_sum: Duration = Duration::from_secs(0);
 
         for (universal_gamepad, start) in receiver.iter() {
-            let _usb_out = gamepad.universal_gamepad_to_usb_output(&universal_gamepad);
+            let _usb_out = output_persona.universal_gamepad_to_usb_output(&universal_gamepad);
 
             let end = Instant::now();
             let diff = end - start;
@@ -0,0 +1,288 @@
+use std::time::Instant;
+
+use crate::config::{Config, ConfigError, ConfigSection};
+use crate::input_mapping::{ButtonCombo, Input, Profile};
+use crate::processing::ProcessingStage;
+use crate::universal_gamepad::UniversalGamepad;
+
+/// Axes count as held (and stay in the layer they were moved in) while they are deflected more than this
+const AXIS_HELD_THRESHOLD: u8 = 16;
+
+/// Keys of a layer section that are not part of its profile
+const LAYER_KEYS: [&str; 2] = ["shift", "mode"];
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LayerMode {
+    /// The layer is active while the shift buttons are held
+    Hold,
+
+    /// Each press of the shift buttons switches the layer on or off
+    Toggle,
+}
+
+/// An alternate mapping on top of a profile, configured with a `[layer <name>]` section
+pub struct Layer {
+    /// The rules of the layer on top of the rules of the profile
+    pub profile: Profile,
+    pub shift: ButtonCombo,
+    pub mode: LayerMode,
+
+    shift_was_pressed: bool,
+    toggled_on: bool,
+}
+
+impl Layer {
+    /// `profile` already has to contain the rules of the base profile, see `Profile::overlaid()`
+    pub fn new(profile: Profile, shift: ButtonCombo, mode: LayerMode) -> Self {
+        Self {
+            profile,
+            shift,
+            mode,
+            shift_was_pressed: false,
+            toggled_on: false,
+        }
+    }
+
+    /// Reads the same lines as a profile section, and:
+    /// - `shift = <button>, <button>, ...`
+    /// - `mode = hold` (default) or `mode = toggle`
+    fn from_section(section: &ConfigSection, base: &Profile) -> Result<Self, ConfigError> {
+        let shift: ButtonCombo = match ButtonCombo::from_section(section, "shift")? {
+            Some(combo) => combo,
+            None => return Err(section.error("shift is missing".to_string())),
+        };
+        let mode: LayerMode = match section.get("mode") {
+            None | Some("hold") => LayerMode::Hold,
+            Some("toggle") => LayerMode::Toggle,
+            Some(other) => return Err(section.error(format!("unknown mode: {other}"))),
+        };
+
+        let layer_profile: Profile = Profile::from_section(section, &LAYER_KEYS)?;
+
+        return Ok(Self::new(base.overlaid(&layer_profile), shift, mode));
+    }
+
+    fn update(&mut self, physical: &UniversalGamepad) -> bool {
+        let is_pressed: bool = self.shift.is_pressed(physical);
+        if is_pressed && self.shift_was_pressed == false {
+            self.toggled_on = !self.toggled_on;
+        }
+        self.shift_was_pressed = is_pressed;
+
+        match self.mode {
+            LayerMode::Hold => return is_pressed,
+            LayerMode::Toggle => return self.toggled_on,
+        }
+    }
+}
+
+/// A profile with layers that are switched to while their shift buttons are held, like keyboard layers in QMK
+///
+/// Every input stays in the layer it was pressed in until it is released,
+/// so switching layers while a button is held neither sticks the old output nor jumps to the new one
+pub struct LayeredProfile {
+    pub base: Profile,
+
+    /// the highest active layer wins, index 0 is the base profile so layer `i` has the index `i + 1`
+    pub layers: Vec<Layer>,
+
+    /// for every input in the order of `Input::all()`: the layer it was pressed in, `None` while it is not held
+    owners: Vec<Option<usize>>,
+}
+
+impl LayeredProfile {
+    /// Reads the `[layer <name>]` sections of all layers named in `base`
+    pub fn from_config(config: &Config, base: Profile) -> Result<Self, ConfigError> {
+        let mut layers: Vec<Layer> = Vec::new();
+
+        for name in &base.layers {
+            match config.section("layer", name) {
+                Some(section) => layers.push(Layer::from_section(section, &base)?),
+                None => return Err(ConfigError::Syntax(0, format!("there is no section [layer {name}]"))),
+            }
+        }
+
+        return Ok(Self {
+            base,
+            layers,
+            owners: vec![None; Input::all().len()],
+        });
+    }
+
+    fn _profile(&self, index: usize) -> &Profile {
+        match index {
+            0 => return &self.base,
+            _ => return &self.layers[index - 1].profile,
+        }
+    }
+
+    pub fn apply(&mut self, input: &UniversalGamepad) -> UniversalGamepad {
+        let mut physical: UniversalGamepad = input.clone();
+
+        let mut active: usize = 0;
+        for (index, layer) in self.layers.iter_mut().enumerate() {
+            if layer.update(&physical) {
+                active = index + 1;
+            }
+        }
+
+        // shift buttons only switch layers, the host never sees them
+        for layer in &self.layers {
+            layer.shift.release(&mut physical);
+        }
+
+        let inputs: Vec<Input> = Input::all();
+        for (input, owner) in inputs.iter().zip(self.owners.iter_mut()) {
+            if input.is_active(&physical, AXIS_HELD_THRESHOLD) == false {
+                *owner = None;
+            } else if owner.is_none() {
+                *owner = Some(active);
+            }
+        }
+        let owner_of = |input: &Input| -> usize {
+            let position: usize = inputs.iter().position(|other| other == input).unwrap_or(0);
+            return self.owners[position].unwrap_or(active);
+        };
+
+        // every layer that is in use maps only the inputs it owns
+        let mut outputs: Vec<Option<UniversalGamepad>> = Vec::new();
+        for index in 0..=self.layers.len() {
+            if index != active && self.owners.contains(&Some(index)) == false {
+                outputs.push(None);
+                continue;
+            }
+
+            let mut owned: UniversalGamepad = physical.clone();
+            for input in inputs.iter().filter(|input| owner_of(input) != index) {
+                input.release(&mut owned);
+            }
+            outputs.push(Some(self._profile(index).apply(&owned)));
+        }
+
+        let mut output: UniversalGamepad = match &outputs[active] {
+            Some(output) => output.clone(),
+            None => physical.clone(),
+        };
+
+        // each output input is read from the layers that own one of its sources
+        for target in &inputs {
+            let mut is_owned: bool = false;
+            let mut merged: UniversalGamepad = output.clone();
+            target.release(&mut merged);
+
+            for (index, layer_output) in outputs.iter().enumerate() {
+                let layer_output: &UniversalGamepad = match layer_output {
+                    Some(layer_output) => layer_output,
+                    None => continue,
+                };
+                if self._profile(index).sources_of(*target).iter().any(|source| owner_of(source) == index) == false {
+                    continue;
+                }
+
+                is_owned = true;
+                match target {
+                    Input::Button(button) => {
+                        if layer_output.button(*button) {
+                            merged.set_button(*button, true);
+                        }
+                    }
+                    Input::Axis(axis) => {
+                        let rest: u8 = axis.rest_value();
+                        if layer_output.axis(*axis).abs_diff(rest) > merged.axis(*axis).abs_diff(rest) {
+                            merged.set_axis(*axis, layer_output.axis(*axis));
+                        }
+                    }
+                }
+            }
+
+            if is_owned {
+                match target {
+                    Input::Button(button) => output.set_button(*button, merged.button(*button)),
+                    Input::Axis(axis) => output.set_axis(*axis, merged.axis(*axis)),
+                }
+            }
+        }
+
+        return output;
+    }
+}
+
+impl ProcessingStage for LayeredProfile {
+    fn display_name(&self) -> &'static str {
+        return "Remapping with layers";
+    }
+
+    fn process(&mut self, gamepad: &mut UniversalGamepad, _now: Instant) {
+        *gamepad = self.apply(gamepad);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layered(content: &str) -> LayeredProfile {
+        let config = Config::parse(content).expect("test config is valid");
+        let base = Profile::from_config(&config, "test").expect("test profile is valid");
+        return LayeredProfile::from_config(&config, base).expect("test layers are valid");
+    }
+
+    const CONFIG: &str = "[profile test]\nlayers = dpad\n[layer dpad]\nshift = specials.logo\ndpad.up = main.upper\ndisable = main.upper\n";
+
+    #[test]
+    fn layer_is_active_while_shift_is_held() {
+        let mut profile = layered(CONFIG);
+        let mut input = UniversalGamepad::nothing_pressed();
+        input.buttons.main.upper = true;
+
+        let output = profile.apply(&input);
+        assert!(output.buttons.main.upper);
+        assert!(output.buttons.dpad.up == false);
+
+        input.buttons.main.upper = false;
+        profile.apply(&input);
+
+        input.buttons.specials.logo = true;
+        input.buttons.main.upper = true;
+        let output = profile.apply(&input);
+        assert!(output.buttons.main.upper == false);
+        assert!(output.buttons.dpad.up);
+        assert!(output.buttons.specials.logo == false);
+    }
+
+    #[test]
+    fn held_buttons_stay_in_their_layer() {
+        let mut profile = layered(CONFIG);
+        let mut input = UniversalGamepad::nothing_pressed();
+
+        // pressed in the layer, then the shift is released first
+        input.buttons.specials.logo = true;
+        input.buttons.main.upper = true;
+        profile.apply(&input);
+        input.buttons.specials.logo = false;
+        let output = profile.apply(&input);
+        assert!(output.buttons.dpad.up);
+        assert!(output.buttons.main.upper == false);
+
+        // released, nothing is stuck
+        input.buttons.main.upper = false;
+        let output = profile.apply(&input);
+        assert!(output.buttons.dpad.up == false);
+        assert!(output.buttons.main.upper == false);
+
+        // pressed in the base layer, then the shift is pressed
+        input.buttons.main.upper = true;
+        profile.apply(&input);
+        input.buttons.specials.logo = true;
+        let output = profile.apply(&input);
+        assert!(output.buttons.main.upper);
+        assert!(output.buttons.dpad.up == false);
+    }
+
+    #[test]
+    fn profile_keys_are_an_error_in_layers() {
+        let config = Config::parse("[profile test]\nlayers = dpad\n[layer dpad]\nshift = specials.logo\nsticky = main.lower\n").unwrap();
+        let base = Profile::from_config(&config, "test").unwrap();
+        assert!(LayeredProfile::from_config(&config, base).is_err());
+    }
+}
@@ -0,0 +1,216 @@
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, ConfigError, ConfigSection};
+use crate::latest_state::Subscriber;
+use crate::processing::Pipeline;
+use crate::universal_gamepad::UniversalGamepad;
+use crate::usb_gadget::HID_POLLING_INTERVAL;
+use crate::watchdog::{InputWatchdog, WatchdogEvent};
+
+/// Used by `Pacing::Changes` if `[output]` has no `keepalive = <ms>`
+pub const DEFAULT_KEEPALIVE: Duration = Duration::from_millis(100);
+
+/// When reports are written to the gadget
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Pacing {
+    /// Every input report is written as soon as it arrives, reports that arrive while writing are skipped for the newest one
+    PassThrough,
+
+    /// The newest state is written once per `interval`, matching the polling rate of the host
+    Polling { interval: Duration },
+
+    /// Only changed states are written, the last one is repeated after `keepalive` without a change
+    Changes { keepalive: Duration },
+}
+
+impl Pacing {
+    /// Reads the `[output]` section:
+    /// - `pacing = passthrough` (default)
+    /// - `pacing = polling` with `interval = <ms>`, by default the polling interval of the gadget
+    /// - `pacing = changes` with `keepalive = <ms>` (default 100)
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        let section: &ConfigSection = match config.section("output", "") {
+            Some(section) => section,
+            None => return Ok(Pacing::PassThrough),
+        };
+
+        let millis = |key: &str, default: Duration| -> Result<Duration, ConfigError> {
+            match section.get_parsed::<u64>(key)? {
+                Some(0) => return Err(section.error(format!("{key} has to be at least 1 ms"))),
+                Some(millis) => return Ok(Duration::from_millis(millis)),
+                None => return Ok(default),
+            }
+        };
+
+        match section.get("pacing").unwrap_or("passthrough") {
+            "passthrough" => return Ok(Pacing::PassThrough),
+            "polling" => {
+                return Ok(Pacing::Polling {
+                    interval: millis("interval", HID_POLLING_INTERVAL)?,
+                })
+            }
+            "changes" => {
+                return Ok(Pacing::Changes {
+                    keepalive: millis("keepalive", DEFAULT_KEEPALIVE)?,
+                })
+            }
+            other => return Err(section.error(format!("pacing has to be passthrough, polling or changes, not {other}"))),
+        }
+    }
+}
+
+/// Decides which gamepads the output thread writes and when, waiting in between without spinning
+pub struct Pacer {
+    pacing: Pacing,
+    watchdog: InputWatchdog,
+}
+
+impl Pacer {
+    pub fn new(pacing: Pacing, watchdog: InputWatchdog) -> Self {
+        Self { pacing, watchdog }
+    }
+
+    /// Calls `write` with every gamepad that is due for the host, until the bridge shuts down
+    ///
+    /// Gamepads are run through the `pipeline` first, the neutral report of the watchdog is not
+    pub fn run(mut self, mut subscriber: Subscriber<UniversalGamepad>, mut pipeline: Pipeline, mut write: impl FnMut(&UniversalGamepad)) {
+        // newest input, written with every tick of `Pacing::Polling`
+        let mut latest: Option<UniversalGamepad> = None;
+        let mut next_tick: Instant = Instant::now();
+        // what the host got last and when, for `Pacing::Changes`
+        let mut written: Option<(UniversalGamepad, Instant)> = None;
+
+        loop {
+            let deadline: Option<Instant> = match self.pacing {
+                Pacing::PassThrough => None,
+                Pacing::Polling { .. } => Some(next_tick),
+                Pacing::Changes { keepalive } => written.as_ref().map(|(_, at)| *at + keepalive),
+            };
+            // a steady stream of input must not delay the ticks
+            let event: WatchdogEvent = match deadline {
+                Some(deadline) if Instant::now() >= deadline => WatchdogEvent::Idle,
+                _ => self.watchdog.recv_until(&mut subscriber, deadline),
+            };
+
+            match event {
+                WatchdogEvent::Input(mut gamepad) => match self.pacing {
+                    Pacing::PassThrough => {
+                        pipeline.process(&mut gamepad);
+                        write(&gamepad);
+                    }
+                    Pacing::Polling { .. } => latest = Some(gamepad),
+                    Pacing::Changes { .. } => {
+                        pipeline.process(&mut gamepad);
+                        // mouse movement is relative, the same movement twice is still a change
+                        if gamepad.other.mouse.is_some() || written.as_ref().is_some_and(|(written, _)| *written == gamepad) == false {
+                            write(&gamepad);
+                            written = Some((gamepad, Instant::now()));
+                        }
+                    }
+                },
+                WatchdogEvent::Idle => match self.pacing {
+                    Pacing::PassThrough => {}
+                    Pacing::Polling { interval } => {
+                        if let Some(latest) = &latest {
+                            let mut gamepad: UniversalGamepad = latest.clone();
+                            pipeline.process(&mut gamepad);
+                            write(&gamepad);
+                        }
+                        // ticks that were missed are skipped, not written in a burst
+                        next_tick = (next_tick + interval).max(Instant::now());
+                    }
+                    Pacing::Changes { .. } => {
+                        if let Some((gamepad, at)) = &mut written {
+                            // repeating it would move the mouse again
+                            gamepad.other.mouse = None;
+                            write(gamepad);
+                            *at = Instant::now();
+                        }
+                    }
+                },
+                // not run through the pipeline, no stage may press anything now
+                WatchdogEvent::Lost => {
+                    let neutral: UniversalGamepad = UniversalGamepad::nothing_pressed();
+                    write(&neutral);
+                    latest = None;
+                    written = Some((neutral, Instant::now()));
+                }
+                WatchdogEvent::Closed => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::latest_state::latest_state;
+    use crate::watchdog::WatchdogSettings;
+    use std::thread;
+
+    fn _run(pacing: Pacing, inputs: Vec<(u64, UniversalGamepad)>) -> Vec<UniversalGamepad> {
+        let (state, subscriber) = latest_state(UniversalGamepad::nothing_pressed(), 1);
+        let mut publisher = state.publisher().unwrap();
+        let feeder = thread::spawn(move || {
+            for (delay, gamepad) in inputs {
+                thread::sleep(Duration::from_millis(delay));
+                publisher.publish(gamepad);
+            }
+            // give the pacer time to take the last input
+            thread::sleep(Duration::from_millis(1));
+            state.close();
+        });
+
+        let mut written: Vec<UniversalGamepad> = Vec::new();
+        let watchdog = InputWatchdog::new(&WatchdogSettings { timeout: None });
+        Pacer::new(pacing, watchdog).run(subscriber, Pipeline::new(), |gamepad| written.push(gamepad.clone()));
+        feeder.join().unwrap();
+        return written;
+    }
+
+    #[test]
+    fn each_strategy_writes_what_it_promises() {
+        let rest = UniversalGamepad::nothing_pressed();
+        let mut pressed = UniversalGamepad::nothing_pressed();
+        pressed.buttons.main.lower = true;
+        let inputs = vec![(0, rest.clone()), (5, rest.clone()), (5, pressed.clone()), (60, pressed.clone())];
+
+        assert_eq!(_run(Pacing::PassThrough, inputs.clone()).len(), 4);
+
+        // the same state twice is written once, and repeated after the keepalive
+        let keepalive = Duration::from_millis(40);
+        assert_eq!(
+            _run(Pacing::Changes { keepalive }, inputs.clone()),
+            vec![rest.clone(), pressed.clone(), pressed.clone()]
+        );
+
+        // about one write every 10 ms over 70 ms, whatever the input does
+        let interval = Duration::from_millis(10);
+        let written = _run(Pacing::Polling { interval }, inputs);
+        assert!((4..=8).contains(&written.len()), "{} writes", written.len());
+        assert_eq!(written.last(), Some(&pressed));
+    }
+
+    #[test]
+    fn pacing_is_read_from_the_output_section() {
+        assert_eq!(Pacing::from_config(&Config::empty()).unwrap(), Pacing::PassThrough);
+
+        let config = Config::parse("[output]\npacing = polling\n").unwrap();
+        assert_eq!(
+            Pacing::from_config(&config).unwrap(),
+            Pacing::Polling {
+                interval: HID_POLLING_INTERVAL
+            }
+        );
+
+        let config = Config::parse("[output]\npacing = changes\nkeepalive = 250\n").unwrap();
+        assert_eq!(
+            Pacing::from_config(&config).unwrap(),
+            Pacing::Changes {
+                keepalive: Duration::from_millis(250)
+            }
+        );
+
+        assert!(Pacing::from_config(&Config::parse("[output]\npacing = sometimes\n").unwrap()).is_err());
+    }
+}
@@ -0,0 +1,380 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use hidapi::{BusType, HidApi};
+
+use crate::bluez::{BluezClient, BtDevice, BtEvent};
+use crate::config::{Config, ConfigError, ConfigSection};
+use crate::dbus::DbusError;
+use crate::driver_registry::DriverRegistry;
+use crate::hidapi_fn::HidGamepad;
+use crate::input_mapping::{ButtonCombo, HeldCombo};
+use crate::universal_gamepad::UniversalGamepad;
+use crate::usb_gamepad::Feedback;
+
+/// How often hidapi is asked for new devices while waiting for the hidraw node
+const HIDRAW_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Pause between two attempts of the same step
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// The gamepad that asked for pairing rumbles this long, and shows this lightbar color until pairing ends
+const RUMBLE_CONFIRMATION_DURATION: Duration = Duration::from_millis(300);
+const LIGHTBAR_PAIRING: (u8, u8, u8) = (255, 255, 255);
+const LIGHTBAR_DEFAULT: (u8, u8, u8) = (0, 0, 255);
+
+#[derive(Debug)]
+pub enum PairingError {
+    Bluetooth(DbusError),
+
+    /// No gamepad in pairing mode was found within `discovery_timeout`
+    NoGamepadFound,
+
+    /// The gamepad is connected, but no hidraw node with its address appeared within `hidraw_timeout`
+    NoHidrawNode(String),
+
+    /// The gamepad has no input driver
+    NotSupported(String),
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct PairingSettings {
+    /// If `false`, gamepads have to be paired and connected before the start
+    pub enabled: bool,
+
+    /// How long to wait for a gamepad in pairing mode (or a known one that connects on its own)
+    pub discovery_timeout: Duration,
+
+    /// How long to wait for the hidraw node after connecting
+    pub hidraw_timeout: Duration,
+
+    /// How often pairing and connecting are tried
+    pub attempts: u32,
+
+    /// Held on a connected gamepad for `combo_time`, looks for another gamepad for `discovery_timeout`
+    pub combo: Option<ButtonCombo>,
+    pub combo_time: Duration,
+}
+
+impl PairingSettings {
+    pub fn default() -> Self {
+        Self {
+            enabled: true,
+            discovery_timeout: Duration::from_secs(60),
+            hidraw_timeout: Duration::from_secs(10),
+            attempts: 3,
+            combo: None,
+            combo_time: Duration::from_secs(3),
+        }
+    }
+
+    /// Reads the `[pairing]` section:
+    /// - `enabled = false`
+    /// - `discovery_timeout = <seconds>`, `hidraw_timeout = <seconds>`
+    /// - `attempts = <count>`
+    /// - `combo = <button>, ...` with `combo_time = <ms>` (default 3000)
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        let mut settings = Self::default();
+        let section: &ConfigSection = match config.section("pairing", "") {
+            Some(section) => section,
+            None => return Ok(settings),
+        };
+
+        if let Some(enabled) = section.get_parsed::<bool>("enabled")? {
+            settings.enabled = enabled;
+        }
+        if let Some(seconds) = section.get_parsed::<u64>("discovery_timeout")? {
+            settings.discovery_timeout = Duration::from_secs(seconds);
+        }
+        if let Some(seconds) = section.get_parsed::<u64>("hidraw_timeout")? {
+            settings.hidraw_timeout = Duration::from_secs(seconds);
+        }
+        if let Some(attempts) = section.get_parsed::<u32>("attempts")? {
+            if attempts == 0 {
+                return Err(section.error("attempts has to be at least 1".to_string()));
+            }
+            settings.attempts = attempts;
+        }
+        settings.combo = ButtonCombo::from_section(section, "combo")?;
+        if let Some(combo_time) = section.get_parsed::<u64>("combo_time")? {
+            settings.combo_time = Duration::from_millis(combo_time);
+        }
+
+        return Ok(settings);
+    }
+}
+
+/// Waits for a gamepad that is put into pairing mode, pairs, trusts and connects it
+///
+/// Known gamepads that connect on their own (PS button) are accepted as well.
+/// `ignored` are the addresses of gamepads that are already in use. Returns the address of the connected gamepad
+pub fn connect_new_gamepad(bluez: &mut BluezClient, settings: &PairingSettings, ignored: &[String]) -> Result<String, PairingError> {
+    bluez.power_on().map_err(PairingError::Bluetooth)?;
+    bluez.set_pairable(true).map_err(PairingError::Bluetooth)?;
+    bluez.start_discovery().map_err(PairingError::Bluetooth)?;
+
+    let found: Result<BtDevice, PairingError> = _wait_for_gamepad(bluez, settings.discovery_timeout, ignored);
+
+    // discovery slows down connections, so it is stopped before pairing
+    if let Err(err) = bluez.stop_discovery() {
+        println!("Stopping bluetooth discovery failed: {:?}", err);
+    }
+    let result: Result<String, PairingError> = found.and_then(|gamepad| pair_trust_connect(bluez, &gamepad, settings));
+    if let Err(err) = bluez.set_pairable(false) {
+        println!("Making bluetooth unpairable failed: {:?}", err);
+    }
+
+    return result;
+}
+
+/// The first gamepad that is not in `ignored` and either waits to be paired or connected on its own
+fn _wait_for_gamepad(bluez: &mut BluezClient, timeout: Duration, ignored: &[String]) -> Result<BtDevice, PairingError> {
+    let is_ignored = |address: &str| ignored.iter().any(|ignored| ignored.eq_ignore_ascii_case(address));
+
+    // events only name the address, so every gamepad has to be remembered
+    let mut gamepads: Vec<BtDevice> = bluez.devices().map_err(PairingError::Bluetooth)?;
+    gamepads.retain(|device| device.is_gamepad() && is_ignored(&device.address) == false);
+    if let Some(connected) = gamepads.iter().find(|device| device.connected) {
+        return Ok(connected.clone());
+    }
+
+    let deadline: Instant = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        let remaining: Duration = deadline.saturating_duration_since(Instant::now());
+
+        match bluez.next_event(remaining).map_err(PairingError::Bluetooth)? {
+            Some(BtEvent::NewDevice(device)) if device.is_gamepad() && is_ignored(&device.address) == false => {
+                println!("Gamepad found: {} ({})", device.name.as_deref().unwrap_or("no name"), device.address);
+                return Ok(device);
+            }
+            Some(BtEvent::Changed {
+                address,
+                connected: Some(true),
+                ..
+            }) => {
+                if let Some(gamepad) = gamepads.iter_mut().find(|gamepad| gamepad.address == address) {
+                    println!("Known gamepad connected: {}", address);
+                    gamepad.connected = true;
+                    return Ok(gamepad.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    return Err(PairingError::NoGamepadFound);
+}
+
+/// Watches one connected gamepad for the pairing combo, owned by its input thread
+///
+/// A pairing thread of the main thread does the pairing: `requested` is set by the hotkey and cleared once pairing is over
+pub struct PairingHotkey {
+    combo: HeldCombo,
+    requested: Arc<AtomicBool>,
+
+    /// `true` while this gamepad shows that pairing is running
+    is_shown: bool,
+    rumble_until: Option<Instant>,
+}
+
+impl PairingHotkey {
+    pub fn new(combo: ButtonCombo, combo_time: Duration, requested: Arc<AtomicBool>) -> Self {
+        Self {
+            combo: HeldCombo::new(combo, combo_time),
+            requested,
+            is_shown: false,
+            rumble_until: None,
+        }
+    }
+
+    /// Call with every report of the physical gamepad
+    ///
+    /// Returns the rumble (right, left) and lightbar color the gamepad should get, if they have to change
+    pub fn update(&mut self, gamepad: &UniversalGamepad, now: Instant) -> Option<Feedback> {
+        if self.combo.update(gamepad, now) && self.requested.swap(true, Ordering::SeqCst) == false {
+            println!("Pairing requested with the pairing combo");
+        }
+
+        let requested: bool = self.requested.load(Ordering::SeqCst);
+        if requested && self.is_shown == false && self.combo.combo.is_pressed(gamepad) {
+            // only the gamepad that asked confirms it
+            self.is_shown = true;
+            self.rumble_until = Some(now + RUMBLE_CONFIRMATION_DURATION);
+            return Some(((255, 255), LIGHTBAR_PAIRING));
+        }
+        if requested == false && self.is_shown {
+            self.is_shown = false;
+            self.rumble_until = None;
+            return Some(((0, 0), LIGHTBAR_DEFAULT));
+        }
+        if self.rumble_until.is_some_and(|until| now >= until) {
+            self.rumble_until = None;
+            return Some(((0, 0), LIGHTBAR_PAIRING));
+        }
+
+        return None;
+    }
+}
+
+/// Pairs, trusts and connects a discovered gamepad, steps that are already done are skipped
+pub fn pair_trust_connect(bluez: &mut BluezClient, gamepad: &BtDevice, settings: &PairingSettings) -> Result<String, PairingError> {
+    let address: &str = &gamepad.address;
+
+    if gamepad.paired == false {
+        _retry(settings.attempts, "Pairing", || match bluez.pair(address) {
+            // paired in the meantime, e.g. by the gamepad itself
+            Err(DbusError::Remote(name, _)) if name == "org.bluez.Error.AlreadyExists" => Ok(()),
+            other => other,
+        })?;
+        println!("Gamepad paired: {}", address);
+    }
+    if gamepad.trusted == false {
+        bluez.trust(address).map_err(PairingError::Bluetooth)?;
+    }
+    if gamepad.connected == false {
+        _retry(settings.attempts, "Connecting", || bluez.connect(address))?;
+    }
+    println!("Gamepad connected: {}", address);
+
+    return Ok(address.to_string());
+}
+
+fn _retry(attempts: u32, step: &str, mut action: impl FnMut() -> Result<(), DbusError>) -> Result<(), PairingError> {
+    let mut attempt: u32 = 1;
+    loop {
+        match action() {
+            Ok(_) => return Ok(()),
+            Err(err) if attempt < attempts => {
+                println!("{step} failed (attempt {attempt} of {attempts}): {:?}", err);
+                attempt += 1;
+                thread::sleep(RETRY_DELAY);
+            }
+            Err(err) => return Err(PairingError::Bluetooth(err)),
+        }
+    }
+}
+
+/// Waits for the hidraw node of the gamepad with this bluetooth address, hidapi reports the address as serial number
+pub fn wait_for_hid_gamepad(api: &mut HidApi, registry: &DriverRegistry, address: &str, timeout: Duration) -> Result<HidGamepad, PairingError> {
+    let deadline: Instant = Instant::now() + timeout;
+
+    loop {
+        if let Err(err) = api.refresh_devices() {
+            println!("Refreshing hid devices failed: {:?}", err);
+        }
+
+        let device_info = api.device_list().find(|device_info| {
+            matches!(device_info.bus_type(), BusType::Bluetooth) && device_info.serial_number().is_some_and(|serial| serial.eq_ignore_ascii_case(address))
+        });
+        if let Some(device_info) = device_info {
+            let entry = match registry.input_driver_for(device_info.vendor_id(), device_info.product_id()) {
+                Some(entry) => entry,
+                None => return Err(PairingError::NotSupported(address.to_string())),
+            };
+            match api.open_path(device_info.path()) {
+                Ok(device) => return Ok((device, (entry.create)())),
+                // the node exists before udev gave it the right permissions
+                Err(err) => println!("Opening hidraw node of {} failed, trying again: {:?}", address, err),
+            }
+        }
+
+        if Instant::now() >= deadline {
+            return Err(PairingError::NoHidrawNode(address.to_string()));
+        }
+        thread::sleep(HIDRAW_POLL_INTERVAL);
+    }
+}
+
+/// Connects gamepads until `count` are connected, each with its hidraw node opened
+///
+/// Every attempt that fails is logged, `attempts` failed gamepads in a row end the search
+pub fn connect_gamepads(api: &mut HidApi, registry: &DriverRegistry, settings: &PairingSettings, count: usize, in_use: &[String]) -> Vec<HidGamepad> {
+    let mut gamepads: Vec<HidGamepad> = Vec::new();
+
+    let mut bluez: BluezClient = match BluezClient::system() {
+        Ok(bluez) => bluez,
+        Err(err) => {
+            println!(
+                "BlueZ is not reachable over D-Bus, gamepads have to be paired and connected manually: {:?}",
+                err
+            );
+            return gamepads;
+        }
+    };
+
+    let mut ignored: Vec<String> = in_use.to_vec();
+    let mut failures: u32 = 0;
+    while gamepads.len() < count && failures < settings.attempts {
+        println!("Waiting for a gamepad: hold Create/Share and PS until the light bar flashes, or press PS on a paired gamepad");
+
+        let connected = connect_new_gamepad(&mut bluez, settings, &ignored)
+            .and_then(|address| wait_for_hid_gamepad(api, registry, &address, settings.hidraw_timeout).map(|gamepad| (address, gamepad)));
+        match connected {
+            Ok((address, gamepad)) => {
+                ignored.push(address);
+                gamepads.push(gamepad);
+                failures = 0;
+            }
+            Err(PairingError::NoGamepadFound) => {
+                println!("No gamepad was found within {:?}", settings.discovery_timeout);
+                break;
+            }
+            Err(err) => {
+                println!("Connecting the gamepad failed: {:?}", err);
+                failures += 1;
+            }
+        }
+    }
+
+    return gamepads;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings_are_read_from_the_pairing_section() {
+        assert_eq!(PairingSettings::from_config(&Config::empty()).unwrap(), PairingSettings::default());
+
+        let config = Config::parse("[pairing]\nenabled = false\ndiscovery_timeout = 30\nattempts = 5\n").unwrap();
+        let settings = PairingSettings::from_config(&config).unwrap();
+        assert!(settings.enabled == false);
+        assert_eq!(settings.discovery_timeout, Duration::from_secs(30));
+        assert_eq!(settings.hidraw_timeout, Duration::from_secs(10));
+        assert_eq!(settings.attempts, 5);
+
+        assert!(PairingSettings::from_config(&Config::parse("[pairing]\nattempts = 0\n").unwrap()).is_err());
+    }
+
+    #[test]
+    fn the_held_combo_requests_pairing_and_confirms_it() {
+        let requested = Arc::new(AtomicBool::new(false));
+        let mut hotkey = PairingHotkey::new(
+            ButtonCombo::parse("specials.logo, specials.left").unwrap(),
+            Duration::from_secs(3),
+            requested.clone(),
+        );
+        let start = Instant::now();
+        let at = |millis: u64| start + Duration::from_millis(millis);
+
+        let mut held = UniversalGamepad::nothing_pressed();
+        held.buttons.specials.logo = true;
+        held.buttons.specials.left = true;
+        assert_eq!(hotkey.update(&held, at(0)), None);
+        assert_eq!(hotkey.update(&held, at(2000)), None);
+        assert_eq!(hotkey.update(&held, at(3000)), Some(((255, 255), LIGHTBAR_PAIRING)));
+        assert!(requested.load(Ordering::SeqCst));
+
+        let released = UniversalGamepad::nothing_pressed();
+        assert_eq!(hotkey.update(&released, at(3100)), None);
+        assert_eq!(hotkey.update(&released, at(3400)), Some(((0, 0), LIGHTBAR_PAIRING)));
+
+        // the main thread is done
+        requested.store(false, Ordering::SeqCst);
+        assert_eq!(hotkey.update(&released, at(20000)), Some(((0, 0), LIGHTBAR_DEFAULT)));
+        assert_eq!(hotkey.update(&released, at(20010)), None);
+    }
+}
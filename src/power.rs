@@ -0,0 +1,182 @@
+use std::process::Command;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::bluez::BluezClient;
+use crate::config::{Config, ConfigError, ConfigSection};
+use crate::input_mapping::{ButtonCombo, HeldCombo, Input};
+use crate::universal_gamepad::UniversalGamepad;
+
+/// Sticks and triggers closer to their rest value than this count as untouched, so a slightly drifting stick does not keep the gamepad on
+const ACTIVITY_THRESHOLD: u8 = 24;
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct PowerSettings {
+    /// Gamepads are turned off after this time without input, `None` keeps them on
+    pub idle_timeout: Option<Duration>,
+
+    /// Turn all gamepads off when the bridge ends
+    pub off_on_exit: bool,
+
+    /// Held for `off_combo_time`, turns all gamepads off
+    pub off_combo: Option<ButtonCombo>,
+    pub off_combo_time: Duration,
+}
+
+impl PowerSettings {
+    pub fn default() -> Self {
+        Self {
+            idle_timeout: None,
+            off_on_exit: true,
+            off_combo: None,
+            off_combo_time: Duration::from_secs(2),
+        }
+    }
+
+    /// Reads the `[power]` section:
+    /// - `idle_timeout = <seconds>`
+    /// - `off_on_exit = false`
+    /// - `off_combo = <button>, ...` with `off_combo_time = <ms>` (default 2000)
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        let mut settings = Self::default();
+        let section: &ConfigSection = match config.section("power", "") {
+            Some(section) => section,
+            None => return Ok(settings),
+        };
+
+        settings.idle_timeout = section.get_parsed::<u64>("idle_timeout")?.map(Duration::from_secs);
+        if let Some(off_on_exit) = section.get_parsed::<bool>("off_on_exit")? {
+            settings.off_on_exit = off_on_exit;
+        }
+        settings.off_combo = ButtonCombo::from_section(section, "off_combo")?;
+        if let Some(off_combo_time) = section.get_parsed::<u64>("off_combo_time")? {
+            settings.off_combo_time = Duration::from_millis(off_combo_time);
+        }
+
+        return Ok(settings);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PowerOffReason {
+    Idle,
+
+    /// `off_combo` was held on this or another gamepad
+    Hotkey,
+}
+
+/// Decides when one gamepad is turned off, owned by its input thread
+pub struct PowerMonitor {
+    /// bluetooth address of the gamepad
+    pub address: String,
+    idle_timeout: Option<Duration>,
+    last_activity: Instant,
+    off_combo: Option<HeldCombo>,
+
+    /// shared by all gamepads, counts how often `off_combo` was used
+    hotkey_uses: Arc<AtomicU32>,
+    seen_hotkey_uses: u32,
+}
+
+impl PowerMonitor {
+    pub fn new(address: &str, settings: PowerSettings, hotkey_uses: Arc<AtomicU32>, now: Instant) -> Self {
+        let seen_hotkey_uses: u32 = hotkey_uses.load(Ordering::SeqCst);
+
+        Self {
+            address: address.to_string(),
+            idle_timeout: settings.idle_timeout,
+            last_activity: now,
+            off_combo: settings.off_combo.map(|combo| HeldCombo::new(combo, settings.off_combo_time)),
+            hotkey_uses,
+            seen_hotkey_uses,
+        }
+    }
+
+    /// Call with every report of the physical gamepad, returns why it should be turned off now
+    pub fn update(&mut self, gamepad: &UniversalGamepad, now: Instant) -> Option<PowerOffReason> {
+        if is_active(gamepad) {
+            self.last_activity = now;
+        }
+
+        if self.off_combo.as_mut().is_some_and(|off_combo| off_combo.update(gamepad, now)) {
+            // the other gamepads notice this with their next report
+            self.hotkey_uses.fetch_add(1, Ordering::SeqCst);
+        }
+        if self.hotkey_uses.load(Ordering::SeqCst) != self.seen_hotkey_uses {
+            return Some(PowerOffReason::Hotkey);
+        }
+
+        if self.idle_timeout.is_some_and(|idle_timeout| now - self.last_activity >= idle_timeout) {
+            return Some(PowerOffReason::Idle);
+        }
+
+        return None;
+    }
+}
+
+/// Is anybody touching the gamepad? Motion sensors are ignored, the gamepad might lie on a wobbly table
+pub fn is_active(gamepad: &UniversalGamepad) -> bool {
+    let is_touched: bool = gamepad
+        .other
+        .touchpad
+        .as_ref()
+        .is_some_and(|touchpad| touchpad.contacts.iter().any(|contact| contact.touched));
+
+    return is_touched || Input::all().iter().any(|input| input.is_active(gamepad, ACTIVITY_THRESHOLD));
+}
+
+/// Disconnecting a DualSense or DualShock 4 turns it off
+///
+/// BlueZ is asked over D-Bus, bluetoothctl is the fallback. Returns `false` if both failed
+pub fn power_off(address: &str) -> bool {
+    match BluezClient::system().and_then(|mut bluez| bluez.disconnect(address)) {
+        Ok(_) => return true,
+        Err(err) => println!("Disconnecting {address} over D-Bus failed, trying bluetoothctl: {:?}", err),
+    }
+
+    match Command::new("bluetoothctl").args(["disconnect", address]).output() {
+        Ok(output) => return output.status.success(),
+        Err(err) => {
+            println!("bluetoothctl could not be run: {err}");
+            return false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_gamepads_and_the_hotkey_turn_gamepads_off() {
+        let settings = PowerSettings {
+            idle_timeout: Some(Duration::from_secs(60)),
+            off_combo: ButtonCombo::parse("specials.logo, main.upper"),
+            ..PowerSettings::default()
+        };
+        let hotkey_uses = Arc::new(AtomicU32::new(0));
+        let start = Instant::now();
+        let mut first = PowerMonitor::new("a0:ab:51:12:34:56", settings.clone(), hotkey_uses.clone(), start);
+        let mut second = PowerMonitor::new("a0:ab:51:65:43:21", settings, hotkey_uses, start);
+        let at = |seconds: u64| start + Duration::from_secs(seconds);
+
+        // a drifting stick is no activity, a pressed button is
+        let mut drifting = UniversalGamepad::nothing_pressed();
+        drifting.sticks.left.x = 140;
+        let mut pressed = UniversalGamepad::nothing_pressed();
+        pressed.buttons.main.lower = true;
+        assert_eq!(first.update(&drifting, at(30)), None);
+        assert_eq!(first.update(&pressed, at(50)), None);
+        assert_eq!(first.update(&drifting, at(100)), None);
+        assert_eq!(first.update(&drifting, at(110)), Some(PowerOffReason::Idle));
+
+        // the hotkey held long enough on the second gamepad turns off the first as well
+        let mut hotkey = UniversalGamepad::nothing_pressed();
+        hotkey.buttons.specials.logo = true;
+        hotkey.buttons.main.upper = true;
+        assert_eq!(second.update(&hotkey, at(40)), None);
+        assert_eq!(second.update(&hotkey, at(42)), Some(PowerOffReason::Hotkey));
+        assert_eq!(first.update(&pressed, at(43)), Some(PowerOffReason::Hotkey));
+    }
+}
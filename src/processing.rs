@@ -0,0 +1,212 @@
+use std::path::Path;
+use std::time::Instant;
+
+use crate::accessibility::{self, Accessibility};
+use crate::button_modifiers::ButtonModifiers;
+use crate::calibration::CalibrationStage;
+use crate::config::{Config, ConfigError};
+use crate::drift::DriftCompensation;
+use crate::gyro_aiming::GyroAiming;
+use crate::input_mapping::{self, Profile};
+use crate::known_controllers::KnownController;
+use crate::macros::MacroStage;
+use crate::mapping_layers::{Layer, LayeredProfile};
+use crate::socd::SocdCleaning;
+use crate::stick_processing::StickProcessing;
+use crate::touchpad_mapping::TouchpadMapping;
+use crate::universal_gamepad::UniversalGamepad;
+
+/// One step between reading the input gamepad and writing the output gamepad
+///
+/// Stages are owned by the output thread and can keep state between calls
+pub trait ProcessingStage: Send {
+    /// Used for verbose output
+    fn display_name(&self) -> &'static str;
+
+    /// `now` is the same for all stages in one run of the pipeline
+    fn process(&mut self, gamepad: &mut UniversalGamepad, now: Instant);
+}
+
+/// Runs all stages in the order they were added
+pub struct Pipeline {
+    stages: Vec<Box<dyn ProcessingStage>>,
+
+    /// name of the selected profile, remembered for the controller
+    pub profile_name: Option<String>,
+}
+
+impl Pipeline {
+    /// A pipeline without stages passes every gamepad on unchanged
+    pub fn new() -> Self {
+        Self {
+            stages: Vec::new(),
+            profile_name: None,
+        }
+    }
+
+    /// Creates all stages that are configured for this combination of input and output gamepad
+    ///
+    /// - `controller_serial`: serial number (MAC address for bluetooth) of the input gamepad
+    /// - `persona_args`: the command line arguments that select the output gamepad
+    /// - `known_controller`: what is remembered about the input gamepad, e.g. the profile it used last time
+    pub fn from_config(
+        config: &Config,
+        controller_serial: Option<&str>,
+        persona_args: &[&str],
+        known_controller: Option<&KnownController>,
+    ) -> Result<Self, ConfigError> {
+        let mut pipeline = Self::new();
+        let known_profile: Option<&str> = known_controller.and_then(|controller| controller.profile.as_deref());
+        let known_calibration: Option<&Path> = known_controller.and_then(|controller| controller.calibration.as_deref());
+
+        // Everything else expects centered sticks that use the full range
+        if let Some(calibration) = CalibrationStage::from_config(config, controller_serial, known_calibration)? {
+            pipeline.add_stage(Box::new(calibration));
+        }
+
+        // Drift moves the resting position, the deadzones have to be around the compensated center
+        if let Some(drift_compensation) = DriftCompensation::from_config(config)? {
+            pipeline.add_stage(Box::new(drift_compensation));
+        }
+
+        // Deadzones belong to the physical sticks, so they run before anything is remapped
+        if let Some(stick_processing) = StickProcessing::from_config(config)? {
+            pipeline.add_stage(Box::new(stick_processing));
+        }
+
+        // Cleans the physical D-pad, so sticks that are remapped from it are clean as well
+        if let Some(socd_cleaning) = SocdCleaning::from_config(config)? {
+            pipeline.add_stage(Box::new(socd_cleaning));
+        }
+
+        // Gyro aiming adds to the physical stick and reads the physical ratchet button, so it also runs before remapping
+        if let Some(gyro_aiming) = GyroAiming::from_config(config)? {
+            pipeline.add_stage(Box::new(gyro_aiming));
+        }
+
+        // Buttons created by the touchpad can be remapped like physical buttons
+        if let Some(touchpad_mapping) = TouchpadMapping::from_config(config)? {
+            pipeline.add_stage(Box::new(touchpad_mapping));
+        }
+
+        if let Some(profile) = input_mapping::select_profile(config, controller_serial, persona_args, known_profile)? {
+            pipeline._add_profile_stages(config, profile)?;
+        }
+
+        // Modifiers work on the buttons the host sees, so they run after remapping
+        if let Some(button_modifiers) = ButtonModifiers::from_config(config)? {
+            pipeline.add_stage(Box::new(button_modifiers));
+        }
+
+        // Macros record and replay exactly what the host sees, so they run after everything that changes it
+        if let Some(macro_stage) = MacroStage::from_config(config)? {
+            pipeline.add_stage(Box::new(macro_stage));
+        }
+
+        // Remapping, modifiers and macros can press opposite directions again, the host only gets a clean D-pad
+        if let Some(socd_cleaning) = SocdCleaning::from_config(config)? {
+            pipeline.add_stage(Box::new(socd_cleaning.on_output()));
+        }
+
+        return Ok(pipeline);
+    }
+
+    /// Reads every stage and every profile of the config once, without selecting anything for a gamepad
+    ///
+    /// Used to find config errors before anything is set up. Calibration files are not read, they belong to the gamepad
+    pub fn check_config(config: &Config, persona_args: &[&str]) -> Result<(), ConfigError> {
+        Self::from_config(config, None, persona_args, None)?;
+
+        for section in config.sections("profile") {
+            Self::new()._add_profile_stages(config, Profile::from_section(section, &input_mapping::ACCESSIBILITY_KEYS)?)?;
+        }
+        // only checks that the profile of each controller exists
+        for section in config.sections("controller") {
+            input_mapping::select_profile(config, Some(&section.name), persona_args, None)?;
+        }
+
+        return Ok(());
+    }
+
+    fn _add_profile_stages(&mut self, config: &Config, profile: Profile) -> Result<(), ConfigError> {
+        self.profile_name = Some(profile.name.clone());
+
+        // Accessibility works on the physical buttons, so the shift of a one-handed layout can be sticky
+        if let Some(accessibility) = Accessibility::from_config(config, &profile)? {
+            self.add_stage(Box::new(accessibility));
+        }
+
+        let one_handed: Option<Layer> = accessibility::one_handed_layer(config, &profile)?;
+        if profile.layers.is_empty() && one_handed.is_none() {
+            self.add_stage(Box::new(profile));
+        } else {
+            let mut layered_profile = LayeredProfile::from_config(config, profile)?;
+            layered_profile.layers.extend(one_handed);
+            self.add_stage(Box::new(layered_profile));
+        }
+
+        return Ok(());
+    }
+
+    pub fn add_stage(&mut self, stage: Box<dyn ProcessingStage>) {
+        self.stages.push(stage);
+    }
+
+    /// The selected profile and all stages, in the order they run
+    pub fn print_stages(&self) {
+        if let Some(profile_name) = &self.profile_name {
+            println!("Using profile {profile_name}");
+        }
+        for stage in &self.stages {
+            println!("Processing stage: {}", stage.display_name());
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.stages.is_empty();
+    }
+
+    pub fn process(&mut self, gamepad: &mut UniversalGamepad) {
+        let now: Instant = Instant::now();
+
+        for stage in self.stages.iter_mut() {
+            stage.process(gamepad, now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_config_reads_profiles_that_are_not_selected() {
+        let config = Config::parse("[profile unused]\nmain.lower = main.nowhere\n").unwrap();
+
+        assert!(Pipeline::from_config(&config, None, &[], None).is_ok());
+        assert!(Pipeline::check_config(&config, &[]).is_err());
+        assert!(Pipeline::check_config(&Config::parse("[profile unused]\nmain.lower = main.right\n").unwrap(), &[]).is_ok());
+    }
+
+    #[test]
+    fn stick_remapped_to_the_dpad_is_cleaned() {
+        let config = Config::parse(
+            "[socd]\nmode = last_input_wins\n\n[persona ps5]\nprofile = stick_dpad\n\n\
+             [profile stick_dpad]\ndpad.left = dpad.left, -sticks.left.x\ndpad.right = dpad.right, sticks.left.x\n",
+        )
+        .unwrap();
+        let mut pipeline = Pipeline::from_config(&config, None, &["ps5"], None).unwrap();
+
+        let mut gamepad = UniversalGamepad::nothing_pressed();
+        gamepad.buttons.dpad.right = true;
+        pipeline.process(&mut gamepad);
+        assert!(gamepad.buttons.dpad.right);
+
+        // the stick is pushed left while the physical right is still held
+        let mut gamepad = UniversalGamepad::nothing_pressed();
+        gamepad.buttons.dpad.right = true;
+        gamepad.sticks.left.x = 0;
+        pipeline.process(&mut gamepad);
+        assert_eq!((gamepad.buttons.dpad.left, gamepad.buttons.dpad.right), (true, false));
+    }
+}
@@ -0,0 +1,276 @@
+use std::time::Instant;
+
+use crate::config::{Config, ConfigError, ConfigSection};
+use crate::processing::ProcessingStage;
+use crate::universal_gamepad::UniversalGamepad;
+
+/// What happens if two opposite directions are pressed at the same time (simultaneous opposing cardinal directions)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SocdMode {
+    /// Both directions are released
+    Neutral,
+
+    /// The direction that was pressed last wins
+    LastInputWins,
+
+    /// The direction that was pressed first wins
+    FirstInputWins,
+
+    /// Up wins against down, left and right are neutral
+    UpPriority,
+}
+
+impl SocdMode {
+    pub fn from_name(name: &str) -> Option<SocdMode> {
+        match name {
+            "neutral" => return Some(SocdMode::Neutral),
+            "last_input_wins" => return Some(SocdMode::LastInputWins),
+            "first_input_wins" => return Some(SocdMode::FirstInputWins),
+            "up_priority" => return Some(SocdMode::UpPriority),
+            _ => return None,
+        }
+    }
+}
+
+/// Which direction of one axis was pressed last
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Side {
+    /// up or left
+    First,
+
+    /// down or right
+    Second,
+}
+
+/// State of the two opposite directions of one axis
+struct SocdAxis {
+    mode: SocdMode,
+    is_vertical: bool,
+    was_pressed: (bool, bool),
+
+    /// `None` if both were pressed in the same report
+    last_pressed: Option<Side>,
+}
+
+impl SocdAxis {
+    fn new(mode: SocdMode, is_vertical: bool) -> Self {
+        Self {
+            mode,
+            is_vertical,
+            was_pressed: (false, false),
+            last_pressed: None,
+        }
+    }
+
+    /// `first` is up or left, `second` is down or right
+    fn resolve(&mut self, first: bool, second: bool) -> (bool, bool) {
+        let new_first: bool = first && self.was_pressed.0 == false;
+        let new_second: bool = second && self.was_pressed.1 == false;
+        self.was_pressed = (first, second);
+
+        match (new_first, new_second) {
+            (true, true) => self.last_pressed = None,
+            (true, false) => self.last_pressed = Some(Side::First),
+            (false, true) => self.last_pressed = Some(Side::Second),
+            (false, false) => {}
+        }
+
+        if (first && second) == false {
+            return (first, second);
+        }
+
+        let winner: Option<Side> = match self.mode {
+            SocdMode::Neutral => None,
+            SocdMode::UpPriority if self.is_vertical => Some(Side::First),
+            SocdMode::UpPriority => None,
+            SocdMode::LastInputWins => self.last_pressed,
+            SocdMode::FirstInputWins => match self.last_pressed {
+                Some(Side::First) => Some(Side::Second),
+                Some(Side::Second) => Some(Side::First),
+                None => None,
+            },
+        };
+
+        match winner {
+            Some(Side::First) => return (true, false),
+            Some(Side::Second) => return (false, true),
+            None => return (false, false),
+        }
+    }
+}
+
+/// Cleans simultaneous opposite directions of the D-pad, configured with a `[socd]` section
+///
+/// One instance runs on the physical D-pad, so a stick that is remapped from the D-pad is cleaned as well.
+/// A second one (see `on_output()`) runs on the D-pad the host sees, for directions that remapping created
+///
+/// Sticks are not cleaned, they can't point in two opposite directions at once
+pub struct SocdCleaning {
+    horizontal: SocdAxis,
+    vertical: SocdAxis,
+    is_output: bool,
+}
+
+impl SocdCleaning {
+    pub fn new(horizontal: SocdMode, vertical: SocdMode) -> Self {
+        Self {
+            horizontal: SocdAxis::new(horizontal, false),
+            vertical: SocdAxis::new(vertical, true),
+            is_output: false,
+        }
+    }
+
+    /// The same cleaning for the D-pad after remapping, only changes the display name
+    pub fn on_output(mut self) -> Self {
+        self.is_output = true;
+        return self;
+    }
+
+    /// Returns `Ok(None)` if there is no `[socd]` section
+    pub fn from_config(config: &Config) -> Result<Option<Self>, ConfigError> {
+        let section: &ConfigSection = match config.section("socd", "") {
+            Some(section) => section,
+            None => return Ok(None),
+        };
+
+        let both: SocdMode = _mode_from_section(section, "mode")?.unwrap_or(SocdMode::Neutral);
+        let horizontal: SocdMode = _mode_from_section(section, "horizontal")?.unwrap_or(both);
+        let vertical: SocdMode = _mode_from_section(section, "vertical")?.unwrap_or(both);
+
+        return Ok(Some(Self::new(horizontal, vertical)));
+    }
+}
+
+/// Reads `neutral`, `last_input_wins`, `first_input_wins` or `up_priority` from `key`
+fn _mode_from_section(section: &ConfigSection, key: &str) -> Result<Option<SocdMode>, ConfigError> {
+    match section.get(key) {
+        Some(name) => match SocdMode::from_name(name) {
+            Some(mode) => return Ok(Some(mode)),
+            None => return Err(section.error(format!("unknown mode for {key}: {name}"))),
+        },
+        None => return Ok(None),
+    }
+}
+
+impl ProcessingStage for SocdCleaning {
+    fn display_name(&self) -> &'static str {
+        match self.is_output {
+            true => return "SOCD cleaning (output)",
+            false => return "SOCD cleaning",
+        }
+    }
+
+    fn process(&mut self, gamepad: &mut UniversalGamepad, _now: Instant) {
+        let dpad = &mut gamepad.buttons.dpad;
+        (dpad.left, dpad.right) = self.horizontal.resolve(dpad.left, dpad.right);
+        (dpad.up, dpad.down) = self.vertical.resolve(dpad.up, dpad.down);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_MODES: [SocdMode; 4] = [SocdMode::Neutral, SocdMode::LastInputWins, SocdMode::FirstInputWins, SocdMode::UpPriority];
+
+    fn _dpad(cleaning: &mut SocdCleaning, up: bool, down: bool, left: bool, right: bool) -> (bool, bool, bool, bool) {
+        let mut gamepad = UniversalGamepad::nothing_pressed();
+        gamepad.buttons.dpad.up = up;
+        gamepad.buttons.dpad.down = down;
+        gamepad.buttons.dpad.left = left;
+        gamepad.buttons.dpad.right = right;
+
+        cleaning.process(&mut gamepad, Instant::now());
+
+        let dpad = &gamepad.buttons.dpad;
+        return (dpad.up, dpad.down, dpad.left, dpad.right);
+    }
+
+    /// All 16 D-pad states pressed at once from neutral, so no direction was pressed before the other
+    #[test]
+    fn simultaneous_presses_in_every_mode() {
+        for mode in ALL_MODES {
+            for state in 0..16u8 {
+                let (up, down, left, right) = (state & 1 != 0, state & 2 != 0, state & 4 != 0, state & 8 != 0);
+                let mut cleaning = SocdCleaning::new(mode, mode);
+
+                let expected_vertical: (bool, bool) = match (up && down, mode) {
+                    (false, _) => (up, down),
+                    (true, SocdMode::UpPriority) => (true, false),
+                    (true, _) => (false, false),
+                };
+                let expected_horizontal: (bool, bool) = match left && right {
+                    false => (left, right),
+                    true => (false, false),
+                };
+
+                let result = _dpad(&mut cleaning, up, down, left, right);
+                assert_eq!(
+                    result,
+                    (expected_vertical.0, expected_vertical.1, expected_horizontal.0, expected_horizontal.1),
+                    "mode {mode:?}, state {state:04b}"
+                );
+            }
+        }
+    }
+
+    /// One direction is held, then the opposite one is pressed, then the first one is released
+    #[test]
+    fn sequential_presses_in_every_mode() {
+        for mode in ALL_MODES {
+            for is_vertical in [false, true] {
+                for first_pressed_is_second_side in [false, true] {
+                    let mut cleaning = SocdCleaning::new(mode, mode);
+                    let press = |cleaning: &mut SocdCleaning, first_side: bool, second_side: bool| -> (bool, bool) {
+                        if is_vertical {
+                            let (up, down, _, _) = _dpad(cleaning, first_side, second_side, false, false);
+                            return (up, down);
+                        }
+                        let (_, _, left, right) = _dpad(cleaning, false, false, first_side, second_side);
+                        return (left, right);
+                    };
+
+                    // (held before, pressed later)
+                    let (held, later): ((bool, bool), (bool, bool)) = match first_pressed_is_second_side {
+                        false => ((true, false), (false, true)),
+                        true => ((false, true), (true, false)),
+                    };
+
+                    assert_eq!(press(&mut cleaning, held.0, held.1), held);
+
+                    let expected_both: (bool, bool) = match mode {
+                        SocdMode::Neutral => (false, false),
+                        SocdMode::LastInputWins => later,
+                        SocdMode::FirstInputWins => held,
+                        SocdMode::UpPriority if is_vertical => (true, false),
+                        SocdMode::UpPriority => (false, false),
+                    };
+                    assert_eq!(
+                        press(&mut cleaning, true, true),
+                        expected_both,
+                        "mode {mode:?}, vertical {is_vertical}, held {held:?}"
+                    );
+
+                    // releasing the held direction lets the later one through in every mode
+                    assert_eq!(
+                        press(&mut cleaning, later.0, later.1),
+                        later,
+                        "mode {mode:?}, vertical {is_vertical}, held {held:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn last_input_wins_follows_repeated_presses() {
+        let mut cleaning = SocdCleaning::new(SocdMode::LastInputWins, SocdMode::LastInputWins);
+
+        assert_eq!(_dpad(&mut cleaning, false, false, true, false), (false, false, true, false));
+        assert_eq!(_dpad(&mut cleaning, false, false, true, true), (false, false, false, true));
+
+        // left is released and pressed again while right is held
+        assert_eq!(_dpad(&mut cleaning, false, false, false, true), (false, false, false, true));
+        assert_eq!(_dpad(&mut cleaning, false, false, true, true), (false, false, true, false));
+    }
+}
@@ -0,0 +1,194 @@
+use std::sync::Arc;
+
+use crate::config::{Config, ConfigError, ConfigSection};
+use crate::input_mapping::{Input, Profile};
+use crate::latest_state::Subscriber;
+use crate::pacing::Pacer;
+use crate::processing::Pipeline;
+use crate::universal_gamepad::{Axis, Button, UniversalGamepad};
+use crate::usb_gamepad::{self, HidgFile, HostFeedback, OutputPersona};
+
+/// Inputs of the left half of the gamepad, used by player 1
+const LEFT_HALF: [Input; 10] = [
+    Input::Axis(Axis::StickLeftX),
+    Input::Axis(Axis::StickLeftY),
+    Input::Button(Button::StickLeftPressed),
+    Input::Button(Button::DPadUp),
+    Input::Button(Button::DPadDown),
+    Input::Button(Button::DPadLeft),
+    Input::Button(Button::DPadRight),
+    Input::Button(Button::BumperLeft),
+    Input::Axis(Axis::TriggerLeft),
+    Input::Button(Button::SpecialLeft),
+];
+
+/// Inputs of the right half of the gamepad, used by player 2
+const RIGHT_HALF: [Input; 10] = [
+    Input::Axis(Axis::StickRightX),
+    Input::Axis(Axis::StickRightY),
+    Input::Button(Button::StickRightPressed),
+    Input::Button(Button::MainUpper),
+    Input::Button(Button::MainLower),
+    Input::Button(Button::MainLeft),
+    Input::Button(Button::MainRight),
+    Input::Button(Button::BumperRight),
+    Input::Axis(Axis::TriggerRight),
+    Input::Button(Button::SpecialRight),
+];
+
+/// Used if `[split]` has no `left_profile`: the D-pad becomes the face buttons, create becomes options
+const DEFAULT_LEFT_PROFILE: &str = "
+[profile split_left]
+main.upper = dpad.up
+main.lower = dpad.down
+main.left = dpad.left
+main.right = dpad.right
+specials.right = specials.left
+disable = dpad.up, dpad.down, dpad.left, dpad.right, specials.left
+";
+
+/// Used if `[split]` has no `right_profile`: the right stick becomes the left stick
+const DEFAULT_RIGHT_PROFILE: &str = "
+[profile split_right]
+sticks.left.x = sticks.right.x
+sticks.left.y = sticks.right.y
+sticks.left.pressed = sticks.right.pressed
+disable = sticks.right.x, sticks.right.y, sticks.right.pressed
+";
+
+/// One half of the physical gamepad, remapped into a full gamepad for one player
+struct Half {
+    inputs: &'static [Input],
+    profile: Profile,
+}
+
+impl Half {
+    fn gamepad_for_player(&self, gamepad: &UniversalGamepad) -> UniversalGamepad {
+        let mut half: UniversalGamepad = gamepad.clone();
+
+        for input in Input::all() {
+            if self.inputs.contains(&input) == false {
+                input.release(&mut half);
+            }
+        }
+        half.other.touchpad = None;
+        half.other.mouse = None;
+
+        return self.profile.apply(&half);
+    }
+}
+
+/// Splits one physical gamepad into two players, configured with a `[split]` section
+///
+/// The gadget gets two hid functions, player 1 is written to `/dev/hidg0`, player 2 to `/dev/hidg1`
+pub struct SplitPlayers {
+    halves: [Half; 2],
+}
+
+impl SplitPlayers {
+    /// Number of hid functions the gadget needs
+    pub const PLAYERS: usize = 2;
+
+    pub fn new(left_profile: Profile, right_profile: Profile) -> Self {
+        Self {
+            halves: [
+                Half {
+                    inputs: &LEFT_HALF,
+                    profile: left_profile,
+                },
+                Half {
+                    inputs: &RIGHT_HALF,
+                    profile: right_profile,
+                },
+            ],
+        }
+    }
+
+    /// Returns `Ok(None)` if there is no `[split]` section
+    ///
+    /// - `left_profile = <name>`, `right_profile = <name>` replace the built-in layouts of the halves
+    pub fn from_config(config: &Config) -> Result<Option<Self>, ConfigError> {
+        let section: &ConfigSection = match config.section("split", "") {
+            Some(section) => section,
+            None => return Ok(None),
+        };
+
+        let left_profile: Profile = _profile_from_section(config, section, "left_profile", DEFAULT_LEFT_PROFILE)?;
+        let right_profile: Profile = _profile_from_section(config, section, "right_profile", DEFAULT_RIGHT_PROFILE)?;
+
+        return Ok(Some(Self::new(left_profile, right_profile)));
+    }
+
+    /// One gamepad per player
+    pub fn split(&self, gamepad: &UniversalGamepad) -> [UniversalGamepad; 2] {
+        return [self.halves[0].gamepad_for_player(gamepad), self.halves[1].gamepad_for_player(gamepad)];
+    }
+
+    /// Like `OutputPersona::write_to_gadget_continously()`, but every gamepad is split and written to both hid functions
+    ///
+    /// `personas` has one instance per player, so each one can keep its own state.
+    /// Mouse movement is not split, it goes to the `mouse` function of the gadget unchanged
+    pub fn write_to_gadget_continously(
+        &self,
+        personas: &mut [Box<dyn OutputPersona>; 2],
+        subscriber: Subscriber<UniversalGamepad>,
+        pipeline: Pipeline,
+        pacer: Pacer,
+        host_feedback: Arc<HostFeedback>,
+        mut mouse: Option<HidgFile>,
+    ) {
+        let mut hidgs: [HidgFile; 2] = [HidgFile::open("/dev/hidg0"), HidgFile::open("/dev/hidg1")];
+
+        pacer.run(subscriber, pipeline, |gamepad| {
+            for ((persona, hidg), player) in personas.iter_mut().zip(hidgs.iter_mut()).zip(self.split(gamepad)) {
+                let usb_output: Vec<u8> = persona.universal_gamepad_to_usb_output(&player);
+                hidg.write(&usb_output);
+                persona.forward_out_reports(hidg, &host_feedback);
+            }
+            usb_gamepad::write_mouse_movement(&mut mouse, gamepad);
+        });
+    }
+}
+
+/// The profile named by `key`, or the built-in one
+fn _profile_from_section(config: &Config, section: &ConfigSection, key: &str, default: &str) -> Result<Profile, ConfigError> {
+    match section.get(key) {
+        Some(name) => return Profile::from_config(config, name),
+        None => {
+            let default_config: Config = Config::parse(default)?;
+            return Profile::from_section(&default_config.sections[0], &[]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_half_becomes_a_full_gamepad() {
+        let split = SplitPlayers::from_config(&Config::parse("[split]\n").unwrap()).unwrap().unwrap();
+
+        let mut gamepad = UniversalGamepad::nothing_pressed();
+        gamepad.sticks.left.x = 255;
+        gamepad.sticks.right.x = 128;
+        gamepad.sticks.right.y = 0;
+        gamepad.buttons.dpad.down = true;
+        gamepad.buttons.main.right = true;
+        gamepad.triggers.left = 200;
+
+        let [player1, player2] = split.split(&gamepad);
+
+        assert_eq!(player1.sticks.left.x, 255);
+        assert!(player1.buttons.main.lower, "D-pad down is the lower face button");
+        assert!(player1.buttons.dpad.down == false);
+        assert!(player1.buttons.main.right == false, "the right half belongs to player 2");
+        assert_eq!(player1.triggers.left, 200);
+
+        assert_eq!(player2.sticks.left.y, 0, "the right stick is the left stick of player 2");
+        assert_eq!(player2.sticks.right.y, 128);
+        assert_eq!(player2.sticks.left.x, 128, "the left stick of player 1 is not used");
+        assert!(player2.buttons.main.right);
+        assert_eq!(player2.triggers.left, 0);
+    }
+}
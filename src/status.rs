@@ -0,0 +1,88 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::config::Config;
+
+/// Used if `[status]` has no `path = <path>`
+pub const DEFAULT_STATUS_PATH: &str = "/run/gamepad-bridge/status";
+
+/// How often the status file is rewritten
+pub const STATUS_WRITE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Everything that is shown on the status interface, sorted by key
+///
+/// Any thread can set values, the status thread writes them to the status file
+static STATUS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// Sets the value of `key`, keys are dotted like input names, e.g. `drift.left`
+pub fn set(key: &str, value: String) {
+    match STATUS.lock() {
+        Ok(mut status) => {
+            status.insert(key.to_string(), value);
+        }
+        Err(err) => println!("Status could not be updated: {:?}", err),
+    }
+}
+
+pub fn remove(key: &str) {
+    if let Ok(mut status) = STATUS.lock() {
+        status.remove(key);
+    }
+}
+
+pub fn get(key: &str) -> Option<String> {
+    return STATUS.lock().ok().and_then(|status| status.get(key).cloned());
+}
+
+/// One `key: value` line per entry
+pub fn to_text() -> String {
+    let mut text: String = String::new();
+
+    if let Ok(status) = STATUS.lock() {
+        for (key, value) in status.iter() {
+            text += &format!("{key}: {value}\n");
+        }
+    }
+
+    return text;
+}
+
+/// `path = <path>` of the `[status]` section
+pub fn path_from_config(config: &Config) -> PathBuf {
+    match config.section("status", "").and_then(|section| section.get("path")) {
+        Some(path) => return PathBuf::from(path),
+        None => return PathBuf::from(DEFAULT_STATUS_PATH),
+    }
+}
+
+/// Rewrites the status file every `STATUS_WRITE_INTERVAL`, never returns
+///
+/// The file is replaced atomically, so readers like `watch cat <path>` never see half of it
+pub fn write_continously(path: PathBuf) {
+    if let Some(directory) = path.parent() {
+        if let Err(err) = fs::create_dir_all(directory) {
+            println!("Could not create status directory {:?}: {:?}", directory, err);
+            return;
+        }
+    }
+
+    let temporary_path: PathBuf = path.with_extension("tmp");
+    loop {
+        let written = fs::write(&temporary_path, to_text()).and_then(|_| fs::rename(&temporary_path, &path));
+        if let Err(err) = written {
+            println!("Could not write status file {:?}: {:?}", path, err);
+            return;
+        }
+
+        thread::sleep(STATUS_WRITE_INTERVAL);
+    }
+}
+
+/// Removes the status file, so nobody reads an old status after the program ended
+pub fn clean_up(path: &Path) {
+    let _ = fs::remove_file(path);
+}
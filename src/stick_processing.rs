@@ -0,0 +1,283 @@
+use std::time::Instant;
+
+use crate::config::{Config, ConfigError, ConfigSection};
+use crate::processing::ProcessingStage;
+use crate::universal_gamepad::UniversalGamepad;
+
+/// How the deflection of a stick (after the deadzones) is turned into the output deflection
+#[derive(Clone, PartialEq, Debug)]
+pub enum ResponseCurve {
+    Linear,
+
+    /// `output = input ^ exponent`, values above 1 make small movements more precise
+    Exponential(f32),
+
+    /// Points `(input, output)` sorted by input, values between them are interpolated linearly
+    Points(Vec<(f32, f32)>),
+}
+
+impl ResponseCurve {
+    /// Accepted values are `linear`, `exponential <exponent>` and `points <in>:<out> <in>:<out> ...`
+    pub fn parse(text: &str) -> Option<ResponseCurve> {
+        let mut words = text.split_whitespace();
+
+        match words.next()? {
+            "linear" => return Some(ResponseCurve::Linear),
+            "exponential" => {
+                let exponent: f32 = words.next()?.parse().ok()?;
+                return Some(ResponseCurve::Exponential(exponent));
+            }
+            "points" => {
+                let mut points: Vec<(f32, f32)> = vec![(0.0, 0.0)];
+                for word in words {
+                    let (input, output) = word.split_once(':')?;
+                    points.push((input.parse().ok()?, output.parse().ok()?));
+                }
+                points.push((1.0, 1.0));
+                points.sort_by(|a, b| a.0.total_cmp(&b.0));
+                return Some(ResponseCurve::Points(points));
+            }
+            _ => return None,
+        }
+    }
+
+    /// `deflection` is 0 - 1
+    pub fn apply(&self, deflection: f32) -> f32 {
+        match self {
+            ResponseCurve::Linear => return deflection,
+            ResponseCurve::Exponential(exponent) => return deflection.powf(*exponent),
+            ResponseCurve::Points(points) => {
+                for pair in points.windows(2) {
+                    let (start, end) = (pair[0], pair[1]);
+                    if deflection <= end.0 {
+                        if end.0 - start.0 <= f32::EPSILON {
+                            return end.1;
+                        }
+                        let progress: f32 = (deflection - start.0) / (end.0 - start.0);
+                        return start.1 + progress * (end.1 - start.1);
+                    }
+                }
+                return deflection;
+            }
+        }
+    }
+}
+
+/// Settings of one stick, all deadzones are fractions of the full deflection (0 - 1)
+#[derive(Clone, PartialEq, Debug)]
+pub struct StickSettings {
+    /// Deflections below this (measured as distance from the center) are ignored
+    pub inner_deadzone: f32,
+
+    /// Deflections above this count as full deflection
+    pub outer_deadzone: f32,
+
+    /// Each axis on its own is ignored below this, makes it easier to move exactly along one axis
+    pub axial_deadzone: f32,
+
+    /// The smallest deflection that is sent, to cancel out the deadzone a game adds itself
+    pub anti_deadzone: f32,
+
+    pub curve: ResponseCurve,
+
+    /// Some sticks reach further than full deflection in the diagonals, this moves them back onto the circle
+    pub circle_correction: bool,
+}
+
+impl StickSettings {
+    /// Passes every value on unchanged
+    pub fn unchanged() -> Self {
+        Self {
+            inner_deadzone: 0.0,
+            outer_deadzone: 1.0,
+            axial_deadzone: 0.0,
+            anti_deadzone: 0.0,
+            curve: ResponseCurve::Linear,
+            circle_correction: false,
+        }
+    }
+
+    /// Reads a `[stick left]` or `[stick right]` section
+    pub fn from_section(section: &ConfigSection) -> Result<Self, ConfigError> {
+        let mut settings = Self::unchanged();
+
+        if let Some(value) = section.get_parsed::<f32>("deadzone")? {
+            settings.inner_deadzone = value;
+        }
+        if let Some(value) = section.get_parsed::<f32>("outer_deadzone")? {
+            settings.outer_deadzone = value;
+        }
+        if let Some(value) = section.get_parsed::<f32>("axial_deadzone")? {
+            settings.axial_deadzone = value;
+        }
+        if let Some(value) = section.get_parsed::<f32>("anti_deadzone")? {
+            settings.anti_deadzone = value;
+        }
+        if let Some(value) = section.get_parsed::<bool>("circle_correction")? {
+            settings.circle_correction = value;
+        }
+        if let Some(text) = section.get("curve") {
+            settings.curve = match ResponseCurve::parse(text) {
+                Some(curve) => curve,
+                None => return Err(section.error(format!("invalid curve: {text}"))),
+            };
+        }
+
+        for value in [
+            settings.inner_deadzone,
+            settings.outer_deadzone,
+            settings.axial_deadzone,
+            settings.anti_deadzone,
+        ] {
+            if (0.0..=1.0).contains(&value) == false {
+                return Err(section.error(format!("deadzones have to be between 0 and 1, not {value}")));
+            }
+        }
+        if settings.inner_deadzone >= settings.outer_deadzone {
+            return Err(section.error("deadzone has to be smaller than outer_deadzone".to_string()));
+        }
+
+        return Ok(settings);
+    }
+
+    /// Applies everything to one stick position, `x` and `y` are 0 - 255 with 128 as center
+    pub fn apply(&self, x: u8, y: u8) -> (u8, u8) {
+        let mut x: f32 = _to_float(x);
+        let mut y: f32 = _to_float(y);
+
+        x = _axial_deadzone(x, self.axial_deadzone);
+        y = _axial_deadzone(y, self.axial_deadzone);
+
+        let mut magnitude: f32 = (x * x + y * y).sqrt();
+        if magnitude <= f32::EPSILON {
+            return (_to_u8(0.0), _to_u8(0.0));
+        }
+
+        if self.circle_correction && magnitude > 1.0 {
+            x /= magnitude;
+            y /= magnitude;
+            magnitude = 1.0;
+        }
+
+        // radial deadzones, scaled so that the full range is still used
+        let mut deflection: f32 = if magnitude < self.inner_deadzone {
+            0.0
+        } else if magnitude > self.outer_deadzone {
+            1.0
+        } else {
+            (magnitude - self.inner_deadzone) / (self.outer_deadzone - self.inner_deadzone)
+        };
+
+        deflection = self.curve.apply(deflection).clamp(0.0, 1.0);
+
+        if deflection > 0.0 {
+            deflection = self.anti_deadzone + (1.0 - self.anti_deadzone) * deflection;
+        }
+
+        // keep the direction, replace the length
+        // without circle correction, diagonals beyond the circle keep their overshoot
+        let scale: f32 = deflection / magnitude.min(1.0);
+
+        return (_to_u8(x * scale), _to_u8(y * scale));
+    }
+}
+
+/// -1 is 0, 1 is 255
+fn _to_float(value: u8) -> f32 {
+    return (value as f32 - 127.5) / 127.5;
+}
+
+fn _to_u8(value: f32) -> u8 {
+    return (127.5 + value * 127.5).round().clamp(0.0, 255.0) as u8;
+}
+
+fn _axial_deadzone(value: f32, deadzone: f32) -> f32 {
+    if deadzone <= 0.0 {
+        return value;
+    }
+    if value.abs() < deadzone {
+        return 0.0;
+    }
+    return value.signum() * (value.abs() - deadzone) / (1.0 - deadzone);
+}
+
+/// Processing stage for both sticks, configured with the sections `[stick left]` and `[stick right]`
+pub struct StickProcessing {
+    pub left: StickSettings,
+    pub right: StickSettings,
+}
+
+impl StickProcessing {
+    /// Returns `Ok(None)` if no stick is configured
+    pub fn from_config(config: &Config) -> Result<Option<Self>, ConfigError> {
+        let left_section = config.section("stick", "left");
+        let right_section = config.section("stick", "right");
+
+        if left_section.is_none() && right_section.is_none() {
+            return Ok(None);
+        }
+
+        let left: StickSettings = match left_section {
+            Some(section) => StickSettings::from_section(section)?,
+            None => StickSettings::unchanged(),
+        };
+        let right: StickSettings = match right_section {
+            Some(section) => StickSettings::from_section(section)?,
+            None => StickSettings::unchanged(),
+        };
+
+        return Ok(Some(Self { left, right }));
+    }
+}
+
+impl ProcessingStage for StickProcessing {
+    fn display_name(&self) -> &'static str {
+        return "Stick deadzones and curves";
+    }
+
+    fn process(&mut self, gamepad: &mut UniversalGamepad, _now: Instant) {
+        for (stick, settings) in [(&mut gamepad.sticks.left, &self.left), (&mut gamepad.sticks.right, &self.right)] {
+            (stick.x, stick.y) = settings.apply(stick.x, stick.y);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unchanged_settings_pass_every_value() {
+        let settings = StickSettings::unchanged();
+
+        for value in [0, 1, 64, 127, 128, 129, 200, 255] {
+            assert_eq!(settings.apply(value, 128), (value, 128));
+        }
+    }
+
+    #[test]
+    fn deadzones_and_anti_deadzone() {
+        let mut settings = StickSettings::unchanged();
+        settings.inner_deadzone = 0.1;
+        settings.anti_deadzone = 0.2;
+
+        // inside the deadzone
+        assert_eq!(settings.apply(135, 120), (128, 128));
+
+        // just outside the deadzone, jumps to the anti deadzone
+        let (x, _) = settings.apply(145, 128);
+        assert!(x >= 128 + 25, "x = {x}");
+
+        // full deflection stays full
+        assert_eq!(settings.apply(255, 128).0, 255);
+    }
+
+    #[test]
+    fn curve_points_are_interpolated() {
+        let curve = ResponseCurve::parse("points 0.5:0.25").expect("valid curve");
+
+        assert_eq!(curve.apply(0.25), 0.125);
+        assert_eq!(curve.apply(0.75), 0.625);
+        assert_eq!(curve.apply(1.0), 1.0);
+    }
+}
@@ -0,0 +1,467 @@
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, ConfigError, ConfigSection};
+use crate::processing::ProcessingStage;
+use crate::universal_gamepad::{Button, Stick, TouchContact, UniversalGamepad};
+
+/// Size of the DualSense touchpad in touch coordinates
+pub const TOUCHPAD_WIDTH: f32 = 1920.0;
+pub const TOUCHPAD_HEIGHT: f32 = 1080.0;
+
+/// How long a swipe keeps its button pressed, long enough for every game to notice it
+const SWIPE_TAP_DURATION: Duration = Duration::from_millis(100);
+
+/// A rectangle on the touchpad, all coordinates are fractions of the touchpad size (0 - 1)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TouchArea {
+    pub left: f32,
+    pub top: f32,
+    pub right: f32,
+    pub bottom: f32,
+}
+
+impl TouchArea {
+    pub fn whole_touchpad() -> Self {
+        Self {
+            left: 0.0,
+            top: 0.0,
+            right: 1.0,
+            bottom: 1.0,
+        }
+    }
+
+    /// `<left> <top> <right> <bottom>`
+    pub fn parse(text: &str) -> Option<TouchArea> {
+        let values: Vec<f32> = text.split_whitespace().map(|word| word.parse::<f32>().ok()).collect::<Option<Vec<f32>>>()?;
+        if values.len() != 4 || values.iter().any(|value| (0.0..=1.0).contains(value) == false) {
+            return None;
+        }
+        if values[0] >= values[2] || values[1] >= values[3] {
+            return None;
+        }
+
+        return Some(TouchArea {
+            left: values[0],
+            top: values[1],
+            right: values[2],
+            bottom: values[3],
+        });
+    }
+
+    fn from_section(section: &ConfigSection) -> Result<Self, ConfigError> {
+        match section.get("area") {
+            Some(text) => match TouchArea::parse(text) {
+                Some(area) => return Ok(area),
+                None => return Err(section.error(format!("invalid area, expected `<left> <top> <right> <bottom>` between 0 and 1: {text}"))),
+            },
+            None => return Ok(Self::whole_touchpad()),
+        }
+    }
+
+    pub fn contains(&self, position: (f32, f32)) -> bool {
+        return (self.left..=self.right).contains(&position.0) && (self.top..=self.bottom).contains(&position.1);
+    }
+
+    fn center(&self) -> (f32, f32) {
+        return ((self.left + self.right) / 2.0, (self.top + self.bottom) / 2.0);
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RegionTrigger {
+    /// The button is pressed while the touchpad is clicked with a finger in the region
+    Click,
+
+    /// The button is pressed while a finger rests in the region
+    Touch,
+}
+
+/// A part of the touchpad that acts as an extra button, configured with `[touch_region <name>]`
+#[derive(Clone, PartialEq, Debug)]
+pub struct TouchRegion {
+    pub name: String,
+    pub area: TouchArea,
+    pub button: Button,
+    pub trigger: RegionTrigger,
+}
+
+impl TouchRegion {
+    /// - `area = <left> <top> <right> <bottom>` as fractions of the touchpad size, default is the whole touchpad
+    /// - `button = <button>`
+    /// - `trigger = click` (default) or `trigger = touch`
+    fn from_section(section: &ConfigSection) -> Result<Self, ConfigError> {
+        let trigger: RegionTrigger = match section.get("trigger") {
+            None | Some("click") => RegionTrigger::Click,
+            Some("touch") => RegionTrigger::Touch,
+            Some(other) => return Err(section.error(format!("unknown trigger: {other}"))),
+        };
+
+        return Ok(Self {
+            name: section.name.clone(),
+            area: TouchArea::from_section(section)?,
+            button: _button_from_section(section)?,
+            trigger,
+        });
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum SwipeDirection {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+impl SwipeDirection {
+    pub fn from_name(name: &str) -> Option<SwipeDirection> {
+        match name {
+            "left" => return Some(SwipeDirection::Left),
+            "right" => return Some(SwipeDirection::Right),
+            "up" => return Some(SwipeDirection::Up),
+            "down" => return Some(SwipeDirection::Down),
+            _ => return None,
+        }
+    }
+}
+
+/// A quick finger movement that taps a button, configured with `[touch_swipe <direction>]`
+#[derive(Clone, PartialEq, Debug)]
+pub struct TouchSwipe {
+    pub direction: SwipeDirection,
+    pub button: Button,
+
+    /// Fraction of the touchpad width the finger has to move
+    pub min_distance: f32,
+
+    /// Slower movements are not swipes
+    pub max_duration: Duration,
+
+    /// The button stays pressed until then
+    tap_until: Option<Instant>,
+}
+
+impl TouchSwipe {
+    /// - `button = <button>`
+    /// - `min_distance = <fraction of the touchpad width>` (default 0.25)
+    /// - `max_duration = <ms>` (default 500)
+    fn from_section(section: &ConfigSection) -> Result<Self, ConfigError> {
+        let direction: SwipeDirection = match SwipeDirection::from_name(&section.name) {
+            Some(direction) => direction,
+            None => return Err(section.error(format!("unknown direction: {}, expected left, right, up or down", section.name))),
+        };
+
+        return Ok(Self {
+            direction,
+            button: _button_from_section(section)?,
+            min_distance: section.get_parsed::<f32>("min_distance")?.unwrap_or(0.25),
+            max_duration: Duration::from_millis(section.get_parsed::<u64>("max_duration")?.unwrap_or(500)),
+            tap_until: None,
+        });
+    }
+}
+
+/// Uses the touchpad like a stick, configured with `[touch_stick]`
+///
+/// The stick is deflected by the distance of the finger from where it first touched the touchpad
+#[derive(Clone, PartialEq, Debug)]
+pub struct TouchStick {
+    /// `true` for the right stick
+    pub right: bool,
+
+    /// Only touches that start here move the stick
+    pub area: TouchArea,
+
+    /// Distance (fraction of the touchpad width) for full deflection
+    pub radius: f32,
+
+    /// Measure from the center of `area` instead of the first touch
+    pub from_center: bool,
+}
+
+impl TouchStick {
+    /// - `stick = right` (default) or `stick = left`
+    /// - `area = <left> <top> <right> <bottom>`, default is the whole touchpad
+    /// - `radius = <fraction of the touchpad width>` (default 0.15)
+    /// - `origin = touch` (default) or `origin = center`
+    fn from_section(section: &ConfigSection) -> Result<Self, ConfigError> {
+        let right: bool = match section.get("stick") {
+            None | Some("right") => true,
+            Some("left") => false,
+            Some(other) => return Err(section.error(format!("unknown stick: {other}"))),
+        };
+        let from_center: bool = match section.get("origin") {
+            None | Some("touch") => false,
+            Some("center") => true,
+            Some(other) => return Err(section.error(format!("unknown origin: {other}"))),
+        };
+        let radius: f32 = section.get_parsed::<f32>("radius")?.unwrap_or(0.15);
+        if radius <= 0.0 {
+            return Err(section.error(format!("radius has to be above 0, not {radius}")));
+        }
+
+        return Ok(Self {
+            right,
+            area: TouchArea::from_section(section)?,
+            radius,
+            from_center,
+        });
+    }
+}
+
+fn _button_from_section(section: &ConfigSection) -> Result<Button, ConfigError> {
+    match section.get("button") {
+        Some(name) => match Button::from_name(name) {
+            Some(button) => return Ok(button),
+            None => return Err(section.error(format!("unknown button: {name}"))),
+        },
+        None => return Err(section.error("button is missing".to_string())),
+    }
+}
+
+/// Position as fractions of the touchpad size (0 - 1)
+fn _position(contact: &TouchContact) -> (f32, f32) {
+    return (contact.x_coord as f32 / TOUCHPAD_WIDTH, contact.y_coord as f32 / TOUCHPAD_HEIGHT);
+}
+
+/// The finger that is currently followed for swipes and the touch stick
+struct TrackedTouch {
+    id: u8,
+    start: (f32, f32),
+    start_time: Instant,
+    last: (f32, f32),
+
+    /// touches that start in the area of the touch stick only move the stick
+    is_stick: bool,
+}
+
+/// Touch regions, swipes and the touch stick, all driven by the touchpad contacts
+///
+/// Runs on the physical inputs, so the created buttons can still be remapped by a profile
+pub struct TouchpadMapping {
+    pub regions: Vec<TouchRegion>,
+    pub swipes: Vec<TouchSwipe>,
+    pub stick: Option<TouchStick>,
+
+    tracked: Option<TrackedTouch>,
+}
+
+impl TouchpadMapping {
+    /// Returns `Ok(None)` if nothing is configured
+    pub fn from_config(config: &Config) -> Result<Option<Self>, ConfigError> {
+        let mut regions: Vec<TouchRegion> = Vec::new();
+        for section in config.sections("touch_region") {
+            regions.push(TouchRegion::from_section(section)?);
+        }
+
+        let mut swipes: Vec<TouchSwipe> = Vec::new();
+        for section in config.sections("touch_swipe") {
+            swipes.push(TouchSwipe::from_section(section)?);
+        }
+
+        let stick: Option<TouchStick> = match config.section("touch_stick", "") {
+            Some(section) => Some(TouchStick::from_section(section)?),
+            None => None,
+        };
+
+        if regions.is_empty() && swipes.is_empty() && stick.is_none() {
+            return Ok(None);
+        }
+
+        return Ok(Some(Self {
+            regions,
+            swipes,
+            stick,
+            tracked: None,
+        }));
+    }
+
+    /// Ends a touch, checks if it was a swipe
+    fn _touch_ended(&mut self, now: Instant) {
+        let tracked: TrackedTouch = match self.tracked.take() {
+            Some(tracked) => tracked,
+            None => return,
+        };
+        if tracked.is_stick {
+            return;
+        }
+
+        let dx: f32 = tracked.last.0 - tracked.start.0;
+        let dy: f32 = (tracked.last.1 - tracked.start.1) * TOUCHPAD_HEIGHT / TOUCHPAD_WIDTH;
+        let direction: SwipeDirection = if dx.abs() >= dy.abs() {
+            if dx > 0.0 {
+                SwipeDirection::Right
+            } else {
+                SwipeDirection::Left
+            }
+        } else if dy > 0.0 {
+            SwipeDirection::Down
+        } else {
+            SwipeDirection::Up
+        };
+        let distance: f32 = dx.abs().max(dy.abs());
+
+        for swipe in self.swipes.iter_mut() {
+            if swipe.direction == direction && distance >= swipe.min_distance && now - tracked.start_time <= swipe.max_duration {
+                swipe.tap_until = Some(now + SWIPE_TAP_DURATION);
+            }
+        }
+    }
+
+    fn _update_tracked(&mut self, contact: &TouchContact, now: Instant) {
+        if contact.touched == false {
+            self._touch_ended(now);
+            return;
+        }
+
+        // a new id means the finger was lifted and put down again between two reports
+        if let Some(tracked) = &self.tracked {
+            if tracked.id != contact.id {
+                self._touch_ended(now);
+            }
+        }
+
+        let position: (f32, f32) = _position(contact);
+        match &mut self.tracked {
+            Some(tracked) => tracked.last = position,
+            None => {
+                let is_stick: bool = match &self.stick {
+                    Some(stick) => stick.area.contains(position),
+                    None => false,
+                };
+                self.tracked = Some(TrackedTouch {
+                    id: contact.id,
+                    start: position,
+                    start_time: now,
+                    last: position,
+                    is_stick,
+                });
+            }
+        }
+    }
+
+    fn _apply_stick(&self, gamepad: &mut UniversalGamepad) {
+        let (stick, tracked) = match (&self.stick, &self.tracked) {
+            (Some(stick), Some(tracked)) if tracked.is_stick => (stick, tracked),
+            _ => return,
+        };
+
+        let origin: (f32, f32) = if stick.from_center { stick.area.center() } else { tracked.start };
+        let dx: f32 = (tracked.last.0 - origin.0) / stick.radius;
+        let dy: f32 = (tracked.last.1 - origin.1) * TOUCHPAD_HEIGHT / TOUCHPAD_WIDTH / stick.radius;
+
+        let magnitude: f32 = (dx * dx + dy * dy).sqrt();
+        let scale: f32 = if magnitude > 1.0 { 1.0 / magnitude } else { 1.0 };
+
+        let output: &mut Stick = if stick.right { &mut gamepad.sticks.right } else { &mut gamepad.sticks.left };
+        output.x = (127.5 + dx * scale * 127.5).round() as u8;
+        output.y = (127.5 + dy * scale * 127.5).round() as u8;
+    }
+}
+
+impl ProcessingStage for TouchpadMapping {
+    fn display_name(&self) -> &'static str {
+        return "Touchpad regions, swipes and stick";
+    }
+
+    fn process(&mut self, gamepad: &mut UniversalGamepad, now: Instant) {
+        let (contacts, clicked) = match &gamepad.other.touchpad {
+            Some(touchpad) => (touchpad.contacts.clone(), touchpad.pressed),
+            None => return,
+        };
+
+        self._update_tracked(&contacts[0], now);
+        self._apply_stick(gamepad);
+
+        let mut click_was_used: bool = false;
+        for region in &self.regions {
+            let is_touched: bool = contacts.iter().any(|contact| contact.touched && region.area.contains(_position(contact)));
+            let is_pressed: bool = match region.trigger {
+                RegionTrigger::Click => is_touched && clicked,
+                RegionTrigger::Touch => is_touched,
+            };
+
+            if is_pressed {
+                gamepad.set_button(region.button, true);
+                if region.trigger == RegionTrigger::Click {
+                    click_was_used = true;
+                }
+            }
+        }
+
+        // the click became a region button, the host should not see it twice
+        if click_was_used {
+            gamepad.set_button(Button::TouchpadPressed, false);
+        }
+
+        for swipe in self.swipes.iter_mut() {
+            match swipe.tap_until {
+                Some(until) if now < until => gamepad.set_button(swipe.button, true),
+                Some(_) => swipe.tap_until = None,
+                None => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::universal_gamepad::Touchpad;
+
+    fn _touching(contact: Option<(u8, u16, u16)>, pressed: bool) -> UniversalGamepad {
+        let mut gamepad = UniversalGamepad::nothing_pressed();
+        let mut first: TouchContact = TouchContact::untouched();
+        if let Some((id, x_coord, y_coord)) = contact {
+            first = TouchContact {
+                touched: true,
+                id,
+                x_coord,
+                y_coord,
+            };
+        }
+        gamepad.other.touchpad = Some(Touchpad {
+            contacts: [first, TouchContact::untouched()],
+            pressed,
+        });
+        return gamepad;
+    }
+
+    #[test]
+    fn clicked_region_replaces_the_touchpad_click() {
+        let config = Config::parse("[touch_region upper_left]\narea = 0 0 0.5 0.5\nbutton = dpad.up\n").expect("valid config");
+        let mut mapping = TouchpadMapping::from_config(&config).expect("valid config").expect("configured");
+        let now = Instant::now();
+
+        let mut gamepad = _touching(Some((1, 100, 100)), true);
+        mapping.process(&mut gamepad, now);
+        assert!(gamepad.buttons.dpad.up);
+        assert!(gamepad.button(Button::TouchpadPressed) == false);
+
+        let mut gamepad = _touching(Some((1, 1800, 100)), true);
+        mapping.process(&mut gamepad, now);
+        assert!(gamepad.buttons.dpad.up == false);
+        assert!(gamepad.button(Button::TouchpadPressed));
+    }
+
+    #[test]
+    fn swipe_taps_its_button() {
+        let config = Config::parse("[touch_swipe right]\nbutton = bumpers.right\n").expect("valid config");
+        let mut mapping = TouchpadMapping::from_config(&config).expect("valid config").expect("configured");
+        let start = Instant::now();
+
+        for (index, x_coord) in [200, 600, 1000].into_iter().enumerate() {
+            let mut gamepad = _touching(Some((3, x_coord, 500)), false);
+            mapping.process(&mut gamepad, start + Duration::from_millis(index as u64 * 50));
+            assert!(gamepad.buttons.bumpers.right == false);
+        }
+
+        let mut gamepad = _touching(None, false);
+        mapping.process(&mut gamepad, start + Duration::from_millis(150));
+        assert!(gamepad.buttons.bumpers.right);
+
+        let mut gamepad = _touching(None, false);
+        mapping.process(&mut gamepad, start + Duration::from_millis(300));
+        assert!(gamepad.buttons.bumpers.right == false);
+    }
+}
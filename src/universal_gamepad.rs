@@ -1,3 +1,4 @@
+#[derive(Clone, PartialEq, Debug)]
 pub struct UniversalGamepad {
     pub sticks: Sticks,
     pub triggers: Triggers,
@@ -5,11 +6,20 @@ pub struct UniversalGamepad {
     pub other: Other,
 }
 impl UniversalGamepad {
+    /// No button pressed, sticks centered, triggers released
     pub fn nothing_pressed() -> Self {
         Self {
             sticks: Sticks {
-                left: Stick { x: 0, y: 0, pressed: false },
-                right: Stick { x: 0, y: 0, pressed: false },
+                left: Stick {
+                    x: 128,
+                    y: 128,
+                    pressed: false,
+                },
+                right: Stick {
+                    x: 128,
+                    y: 128,
+                    pressed: false,
+                },
             },
             triggers: Triggers { left: 0, right: 0 },
             buttons: Buttons {
@@ -35,6 +45,9 @@ impl UniversalGamepad {
             other: Other {
                 touchpad: None,
                 gyroscope: None,
+                accelerometer: None,
+                battery: None,
+                mouse: None,
             },
         }
     }
@@ -42,6 +55,7 @@ impl UniversalGamepad {
 
 // ----- //
 
+#[derive(Clone, PartialEq, Debug)]
 pub struct Sticks {
     pub left: Stick,
     pub right: Stick,
@@ -55,6 +69,7 @@ impl Sticks {
     }
 }
 
+#[derive(Clone, PartialEq, Debug)]
 pub struct Stick {
     pub x: u8,
     pub y: u8,
@@ -63,6 +78,7 @@ pub struct Stick {
 
 // ----- //
 
+#[derive(Clone, PartialEq, Debug)]
 pub struct Triggers {
     pub left: u8,
     pub right: u8,
@@ -70,6 +86,7 @@ pub struct Triggers {
 
 // ----- //
 
+#[derive(Clone, PartialEq, Debug)]
 pub struct Buttons {
     pub bumpers: Bumpers,
     pub dpad: DPad,
@@ -77,6 +94,7 @@ pub struct Buttons {
     pub specials: SpecialButtons,
 }
 
+#[derive(Clone, PartialEq, Debug)]
 pub struct Bumpers {
     pub left: bool,
     pub right: bool,
@@ -87,6 +105,7 @@ impl Bumpers {
     }
 }
 
+#[derive(Clone, PartialEq, Debug)]
 pub struct DPad {
     pub up: bool,
     pub down: bool,
@@ -104,6 +123,7 @@ impl DPad {
     }
 }
 
+#[derive(Clone, PartialEq, Debug)]
 pub struct MainButtons {
     pub upper: bool,
     pub lower: bool,
@@ -121,6 +141,7 @@ impl MainButtons {
     }
 }
 
+#[derive(Clone, PartialEq, Debug)]
 pub struct SpecialButtons {
     /// menu button
     pub right: bool,
@@ -143,20 +164,298 @@ impl SpecialButtons {
 
 // ----- //
 
+#[derive(Clone, PartialEq, Debug)]
 pub struct Other {
     pub touchpad: Option<Touchpad>,
     pub gyroscope: Option<Gyroscope>,
+    pub accelerometer: Option<Accelerometer>,
+    pub battery: Option<Battery>,
+
+    /// Relative mouse movement created by processing (e.g. gyro aiming), sent by the mouse function of the gadget
+    pub mouse: Option<MouseMovement>,
 }
 
+/// Angular velocity in the raw units of the input gamepad
+#[derive(Clone, PartialEq, Debug)]
 pub struct Gyroscope {
-    pub x_coord: u8,
-    pub y_coord: u8,
-    pub z_coord: u8,
+    pub x: i16,
+    pub y: i16,
+    pub z: i16,
+}
+
+/// Acceleration in the raw units of the input gamepad
+#[derive(Clone, PartialEq, Debug)]
+pub struct Accelerometer {
+    pub x: i16,
+    pub y: i16,
+    pub z: i16,
 }
 
+#[derive(Clone, PartialEq, Debug)]
 pub struct Touchpad {
-    pub x_coord: u8,
-    pub y_coord: u8,
-    pub touched: bool,
+    /// PS Controllers track up to two fingers at once
+    pub contacts: [TouchContact; 2],
     pub pressed: bool,
 }
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct TouchContact {
+    pub touched: bool,
+
+    /// Changes every time a new finger touches the touchpad
+    pub id: u8,
+
+    /// 0 is left, 1919 is right (for a DualSense)
+    pub x_coord: u16,
+
+    /// 0 is top, 1079 is bottom (for a DualSense)
+    pub y_coord: u16,
+}
+impl TouchContact {
+    pub fn untouched() -> Self {
+        Self {
+            touched: false,
+            id: 0,
+            x_coord: 0,
+            y_coord: 0,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Battery {
+    /// 0 - 100
+    pub level_percent: u8,
+    pub charging: bool,
+}
+
+/// Movement since the last report in mouse counts, positive is right and down
+#[derive(Clone, PartialEq, Debug)]
+pub struct MouseMovement {
+    pub x: i16,
+    pub y: i16,
+}
+
+// ----- //
+
+/// Every digital input of a `UniversalGamepad`
+///
+/// The names are the paths of the fields inside `UniversalGamepad`, so `Button::MainLower` is `buttons.main.lower`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Button {
+    MainUpper,
+    MainLower,
+    MainLeft,
+    MainRight,
+    DPadUp,
+    DPadDown,
+    DPadLeft,
+    DPadRight,
+    BumperLeft,
+    BumperRight,
+    SpecialLeft,
+    SpecialRight,
+    SpecialLogo,
+    StickLeftPressed,
+    StickRightPressed,
+    TouchpadPressed,
+}
+
+impl Button {
+    pub const ALL: [Button; 16] = [
+        Button::MainUpper,
+        Button::MainLower,
+        Button::MainLeft,
+        Button::MainRight,
+        Button::DPadUp,
+        Button::DPadDown,
+        Button::DPadLeft,
+        Button::DPadRight,
+        Button::BumperLeft,
+        Button::BumperRight,
+        Button::SpecialLeft,
+        Button::SpecialRight,
+        Button::SpecialLogo,
+        Button::StickLeftPressed,
+        Button::StickRightPressed,
+        Button::TouchpadPressed,
+    ];
+
+    /// The name used in config files
+    pub fn name(&self) -> &'static str {
+        match self {
+            Button::MainUpper => "main.upper",
+            Button::MainLower => "main.lower",
+            Button::MainLeft => "main.left",
+            Button::MainRight => "main.right",
+            Button::DPadUp => "dpad.up",
+            Button::DPadDown => "dpad.down",
+            Button::DPadLeft => "dpad.left",
+            Button::DPadRight => "dpad.right",
+            Button::BumperLeft => "bumpers.left",
+            Button::BumperRight => "bumpers.right",
+            Button::SpecialLeft => "specials.left",
+            Button::SpecialRight => "specials.right",
+            Button::SpecialLogo => "specials.logo",
+            Button::StickLeftPressed => "sticks.left.pressed",
+            Button::StickRightPressed => "sticks.right.pressed",
+            Button::TouchpadPressed => "touchpad.pressed",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Button> {
+        return Button::ALL.into_iter().find(|button| button.name() == name);
+    }
+}
+
+/// Every analog input of a `UniversalGamepad`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Axis {
+    StickLeftX,
+    StickLeftY,
+    StickRightX,
+    StickRightY,
+    TriggerLeft,
+    TriggerRight,
+}
+
+impl Axis {
+    pub const ALL: [Axis; 6] = [
+        Axis::StickLeftX,
+        Axis::StickLeftY,
+        Axis::StickRightX,
+        Axis::StickRightY,
+        Axis::TriggerLeft,
+        Axis::TriggerRight,
+    ];
+
+    /// The name used in config files
+    pub fn name(&self) -> &'static str {
+        match self {
+            Axis::StickLeftX => "sticks.left.x",
+            Axis::StickLeftY => "sticks.left.y",
+            Axis::StickRightX => "sticks.right.x",
+            Axis::StickRightY => "sticks.right.y",
+            Axis::TriggerLeft => "triggers.left",
+            Axis::TriggerRight => "triggers.right",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Axis> {
+        return Axis::ALL.into_iter().find(|axis| axis.name() == name);
+    }
+
+    /// The value of this axis if nothing is touched
+    ///
+    /// Sticks rest in the middle, triggers at 0
+    pub fn rest_value(&self) -> u8 {
+        match self {
+            Axis::TriggerLeft | Axis::TriggerRight => 0,
+            _ => 128,
+        }
+    }
+}
+
+impl UniversalGamepad {
+    pub fn button(&self, button: Button) -> bool {
+        match button {
+            Button::MainUpper => self.buttons.main.upper,
+            Button::MainLower => self.buttons.main.lower,
+            Button::MainLeft => self.buttons.main.left,
+            Button::MainRight => self.buttons.main.right,
+            Button::DPadUp => self.buttons.dpad.up,
+            Button::DPadDown => self.buttons.dpad.down,
+            Button::DPadLeft => self.buttons.dpad.left,
+            Button::DPadRight => self.buttons.dpad.right,
+            Button::BumperLeft => self.buttons.bumpers.left,
+            Button::BumperRight => self.buttons.bumpers.right,
+            Button::SpecialLeft => self.buttons.specials.left,
+            Button::SpecialRight => self.buttons.specials.right,
+            Button::SpecialLogo => self.buttons.specials.logo,
+            Button::StickLeftPressed => self.sticks.left.pressed,
+            Button::StickRightPressed => self.sticks.right.pressed,
+            Button::TouchpadPressed => match &self.other.touchpad {
+                Some(touchpad) => touchpad.pressed,
+                None => false,
+            },
+        }
+    }
+
+    /// Setting `Button::TouchpadPressed` on a gamepad without touchpad does nothing
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        match button {
+            Button::MainUpper => self.buttons.main.upper = pressed,
+            Button::MainLower => self.buttons.main.lower = pressed,
+            Button::MainLeft => self.buttons.main.left = pressed,
+            Button::MainRight => self.buttons.main.right = pressed,
+            Button::DPadUp => self.buttons.dpad.up = pressed,
+            Button::DPadDown => self.buttons.dpad.down = pressed,
+            Button::DPadLeft => self.buttons.dpad.left = pressed,
+            Button::DPadRight => self.buttons.dpad.right = pressed,
+            Button::BumperLeft => self.buttons.bumpers.left = pressed,
+            Button::BumperRight => self.buttons.bumpers.right = pressed,
+            Button::SpecialLeft => self.buttons.specials.left = pressed,
+            Button::SpecialRight => self.buttons.specials.right = pressed,
+            Button::SpecialLogo => self.buttons.specials.logo = pressed,
+            Button::StickLeftPressed => self.sticks.left.pressed = pressed,
+            Button::StickRightPressed => self.sticks.right.pressed = pressed,
+            Button::TouchpadPressed => {
+                if let Some(touchpad) = &mut self.other.touchpad {
+                    touchpad.pressed = pressed;
+                }
+            }
+        }
+    }
+
+    pub fn axis(&self, axis: Axis) -> u8 {
+        match axis {
+            Axis::StickLeftX => self.sticks.left.x,
+            Axis::StickLeftY => self.sticks.left.y,
+            Axis::StickRightX => self.sticks.right.x,
+            Axis::StickRightY => self.sticks.right.y,
+            Axis::TriggerLeft => self.triggers.left,
+            Axis::TriggerRight => self.triggers.right,
+        }
+    }
+
+    /// Releases every button and moves every axis to its rest value
+    pub fn release_all(&mut self) {
+        for button in Button::ALL {
+            self.set_button(button, false);
+        }
+        for axis in Axis::ALL {
+            self.set_axis(axis, axis.rest_value());
+        }
+        if let Some(touchpad) = &mut self.other.touchpad {
+            touchpad.contacts = [TouchContact::untouched(), TouchContact::untouched()];
+        }
+    }
+
+    /// Combines `other` into `self`:
+    /// - buttons are pressed if they are pressed in either gamepad
+    /// - axes take the value with the bigger deflection from their rest value
+    pub fn merge(&mut self, other: &UniversalGamepad) {
+        for button in Button::ALL {
+            if other.button(button) {
+                self.set_button(button, true);
+            }
+        }
+        for axis in Axis::ALL {
+            let rest: u8 = axis.rest_value();
+            if other.axis(axis).abs_diff(rest) > self.axis(axis).abs_diff(rest) {
+                self.set_axis(axis, other.axis(axis));
+            }
+        }
+    }
+
+    pub fn set_axis(&mut self, axis: Axis, value: u8) {
+        match axis {
+            Axis::StickLeftX => self.sticks.left.x = value,
+            Axis::StickLeftY => self.sticks.left.y = value,
+            Axis::StickRightX => self.sticks.right.x = value,
+            Axis::StickRightY => self.sticks.right.y = value,
+            Axis::TriggerLeft => self.triggers.left = value,
+            Axis::TriggerRight => self.triggers.right = value,
+        }
+    }
+}
@@ -14,6 +14,7 @@ use std::{
     fs::{self, File},
     io::Write,
     process::exit,
+    time::Duration,
 };
 
 use crate::helper_fn::run_cmd;
@@ -22,7 +23,52 @@ use crate::print_and_exit;
 const DEVICE_DIR: &str = "/sys/kernel/config/usb_gadget/raspi";
 const ENG_STR_DIR: &str = "/sys/kernel/config/usb_gadget/raspi/strings/0x409";
 const CONFIGS_DIR: &str = "/sys/kernel/config/usb_gadget/raspi/configs/c.1";
-const FNC_HID_DIR: &str = "/sys/kernel/config/usb_gadget/raspi/functions/hid.usb0";
+const FUNCTIONS_DIR: &str = "/sys/kernel/config/usb_gadget/raspi/functions";
+
+/// How often the host asks for a report: the kernel's f_hid uses bInterval 4 at high speed, 2^(4-1) microframes of 125 µs
+pub const HID_POLLING_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Name of the directory of the hid function `index`, the host side is `/dev/hidg<index>`
+fn hid_function_name(index: usize) -> String {
+    return format!("hid.usb{index}");
+}
+
+/// A boot mouse, added after the gamepad functions for relative mouse movement (e.g. by gyro aiming)
+///
+/// Reports are 3 bytes: buttons, x and y movement from -127 to 127
+pub const MOUSE_FUNCTION: UsbGadgetFunctionsHid = UsbGadgetFunctionsHid {
+    protocol: 2,
+    report_length: 3,
+    hid_subclass: 1,
+    report_descriptor: &[
+        0x05, 0x01, // Usage Page (Generic Desktop Ctrls)
+        0x09, 0x02, // Usage (Mouse)
+        0xA1, 0x01, // Collection (Application)
+        0x09, 0x01, //   Usage (Pointer)
+        0xA1, 0x00, //   Collection (Physical)
+        0x05, 0x09, //     Usage Page (Button)
+        0x19, 0x01, //     Usage Minimum (0x01)
+        0x29, 0x03, //     Usage Maximum (0x03)
+        0x15, 0x00, //     Logical Minimum (0)
+        0x25, 0x01, //     Logical Maximum (1)
+        0x95, 0x03, //     Report Count (3)
+        0x75, 0x01, //     Report Size (1)
+        0x81, 0x02, //     Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+        0x95, 0x01, //     Report Count (1)
+        0x75, 0x05, //     Report Size (5)
+        0x81, 0x03, //     Input (Const,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+        0x05, 0x01, //     Usage Page (Generic Desktop Ctrls)
+        0x09, 0x30, //     Usage (X)
+        0x09, 0x31, //     Usage (Y)
+        0x15, 0x81, //     Logical Minimum (-127)
+        0x25, 0x7F, //     Logical Maximum (127)
+        0x75, 0x08, //     Report Size (8)
+        0x95, 0x02, //     Report Count (2)
+        0x81, 0x06, //     Input (Data,Var,Rel,No Wrap,Linear,Preferred State,No Null Position)
+        0xC0, //   End Collection
+        0xC0, // End Collection
+    ],
+};
 
 pub struct UsbGadgetDescriptor {
     pub bcd_usb: u16,           // USB HID Specification Release 1.0.                               | 0x200 = 2.00
@@ -41,6 +87,13 @@ pub struct UsbGadgetDescriptor {
 impl UsbGadgetDescriptor {
     /// Unbinds the gadget and removes every that gets created by `configure_device()`
     pub fn clean_up_device(&self) {
+        self.clean_up_composite_device(1, false);
+    }
+
+    /// Like `clean_up_device()`, for a gadget created by `configure_composite_device()`
+    pub fn clean_up_composite_device(&self, function_count: usize, with_mouse: bool) {
+        let function_count: usize = function_count + with_mouse as usize;
+
         // Free up UDC = disconnect from host
         match File::options().write(true).truncate(true).open(&(DEVICE_DIR.to_string() + "/UDC")) {
             Ok(mut file) => match file.write_all("".as_bytes()) {
@@ -52,24 +105,44 @@ impl UsbGadgetDescriptor {
 
         // remove everything from usb_gadget directory
         // rm -rf is not permitted
-        fs::remove_file(CONFIGS_DIR.to_string() + "/hid.usb0").expect("removing configs/hid.usb0 failed");
+        for index in 0..function_count {
+            let name: String = hid_function_name(index);
+            fs::remove_file(format!("{CONFIGS_DIR}/{name}")).expect("removing configs/hid.usb<X> failed");
+        }
         fs::remove_dir(CONFIGS_DIR.to_string() + "/strings/0x409/").expect("removing configs/strings/0x409 failed");
         fs::remove_dir(CONFIGS_DIR).expect("removing configs/c.1 failed");
-        fs::remove_dir(FNC_HID_DIR).expect("removing functions/hid.usb0 failed");
+        for index in 0..function_count {
+            let name: String = hid_function_name(index);
+            fs::remove_dir(format!("{FUNCTIONS_DIR}/{name}")).expect("removing functions/hid.usb<X> failed");
+        }
         fs::remove_dir(ENG_STR_DIR).expect("removing strings/0x409 failed");
         fs::remove_dir(DEVICE_DIR).expect("removing usb_gadget/raspi failed");
     }
 
     /// Using linux' ConfigFS, create the given usb device
     pub fn configure_device(&self) {
-        self._create_directories();
+        self.configure_composite_device(1, false);
+    }
+
+    /// Like `configure_device()`, but the gadget gets `function_count` identical hid functions
+    ///
+    /// The host sees one gamepad per function, they are written with `/dev/hidg0`, `/dev/hidg1`, ...
+    /// With `with_mouse`, a `MOUSE_FUNCTION` follows them, written with `/dev/hidg<function_count>`
+    pub fn configure_composite_device(&self, function_count: usize, with_mouse: bool) {
+        self._create_directories(function_count + with_mouse as usize);
 
         self._write_to_disk();
         self.configs_c1.write_to_disk();
         self.strings_0x409.write_to_disk();
-        self.functions_hid.write_to_disk();
+        for index in 0..function_count {
+            self.functions_hid.write_to_disk(&format!("{FUNCTIONS_DIR}/{}", hid_function_name(index)));
+            self._assign_fn_to_config(index);
+        }
+        if with_mouse {
+            MOUSE_FUNCTION.write_to_disk(&format!("{FUNCTIONS_DIR}/{}", hid_function_name(function_count)));
+            self._assign_fn_to_config(function_count);
+        }
 
-        self._assign_fn_to_config();
         match self._bind_to_udc() {
             Ok(_) => (),
             Err(err) => {
@@ -96,7 +169,7 @@ impl UsbGadgetDescriptor {
     // }
 
     /// will exit if any operation is not successful
-    fn _create_directories(&self) {
+    fn _create_directories(&self, function_count: usize) {
         match run_cmd("/sys/kernel/config/usb_gadget", "mkdir raspi") {
             Ok(_) => (),
             Err(_) => print_and_exit!("Could not create directory /sys/kernel/config/usb_gadget/raspi", 9),
@@ -123,10 +196,13 @@ impl UsbGadgetDescriptor {
 
         // Functions
         // The system already creates the directory "functions"
-        match run_cmd("/sys/kernel/config/usb_gadget/raspi/functions", "mkdir hid.usb0") {
-            Ok(_) => (),
-            Err(_) => print_and_exit!("Could not create directory /sys/kernel/config/usb_gadget/raspi/functions/hid.usb0", 9),
-        };
+        for index in 0..function_count {
+            let name: String = hid_function_name(index);
+            match run_cmd(FUNCTIONS_DIR, &format!("mkdir {name}")) {
+                Ok(_) => (),
+                Err(_) => print_and_exit!(format!("Could not create directory {FUNCTIONS_DIR}/{name}").as_str(), 9),
+            };
+        }
     }
 
     /// Writes the data of `UsbGadgetDescriptor` into the files `bcdDevice`, `bcdUSB`, `bDeviceClass`, `bDeviceSubClass`, `bDeviceProtocol`, `bMaxPacketSize0`, `idVendor`, `idProduct`
@@ -145,7 +221,7 @@ impl UsbGadgetDescriptor {
     /// This implies that some of the work is done by the driver.
     fn _write_to_disk(&self) {
         match File::options().write(true).truncate(true).open(&(DEVICE_DIR.to_string() + "/bcdDevice")) {
-            Ok(mut file) => match file.write_all(&self.bcd_device.to_string().as_bytes()) {
+            Ok(mut file) => match file.write_all(self.bcd_device.to_string().as_bytes()) {
                 Ok(_) => (),
                 Err(_) => print_and_exit!("Could not write to file bcdDevice", 10),
             },
@@ -153,7 +229,7 @@ impl UsbGadgetDescriptor {
         };
 
         match File::options().write(true).truncate(true).open(&(DEVICE_DIR.to_string() + "/bcdUSB")) {
-            Ok(mut file) => match file.write_all(&self.bcd_usb.to_string().as_bytes()) {
+            Ok(mut file) => match file.write_all(self.bcd_usb.to_string().as_bytes()) {
                 Ok(_) => (),
                 Err(_) => print_and_exit!("Could not write to file bcdUSB", 10),
             },
@@ -161,7 +237,7 @@ impl UsbGadgetDescriptor {
         };
 
         match File::options().write(true).truncate(true).open(&(DEVICE_DIR.to_string() + "/bDeviceClass")) {
-            Ok(mut file) => match file.write_all(&self.b_device_class.to_string().as_bytes()) {
+            Ok(mut file) => match file.write_all(self.b_device_class.to_string().as_bytes()) {
                 Ok(_) => (),
                 Err(_) => print_and_exit!("Could not write to file bDeviceClass", 10),
             },
@@ -169,7 +245,7 @@ impl UsbGadgetDescriptor {
         };
 
         match File::options().write(true).truncate(true).open(&(DEVICE_DIR.to_string() + "/bDeviceSubClass")) {
-            Ok(mut file) => match file.write_all(&self.b_device_sub_class.to_string().as_bytes()) {
+            Ok(mut file) => match file.write_all(self.b_device_sub_class.to_string().as_bytes()) {
                 Ok(_) => (),
                 Err(_) => print_and_exit!("Could not write to file bDeviceSubClass", 10),
             },
@@ -177,7 +253,7 @@ impl UsbGadgetDescriptor {
         };
 
         match File::options().write(true).truncate(true).open(&(DEVICE_DIR.to_string() + "/bDeviceProtocol")) {
-            Ok(mut file) => match file.write_all(&self.b_device_protocol.to_string().as_bytes()) {
+            Ok(mut file) => match file.write_all(self.b_device_protocol.to_string().as_bytes()) {
                 Ok(_) => (),
                 Err(_) => print_and_exit!("Could not write to file bDeviceProtocol", 10),
             },
@@ -185,7 +261,7 @@ impl UsbGadgetDescriptor {
         };
 
         match File::options().write(true).truncate(true).open(&(DEVICE_DIR.to_string() + "/bMaxPacketSize0")) {
-            Ok(mut file) => match file.write_all(&self.b_max_packet_size0.to_string().as_bytes()) {
+            Ok(mut file) => match file.write_all(self.b_max_packet_size0.to_string().as_bytes()) {
                 Ok(_) => (),
                 Err(_) => print_and_exit!("Could not write to file bMaxPacketSize0", 10),
             },
@@ -193,7 +269,7 @@ impl UsbGadgetDescriptor {
         };
 
         match File::options().write(true).truncate(true).open(&(DEVICE_DIR.to_string() + "/idVendor")) {
-            Ok(mut file) => match file.write_all(&self.id_vendor.to_string().as_bytes()) {
+            Ok(mut file) => match file.write_all(self.id_vendor.to_string().as_bytes()) {
                 Ok(_) => (),
                 Err(_) => print_and_exit!("Could not write to file idVendor", 10),
             },
@@ -201,7 +277,7 @@ impl UsbGadgetDescriptor {
         };
 
         match File::options().write(true).truncate(true).open(&(DEVICE_DIR.to_string() + "/idProduct")) {
-            Ok(mut file) => match file.write_all(&self.id_product.to_string().as_bytes()) {
+            Ok(mut file) => match file.write_all(self.id_product.to_string().as_bytes()) {
                 Ok(_) => (),
                 Err(_) => print_and_exit!("Could not write to file idProduct", 10),
             },
@@ -209,10 +285,11 @@ impl UsbGadgetDescriptor {
         };
     }
 
-    fn _assign_fn_to_config(&self) {
-        match run_cmd(DEVICE_DIR, "ln -s functions/hid.usb0/ configs/c.1/") {
+    fn _assign_fn_to_config(&self, index: usize) {
+        let name: String = hid_function_name(index);
+        match run_cmd(DEVICE_DIR, &format!("ln -s functions/{name}/ configs/c.1/")) {
             Ok(_) => (),
-            Err(_) => print_and_exit!("Could not link functions (functions/hid.usb0/) to configs (configs/c.1/)", 14),
+            Err(_) => print_and_exit!(format!("Could not link functions (functions/{name}/) to configs (configs/c.1/)").as_str(), 14),
         }
     }
 
@@ -238,7 +315,7 @@ impl UsbGadgetDescriptor {
         };
 
         match File::options().write(true).truncate(true).open(&(DEVICE_DIR.to_string() + "/UDC")) {
-            Ok(mut file) => match file.write_all(&first_udc.as_bytes()) {
+            Ok(mut file) => match file.write_all(first_udc.as_bytes()) {
                 Ok(_) => (),
                 Err(_) => print_and_exit!("Could not write to file UDC", 10),
             },
@@ -265,7 +342,7 @@ impl UsbGadgetStrings {
     fn write_to_disk(&self) {
         if self.manufacturer.is_empty() == false {
             match File::create(&(ENG_STR_DIR.to_string() + "/manufacturer")) {
-                Ok(mut file) => match file.write_all(&self.manufacturer.as_bytes()) {
+                Ok(mut file) => match file.write_all(self.manufacturer.as_bytes()) {
                     Ok(_) => (),
                     Err(_) => print_and_exit!("Could not write to file manufacturer", 14),
                 },
@@ -275,7 +352,7 @@ impl UsbGadgetStrings {
 
         if self.product.is_empty() == false {
             match File::create(&(ENG_STR_DIR.to_string() + "/product")) {
-                Ok(mut file) => match file.write_all(&self.product.as_bytes()) {
+                Ok(mut file) => match file.write_all(self.product.as_bytes()) {
                     Ok(_) => (),
                     Err(_) => print_and_exit!("Could not write to file product", 14),
                 },
@@ -285,7 +362,7 @@ impl UsbGadgetStrings {
 
         if self.serialnumber.is_empty() == false {
             match File::create(&(ENG_STR_DIR.to_string() + "/serialnumber")) {
-                Ok(mut file) => match file.write_all(&self.serialnumber.as_bytes()) {
+                Ok(mut file) => match file.write_all(self.serialnumber.as_bytes()) {
                     Ok(_) => (),
                     Err(_) => print_and_exit!("Could not write to file serialnumber", 14),
                 },
@@ -315,7 +392,7 @@ pub struct UsbGadgetConfigs {
 impl UsbGadgetConfigs {
     fn write_to_disk(&self) {
         match File::options().write(true).truncate(true).open(&(CONFIGS_DIR.to_string() + "/bmAttributes")) {
-            Ok(mut file) => match file.write_all(&self.bm_attributes.to_string().as_bytes()) {
+            Ok(mut file) => match file.write_all(self.bm_attributes.to_string().as_bytes()) {
                 Ok(_) => (),
                 Err(_) => print_and_exit!("Could not write to file bmAttributes", 12),
             },
@@ -325,7 +402,7 @@ impl UsbGadgetConfigs {
         // this value is orignially called bMaxPower (usb.org and in kernel source code)
         // but this file gets created by the driver as soon as a folder is created in /configs
         match File::options().write(true).truncate(true).open(&(CONFIGS_DIR.to_string() + "/MaxPower")) {
-            Ok(mut file) => match file.write_all(&self.max_power.to_string().as_bytes()) {
+            Ok(mut file) => match file.write_all(self.max_power.to_string().as_bytes()) {
                 Ok(_) => (),
                 Err(_) => print_and_exit!("Could not write to file MaxPower", 12),
             },
@@ -338,7 +415,7 @@ impl UsbGadgetConfigs {
                 .truncate(true)
                 .open(&(CONFIGS_DIR.to_string() + "/strings/0x409/configuration"))
             {
-                Ok(mut file) => match file.write_all(&self.configs_string.as_bytes()) {
+                Ok(mut file) => match file.write_all(self.configs_string.as_bytes()) {
                     Ok(_) => (),
                     Err(_) => print_and_exit!("Could not write to file configuration", 12),
                 },
@@ -348,11 +425,11 @@ impl UsbGadgetConfigs {
     }
 }
 
-/// This represents everything that has to be written into the directory .../usb_gadget/NAME/functions/hid.usb0/
+/// This represents everything that has to be written into the directory .../usb_gadget/NAME/functions/hid.usb<X>/
 pub struct UsbGadgetFunctionsHid {
     /// HID protocol to use
     ///
-    /// Default is `0`, Keyboard is `1`, Mouse might be `2`
+    /// Default is `0`, Keyboard is `1`, Mouse is `2`
     pub protocol: u8,
 
     /// data to be used in HID reports, except data passed with /dev/hidg<X>
@@ -370,10 +447,11 @@ pub struct UsbGadgetFunctionsHid {
 }
 
 impl UsbGadgetFunctionsHid {
-    fn write_to_disk(&self) {
+    /// `function_dir` is the directory of one hid function, e.g. `.../functions/hid.usb0`
+    fn write_to_disk(&self, function_dir: &str) {
         // protocol
-        match File::options().write(true).truncate(true).open(&(FNC_HID_DIR.to_string() + "/protocol")) {
-            Ok(mut file) => match file.write_all(&self.protocol.to_string().as_bytes()) {
+        match File::options().write(true).truncate(true).open(&(function_dir.to_string() + "/protocol")) {
+            Ok(mut file) => match file.write_all(self.protocol.to_string().as_bytes()) {
                 Ok(_) => (),
                 Err(_) => print_and_exit!("Could not write to file protocol", 12),
             },
@@ -381,8 +459,8 @@ impl UsbGadgetFunctionsHid {
         }
 
         // report_length
-        match File::options().write(true).truncate(true).open(&(FNC_HID_DIR.to_string() + "/report_length")) {
-            Ok(mut file) => match file.write_all(&self.report_length.to_string().as_bytes()) {
+        match File::options().write(true).truncate(true).open(&(function_dir.to_string() + "/report_length")) {
+            Ok(mut file) => match file.write_all(self.report_length.to_string().as_bytes()) {
                 Ok(_) => (),
                 Err(_) => print_and_exit!("Could not write to file report_length", 12),
             },
@@ -390,8 +468,8 @@ impl UsbGadgetFunctionsHid {
         }
 
         // subclass
-        match File::options().write(true).truncate(true).open(&(FNC_HID_DIR.to_string() + "/subclass")) {
-            Ok(mut file) => match file.write_all(&self.hid_subclass.to_string().as_bytes()) {
+        match File::options().write(true).truncate(true).open(&(function_dir.to_string() + "/subclass")) {
+            Ok(mut file) => match file.write_all(self.hid_subclass.to_string().as_bytes()) {
                 Ok(_) => (),
                 Err(_) => print_and_exit!("Could not write to file subclass", 12),
             },
@@ -399,8 +477,8 @@ impl UsbGadgetFunctionsHid {
         }
 
         // report_desc
-        match File::options().write(true).truncate(true).open(&(FNC_HID_DIR.to_string() + "/report_desc")) {
-            Ok(mut file) => match file.write_all(&self.report_descriptor) {
+        match File::options().write(true).truncate(true).open(&(function_dir.to_string() + "/report_desc")) {
+            Ok(mut file) => match file.write_all(self.report_descriptor) {
                 Ok(_) => (),
                 Err(_) => print_and_exit!("Could not write to file report_desc", 12),
             },
@@ -1,231 +1,276 @@
-use flume::Receiver;
-use flume::TryRecvError;
-use std::env;
 use std::fs::File;
-use std::{io::Write, process::exit, thread, time::Instant};
-use std::{
-    sync::{Arc, Mutex},
-    time::Duration,
-};
-
-use crate::usb_gamepad_ps4::DUALSHOCK;
-use crate::usb_gamepad_ps5::DUALSENSE;
-use crate::{print_error_and_exit, universal_gamepad::UniversalGamepad, usb_gadget::UsbGadgetDescriptor};
-
-pub const OUTPUT_GAMEPADS: [&Gamepad; 2] = [&DUALSENSE, &DUALSHOCK];
-
-pub struct Gamepad {
-    pub gadget: UsbGadgetDescriptor,
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::latest_state::Subscriber;
+use crate::pacing::Pacer;
+use crate::processing::Pipeline;
+use crate::universal_gamepad::{MouseMovement, UniversalGamepad};
+use crate::usb_gadget::UsbGadgetDescriptor;
+
+/// Rumble motor strength (right, left) and lightbar color (r, g, b) for a physical gamepad
+pub type Feedback = ((u8, u8), (u8, u8, u8));
+
+/// Turns the bluetooth input reports of one physical gamepad model into a `UniversalGamepad`
+///
+/// One instance is created per connected gamepad, so implementations can keep state between reports
+pub trait InputDriver: Send {
+    /// Used for verbose output
+    fn display_name(&self) -> &'static str;
 
     /// This depends on how the function bt_input_to_universal_gamepad() works
     /// Currently this is used to be sure that at least all standard buttons, triggers, bumpers etc are readable
-    pub min_bt_report_size: usize,
+    fn min_bt_report_size(&self) -> usize;
 
-    /// Is this gamepad fully usable as an output gamepad
-    pub is_supported: bool,
+    fn bt_input_to_universal_gamepad(&mut self, bt_input: &[u8]) -> UniversalGamepad;
 
-    /// Used for verbose output
-    pub display_name: &'static str,
+    /// Counter that advances by one with every input report, used to find reports that got lost on the way
+    ///
+    /// Returns `None` if this gamepad model has no such counter
+    fn report_sequence(&self, _bt_input: &[u8]) -> Option<u8> {
+        return None;
+    }
 
-    /// what strings can a user input as the second commandline argument to select this gamepad for use as the output gamepad
-    pub associated_args: [&'static str; 2],
-    pub bt_input_to_universal_gamepad: fn(&Vec<u8>) -> UniversalGamepad,
-    pub universal_gamepad_to_usb_output: fn(&UniversalGamepad) -> Vec<u8>,
-}
-impl Gamepad {
-    /// Checks if there has been one command line argument given, exits with descriptive error if not
+    /// The bluetooth output report that sets the rumble motors (right, left) and the lightbar color (r, g, b)
     ///
-    /// If argument was given, checks if it contains a string describing any supported gamepad
-    pub fn from_cmdline_args() -> &'static Gamepad {
-        let args: Vec<String> = env::args().collect();
-
-        if args.len() != 2 {
-            println!("One command line argument was expected to describe the desired output gamepad");
-            println!("If run with cargo, use: cargo run -- <argument>");
-            _display_supported_gamepads();
-        }
+    /// Returns `None` if this gamepad model can not be controlled
+    fn bt_output_report(&mut self, _rumble: (u8, u8), _lightbar: (u8, u8, u8)) -> Option<Vec<u8>> {
+        return None;
+    }
+}
 
-        let given_arg: &String = &args[1];
-
-        for gamepad in OUTPUT_GAMEPADS {
-            for associated_arg in gamepad.associated_args {
-                if given_arg.contains(associated_arg) {
-                    if gamepad.is_supported {
-                        println!("Output gamepad is {}", gamepad.display_name);
-                        return gamepad;
-                    } else {
-                        println!("The gamepad {} is not yet supported", gamepad.display_name);
-                        break;
-                    }
-                }
-            }
-        }
+/// The gamepad the host sees on the other side of the usb gadget
+///
+/// One instance is created per gadget, so implementations can keep state between reports
+pub trait OutputPersona: Send {
+    /// Used for verbose output
+    fn display_name(&self) -> &'static str;
+
+    fn gadget(&self) -> &'static UsbGadgetDescriptor;
 
-        println!("No supported gamepad is associated with the input '{}'", given_arg);
-        _display_supported_gamepads();
+    /// creates a `Vec<u8>` that is the HID Report which has to be written in `/dev/hidg0`
+    ///
+    /// The length will be asserted at runtime to be `self.gadget().functions_hid.report_length`. This function will **panic** if the length is not correct
+    fn universal_gamepad_to_usb_output(&mut self, gamepad: &UniversalGamepad) -> Vec<u8>;
+
+    /// Called with every OUT report the host has written into `/dev/hidg0` (rumble, leds etc.)
+    ///
+    /// Returns the rumble and lightbar color the host asked for, they are forwarded to the physical gamepads
+    fn handle_out_report(&mut self, _report: &[u8]) -> Option<Feedback> {
+        return None;
     }
 
-    pub fn bt_input_to_universal_gamepad(&self, bt_input: &Vec<u8>) -> UniversalGamepad {
-        return (self.bt_input_to_universal_gamepad)(&bt_input);
+    /// The answer to a GET_REPORT request of the host for the feature report `report_id`
+    ///
+    /// Returns `None` if this persona does not know this feature report
+    fn feature_report(&mut self, _report_id: u8) -> Option<Vec<u8>> {
+        return None;
     }
+}
 
-    /// - Waits for a new UniversalGamepad, evaluating only the latest message in the channel, exits automatically if the channel is closed
-    /// - Transforms the given `UniversalGamepad` into the correct output array for this `Gamepad`
+impl dyn OutputPersona {
+    /// - Waits until the `pacer` says a gamepad is due, exits automatically once the latest state is closed
+    /// - Runs the `UniversalGamepad` through all stages of the `pipeline`
+    /// - Transforms the given `UniversalGamepad` into the correct output array for this `OutputPersona`
     /// - Attempts to write the entire output array into the file /dev/hidg0
-    pub fn write_to_gadget_continously(&self, receiver: Receiver<UniversalGamepad>) {
-        let start_instant: Instant = Instant::now();
-
-        for gamepad in receiver.iter() {
-            let msg_count = receiver.len();
-            if msg_count > 5 {
-                let all_msgs = receiver.drain().enumerate();
+    /// - Passes the OUT reports of the host on to `host_feedback`
+    pub fn write_to_gadget_continously(
+        &mut self,
+        subscriber: Subscriber<UniversalGamepad>,
+        pipeline: Pipeline,
+        pacer: Pacer,
+        host_feedback: Arc<HostFeedback>,
+        mut mouse: Option<HidgFile>,
+    ) {
+        let mut hidg0 = HidgFile::open("/dev/hidg0");
+
+        pacer.run(subscriber, pipeline, |gamepad| {
+            let usb_output: Vec<u8> = self.universal_gamepad_to_usb_output(gamepad);
+            hidg0.write(&usb_output);
+            write_mouse_movement(&mut mouse, gamepad);
+            self.forward_out_reports(&mut hidg0, &host_feedback);
+        });
+    }
 
-                for (index, _) in all_msgs {
-                    if index + 1 < msg_count {
-                        continue;
-                    }
-                }
-                println!("skipped {msg_count} inputs {:?} after launch", (Instant::now() - start_instant));
-            } else if msg_count > 1 {
-                println!("skipped 1 input {:?} after launch", (Instant::now() - start_instant));
-                continue; // take only the latest inputs
+    /// Reads all OUT reports the host has written since the last call, without waiting for new ones
+    pub fn forward_out_reports(&mut self, hidg: &mut HidgFile, host_feedback: &HostFeedback) {
+        while let Some(report) = hidg.read_out_report() {
+            if let Some(feedback) = self.handle_out_report(&report) {
+                host_feedback.set(feedback);
             }
+        }
+    }
+}
 
-            let usb_output: Vec<u8> = self.universal_gamepad_to_usb_output(&gamepad);
+/// Writes the mouse movement of `gamepad` to the mouse function of the gadget, if both exist
+pub fn write_mouse_movement(mouse: &mut Option<HidgFile>, gamepad: &UniversalGamepad) {
+    if let (Some(mouse), Some(movement)) = (mouse, &gamepad.other.mouse) {
+        mouse.write(&mouse_report(movement));
+    }
+}
 
-            let mut hidg0 = match File::options().write(true).append(false).open("/dev/hidg0") {
-                Ok(file) => file,
-                Err(err) => print_error_and_exit!("Could not open file hidg0", err, 1),
-            };
+/// Report of `usb_gadget::MOUSE_FUNCTION`: no buttons, movement beyond one report is cut off
+pub fn mouse_report(movement: &MouseMovement) -> [u8; 3] {
+    let x: i8 = movement.x.clamp(-127, 127) as i8;
+    let y: i8 = movement.y.clamp(-127, 127) as i8;
+    return [0, x as u8, y as u8];
+}
 
-            match hidg0.write_all(&usb_output) {
-                Ok(_) => (),
-                Err(err) => println!("write to hidg0 failed: {:?}", err),
-            }
+/// The rumble and lightbar color the host asked for, set by the output thread and sent to the gamepads by the input threads
+pub struct HostFeedback {
+    feedback: Mutex<Feedback>,
+
+    /// increases with every change, so each input thread can tell if it has sent the newest feedback
+    generation: AtomicU32,
+}
+
+impl HostFeedback {
+    pub fn new() -> Self {
+        Self {
+            feedback: Mutex::new(((0, 0), (0, 0, 0))),
+            generation: AtomicU32::new(0),
         }
     }
 
-    /// To adjust how precice the interval has to be "hit", `max_deviation` can be used.
-    ///
-    /// - `max_deviation`:
-    ///     - using `1` would mean, that the interval is practically disregarded and writing to file is done as soon as possible
-    ///     - using `0` would nearly never write to file, because this loop itself has a bit of runtime and never hits the interval with 0ns accuracy
-    ///     - useful values are
-    ///         - `~ 0.05`, if its important that writing is done nearly every interval
-    ///         - `< 0.001`, if its important that the interval is precicely hit
-    pub fn write_to_gadget_intervalic(&self, universal_gamepad: Arc<Mutex<UniversalGamepad>>, interval: Duration, max_deviation: f32, recv: Receiver<bool>) {
-        // its safe to use u128 for nanoseconds
-        // 2^64 ns are ~580 years
-        // so 2^128 are 580² years
-
-        let start: Instant = Instant::now();
-        let interval_ns = interval.as_nanos();
-        let mut interval_counts_before: u128 = 0;
-
-        let mut code_ran: bool = false;
-        let mut usb_output: Vec<u8> = Vec::new();
-
-        loop {
-            match recv.try_recv() {
-                Ok(_) | Err(TryRecvError::Disconnected) => break,
-                Err(TryRecvError::Empty) => (),
-            }
-            {
-                if code_ran == false {
-                    // program code that might not run fast enough for interval here
-                    let gamepad_locked = universal_gamepad.lock().expect("Locking Arc<Mutex<UniversalGamepad>> failed!");
-                    usb_output = self.universal_gamepad_to_usb_output(&gamepad_locked);
-                }
-                code_ran = true;
-            }
+    pub fn set(&self, feedback: Feedback) {
+        *self.feedback.lock().expect("host feedback lock poisoned") = feedback;
+        self.generation.fetch_add(1, Ordering::Release);
+    }
 
-            // Is this loop still "on time"?
-            let now: Instant = Instant::now();
-            let elapsed_ns: u128 = (now - start).as_nanos();
-            let interval_counts_now: u128 = elapsed_ns / interval_ns;
+    /// Returns the feedback if it changed since `seen_generation`, which is then set to the current one
+    ///
+    /// Start with `seen_generation = 0` to get nothing until the host sends something
+    pub fn changed_since(&self, seen_generation: &mut u32) -> Option<Feedback> {
+        let generation: u32 = self.generation.load(Ordering::Acquire);
+        if generation == *seen_generation {
+            return None;
+        }
+        *seen_generation = generation;
+        return Some(*self.feedback.lock().expect("host feedback lock poisoned"));
+    }
+}
 
-            // By how many ns does the current run deviate from the cycle
-            let diff_from_interval_ns: u128 = elapsed_ns % interval_ns;
+/// A `/dev/hidg<n>` file of the gadget, opened once and reused for every report
+pub struct HidgFile {
+    path: String,
+    file: Option<File>,
 
-            let is_next_interval: bool = interval_counts_now > interval_counts_before;
-            let is_close_enough: bool = (diff_from_interval_ns as f32 / interval_ns as f32) <= max_deviation;
+    /// opened a second time without blocking, so reading OUT reports never delays the next input report
+    reader: Option<File>,
+}
 
-            if is_next_interval && is_close_enough {
-                {
-                    // code that is supposed to be timed, herek
-                    let mut hidg0 = match File::options().write(true).append(false).open("/dev/hidg0") {
-                        Ok(file) => file,
-                        Err(err) => print_error_and_exit!("Could not open file hidg0", err, 1),
-                    };
+impl HidgFile {
+    /// If opening fails, it is tried again with every report
+    pub fn open(path: &str) -> Self {
+        let mut hidg = Self {
+            path: path.to_string(),
+            file: None,
+            reader: None,
+        };
+        hidg._try_open(true);
+        return hidg;
+    }
 
-                    match hidg0.write_all(&usb_output) {
-                        Ok(_) => (),
-                        Err(err) => println!("write to hidg0 failed: {:?}", err),
-                    }
-                }
+    /// Errors are logged, the output thread keeps running
+    pub fn write(&mut self, report: &[u8]) {
+        if self.file.is_none() {
+            self._try_open(false);
+        }
 
-                interval_counts_before = interval_counts_now;
-                code_ran = false;
-                usb_output.clear();
+        if let Some(file) = &mut self.file {
+            match file.write_all(report) {
+                Ok(_) => (),
+                Err(err) => println!("write to {} failed: {:?}", self.path, err),
             }
-
-            thread::sleep(Duration::from_nanos(1));
         }
     }
 
-    pub fn debug_output_bt_input(&self, gamepad: &UniversalGamepad) {
-        print!("{}", termion::clear::All);
-        print!("{}", termion::cursor::Goto(1, 1));
-        println!(
-            "Lx:{:5?}\tLy:{:5?}\tL: {:5?}\tRx:{:5?}\tRy:{:5?}\tR: {:5?}",
-            gamepad.sticks.left.x,
-            gamepad.sticks.left.y,
-            gamepad.sticks.left.pressed,
-            gamepad.sticks.right.x,
-            gamepad.sticks.right.y,
-            gamepad.sticks.right.pressed,
-        );
-
-        print!("{}", termion::cursor::Goto(1, 2));
-        println!(
-            "Tl:{:5?}\tTr:{:5?}\tBl:{:?}\tBr:{:?}",
-            gamepad.triggers.left, gamepad.triggers.right, gamepad.buttons.bumpers.left, gamepad.buttons.bumpers.right,
-        );
-
-        print!("{}", termion::cursor::Goto(1, 3));
-        println!(
-            "X: {:5?}\tO: {:5?}\t□: {:5?}\t∆: {:5?}",
-            gamepad.buttons.main.lower, gamepad.buttons.main.right, gamepad.buttons.main.left, gamepad.buttons.main.upper
-        );
-
-        print!("{}", termion::cursor::Goto(1, 4));
-        println!(
-            "↑: {:5?}\t→: {:5?}\t↓: {:5?}\t←: {:5?}",
-            gamepad.buttons.dpad.up, gamepad.buttons.dpad.right, gamepad.buttons.dpad.down, gamepad.buttons.dpad.left
-        );
-
-        print!("{}", termion::cursor::Goto(1, 5));
-        println!(
-            "S: {:5?}\tM: {:5?}\tLogo: {:5?}",
-            gamepad.buttons.specials.left, gamepad.buttons.specials.right, gamepad.buttons.specials.logo
-        );
+    /// Returns the next OUT report the host has written, or `None` if there is none right now
+    pub fn read_out_report(&mut self) -> Option<Vec<u8>> {
+        let reader: &mut File = self.reader.as_mut()?;
+        let mut buf: [u8; 64] = [0_u8; 64];
+
+        match reader.read(&mut buf) {
+            Ok(0) => return None,
+            Ok(length) => return Some(buf[..length].to_vec()),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => return None,
+            Err(err) => {
+                println!("read from {} failed: {:?}", self.path, err);
+                return None;
+            }
+        }
     }
 
-    /// creates a `Vec<u8>` that is the HID Report which has to be written in `/dev/hidg0`
-    ///
-    /// The length will be asserted at runtime to be `self.gadget.functions_hid.report_length`. This function will **panic** if the length is not correct
-    pub fn universal_gamepad_to_usb_output(&self, gamepad: &UniversalGamepad) -> Vec<u8> {
-        return (self.universal_gamepad_to_usb_output)(gamepad);
+    /// Only the first failure is logged, the retries would log with every report
+    fn _try_open(&mut self, log_error: bool) {
+        match File::options().write(true).append(false).open(&self.path) {
+            Ok(file) => {
+                if log_error == false {
+                    println!("Opened {} after all", self.path);
+                }
+                self.file = Some(file);
+                self.reader = match File::options().read(true).custom_flags(libc::O_NONBLOCK).open(&self.path) {
+                    Ok(reader) => Some(reader),
+                    Err(err) => {
+                        println!(
+                            "Could not open {} for reading, rumble and lightbar of the host are not passed on: {:?}",
+                            self.path, err
+                        );
+                        None
+                    }
+                };
+            }
+            Err(err) => {
+                if log_error {
+                    println!("Could not open {}, trying again with every report: {:?}", self.path, err);
+                }
+            }
+        }
     }
 }
 
-fn _display_supported_gamepads() -> ! {
-    println!("");
-    println!("Supported gamepads are:");
-    for gamepad in OUTPUT_GAMEPADS {
-        if gamepad.is_supported {
-            println!("{}: with any of {:?} as the argument", gamepad.display_name, gamepad.associated_args);
-        }
+pub fn debug_output_bt_input(gamepad: &UniversalGamepad) {
+    print!("{}", termion::clear::All);
+    print!("{}", termion::cursor::Goto(1, 1));
+    println!(
+        "Lx:{:5?}\tLy:{:5?}\tL: {:5?}\tRx:{:5?}\tRy:{:5?}\tR: {:5?}",
+        gamepad.sticks.left.x, gamepad.sticks.left.y, gamepad.sticks.left.pressed, gamepad.sticks.right.x, gamepad.sticks.right.y, gamepad.sticks.right.pressed,
+    );
+
+    print!("{}", termion::cursor::Goto(1, 2));
+    println!(
+        "Tl:{:5?}\tTr:{:5?}\tBl:{:?}\tBr:{:?}",
+        gamepad.triggers.left, gamepad.triggers.right, gamepad.buttons.bumpers.left, gamepad.buttons.bumpers.right,
+    );
+
+    print!("{}", termion::cursor::Goto(1, 3));
+    println!(
+        "X: {:5?}\tO: {:5?}\t□: {:5?}\t∆: {:5?}",
+        gamepad.buttons.main.lower, gamepad.buttons.main.right, gamepad.buttons.main.left, gamepad.buttons.main.upper
+    );
+
+    print!("{}", termion::cursor::Goto(1, 4));
+    println!(
+        "↑: {:5?}\t→: {:5?}\t↓: {:5?}\t←: {:5?}",
+        gamepad.buttons.dpad.up, gamepad.buttons.dpad.right, gamepad.buttons.dpad.down, gamepad.buttons.dpad.left
+    );
+
+    print!("{}", termion::cursor::Goto(1, 5));
+    println!(
+        "S: {:5?}\tM: {:5?}\tLogo: {:5?}",
+        gamepad.buttons.specials.left, gamepad.buttons.specials.right, gamepad.buttons.specials.logo
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mouse_movement_is_clamped_to_one_report() {
+        assert_eq!(mouse_report(&MouseMovement { x: 5, y: -3 }), [0, 5, 253]);
+        assert_eq!(mouse_report(&MouseMovement { x: 300, y: -300 }), [0, 127, 129]);
     }
-    exit(1);
 }
@@ -1,299 +1,637 @@
-use crate::{universal_gamepad::UniversalGamepad, usb_gadget::*, usb_gamepad::Gamepad};
+use std::time::Instant;
 
-pub const DUALSHOCK: Gamepad = Gamepad {
-    min_bt_report_size: 20,
-    is_supported: false,
-    associated_args: ["ps4", "dualshock"],
+use crate::driver_registry::{InputDriverEntry, OutputPersonaEntry};
+use crate::universal_gamepad::*;
+use crate::usb_gadget::*;
+use crate::usb_gamepad::{Feedback, InputDriver, OutputPersona};
+use crate::usb_gamepad_ps5::{crc32, decode_touch_contact, encode_touch_contact, main_buttons_and_dpad, shoulder_and_special_buttons};
+
+pub const DUALSHOCK_INPUT: InputDriverEntry = InputDriverEntry {
     display_name: "PS4 DualShock",
-    gadget: UsbGadgetDescriptor {
-        bcd_usb: 0x200,
-        b_device_class: 0,
-        b_device_sub_class: 0,
-        b_device_protocol: 0,
-        b_max_packet_size0: 64,
-        id_vendor: 0x054c,
-        id_product: 0x09cc,
-        bcd_device: 0x100,
-        strings_0x409: UsbGadgetStrings {
-            serialnumber: "",
-            product: "Wireless Controller",
-            manufacturer: "Sony Interactive Entertainment",
-        },
-        configs_c1: UsbGadgetConfigs {
-            bm_attributes: 0b11000000,
-            max_power: 500,
-            configs_string: "",
-        },
-        functions_hid: UsbGadgetFunctionsHid {
-            protocol: 0,
-            report_length: 64,
-            hid_subclass: 0,
-            report_descriptor: &[
-                0x05, 0x01, // Usage Page (Generic Desktop Ctrls)
-                0x09, 0x05, // Usage (Game Pad)
-                0xA1, 0x01, // Collection (Application)
-                0x85, 0x01, //   Report ID (1)
-                0x09, 0x30, //   Usage (X)
-                0x09, 0x31, //   Usage (Y)
-                0x09, 0x32, //   Usage (Z)
-                0x09, 0x35, //   Usage (Rz)
-                0x15, 0x00, //   Logical Minimum (0)
-                0x26, 0xFF, 0x00, //   Logical Maximum (255)
-                0x75, 0x08, //   Report Size (8)
-                0x95, 0x04, //   Report Count (4)
-                0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
-                0x09, 0x39, //   Usage (Hat switch)
-                0x15, 0x00, //   Logical Minimum (0)
-                0x25, 0x07, //   Logical Maximum (7)
-                0x35, 0x00, //   Physical Minimum (0)
-                0x46, 0x3B, 0x01, //   Physical Maximum (315)
-                0x65, 0x14, //   Unit (System: English Rotation, Length: Centimeter)
-                0x75, 0x04, //   Report Size (4)
-                0x95, 0x01, //   Report Count (1)
-                0x81, 0x42, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,Null State)
-                0x65, 0x00, //   Unit (None)
-                0x05, 0x09, //   Usage Page (Button)
-                0x19, 0x01, //   Usage Minimum (0x01)
-                0x29, 0x0E, //   Usage Maximum (0x0E)
-                0x15, 0x00, //   Logical Minimum (0)
-                0x25, 0x01, //   Logical Maximum (1)
-                0x75, 0x01, //   Report Size (1)
-                0x95, 0x0E, //   Report Count (14)
-                0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
-                0x06, 0x00, 0xFF, //   Usage Page (Vendor Defined 0xFF00)
-                0x09, 0x20, //   Usage (0x20)
-                0x75, 0x06, //   Report Size (6)
-                0x95, 0x01, //   Report Count (1)
-                0x15, 0x00, //   Logical Minimum (0)
-                0x25, 0x7F, //   Logical Maximum (127)
-                0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
-                0x05, 0x01, //   Usage Page (Generic Desktop Ctrls)
-                0x09, 0x33, //   Usage (Rx)
-                0x09, 0x34, //   Usage (Ry)
-                0x15, 0x00, //   Logical Minimum (0)
-                0x26, 0xFF, 0x00, //   Logical Maximum (255)
-                0x75, 0x08, //   Report Size (8)
-                0x95, 0x02, //   Report Count (2)
-                0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
-                0x06, 0x00, 0xFF, //   Usage Page (Vendor Defined 0xFF00)
-                0x09, 0x21, //   Usage (0x21)
-                0x95, 0x36, //   Report Count (54)
-                0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
-                0x85, 0x05, //   Report ID (5)
-                0x09, 0x22, //   Usage (0x22)
-                0x95, 0x1F, //   Report Count (31)
-                0x91, 0x02, //   Output (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x04, //   Report ID (4)
-                0x09, 0x23, //   Usage (0x23)
-                0x95, 0x24, //   Report Count (36)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x02, //   Report ID (2)
-                0x09, 0x24, //   Usage (0x24)
-                0x95, 0x24, //   Report Count (36)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x08, //   Report ID (8)
-                0x09, 0x25, //   Usage (0x25)
-                0x95, 0x03, //   Report Count (3)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x10, //   Report ID (16)
-                0x09, 0x26, //   Usage (0x26)
-                0x95, 0x04, //   Report Count (4)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x11, //   Report ID (17)
-                0x09, 0x27, //   Usage (0x27)
-                0x95, 0x02, //   Report Count (2)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x12, //   Report ID (18)
-                0x06, 0x02, 0xFF, //   Usage Page (Vendor Defined 0xFF02)
-                0x09, 0x21, //   Usage (0x21)
-                0x95, 0x0F, //   Report Count (15)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x13, //   Report ID (19)
-                0x09, 0x22, //   Usage (0x22)
-                0x95, 0x16, //   Report Count (22)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x14, //   Report ID (20)
-                0x06, 0x05, 0xFF, //   Usage Page (Vendor Defined 0xFF05)
-                0x09, 0x20, //   Usage (0x20)
-                0x95, 0x10, //   Report Count (16)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x15, //   Report ID (21)
-                0x09, 0x21, //   Usage (0x21)
-                0x95, 0x2C, //   Report Count (44)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x06, 0x80, 0xFF, //   Usage Page (Vendor Defined 0xFF80)
-                0x85, 0x80, //   Report ID (-128)
-                0x09, 0x20, //   Usage (0x20)
-                0x95, 0x06, //   Report Count (6)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x81, //   Report ID (-127)
-                0x09, 0x21, //   Usage (0x21)
-                0x95, 0x06, //   Report Count (6)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x82, //   Report ID (-126)
-                0x09, 0x22, //   Usage (0x22)
-                0x95, 0x05, //   Report Count (5)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x83, //   Report ID (-125)
-                0x09, 0x23, //   Usage (0x23)
-                0x95, 0x01, //   Report Count (1)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x84, //   Report ID (-124)
-                0x09, 0x24, //   Usage (0x24)
-                0x95, 0x04, //   Report Count (4)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x85, //   Report ID (-123)
-                0x09, 0x25, //   Usage (0x25)
-                0x95, 0x06, //   Report Count (6)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x86, //   Report ID (-122)
-                0x09, 0x26, //   Usage (0x26)
-                0x95, 0x06, //   Report Count (6)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x87, //   Report ID (-121)
-                0x09, 0x27, //   Usage (0x27)
-                0x95, 0x23, //   Report Count (35)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x88, //   Report ID (-120)
-                0x09, 0x28, //   Usage (0x28)
-                0x95, 0x22, //   Report Count (34)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x89, //   Report ID (-119)
-                0x09, 0x29, //   Usage (0x29)
-                0x95, 0x02, //   Report Count (2)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x90, //   Report ID (-112)
-                0x09, 0x30, //   Usage (0x30)
-                0x95, 0x05, //   Report Count (5)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x91, //   Report ID (-111)
-                0x09, 0x31, //   Usage (0x31)
-                0x95, 0x03, //   Report Count (3)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x92, //   Report ID (-110)
-                0x09, 0x32, //   Usage (0x32)
-                0x95, 0x03, //   Report Count (3)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x93, //   Report ID (-109)
-                0x09, 0x33, //   Usage (0x33)
-                0x95, 0x0C, //   Report Count (12)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xA0, //   Report ID (-96)
-                0x09, 0x40, //   Usage (0x40)
-                0x95, 0x06, //   Report Count (6)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xA1, //   Report ID (-95)
-                0x09, 0x41, //   Usage (0x41)
-                0x95, 0x01, //   Report Count (1)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xA2, //   Report ID (-94)
-                0x09, 0x42, //   Usage (0x42)
-                0x95, 0x01, //   Report Count (1)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xA3, //   Report ID (-93)
-                0x09, 0x43, //   Usage (0x43)
-                0x95, 0x30, //   Report Count (48)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xA4, //   Report ID (-92)
-                0x09, 0x44, //   Usage (0x44)
-                0x95, 0x0D, //   Report Count (13)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xA5, //   Report ID (-91)
-                0x09, 0x45, //   Usage (0x45)
-                0x95, 0x15, //   Report Count (21)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xA6, //   Report ID (-90)
-                0x09, 0x46, //   Usage (0x46)
-                0x95, 0x15, //   Report Count (21)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xF0, //   Report ID (-16)
-                0x09, 0x47, //   Usage (0x47)
-                0x95, 0x3F, //   Report Count (63)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xF1, //   Report ID (-15)
-                0x09, 0x48, //   Usage (0x48)
-                0x95, 0x3F, //   Report Count (63)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xF2, //   Report ID (-14)
-                0x09, 0x49, //   Usage (0x49)
-                0x95, 0x0F, //   Report Count (15)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xA7, //   Report ID (-89)
-                0x09, 0x4A, //   Usage (0x4A)
-                0x95, 0x01, //   Report Count (1)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xA8, //   Report ID (-88)
-                0x09, 0x4B, //   Usage (0x4B)
-                0x95, 0x01, //   Report Count (1)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xA9, //   Report ID (-87)
-                0x09, 0x4C, //   Usage (0x4C)
-                0x95, 0x08, //   Report Count (8)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xAA, //   Report ID (-86)
-                0x09, 0x4E, //   Usage (0x4E)
-                0x95, 0x01, //   Report Count (1)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xAB, //   Report ID (-85)
-                0x09, 0x4F, //   Usage (0x4F)
-                0x95, 0x39, //   Report Count (57)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xAC, //   Report ID (-84)
-                0x09, 0x50, //   Usage (0x50)
-                0x95, 0x39, //   Report Count (57)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xAD, //   Report ID (-83)
-                0x09, 0x51, //   Usage (0x51)
-                0x95, 0x0B, //   Report Count (11)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xAE, //   Report ID (-82)
-                0x09, 0x52, //   Usage (0x52)
-                0x95, 0x01, //   Report Count (1)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xAF, //   Report ID (-81)
-                0x09, 0x53, //   Usage (0x53)
-                0x95, 0x02, //   Report Count (2)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xB0, //   Report ID (-80)
-                0x09, 0x54, //   Usage (0x54)
-                0x95, 0x3F, //   Report Count (63)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xB1, //   Report ID (-79)
-                0x09, 0x55, //   Usage (0x55)
-                0x95, 0x02, //   Report Count (2)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xB2, //   Report ID (-78)
-                0x09, 0x56, //   Usage (0x56)
-                0x95, 0x02, //   Report Count (2)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xE0, //   Report ID (-32)
-                0x09, 0x57, //   Usage (0x57)
-                0x95, 0x02, //   Report Count (2)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xB3, //   Report ID (-77)
-                0x09, 0x55, //   Usage (0x55)
-                0x95, 0x3F, //   Report Count (63)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xB4, //   Report ID (-76)
-                0x09, 0x55, //   Usage (0x55)
-                0x95, 0x3F, //   Report Count (63)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0xC0, // End Collection
-
-                      // 507 bytes
-            ],
-        },
+    vendor_id: 0x054c,
+    product_id: 0x09cc,
+    is_supported: true,
+    create: || Box::new(DualShockInput {}),
+};
+
+pub const DUALSHOCK_OUTPUT: OutputPersonaEntry = OutputPersonaEntry {
+    display_name: "PS4 DualShock",
+    associated_args: ["ps4", "dualshock"],
+    is_supported: true,
+    create: || Box::new(DualShockOutput::new()),
+};
+
+pub const DUALSHOCK_GADGET: UsbGadgetDescriptor = UsbGadgetDescriptor {
+    bcd_usb: 0x200,
+    b_device_class: 0,
+    b_device_sub_class: 0,
+    b_device_protocol: 0,
+    b_max_packet_size0: 64,
+    id_vendor: 0x054c,
+    id_product: 0x09cc,
+    bcd_device: 0x100,
+    strings_0x409: UsbGadgetStrings {
+        serialnumber: "",
+        product: "Wireless Controller",
+        manufacturer: "Sony Interactive Entertainment",
+    },
+    configs_c1: UsbGadgetConfigs {
+        bm_attributes: 0b11000000,
+        max_power: 500,
+        configs_string: "",
+    },
+    functions_hid: UsbGadgetFunctionsHid {
+        protocol: 0,
+        report_length: 64,
+        hid_subclass: 0,
+        report_descriptor: &[
+            0x05, 0x01, // Usage Page (Generic Desktop Ctrls)
+            0x09, 0x05, // Usage (Game Pad)
+            0xA1, 0x01, // Collection (Application)
+            0x85, 0x01, //   Report ID (1)
+            0x09, 0x30, //   Usage (X)
+            0x09, 0x31, //   Usage (Y)
+            0x09, 0x32, //   Usage (Z)
+            0x09, 0x35, //   Usage (Rz)
+            0x15, 0x00, //   Logical Minimum (0)
+            0x26, 0xFF, 0x00, //   Logical Maximum (255)
+            0x75, 0x08, //   Report Size (8)
+            0x95, 0x04, //   Report Count (4)
+            0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+            0x09, 0x39, //   Usage (Hat switch)
+            0x15, 0x00, //   Logical Minimum (0)
+            0x25, 0x07, //   Logical Maximum (7)
+            0x35, 0x00, //   Physical Minimum (0)
+            0x46, 0x3B, 0x01, //   Physical Maximum (315)
+            0x65, 0x14, //   Unit (System: English Rotation, Length: Centimeter)
+            0x75, 0x04, //   Report Size (4)
+            0x95, 0x01, //   Report Count (1)
+            0x81, 0x42, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,Null State)
+            0x65, 0x00, //   Unit (None)
+            0x05, 0x09, //   Usage Page (Button)
+            0x19, 0x01, //   Usage Minimum (0x01)
+            0x29, 0x0E, //   Usage Maximum (0x0E)
+            0x15, 0x00, //   Logical Minimum (0)
+            0x25, 0x01, //   Logical Maximum (1)
+            0x75, 0x01, //   Report Size (1)
+            0x95, 0x0E, //   Report Count (14)
+            0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+            0x06, 0x00, 0xFF, //   Usage Page (Vendor Defined 0xFF00)
+            0x09, 0x20, //   Usage (0x20)
+            0x75, 0x06, //   Report Size (6)
+            0x95, 0x01, //   Report Count (1)
+            0x15, 0x00, //   Logical Minimum (0)
+            0x25, 0x7F, //   Logical Maximum (127)
+            0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+            0x05, 0x01, //   Usage Page (Generic Desktop Ctrls)
+            0x09, 0x33, //   Usage (Rx)
+            0x09, 0x34, //   Usage (Ry)
+            0x15, 0x00, //   Logical Minimum (0)
+            0x26, 0xFF, 0x00, //   Logical Maximum (255)
+            0x75, 0x08, //   Report Size (8)
+            0x95, 0x02, //   Report Count (2)
+            0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+            0x06, 0x00, 0xFF, //   Usage Page (Vendor Defined 0xFF00)
+            0x09, 0x21, //   Usage (0x21)
+            0x95, 0x36, //   Report Count (54)
+            0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+            0x85, 0x05, //   Report ID (5)
+            0x09, 0x22, //   Usage (0x22)
+            0x95, 0x1F, //   Report Count (31)
+            0x91, 0x02, //   Output (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x04, //   Report ID (4)
+            0x09, 0x23, //   Usage (0x23)
+            0x95, 0x24, //   Report Count (36)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x02, //   Report ID (2)
+            0x09, 0x24, //   Usage (0x24)
+            0x95, 0x24, //   Report Count (36)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x08, //   Report ID (8)
+            0x09, 0x25, //   Usage (0x25)
+            0x95, 0x03, //   Report Count (3)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x10, //   Report ID (16)
+            0x09, 0x26, //   Usage (0x26)
+            0x95, 0x04, //   Report Count (4)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x11, //   Report ID (17)
+            0x09, 0x27, //   Usage (0x27)
+            0x95, 0x02, //   Report Count (2)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x12, //   Report ID (18)
+            0x06, 0x02, 0xFF, //   Usage Page (Vendor Defined 0xFF02)
+            0x09, 0x21, //   Usage (0x21)
+            0x95, 0x0F, //   Report Count (15)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x13, //   Report ID (19)
+            0x09, 0x22, //   Usage (0x22)
+            0x95, 0x16, //   Report Count (22)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x14, //   Report ID (20)
+            0x06, 0x05, 0xFF, //   Usage Page (Vendor Defined 0xFF05)
+            0x09, 0x20, //   Usage (0x20)
+            0x95, 0x10, //   Report Count (16)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x15, //   Report ID (21)
+            0x09, 0x21, //   Usage (0x21)
+            0x95, 0x2C, //   Report Count (44)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x06, 0x80, 0xFF, //   Usage Page (Vendor Defined 0xFF80)
+            0x85, 0x80, //   Report ID (-128)
+            0x09, 0x20, //   Usage (0x20)
+            0x95, 0x06, //   Report Count (6)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x81, //   Report ID (-127)
+            0x09, 0x21, //   Usage (0x21)
+            0x95, 0x06, //   Report Count (6)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x82, //   Report ID (-126)
+            0x09, 0x22, //   Usage (0x22)
+            0x95, 0x05, //   Report Count (5)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x83, //   Report ID (-125)
+            0x09, 0x23, //   Usage (0x23)
+            0x95, 0x01, //   Report Count (1)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x84, //   Report ID (-124)
+            0x09, 0x24, //   Usage (0x24)
+            0x95, 0x04, //   Report Count (4)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x85, //   Report ID (-123)
+            0x09, 0x25, //   Usage (0x25)
+            0x95, 0x06, //   Report Count (6)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x86, //   Report ID (-122)
+            0x09, 0x26, //   Usage (0x26)
+            0x95, 0x06, //   Report Count (6)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x87, //   Report ID (-121)
+            0x09, 0x27, //   Usage (0x27)
+            0x95, 0x23, //   Report Count (35)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x88, //   Report ID (-120)
+            0x09, 0x28, //   Usage (0x28)
+            0x95, 0x22, //   Report Count (34)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x89, //   Report ID (-119)
+            0x09, 0x29, //   Usage (0x29)
+            0x95, 0x02, //   Report Count (2)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x90, //   Report ID (-112)
+            0x09, 0x30, //   Usage (0x30)
+            0x95, 0x05, //   Report Count (5)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x91, //   Report ID (-111)
+            0x09, 0x31, //   Usage (0x31)
+            0x95, 0x03, //   Report Count (3)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x92, //   Report ID (-110)
+            0x09, 0x32, //   Usage (0x32)
+            0x95, 0x03, //   Report Count (3)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x93, //   Report ID (-109)
+            0x09, 0x33, //   Usage (0x33)
+            0x95, 0x0C, //   Report Count (12)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xA0, //   Report ID (-96)
+            0x09, 0x40, //   Usage (0x40)
+            0x95, 0x06, //   Report Count (6)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xA1, //   Report ID (-95)
+            0x09, 0x41, //   Usage (0x41)
+            0x95, 0x01, //   Report Count (1)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xA2, //   Report ID (-94)
+            0x09, 0x42, //   Usage (0x42)
+            0x95, 0x01, //   Report Count (1)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xA3, //   Report ID (-93)
+            0x09, 0x43, //   Usage (0x43)
+            0x95, 0x30, //   Report Count (48)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xA4, //   Report ID (-92)
+            0x09, 0x44, //   Usage (0x44)
+            0x95, 0x0D, //   Report Count (13)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xA5, //   Report ID (-91)
+            0x09, 0x45, //   Usage (0x45)
+            0x95, 0x15, //   Report Count (21)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xA6, //   Report ID (-90)
+            0x09, 0x46, //   Usage (0x46)
+            0x95, 0x15, //   Report Count (21)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xF0, //   Report ID (-16)
+            0x09, 0x47, //   Usage (0x47)
+            0x95, 0x3F, //   Report Count (63)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xF1, //   Report ID (-15)
+            0x09, 0x48, //   Usage (0x48)
+            0x95, 0x3F, //   Report Count (63)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xF2, //   Report ID (-14)
+            0x09, 0x49, //   Usage (0x49)
+            0x95, 0x0F, //   Report Count (15)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xA7, //   Report ID (-89)
+            0x09, 0x4A, //   Usage (0x4A)
+            0x95, 0x01, //   Report Count (1)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xA8, //   Report ID (-88)
+            0x09, 0x4B, //   Usage (0x4B)
+            0x95, 0x01, //   Report Count (1)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xA9, //   Report ID (-87)
+            0x09, 0x4C, //   Usage (0x4C)
+            0x95, 0x08, //   Report Count (8)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xAA, //   Report ID (-86)
+            0x09, 0x4E, //   Usage (0x4E)
+            0x95, 0x01, //   Report Count (1)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xAB, //   Report ID (-85)
+            0x09, 0x4F, //   Usage (0x4F)
+            0x95, 0x39, //   Report Count (57)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xAC, //   Report ID (-84)
+            0x09, 0x50, //   Usage (0x50)
+            0x95, 0x39, //   Report Count (57)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xAD, //   Report ID (-83)
+            0x09, 0x51, //   Usage (0x51)
+            0x95, 0x0B, //   Report Count (11)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xAE, //   Report ID (-82)
+            0x09, 0x52, //   Usage (0x52)
+            0x95, 0x01, //   Report Count (1)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xAF, //   Report ID (-81)
+            0x09, 0x53, //   Usage (0x53)
+            0x95, 0x02, //   Report Count (2)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xB0, //   Report ID (-80)
+            0x09, 0x54, //   Usage (0x54)
+            0x95, 0x3F, //   Report Count (63)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xB1, //   Report ID (-79)
+            0x09, 0x55, //   Usage (0x55)
+            0x95, 0x02, //   Report Count (2)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xB2, //   Report ID (-78)
+            0x09, 0x56, //   Usage (0x56)
+            0x95, 0x02, //   Report Count (2)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xE0, //   Report ID (-32)
+            0x09, 0x57, //   Usage (0x57)
+            0x95, 0x02, //   Report Count (2)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xB3, //   Report ID (-77)
+            0x09, 0x55, //   Usage (0x55)
+            0x95, 0x3F, //   Report Count (63)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xB4, //   Report ID (-76)
+            0x09, 0x55, //   Usage (0x55)
+            0x95, 0x3F, //   Report Count (63)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0xC0, // End Collection
+
+                  // 507 bytes
+        ],
     },
-    bt_input_to_universal_gamepad: _bt_input_to_universal_gamepad_dualsense,
-    universal_gamepad_to_usb_output: _universal_gamepad_to_usb_output_dualsense,
 };
 
-fn _bt_input_to_universal_gamepad_dualsense(_bt_input: &Vec<u8>) -> UniversalGamepad {
-    todo!();
+/// Reads the bluetooth input reports of a DualShock 4
+///
+/// Once the host has read its calibration, the gamepad sends the full report `0x11`, before that only the short report `0x01`
+pub struct DualShockInput {}
+
+impl InputDriver for DualShockInput {
+    fn display_name(&self) -> &'static str {
+        return DUALSHOCK_INPUT.display_name;
+    }
+
+    /// The short report `0x01` ends with the triggers
+    fn min_bt_report_size(&self) -> usize {
+        return 10;
+    }
+
+    fn bt_input_to_universal_gamepad(&mut self, bt_input: &[u8]) -> UniversalGamepad {
+        return _bt_input_to_universal_gamepad(bt_input);
+    }
+
+    /// Output report `0x11` (bluetooth), layout as in the linux driver `hid-playstation`:
+    /// - byte 1 enables the HID and CRC mode and sets the poll interval, byte 2 is the audio control
+    /// - from byte 3 on the same values as the USB output report `0x05` (see `DualShockOutput::handle_out_report()`)
+    /// - the last 4 bytes are a CRC32 of `0xA2` followed by the report
+    fn bt_output_report(&mut self, rumble: (u8, u8), lightbar: (u8, u8, u8)) -> Option<Vec<u8>> {
+        let mut report: Vec<u8> = vec![0; 78];
+        report[0] = 0x11;
+        report[1] = 0b1100_0000 | BT_POLL_INTERVAL_MS;
+
+        // rumble and lightbar are valid
+        report[3] = 0b0000_0011;
+        (report[6], report[7]) = rumble;
+        (report[8], report[9], report[10]) = lightbar;
+
+        let crc: u32 = crc32(&[&[0xA2], &report[..74]]);
+        report[74..].copy_from_slice(&crc.to_le_bytes());
+
+        return Some(report);
+    }
+}
+
+/// Asked for with every output report, the same as the linux driver uses
+const BT_POLL_INTERVAL_MS: u8 = 4;
+
+/// The full report has 2 more bytes in front, after that it is the same as the usb report `0x01`.
+/// Everything up to the second touch point is needed
+const BT_FULL_REPORT_SIZE: usize = 45;
+
+fn _bt_input_to_universal_gamepad(bt_input: &[u8]) -> UniversalGamepad {
+    let mut gamepad: UniversalGamepad = UniversalGamepad::nothing_pressed();
+
+    // from here on the byte positions are the same as in the usb report
+    let input: &[u8] = match bt_input[0] {
+        0x11 => &bt_input[2..],
+        _ => bt_input,
+    };
+    let dpad_byte = 0b00001111 & input[5];
+
+    gamepad.sticks = Sticks {
+        left: Stick {
+            x: input[1],
+            y: input[2],
+            pressed: (input[6] & 0b0100_0000 != 0),
+        },
+        right: Stick {
+            x: input[3],
+            y: input[4],
+            pressed: (input[6] & 0b1000_0000 != 0),
+        },
+    };
+    gamepad.triggers = Triggers {
+        left: input[8],
+        right: input[9],
+    };
+    gamepad.buttons.bumpers = Bumpers {
+        left: (input[6] & 0b0000_0001 != 0),
+        right: (input[6] & 0b0000_0010 != 0),
+    };
+    gamepad.buttons.main = MainButtons {
+        upper: (input[5] & 0b1000_0000 != 0),
+        right: (input[5] & 0b0100_0000 != 0),
+        lower: (input[5] & 0b0010_0000 != 0),
+        left: (input[5] & 0b0001_0000 != 0),
+    };
+    gamepad.buttons.dpad = DPad {
+        right: (dpad_byte == 1 || dpad_byte == 2 || dpad_byte == 3),
+        down: (dpad_byte == 3 || dpad_byte == 4 || dpad_byte == 5),
+        left: (dpad_byte == 5 || dpad_byte == 6 || dpad_byte == 7),
+        up: (dpad_byte == 0 || dpad_byte == 1 || dpad_byte == 7),
+    };
+    gamepad.buttons.specials = SpecialButtons {
+        right: (input[6] & 0b0010_0000 != 0),
+        left: (input[6] & 0b0001_0000 != 0),
+        logo: (input[7] & 0b0000_0001 != 0),
+    };
+    gamepad.other.touchpad = Some(Touchpad {
+        contacts: [TouchContact::untouched(), TouchContact::untouched()],
+        pressed: (input[7] & 0b0000_0010 != 0),
+    });
+
+    if bt_input[0] != 0x11 || bt_input.len() < BT_FULL_REPORT_SIZE {
+        return gamepad;
+    }
+
+    gamepad.other.gyroscope = Some(Gyroscope {
+        x: i16::from_le_bytes([input[13], input[14]]),
+        y: i16::from_le_bytes([input[15], input[16]]),
+        z: i16::from_le_bytes([input[17], input[18]]),
+    });
+    gamepad.other.accelerometer = Some(Accelerometer {
+        x: i16::from_le_bytes([input[19], input[20]]),
+        y: i16::from_le_bytes([input[21], input[22]]),
+        z: i16::from_le_bytes([input[23], input[24]]),
+    });
+    if let Some(touchpad) = &mut gamepad.other.touchpad {
+        touchpad.contacts = [decode_touch_contact(&input[35..39]), decode_touch_contact(&input[39..43])];
+    }
+
+    // lower nibble is the battery level in 10% steps (up to 11 while charging), bit 4 is set while the cable is connected
+    let battery_status: u8 = input[30];
+    let level: u8 = battery_status & 0x0F;
+    gamepad.other.battery = Some(Battery {
+        level_percent: u8::min(level * 10 + 5, 100),
+        charging: (battery_status & 0x10 != 0) && level < 10,
+    });
+
+    return gamepad;
+}
+
+/// Presents itself to the host as a DualShock 4 connected via USB
+pub struct DualShockOutput {
+    /// Rumble motor strength (right, left) last requested by the host with output report `0x05`
+    pub rumble: (u8, u8),
+
+    /// Lightbar color (r, g, b) last requested by the host with output report `0x05`
+    pub lightbar: (u8, u8, u8),
+
+    /// 6 bit counter in byte 7, increases with every report
+    report_counter: u8,
+
+    /// Reference for the sensor timestamp
+    start: Instant,
+}
+
+impl DualShockOutput {
+    pub fn new() -> Self {
+        Self {
+            rumble: (0, 0),
+            lightbar: (0, 0, 0),
+            report_counter: 0,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl OutputPersona for DualShockOutput {
+    fn display_name(&self) -> &'static str {
+        return DUALSHOCK_OUTPUT.display_name;
+    }
+
+    fn gadget(&self) -> &'static UsbGadgetDescriptor {
+        return &DUALSHOCK_GADGET;
+    }
 
-    // TODO set min_bt_report_size correctly
+    fn universal_gamepad_to_usb_output(&mut self, gamepad: &UniversalGamepad) -> Vec<u8> {
+        self.report_counter = (self.report_counter + 1) & 0b0011_1111;
+
+        // The real gamepad counts in units of 16/3 µs, the linux driver converts this back to µs
+        let timestamp_us: u128 = (Instant::now() - self.start).as_micros();
+        let sensor_timestamp: u16 = (timestamp_us * 3 / 16) as u16;
+
+        return _universal_gamepad_to_usb_output(gamepad, self.report_counter, sensor_timestamp);
+    }
+
+    /// Output report `0x05` (USB), layout as in the linux driver `hid-playstation`:
+    /// - byte 1 are flags which of the following values are valid
+    /// - byte 4 and 5 are the right and left rumble motor
+    /// - bytes 6 - 8 are the lightbar color
+    fn handle_out_report(&mut self, report: &[u8]) -> Option<Feedback> {
+        if report.len() < 9 || report[0] != 0x05 {
+            return None;
+        }
+
+        let valid_flag0: u8 = report[1];
+
+        // bit 0: rumble, bit 1: lightbar
+        if valid_flag0 & 0b0000_0001 != 0 {
+            self.rumble = (report[4], report[5]);
+        }
+        if valid_flag0 & 0b0000_0010 != 0 {
+            self.lightbar = (report[6], report[7], report[8]);
+        }
+
+        if valid_flag0 & 0b0000_0011 == 0 {
+            return None;
+        }
+        return Some((self.rumble, self.lightbar));
+    }
 }
 
-fn _universal_gamepad_to_usb_output_dualsense(_gamepad: &UniversalGamepad) -> Vec<u8> {
-    todo!();
+fn _universal_gamepad_to_usb_output(gamepad: &UniversalGamepad, report_counter: u8, sensor_timestamp: u16) -> Vec<u8> {
+    let touchpad_pressed: bool = match &gamepad.other.touchpad {
+        Some(touchpad) => touchpad.pressed,
+        None => false,
+    };
+
+    let mut out: Vec<u8> = vec![0; 64];
+
+    out[0] = 0x01; // report id
+    out[1] = gamepad.sticks.left.x;
+    out[2] = gamepad.sticks.left.y;
+    out[3] = gamepad.sticks.right.x;
+    out[4] = gamepad.sticks.right.y;
+    out[5] = main_buttons_and_dpad(gamepad);
+    out[6] = shoulder_and_special_buttons(gamepad);
+    out[7] = (report_counter << 2) | ((touchpad_pressed as u8) << 1) | (gamepad.buttons.specials.logo as u8);
+    out[8] = gamepad.triggers.left;
+    out[9] = gamepad.triggers.right;
+    out[10..12].copy_from_slice(&sensor_timestamp.to_le_bytes());
+    // out[12] is the temperature
+
+    if let Some(gyroscope) = &gamepad.other.gyroscope {
+        out[13..15].copy_from_slice(&gyroscope.x.to_le_bytes());
+        out[15..17].copy_from_slice(&gyroscope.y.to_le_bytes());
+        out[17..19].copy_from_slice(&gyroscope.z.to_le_bytes());
+    }
+    if let Some(accelerometer) = &gamepad.other.accelerometer {
+        out[19..21].copy_from_slice(&accelerometer.x.to_le_bytes());
+        out[21..23].copy_from_slice(&accelerometer.y.to_le_bytes());
+        out[23..25].copy_from_slice(&accelerometer.z.to_le_bytes());
+    }
+
+    // lower nibble is the battery level in 10% steps (11 is full while charging), bit 4 is set while the cable is connected
+    out[30] = match &gamepad.other.battery {
+        Some(battery) if battery.charging => 0x10 | u8::min(battery.level_percent / 10, 10),
+        Some(battery) => u8::min(battery.level_percent / 10, 10),
+        // An input gamepad without battery is powered, like the gadget itself
+        None => 0x10 | 11,
+    };
+
+    // one touch report, with its own counter
+    out[33] = 1;
+    out[34] = report_counter;
+    let untouched: TouchContact = TouchContact::untouched();
+    let (first_contact, second_contact) = match &gamepad.other.touchpad {
+        Some(touchpad) => (&touchpad.contacts[0], &touchpad.contacts[1]),
+        None => (&untouched, &untouched),
+    };
+    out[35..39].copy_from_slice(&encode_touch_contact(first_contact));
+    out[39..43].copy_from_slice(&encode_touch_contact(second_contact));
+
+    let expected_length = DUALSHOCK_GADGET.functions_hid.report_length as usize;
+    let vec_length = out.len();
+    assert!(
+        expected_length == vec_length,
+        "The given vector for the usb gadget output is not the correct length. Expected: {expected_length}  Actual: {vec_length}"
+    );
+
+    return out;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usb_report_reads_back_as_bt_report() {
+        let mut gamepad = UniversalGamepad::nothing_pressed();
+        gamepad.sticks.left.x = 12;
+        gamepad.sticks.right.y = 240;
+        gamepad.sticks.right.pressed = true;
+        gamepad.triggers.left = 200;
+        gamepad.buttons.main.right = true;
+        gamepad.buttons.dpad.down = true;
+        gamepad.buttons.dpad.left = true;
+        gamepad.buttons.bumpers.right = true;
+        gamepad.buttons.specials.logo = true;
+        gamepad.other.gyroscope = Some(Gyroscope { x: -300, y: 5, z: 1000 });
+        gamepad.other.accelerometer = Some(Accelerometer { x: 10, y: 8000, z: -20 });
+        gamepad.other.battery = Some(Battery {
+            level_percent: 55,
+            charging: true,
+        });
+        gamepad.other.touchpad = Some(Touchpad {
+            contacts: [
+                TouchContact {
+                    touched: true,
+                    id: 3,
+                    x_coord: 1000,
+                    y_coord: 500,
+                },
+                TouchContact::untouched(),
+            ],
+            pressed: true,
+        });
+
+        let usb_report: Vec<u8> = DualShockOutput::new().universal_gamepad_to_usb_output(&gamepad);
+        assert_eq!(usb_report.len(), 64);
+
+        // the full bluetooth report has 2 more bytes in front of the same values
+        let mut bt_report: Vec<u8> = vec![0x11, 0xC0];
+        bt_report.extend_from_slice(&usb_report);
+        bt_report.resize(78, 0);
+
+        assert_eq!(DualShockInput {}.bt_input_to_universal_gamepad(&bt_report), gamepad);
+    }
+
+    #[test]
+    fn short_bt_report_has_the_buttons() {
+        let bt_report: Vec<u8> = vec![0x01, 128, 128, 128, 128, 0b0010_1000, 0b0000_0001, 0, 0, 255];
+
+        let gamepad = DualShockInput {}.bt_input_to_universal_gamepad(&bt_report);
+
+        assert!(gamepad.buttons.main.lower);
+        assert!(gamepad.buttons.bumpers.left);
+        assert_eq!(gamepad.triggers.right, 255);
+        assert_eq!(gamepad.other.gyroscope, None);
+    }
+
+    #[test]
+    fn host_rumble_and_lightbar_become_a_bt_report() {
+        let mut persona = DualShockOutput::new();
+        let out_report: Vec<u8> = vec![0x05, 0b0000_0011, 0, 0, 40, 200, 128, 0, 255];
+
+        let (rumble, lightbar) = persona.handle_out_report(&out_report).expect("rumble and lightbar are valid");
+        let bt_report: Vec<u8> = DualShockInput {}.bt_output_report(rumble, lightbar).unwrap();
+
+        assert_eq!(bt_report.len(), 78);
+        assert_eq!((bt_report[0], bt_report[3]), (0x11, 0b0000_0011));
+        assert_eq!((bt_report[6], bt_report[7]), (40, 200));
+        assert_eq!((bt_report[8], bt_report[9], bt_report[10]), (128, 0, 255));
+        let crc = u32::from_le_bytes([bt_report[74], bt_report[75], bt_report[76], bt_report[77]]);
+        assert_eq!(crc, crc32(&[&[0xA2], &bt_report[..74]]));
+    }
 }
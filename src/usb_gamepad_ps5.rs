@@ -1,210 +1,385 @@
+use std::time::Instant;
+
+use crate::driver_registry::{InputDriverEntry, OutputPersonaEntry};
 use crate::universal_gamepad::*;
 use crate::usb_gadget::*;
-use crate::usb_gamepad::Gamepad;
+use crate::usb_gamepad::{Feedback, InputDriver, OutputPersona};
 use crate::UsbGadgetDescriptor;
 
-pub const DUALSENSE: Gamepad = Gamepad {
-    min_bt_report_size: 12,
+pub const DUALSENSE_INPUT: InputDriverEntry = InputDriverEntry {
+    display_name: "PS5 DualSense",
+    vendor_id: 0x054c,
+    product_id: 0x0ce6,
     is_supported: true,
-    associated_args: ["ps5", "dualsense"],
+    create: || Box::new(DualSenseInput { output_sequence: 0 }),
+};
+
+pub const DUALSENSE_OUTPUT: OutputPersonaEntry = OutputPersonaEntry {
     display_name: "PS5 DualSense",
-    gadget: UsbGadgetDescriptor {
-        bcd_usb: 0x200,
-        b_device_class: 0,
-        b_device_sub_class: 0,
-        b_device_protocol: 0,
-        b_max_packet_size0: 64,
-        id_vendor: 0x054c,
-        id_product: 0x0ce6,
-        bcd_device: 0x100,
-        strings_0x409: UsbGadgetStrings {
-            manufacturer: "Sony Interactive Entertainment",
-            product: "Wireless Controller",
-            serialnumber: "",
-        },
-        configs_c1: UsbGadgetConfigs {
-            bm_attributes: 0b11000000,
-            max_power: 500,
-            configs_string: "",
-        },
-        functions_hid: UsbGadgetFunctionsHid {
-            hid_subclass: 0,
-            protocol: 0,
-            report_length: 64,
-            report_descriptor: &[
-                0x05, 0x01, // Usage Page (Generic Desktop Ctrls)
-                0x09, 0x05, // Usage (Game Pad)
-                0xA1, 0x01, // Collection (Application)
-                0x85, 0x01, //   Report ID (1)
-                0x09, 0x30, //   Usage (X)                          Describes actual position (xyz coords in world)
-                0x09, 0x31, //   Usage (Y)                          Describes actual position (xyz coords in world)
-                0x09, 0x32, //   Usage (Z)                          Describes actual position (xyz coords in world)
-                0x09, 0x35, //   Usage (Rz)                         Describes actual rotation xyz
-                0x09, 0x33, //   Usage (Rx)                         Describes actual rotation xyz
-                0x09, 0x34, //   Usage (Ry)                         Describes actual rotation xyz
-                0x15, 0x00, //   Logical Minimum (0)                All of these coords can range from 0 to 255 (both inclusive)
-                0x26, 0xFF, 0x00, //   Logical Maximum (255)        All of these coords can range from 0 to 255 (both inclusive)
-                0x75, 0x08, //   Report Size (8)                    [0, 255] is represented by 8 bit
-                0x95, 0x06, //   Report Count (6)                   = 6x8bit
-                0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
-                //                               ^ Rel instead of Abs would mean they represent the change since last step
-                //
-                0x06, 0x00, 0xFF, //   Usage Page (Vendor Defined 0xFF00)
-                0x09, 0x20, //   Usage (0x20)
-                0x95, 0x01, //   Report Count (1)
-                0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
-                //
-                0x05, 0x01, //   Usage Page (Generic Desktop Ctrls)
-                0x09, 0x39, //   Usage (Hat switch)
-                0x15, 0x00, //   Logical Minimum (0)
-                0x25, 0x07, //   Logical Maximum (7)
-                0x35, 0x00, //   Physical Minimum (0)
-                0x46, 0x3B, 0x01, //   Physical Maximum (315)
-                0x65, 0x14, //   Unit (System: English Rotation, Length: Centimeter)
-                0x75, 0x04, //   Report Size (4)
-                0x95, 0x01, //   Report Count (1)
-                0x81, 0x42, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,Null State)
-                0x65, 0x00, //   Unit (None)
-                //
-                0x05, 0x09, //   Usage Page (Button)
-                0x19, 0x01, //   Usage Minimum (0x01)               First Button is ID 1
-                0x29, 0x0F, //   Usage Maximum (0x0F)               Last Button is ID 15
-                0x15, 0x00, //   Logical Minimum (0)                Each Button can send 0
-                0x25, 0x01, //   Logical Maximum (1)                or 1
-                0x75, 0x01, //   Report Size (1)                    sending 1/0 needs 1 bit
-                0x95, 0x0F, //   Report Count (15)                  Confirms that there are 15 Buttons
-                0x81,
-                0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)       Information about what these Buttons are (HID spec Sec. 6.2.2.5)
-                //
-                0x06, 0x00, 0xFF, //   Usage Page (Vendor Defined 0xFF00)
-                0x09, 0x21, //   Usage (0x21)
-                0x95, 0x0D, //   Report Count (13)
-                0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
-                //
-                0x06, 0x00, 0xFF, //   Usage Page (Vendor Defined 0xFF00)
-                0x09, 0x22, //   Usage (0x22)
-                0x15, 0x00, //   Logical Minimum (0)
-                0x26, 0xFF, 0x00, //   Logical Maximum (255)
-                0x75, 0x08, //   Report Size (8)
-                0x95, 0x34, //   Report Count (52)
-                0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
-                0x85, 0x02, //   Report ID (2)
-                //
-                // This might be the rumble???
-                0x09, 0x23, //   Usage (0x23)
-                0x95, 0x2F, //   Report Count (47)
-                0x91, 0x02, //   Output (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x05, //   Report ID (5)
-                //
-                // The following are Feature Reports, probably for the LEDs
-                0x09, 0x33, //   Usage (0x33)
-                0x95, 0x28, //   Report Count (40)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x08, //   Report ID (8)
-                //
-                0x09, 0x34, //   Usage (0x34)
-                0x95, 0x2F, //   Report Count (47)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x09, //   Report ID (9)
-                //
-                0x09, 0x24, //   Usage (0x24)
-                0x95, 0x13, //   Report Count (19)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x0A, //   Report ID (10)
-                //
-                0x09, 0x25, //   Usage (0x25)
-                0x95, 0x1A, //   Report Count (26)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x20, //   Report ID (32)
-                //
-                0x09, 0x26, //   Usage (0x26)
-                0x95, 0x3F, //   Report Count (63)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x21, //   Report ID (33)
-                //
-                0x09, 0x27, //   Usage (0x27)
-                0x95, 0x04, //   Report Count (4)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x22, //   Report ID (34)
-                //
-                0x09, 0x40, //   Usage (0x40)
-                0x95, 0x3F, //   Report Count (63)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x80, //   Report ID (-128)
-                //
-                0x09, 0x28, //   Usage (0x28)
-                0x95, 0x3F, //   Report Count (63)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x81, //   Report ID (-127)
-                //
-                0x09, 0x29, //   Usage (0x29)
-                0x95, 0x3F, //   Report Count (63)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x82, //   Report ID (-126)
-                //
-                0x09, 0x2A, //   Usage (0x2A)
-                0x95, 0x09, //   Report Count (9)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x83, //   Report ID (-125)
-                //
-                0x09, 0x2B, //   Usage (0x2B)
-                0x95, 0x3F, //   Report Count (63)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x84, //   Report ID (-124)
-                //
-                0x09, 0x2C, //   Usage (0x2C)
-                0x95, 0x3F, //   Report Count (63)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0x85, //   Report ID (-123)
-                //
-                0x09, 0x2D, //   Usage (0x2D)
-                0x95, 0x02, //   Report Count (2)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xA0, //   Report ID (-96)
-                //
-                0x09, 0x2E, //   Usage (0x2E)
-                0x95, 0x01, //   Report Count (1)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xE0, //   Report ID (-32)
-                //
-                0x09, 0x2F, //   Usage (0x2F)
-                0x95, 0x3F, //   Report Count (63)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xF0, //   Report ID (-16)
-                //
-                0x09, 0x30, //   Usage (0x30)
-                0x95, 0x3F, //   Report Count (63)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xF1, //   Report ID (-15)
-                //
-                0x09, 0x31, //   Usage (0x31)
-                0x95, 0x3F, //   Report Count (63)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xF2, //   Report ID (-14)
-                //
-                0x09, 0x32, //   Usage (0x32)
-                0x95, 0x0F, //   Report Count (15)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xF4, //   Report ID (-12)
-                //
-                0x09, 0x35, //   Usage (0x35)
-                0x95, 0x3F, //   Report Count (63)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                0x85, 0xF5, //   Report ID (-11)
-                //
-                0x09, 0x36, //   Usage (0x36)
-                0x95, 0x03, //   Report Count (3)
-                0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
-                //
-                0xC0, //   End of Collection with Report ID 1
-            ],
-        },
+    associated_args: ["ps5", "dualsense"],
+    is_supported: true,
+    create: || Box::new(DualSenseOutput::new()),
+};
+
+pub const DUALSENSE_GADGET: UsbGadgetDescriptor = UsbGadgetDescriptor {
+    bcd_usb: 0x200,
+    b_device_class: 0,
+    b_device_sub_class: 0,
+    b_device_protocol: 0,
+    b_max_packet_size0: 64,
+    id_vendor: 0x054c,
+    id_product: 0x0ce6,
+    bcd_device: 0x100,
+    strings_0x409: UsbGadgetStrings {
+        manufacturer: "Sony Interactive Entertainment",
+        product: "Wireless Controller",
+        serialnumber: "",
+    },
+    configs_c1: UsbGadgetConfigs {
+        bm_attributes: 0b11000000,
+        max_power: 500,
+        configs_string: "",
+    },
+    functions_hid: UsbGadgetFunctionsHid {
+        hid_subclass: 0,
+        protocol: 0,
+        report_length: 64,
+        report_descriptor: &[
+            0x05, 0x01, // Usage Page (Generic Desktop Ctrls)
+            0x09, 0x05, // Usage (Game Pad)
+            0xA1, 0x01, // Collection (Application)
+            0x85, 0x01, //   Report ID (1)
+            0x09, 0x30, //   Usage (X)                          Describes actual position (xyz coords in world)
+            0x09, 0x31, //   Usage (Y)                          Describes actual position (xyz coords in world)
+            0x09, 0x32, //   Usage (Z)                          Describes actual position (xyz coords in world)
+            0x09, 0x35, //   Usage (Rz)                         Describes actual rotation xyz
+            0x09, 0x33, //   Usage (Rx)                         Describes actual rotation xyz
+            0x09, 0x34, //   Usage (Ry)                         Describes actual rotation xyz
+            0x15, 0x00, //   Logical Minimum (0)                All of these coords can range from 0 to 255 (both inclusive)
+            0x26, 0xFF, 0x00, //   Logical Maximum (255)        All of these coords can range from 0 to 255 (both inclusive)
+            0x75, 0x08, //   Report Size (8)                    [0, 255] is represented by 8 bit
+            0x95, 0x06, //   Report Count (6)                   = 6x8bit
+            0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+            //                               ^ Rel instead of Abs would mean they represent the change since last step
+            //
+            0x06, 0x00, 0xFF, //   Usage Page (Vendor Defined 0xFF00)
+            0x09, 0x20, //   Usage (0x20)
+            0x95, 0x01, //   Report Count (1)
+            0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+            //
+            0x05, 0x01, //   Usage Page (Generic Desktop Ctrls)
+            0x09, 0x39, //   Usage (Hat switch)
+            0x15, 0x00, //   Logical Minimum (0)
+            0x25, 0x07, //   Logical Maximum (7)
+            0x35, 0x00, //   Physical Minimum (0)
+            0x46, 0x3B, 0x01, //   Physical Maximum (315)
+            0x65, 0x14, //   Unit (System: English Rotation, Length: Centimeter)
+            0x75, 0x04, //   Report Size (4)
+            0x95, 0x01, //   Report Count (1)
+            0x81, 0x42, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,Null State)
+            0x65, 0x00, //   Unit (None)
+            //
+            0x05, 0x09, //   Usage Page (Button)
+            0x19, 0x01, //   Usage Minimum (0x01)               First Button is ID 1
+            0x29, 0x0F, //   Usage Maximum (0x0F)               Last Button is ID 15
+            0x15, 0x00, //   Logical Minimum (0)                Each Button can send 0
+            0x25, 0x01, //   Logical Maximum (1)                or 1
+            0x75, 0x01, //   Report Size (1)                    sending 1/0 needs 1 bit
+            0x95, 0x0F, //   Report Count (15)                  Confirms that there are 15 Buttons
+            0x81,
+            0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)       Information about what these Buttons are (HID spec Sec. 6.2.2.5)
+            //
+            0x06, 0x00, 0xFF, //   Usage Page (Vendor Defined 0xFF00)
+            0x09, 0x21, //   Usage (0x21)
+            0x95, 0x0D, //   Report Count (13)
+            0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+            //
+            0x06, 0x00, 0xFF, //   Usage Page (Vendor Defined 0xFF00)
+            0x09, 0x22, //   Usage (0x22)
+            0x15, 0x00, //   Logical Minimum (0)
+            0x26, 0xFF, 0x00, //   Logical Maximum (255)
+            0x75, 0x08, //   Report Size (8)
+            0x95, 0x34, //   Report Count (52)
+            0x81, 0x02, //   Input (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position)
+            0x85, 0x02, //   Report ID (2)
+            //
+            // This might be the rumble???
+            0x09, 0x23, //   Usage (0x23)
+            0x95, 0x2F, //   Report Count (47)
+            0x91, 0x02, //   Output (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x05, //   Report ID (5)
+            //
+            // The following are Feature Reports, probably for the LEDs
+            0x09, 0x33, //   Usage (0x33)
+            0x95, 0x28, //   Report Count (40)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x08, //   Report ID (8)
+            //
+            0x09, 0x34, //   Usage (0x34)
+            0x95, 0x2F, //   Report Count (47)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x09, //   Report ID (9)
+            //
+            0x09, 0x24, //   Usage (0x24)
+            0x95, 0x13, //   Report Count (19)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x0A, //   Report ID (10)
+            //
+            0x09, 0x25, //   Usage (0x25)
+            0x95, 0x1A, //   Report Count (26)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x20, //   Report ID (32)
+            //
+            0x09, 0x26, //   Usage (0x26)
+            0x95, 0x3F, //   Report Count (63)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x21, //   Report ID (33)
+            //
+            0x09, 0x27, //   Usage (0x27)
+            0x95, 0x04, //   Report Count (4)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x22, //   Report ID (34)
+            //
+            0x09, 0x40, //   Usage (0x40)
+            0x95, 0x3F, //   Report Count (63)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x80, //   Report ID (-128)
+            //
+            0x09, 0x28, //   Usage (0x28)
+            0x95, 0x3F, //   Report Count (63)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x81, //   Report ID (-127)
+            //
+            0x09, 0x29, //   Usage (0x29)
+            0x95, 0x3F, //   Report Count (63)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x82, //   Report ID (-126)
+            //
+            0x09, 0x2A, //   Usage (0x2A)
+            0x95, 0x09, //   Report Count (9)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x83, //   Report ID (-125)
+            //
+            0x09, 0x2B, //   Usage (0x2B)
+            0x95, 0x3F, //   Report Count (63)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x84, //   Report ID (-124)
+            //
+            0x09, 0x2C, //   Usage (0x2C)
+            0x95, 0x3F, //   Report Count (63)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0x85, //   Report ID (-123)
+            //
+            0x09, 0x2D, //   Usage (0x2D)
+            0x95, 0x02, //   Report Count (2)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xA0, //   Report ID (-96)
+            //
+            0x09, 0x2E, //   Usage (0x2E)
+            0x95, 0x01, //   Report Count (1)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xE0, //   Report ID (-32)
+            //
+            0x09, 0x2F, //   Usage (0x2F)
+            0x95, 0x3F, //   Report Count (63)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xF0, //   Report ID (-16)
+            //
+            0x09, 0x30, //   Usage (0x30)
+            0x95, 0x3F, //   Report Count (63)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xF1, //   Report ID (-15)
+            //
+            0x09, 0x31, //   Usage (0x31)
+            0x95, 0x3F, //   Report Count (63)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xF2, //   Report ID (-14)
+            //
+            0x09, 0x32, //   Usage (0x32)
+            0x95, 0x0F, //   Report Count (15)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xF4, //   Report ID (-12)
+            //
+            0x09, 0x35, //   Usage (0x35)
+            0x95, 0x3F, //   Report Count (63)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            0x85, 0xF5, //   Report ID (-11)
+            //
+            0x09, 0x36, //   Usage (0x36)
+            0x95, 0x03, //   Report Count (3)
+            0xB1, 0x02, //   Feature (Data,Var,Abs,No Wrap,Linear,Preferred State,No Null Position,Non-volatile)
+            //
+            0xC0, //   End of Collection with Report ID 1
+        ],
     },
-    bt_input_to_universal_gamepad: _bt_input_to_universal_gamepad,
-    universal_gamepad_to_usb_output: _universal_gamepad_to_usb_output,
 };
 
-fn _bt_input_to_universal_gamepad(bt_input: &Vec<u8>) -> UniversalGamepad {
+/// Reads the bluetooth input report `0x31` of a DualSense
+pub struct DualSenseInput {
+    /// 4 bit counter of the bluetooth output reports
+    output_sequence: u8,
+}
+
+impl InputDriver for DualSenseInput {
+    fn display_name(&self) -> &'static str {
+        return DUALSENSE_INPUT.display_name;
+    }
+
+    fn min_bt_report_size(&self) -> usize {
+        return 12;
+    }
+
+    fn bt_input_to_universal_gamepad(&mut self, bt_input: &[u8]) -> UniversalGamepad {
+        return _bt_input_to_universal_gamepad(bt_input);
+    }
+
+    /// Byte 8 is the sequence number of the report (byte 7 of the USB report)
+    fn report_sequence(&self, bt_input: &[u8]) -> Option<u8> {
+        return bt_input.get(8).copied();
+    }
+
+    /// Output report `0x31` (bluetooth), layout as in the linux driver `hid-playstation`:
+    /// - byte 1 is the sequence number in the upper 4 bits, byte 2 the tag `0x10`
+    /// - from byte 3 on the same values as the USB output report `0x02` (see `DualSenseOutput::handle_out_report()`)
+    /// - the last 4 bytes are a CRC32 of `0xA2` followed by the report
+    fn bt_output_report(&mut self, rumble: (u8, u8), lightbar: (u8, u8, u8)) -> Option<Vec<u8>> {
+        let mut report: Vec<u8> = vec![0; 78];
+        report[0] = 0x31;
+        report[1] = self.output_sequence << 4;
+        report[2] = 0x10;
+        self.output_sequence = (self.output_sequence + 1) & 0x0F;
+
+        // compatible vibration, haptics select and lightbar control enable
+        report[3] = 0b0000_0011;
+        report[4] = 0b0000_0100;
+        (report[5], report[6]) = rumble;
+        (report[47], report[48], report[49]) = lightbar;
+
+        let crc: u32 = crc32(&[&[0xA2], &report[..74]]);
+        report[74..].copy_from_slice(&crc.to_le_bytes());
+
+        return Some(report);
+    }
+}
+
+/// CRC32 (IEEE 802.3, as used by zip) over all `parts`
+pub fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc: u32 = 0xFFFF_FFFF;
+    for byte in parts.iter().flat_map(|part| part.iter()) {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xEDB8_8320,
+                _ => crc >> 1,
+            };
+        }
+    }
+    return crc ^ 0xFFFF_FFFF;
+}
+
+/// Presents itself to the host as a DualSense connected via USB
+pub struct DualSenseOutput {
+    /// Rumble motor strength (right, left) last requested by the host with output report `0x02`
+    pub rumble: (u8, u8),
+
+    /// Lightbar color (r, g, b) last requested by the host with output report `0x02`
+    pub lightbar: (u8, u8, u8),
+
+    report_counter: u8,
+    packet_sequence: u32,
+
+    /// Reference for the sensor timestamp
+    start: Instant,
+}
+
+impl DualSenseOutput {
+    pub fn new() -> Self {
+        Self {
+            rumble: (0, 0),
+            lightbar: (0, 0, 0),
+            report_counter: 0,
+            packet_sequence: 0,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl OutputPersona for DualSenseOutput {
+    fn display_name(&self) -> &'static str {
+        return DUALSENSE_OUTPUT.display_name;
+    }
+
+    fn gadget(&self) -> &'static UsbGadgetDescriptor {
+        return &DUALSENSE_GADGET;
+    }
+
+    fn universal_gamepad_to_usb_output(&mut self, gamepad: &UniversalGamepad) -> Vec<u8> {
+        self.report_counter = self.report_counter.wrapping_add(1);
+        self.packet_sequence = self.packet_sequence.wrapping_add(1);
+
+        // The real gamepad counts in units of 1/3 µs, the linux driver converts this back to µs
+        let timestamp_us: u128 = (Instant::now() - self.start).as_micros();
+        let counters = ReportCounters {
+            report_counter: self.report_counter,
+            packet_sequence: self.packet_sequence,
+            sensor_timestamp: (timestamp_us * 3) as u32,
+        };
+
+        return _universal_gamepad_to_usb_output(gamepad, counters);
+    }
+
+    /// Output report `0x02` (USB), layout as in the linux driver `hid-playstation`:
+    /// - byte 1 and 2 are flags which of the following values are valid
+    /// - byte 3 and 4 are the right and left rumble motor
+    /// - bytes 45 - 47 are the lightbar color
+    fn handle_out_report(&mut self, report: &[u8]) -> Option<Feedback> {
+        if report.len() < 48 || report[0] != 0x02 {
+            return None;
+        }
+
+        let valid_flag0: u8 = report[1];
+        let valid_flag1: u8 = report[2];
+        let mut changed: bool = false;
+
+        // bit 0 and 1: compatible vibration / haptics select
+        if valid_flag0 & 0b0000_0011 != 0 {
+            self.rumble = (report[3], report[4]);
+            changed = true;
+        }
+
+        // bit 2: lightbar control enable
+        if valid_flag1 & 0b0000_0100 != 0 {
+            self.lightbar = (report[45], report[46], report[47]);
+            changed = true;
+        }
+
+        if changed == false {
+            return None;
+        }
+        return Some((self.rumble, self.lightbar));
+    }
+
+    /// Feature reports the host (e.g. the linux `hid-playstation` driver) requests while setting up the device
+    fn feature_report(&mut self, report_id: u8) -> Option<Vec<u8>> {
+        match report_id {
+            // pairing info, bytes 1 - 6 would be the MAC address of the controller
+            0x09 => {
+                let mut report: Vec<u8> = vec![0; 20];
+                report[0] = 0x09;
+                return Some(report);
+            }
+            // firmware info, an all zero firmware is accepted by the linux driver
+            0x20 => {
+                let mut report: Vec<u8> = vec![0; 64];
+                report[0] = 0x20;
+                return Some(report);
+            }
+            _ => return None,
+        }
+    }
+}
+
+fn _bt_input_to_universal_gamepad(bt_input: &[u8]) -> UniversalGamepad {
     let mut gamepad: UniversalGamepad = UniversalGamepad::nothing_pressed();
     let dpad_byte = 0b00001111 & bt_input[9];
 
@@ -243,124 +418,178 @@ fn _bt_input_to_universal_gamepad(bt_input: &Vec<u8>) -> UniversalGamepad {
     gamepad.buttons.specials = SpecialButtons {
         right: (bt_input[10] & 0b0010_0000 != 0),
         left: (bt_input[10] & 0b0001_0000 != 0),
-        logo: match bt_input[11] {
-            1 => true,
-            _ => false,
-        },
+        logo: (bt_input[11] & 0b0000_0001 != 0),
     };
     gamepad.other.touchpad = Some(Touchpad {
-        x_coord: 0,
-        y_coord: 0,
-        touched: false,
-        pressed: match bt_input[11] {
-            2 => true,
-            _ => false,
-        },
+        contacts: [TouchContact::untouched(), TouchContact::untouched()],
+        pressed: (bt_input[11] & 0b0000_0010 != 0),
     });
 
-    // DUALSENSE.debug_output_bt_input(&gamepad);
+    // Everything below is only part of the full bluetooth report
+    // The byte positions are the same as in the usb report, just shifted by one
+    if bt_input.len() < BT_FULL_REPORT_SIZE {
+        return gamepad;
+    }
 
-    return gamepad;
+    gamepad.other.gyroscope = Some(Gyroscope {
+        x: i16::from_le_bytes([bt_input[17], bt_input[18]]),
+        y: i16::from_le_bytes([bt_input[19], bt_input[20]]),
+        z: i16::from_le_bytes([bt_input[21], bt_input[22]]),
+    });
+    gamepad.other.accelerometer = Some(Accelerometer {
+        x: i16::from_le_bytes([bt_input[23], bt_input[24]]),
+        y: i16::from_le_bytes([bt_input[25], bt_input[26]]),
+        z: i16::from_le_bytes([bt_input[27], bt_input[28]]),
+    });
+    if let Some(touchpad) = &mut gamepad.other.touchpad {
+        touchpad.contacts = [decode_touch_contact(&bt_input[34..38]), decode_touch_contact(&bt_input[38..42])];
+    }
 
-    // maybe bytes 35 and 36 together are left-right
+    // lower nibble is the battery level in 10% steps, upper nibble 0 = discharging, 1 = charging, 2 = full
+    let battery_status: u8 = bt_input[54];
+    gamepad.other.battery = Some(Battery {
+        level_percent: u8::min((battery_status & 0x0F) * 10 + 5, 100),
+        charging: (battery_status >> 4) == 1,
+    });
 
-    // print!("{}", termion::cursor::Goto(1, 1));
+    // debug_output_bt_input(&gamepad);
 
-    // let combined_u16: u16 = (input[35] as u16) << 8 | (input[36] as u16);
+    return gamepad;
+}
+
+/// The bluetooth report `0x31` is 78 bytes long, everything up to the battery status is needed
+const BT_FULL_REPORT_SIZE: usize = 55;
 
-    // adjust which bytes should be visible. For PS Gamepads the first two bytes are just counters
-    // print!("{:05}\t", combined_u16);
+/// One touch point is 4 bytes:
+/// - byte 0: bit 7 is set if the finger is NOT touching, bits 0 - 6 are the id of the touch
+/// - bytes 1 - 3: 12 bit x coordinate, followed by 12 bit y coordinate
+pub fn decode_touch_contact(bytes: &[u8]) -> TouchContact {
+    return TouchContact {
+        touched: (bytes[0] & 0b1000_0000 == 0),
+        id: bytes[0] & 0b0111_1111,
+        x_coord: (bytes[1] as u16) | ((bytes[2] as u16 & 0x0F) << 8),
+        y_coord: ((bytes[2] as u16) >> 4) | ((bytes[3] as u16) << 4),
+    };
+}
 
-    // TODO Battery-State Support (see vec in _universal_gamepad_to_usb_output)
-    // TODO gyroscope support
+/// Inverse of `decode_touch_contact()`
+pub fn encode_touch_contact(contact: &TouchContact) -> [u8; 4] {
+    let not_touched: u8 = if contact.touched { 0 } else { 0b1000_0000 };
 
-    // TODO Touchpad Support
-    // when Byte 34 changes, the touchpad state changed (either now touched or now not touched)
-    //  also this counts up each time the state changes
-    // Touchpad Y Axis is byte 37
-    // Touchpad X Axis is strange, (byte 35 or 36) probably consists of multiple bytes
-    //   if only touched, the value is somewhat correct (0 is left, 255 is right)
-    //   if you drag the finger across, this value overflows 4x on the whole way (l->r)
+    return [
+        not_touched | (contact.id & 0b0111_1111),
+        (contact.x_coord & 0xFF) as u8,
+        ((contact.x_coord >> 8) & 0x0F) as u8 | ((contact.y_coord & 0x0F) << 4) as u8,
+        ((contact.y_coord >> 4) & 0xFF) as u8,
+    ];
 }
 
-fn _universal_gamepad_to_usb_output(gamepad: &UniversalGamepad) -> Vec<u8> {
-    let buttons_and_dpad: u8 = {
-        let mut byte: u8 = 0;
+/// Values of the usb report that change with every report, independent of the input
+struct ReportCounters {
+    /// increases by 1 with every report
+    report_counter: u8,
 
-        if gamepad.buttons.main.upper {
-            byte += 0b1000_0000
-        };
-        if gamepad.buttons.main.right {
-            byte += 0b0100_0000
-        };
-        if gamepad.buttons.main.lower {
-            byte += 0b0010_0000
-        };
-        if gamepad.buttons.main.left {
-            byte += 0b0001_0000
-        };
+    /// increases by 1 with every report, but does not overflow after 255
+    packet_sequence: u32,
 
-        // The DPAD Buttons are not like above
-        // Up is 0, right 2, down 4, left 6
-        // If right and down are pressed at the same time its 3
-        // If no buttons are pressed, the value is 0bxxxx_1000
-
-        if gamepad.buttons.dpad.up && gamepad.buttons.dpad.right {
-            byte |= 1
-        } else if gamepad.buttons.dpad.right && gamepad.buttons.dpad.down {
-            byte |= 3
-        } else if gamepad.buttons.dpad.down && gamepad.buttons.dpad.left {
-            byte |= 5
-        } else if gamepad.buttons.dpad.left && gamepad.buttons.dpad.up {
-            byte |= 7
-        } else if gamepad.buttons.dpad.up {
-            byte |= 0
-        } else if gamepad.buttons.dpad.right {
-            byte |= 2
-        } else if gamepad.buttons.dpad.down {
-            byte |= 4
-        } else if gamepad.buttons.dpad.left {
-            byte |= 6
-        } else {
-            byte |= 8
-        }
+    /// IMU timestamp in units of 1/3 µs, overflows after ~24 minutes
+    sensor_timestamp: u32,
+}
 
-        byte
+/// Byte 8 of the usb report: the main buttons in the upper and the D-pad in the lower 4 bits
+///
+/// The DualShock 4 uses the same layout
+pub fn main_buttons_and_dpad(gamepad: &UniversalGamepad) -> u8 {
+    let mut byte: u8 = 0;
+
+    if gamepad.buttons.main.upper {
+        byte += 0b1000_0000
+    };
+    if gamepad.buttons.main.right {
+        byte += 0b0100_0000
+    };
+    if gamepad.buttons.main.lower {
+        byte += 0b0010_0000
+    };
+    if gamepad.buttons.main.left {
+        byte += 0b0001_0000
     };
 
-    let remaining: u8 = {
-        let mut byte: u8 = 0;
+    // The DPAD Buttons are not like above
+    // Up is 0, right 2, down 4, left 6
+    // If right and down are pressed at the same time its 3
+    // If no buttons are pressed, the value is 0bxxxx_1000
+    // Opposite directions cancel each other out, use `SocdCleaning` for other behaviours
 
-        if gamepad.buttons.bumpers.left {
-            byte += 0x1;
-        }
-        if gamepad.buttons.bumpers.right {
-            byte += 0x2;
-        }
-        if gamepad.triggers.left != 0 {
-            byte += 0x4;
-        }
-        if gamepad.triggers.right != 0 {
-            byte += 0x8;
-        }
-        // ^ this is max 0x0F if all are pressed
+    let dpad = &gamepad.buttons.dpad;
+    let up: bool = dpad.up && dpad.down == false;
+    let down: bool = dpad.down && dpad.up == false;
+    let left: bool = dpad.left && dpad.right == false;
+    let right: bool = dpad.right && dpad.left == false;
 
-        if gamepad.buttons.specials.left {
-            byte += 0x10;
-        }
-        if gamepad.buttons.specials.right {
-            byte += 0x20;
-        }
-        if gamepad.sticks.left.pressed {
-            byte += 0x40;
-        }
-        if gamepad.sticks.right.pressed {
-            byte += 0x80;
-        }
-        // ^ this is max 0xF0 if all are pressed
+    if up && right {
+        byte |= 1
+    } else if right && down {
+        byte |= 3
+    } else if down && left {
+        byte |= 5
+    } else if left && up {
+        byte |= 7
+    } else if up {
+        byte |= 0
+    } else if right {
+        byte |= 2
+    } else if down {
+        byte |= 4
+    } else if left {
+        byte |= 6
+    } else {
+        byte |= 8
+    }
 
-        byte
-    };
+    return byte;
+}
+
+/// Byte 9 of the usb report: bumpers, triggers pressed, special buttons and stick presses
+///
+/// The DualShock 4 uses the same layout
+pub fn shoulder_and_special_buttons(gamepad: &UniversalGamepad) -> u8 {
+    let mut byte: u8 = 0;
+
+    if gamepad.buttons.bumpers.left {
+        byte += 0x1;
+    }
+    if gamepad.buttons.bumpers.right {
+        byte += 0x2;
+    }
+    if gamepad.triggers.left != 0 {
+        byte += 0x4;
+    }
+    if gamepad.triggers.right != 0 {
+        byte += 0x8;
+    }
+    // ^ this is max 0x0F if all are pressed
+
+    if gamepad.buttons.specials.left {
+        byte += 0x10;
+    }
+    if gamepad.buttons.specials.right {
+        byte += 0x20;
+    }
+    if gamepad.sticks.left.pressed {
+        byte += 0x40;
+    }
+    if gamepad.sticks.right.pressed {
+        byte += 0x80;
+    }
+    // ^ this is max 0xF0 if all are pressed
+
+    return byte;
+}
+
+fn _universal_gamepad_to_usb_output(gamepad: &UniversalGamepad, counters: ReportCounters) -> Vec<u8> {
+    let buttons_and_dpad: u8 = main_buttons_and_dpad(gamepad);
+    let remaining: u8 = shoulder_and_special_buttons(gamepad);
 
     let logo_touchpad: u8 = {
         let touchpad_pressed: bool = match &gamepad.other.touchpad {
@@ -379,74 +608,55 @@ fn _universal_gamepad_to_usb_output(gamepad: &UniversalGamepad) -> Vec<u8> {
         }
     };
 
-    let out: Vec<u8> = vec![
-        0x01,
-        gamepad.sticks.left.x,
-        gamepad.sticks.left.y,
-        gamepad.sticks.right.x,
-        gamepad.sticks.right.y,
-        gamepad.triggers.left,
-        gamepad.triggers.right,
-        0,                // counter
-        buttons_and_dpad, // Buttons and DPad
-        remaining,        // Special Buttons, Bumpers, Triggers and Sticks (only WHAT is pressed, for triggers not value)
-        logo_touchpad,    // Logo / Touchpad
-        0,                // always 0
-        0,                // counter
-        0,                // seconds ?
-        0xee,             // might be charging state (in %)
-        0xad,             // might be charging state (in %)
-        0x00,             // gyroscope here (seems to be relative, not absolute)
-        0x00,             // gyroscope here (seems to be relative, not absolute)
-        0xff,             // gyroscope here (seems to be relative, not absolute)
-        0xff,             // gyroscope here (seems to be relative, not absolute)
-        0x02,             // gyroscope here (seems to be relative, not absolute)
-        0x00,             // gyroscope here (seems to be relative, not absolute)
-        0x06,             // gyroscope here (seems to be relative, not absolute)
-        0x00,             // gyroscope here (seems to be relative, not absolute)
-        0x81,             // gyroscope here (seems to be relative, not absolute)
-        0x1f,             // gyroscope here (seems to be relative, not absolute)
-        0x07,             // gyroscope here (seems to be relative, not absolute)
-        0x06,             // gyroscope here (seems to be relative, not absolute)
-        0x46,             // gyroscope here (seems to be relative, not absolute)
-        0x66,             // gyroscope here (seems to be relative, not absolute)
-        0,                // this is a really slow counter (goes up every ~10s)
-        0x00,             // ??
-        0x14,             // ??
-        0x80,             // ??
-        0x00,             // ??
-        0x00,             // ??
-        0x00,             // ??
-        0x80,             // ??
-        0x00,             // ??
-        0x00,             // ??
-        0x00,             // ??
-        0x00,             // ??
-        0x09,             // ??
-        0x09,             // ??
-        0x00,             // ??
-        0x00,             // ??
-        0x00,             // ??
-        0x00,             // ??
-        0x00,             // ??
-        0xe3,             // random?
-        0x79,             // random?
-        0xab,             // random?
-        0x00,             // slow counter
-        0x17,             // constant?
-        0x08,             // constant?
-        0x00,             // constant?
-        0x5b,             // random?
-        0x7f,             // random?
-        0xef,             // random?
-        0x9c,             // random?
-        0xac,             // random?
-        0x03,             // random?
-        0x92,             // random?
-        0x30,             // random?
-    ];
+    let mut out: Vec<u8> = vec![0; 64];
+
+    out[0] = 0x01; // report id
+    out[1] = gamepad.sticks.left.x;
+    out[2] = gamepad.sticks.left.y;
+    out[3] = gamepad.sticks.right.x;
+    out[4] = gamepad.sticks.right.y;
+    out[5] = gamepad.triggers.left;
+    out[6] = gamepad.triggers.right;
+    out[7] = counters.report_counter;
+    out[8] = buttons_and_dpad; // Buttons and DPad
+    out[9] = remaining; // Special Buttons, Bumpers, Triggers and Sticks (only WHAT is pressed, for triggers not value)
+    out[10] = logo_touchpad; // Logo / Touchpad
+                             // out[11] is always 0
+    out[12..16].copy_from_slice(&counters.packet_sequence.to_le_bytes());
 
-    let expected_length = DUALSENSE.gadget.functions_hid.report_length as usize;
+    // IMU values are relative, a resting gamepad has an acceleration of ~8000 on the axis pointing down
+    if let Some(gyroscope) = &gamepad.other.gyroscope {
+        out[16..18].copy_from_slice(&gyroscope.x.to_le_bytes());
+        out[18..20].copy_from_slice(&gyroscope.y.to_le_bytes());
+        out[20..22].copy_from_slice(&gyroscope.z.to_le_bytes());
+    }
+    if let Some(accelerometer) = &gamepad.other.accelerometer {
+        out[22..24].copy_from_slice(&accelerometer.x.to_le_bytes());
+        out[24..26].copy_from_slice(&accelerometer.y.to_le_bytes());
+        out[26..28].copy_from_slice(&accelerometer.z.to_le_bytes());
+    }
+    out[28..32].copy_from_slice(&counters.sensor_timestamp.to_le_bytes());
+    // out[32] is the temperature
+
+    // all zeros would be a finger touching the upper left corner
+    let untouched: TouchContact = TouchContact::untouched();
+    let (first_contact, second_contact) = match &gamepad.other.touchpad {
+        Some(touchpad) => (&touchpad.contacts[0], &touchpad.contacts[1]),
+        None => (&untouched, &untouched),
+    };
+    out[33..37].copy_from_slice(&encode_touch_contact(first_contact));
+    out[37..41].copy_from_slice(&encode_touch_contact(second_contact));
+
+    // lower nibble is the battery level in 10% steps, upper nibble 0 = discharging, 1 = charging, 2 = full
+    out[53] = match &gamepad.other.battery {
+        Some(battery) if battery.charging && battery.level_percent >= 100 => 0x20 | 10,
+        Some(battery) if battery.charging => 0x10 | (battery.level_percent / 10),
+        Some(battery) => battery.level_percent / 10,
+        // An input gamepad without battery is powered, like the gadget itself
+        None => 0x20 | 10,
+    };
+
+    let expected_length = DUALSENSE_GADGET.functions_hid.report_length as usize;
     let vec_length = out.len();
     assert!(
         expected_length == vec_length,
@@ -455,3 +665,109 @@ fn _universal_gamepad_to_usb_output(gamepad: &UniversalGamepad) -> Vec<u8> {
 
     return out;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::usb_gamepad::{HidgFile, HostFeedback};
+    use std::path::PathBuf;
+
+    #[test]
+    fn usb_report_counters_advance() {
+        let mut persona = DualSenseOutput::new();
+        let gamepad = UniversalGamepad::nothing_pressed();
+
+        let first: Vec<u8> = persona.universal_gamepad_to_usb_output(&gamepad);
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        let second: Vec<u8> = persona.universal_gamepad_to_usb_output(&gamepad);
+
+        assert_eq!(first.len(), 64);
+        assert_eq!(second[7], first[7].wrapping_add(1));
+
+        let first_sequence = u32::from_le_bytes([first[12], first[13], first[14], first[15]]);
+        let second_sequence = u32::from_le_bytes([second[12], second[13], second[14], second[15]]);
+        assert_eq!(second_sequence, first_sequence + 1);
+
+        let first_timestamp = u32::from_le_bytes([first[28], first[29], first[30], first[31]]);
+        let second_timestamp = u32::from_le_bytes([second[28], second[29], second[30], second[31]]);
+        assert!(second_timestamp - first_timestamp >= 3000, "1ms are 3000 sensor ticks");
+    }
+
+    #[test]
+    fn bt_output_report_is_checksummed() {
+        assert_eq!(crc32(&[b"123", b"456789"]), 0xCBF4_3926, "check value of CRC32");
+
+        let mut input = DualSenseInput { output_sequence: 0 };
+        let first: Vec<u8> = input.bt_output_report((0, 255), (255, 128, 0)).unwrap();
+        let second: Vec<u8> = input.bt_output_report((0, 0), (0, 0, 255)).unwrap();
+
+        assert_eq!(first.len(), 78);
+        assert_eq!((first[6], first[47], first[48]), (255, 255, 128));
+        assert_eq!((first[1], second[1]), (0x00, 0x10));
+        let crc = u32::from_le_bytes([first[74], first[75], first[76], first[77]]);
+        assert_eq!(crc, crc32(&[&[0xA2], &first[..74]]));
+    }
+
+    #[test]
+    fn host_rumble_and_lightbar_reach_the_gamepad() {
+        // what the host writes into /dev/hidg0: rumble (right 40, left 200) and a purple lightbar
+        let mut out_report: Vec<u8> = vec![0; 48];
+        out_report[0] = 0x02;
+        out_report[1] = 0b0000_0011;
+        out_report[2] = 0b0000_0100;
+        (out_report[3], out_report[4]) = (40, 200);
+        (out_report[45], out_report[46], out_report[47]) = (128, 0, 255);
+        let path: PathBuf = std::env::temp_dir().join(format!("gamepad-bridge-hidg-{}", std::process::id()));
+        std::fs::write(&path, &out_report).unwrap();
+
+        let mut hidg = HidgFile::open(path.to_str().unwrap());
+        let host_feedback = HostFeedback::new();
+        let mut persona: Box<dyn OutputPersona> = Box::new(DualSenseOutput::new());
+        persona.forward_out_reports(&mut hidg, &host_feedback);
+        std::fs::remove_file(&path).unwrap();
+
+        let mut seen_generation: u32 = 0;
+        let (rumble, lightbar) = host_feedback.changed_since(&mut seen_generation).expect("the report was passed on");
+        assert_eq!(host_feedback.changed_since(&mut seen_generation), None);
+
+        let mut input = DualSenseInput { output_sequence: 0 };
+        let bt_report: Vec<u8> = input.bt_output_report(rumble, lightbar).unwrap();
+        assert_eq!(bt_report[0], 0x31);
+        assert_eq!((bt_report[5], bt_report[6]), (40, 200));
+        assert_eq!((bt_report[47], bt_report[48], bt_report[49]), (128, 0, 255));
+        let crc = u32::from_le_bytes([bt_report[74], bt_report[75], bt_report[76], bt_report[77]]);
+        assert_eq!(crc, crc32(&[&[0xA2], &bt_report[..74]]));
+    }
+
+    #[test]
+    fn touch_contact_survives_encoding() {
+        let contact = TouchContact {
+            touched: true,
+            id: 42,
+            x_coord: 1919,
+            y_coord: 1079,
+        };
+
+        let decoded: TouchContact = decode_touch_contact(&encode_touch_contact(&contact));
+
+        assert!(decoded.touched);
+        assert_eq!(decoded.id, 42);
+        assert_eq!(decoded.x_coord, 1919);
+        assert_eq!(decoded.y_coord, 1079);
+        assert_eq!(encode_touch_contact(&TouchContact::untouched())[0], 0b1000_0000);
+    }
+
+    #[test]
+    fn opposite_dpad_directions_cancel_out() {
+        let mut persona = DualSenseOutput::new();
+        let mut gamepad = UniversalGamepad::nothing_pressed();
+
+        gamepad.buttons.dpad.left = true;
+        gamepad.buttons.dpad.right = true;
+        gamepad.buttons.dpad.up = true;
+        assert_eq!(persona.universal_gamepad_to_usb_output(&gamepad)[8] & 0x0F, 0, "only up");
+
+        gamepad.buttons.dpad.down = true;
+        assert_eq!(persona.universal_gamepad_to_usb_output(&gamepad)[8] & 0x0F, 8, "neutral");
+    }
+}
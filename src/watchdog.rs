@@ -0,0 +1,150 @@
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, ConfigError, ConfigSection};
+use crate::latest_state::{LatestStateError, Subscriber};
+use crate::status;
+use crate::universal_gamepad::UniversalGamepad;
+
+/// Bluetooth gamepads send at least every few ms, so this is a lost connection and not a pause
+pub const DEFAULT_WATCHDOG_TIMEOUT: Duration = Duration::from_millis(250);
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct WatchdogSettings {
+    /// `None` waits for input forever, the host keeps the last state meanwhile
+    pub timeout: Option<Duration>,
+}
+
+impl WatchdogSettings {
+    pub fn default() -> Self {
+        Self {
+            timeout: Some(DEFAULT_WATCHDOG_TIMEOUT),
+        }
+    }
+
+    /// Reads the `[watchdog]` section:
+    /// - `enabled = false`
+    /// - `timeout = <ms>`
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        let mut settings = Self::default();
+        let section: &ConfigSection = match config.section("watchdog", "") {
+            Some(section) => section,
+            None => return Ok(settings),
+        };
+
+        if let Some(timeout) = section.get_parsed::<u64>("timeout")? {
+            if timeout == 0 {
+                return Err(section.error("timeout has to be at least 1 ms".to_string()));
+            }
+            settings.timeout = Some(Duration::from_millis(timeout));
+        }
+        if section.get_parsed::<bool>("enabled")? == Some(false) {
+            settings.timeout = None;
+        }
+
+        return Ok(settings);
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum WatchdogEvent {
+    Input(UniversalGamepad),
+
+    /// Nothing arrived within the timeout, the host should get a neutral report now. Returned once until input is back
+    Lost,
+
+    /// The deadline passed without input, but the input is not lost (yet)
+    Idle,
+
+    /// The bridge is shutting down
+    Closed,
+}
+
+/// Sits between the input threads and the output thread, notices when input stops arriving
+///
+/// Shown on the status interface as `gamepad.input.connected`
+pub struct InputWatchdog {
+    timeout: Option<Duration>,
+    is_lost: bool,
+    last_input: Instant,
+}
+
+impl InputWatchdog {
+    pub fn new(settings: &WatchdogSettings) -> Self {
+        status::set("gamepad.input.connected", true.to_string());
+
+        Self {
+            timeout: settings.timeout,
+            is_lost: false,
+            last_input: Instant::now(),
+        }
+    }
+
+    /// Waits for the next gamepad from the input threads
+    pub fn recv(&mut self, subscriber: &mut Subscriber<UniversalGamepad>) -> WatchdogEvent {
+        return self.recv_until(subscriber, None);
+    }
+
+    /// Like `recv()`, but returns `WatchdogEvent::Idle` at the `deadline`
+    pub fn recv_until(&mut self, subscriber: &mut Subscriber<UniversalGamepad>, deadline: Option<Instant>) -> WatchdogEvent {
+        // once lost, there is nothing to watch until the input is back
+        let lost_at: Option<Instant> = match self.is_lost {
+            true => None,
+            false => self.timeout.map(|timeout| self.last_input + timeout),
+        };
+        let wake_up: Option<Instant> = match (deadline, lost_at) {
+            (Some(deadline), Some(lost_at)) => Some(deadline.min(lost_at)),
+            (deadline, lost_at) => deadline.or(lost_at),
+        };
+
+        match subscriber.recv_deadline(wake_up) {
+            Ok(gamepad) => {
+                self.last_input = Instant::now();
+                if self.is_lost {
+                    self.is_lost = false;
+                    println!("Input is back");
+                    status::set("gamepad.input.connected", true.to_string());
+                }
+                return WatchdogEvent::Input(gamepad);
+            }
+            Err(LatestStateError::Timeout) if lost_at.is_some_and(|lost_at| Instant::now() >= lost_at) => {
+                self.is_lost = true;
+                println!("No input for {:?}, releasing everything on the host", self.timeout.unwrap_or_default());
+                status::set("gamepad.input.connected", false.to_string());
+                return WatchdogEvent::Lost;
+            }
+            Err(LatestStateError::Timeout) => return WatchdogEvent::Idle,
+            Err(LatestStateError::Closed) => return WatchdogEvent::Closed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::latest_state::latest_state;
+
+    #[test]
+    fn lost_input_is_reported_once_and_recovers() {
+        let (state, mut subscriber) = latest_state(UniversalGamepad::nothing_pressed(), 1);
+        let mut publisher = state.publisher().unwrap();
+        let mut watchdog = InputWatchdog::new(&WatchdogSettings {
+            timeout: Some(Duration::from_millis(10)),
+        });
+
+        publisher.publish(UniversalGamepad::nothing_pressed());
+        assert_eq!(watchdog.recv(&mut subscriber), WatchdogEvent::Input(UniversalGamepad::nothing_pressed()));
+        assert_eq!(watchdog.recv(&mut subscriber), WatchdogEvent::Lost);
+
+        // the next timeout is not reported again, the watchdog waits for the reconnect
+        let reconnect = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(30));
+            publisher.publish(UniversalGamepad::nothing_pressed());
+        });
+        assert_eq!(watchdog.recv(&mut subscriber), WatchdogEvent::Input(UniversalGamepad::nothing_pressed()));
+        reconnect.join().unwrap();
+
+        state.close();
+
+        assert_eq!(watchdog.recv(&mut subscriber), WatchdogEvent::Closed);
+    }
+}